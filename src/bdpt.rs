@@ -0,0 +1,745 @@
+//! Bidirectional path tracing, connecting every vertex of a path from the camera with every
+//! vertex of a path from an emissive object, weighted by multiple importance sampling.
+//! The environment and the sun are only reached from the camera, by hitting or sampling them.
+//! https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Bidirectional_Path_Tracing
+
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::material::{scatter_glass, Ior};
+use crate::prelude::*;
+use crate::ray::{self, Ray};
+use crate::sampling::{self, power_heuristic};
+use crate::scene::Scene;
+use crate::texture::SurfacePoint;
+use crate::vector::{Point, Vector};
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+fn random2() -> [float; 2] {
+    [sampling::random(), sampling::random()]
+}
+
+fn random3() -> [float; 3] {
+    [sampling::random(), sampling::random(), sampling::random()]
+}
+
+/// Image that light paths add to from any thread, at the pixels they are seen at
+#[derive(Debug)]
+pub struct Splats {
+    width: u32,
+    height: u32,
+    /// Bits of the red, green and blue sums
+    pixels: Vec<[AtomicU32; 3]>,
+}
+
+impl Splats {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: (0..width * height).map(|_| Default::default()).collect(),
+        }
+    }
+
+    /// Adds to the pixel containing film position `(x, y)`
+    pub fn add(&self, x: float, y: float, color: Color) {
+        if x < 0.0 || y < 0.0 || x >= self.width as float || y >= self.height as float {
+            return;
+        }
+        let pixel = &self.pixels[(y as u32 * self.width + x as u32) as usize];
+        for (sum, value) in pixel.iter().zip([color.r, color.g, color.b]) {
+            let _ = sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((float::from_bits(bits) + value).to_bits())
+            });
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        let [r, g, b] = &self.pixels[(y * self.width + x) as usize];
+        Color {
+            r: float::from_bits(r.load(Ordering::Relaxed)),
+            g: float::from_bits(g.load(Ordering::Relaxed)),
+            b: float::from_bits(b.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for sum in self.pixels.iter_mut().flatten() {
+            *sum.get_mut() = (0.0 as float).to_bits();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Camera,
+    /// Start of a light subpath
+    Light,
+    Surface,
+}
+
+/// What flows along a subpath, which matters as shading normals make scattering asymmetric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// From the lights, traced from the camera
+    Radiance,
+    /// From the camera, traced from the lights
+    Importance,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Vertex {
+    kind: Kind,
+    point: Point,
+    /// Geometric normal, on the side the subpath arrives from or the side a light emits to.
+    /// Zero for the camera.
+    normal: Vector,
+    shading_normal: Vector,
+    error: Vector,
+    /// Unit vector towards the previous vertex of the subpath
+    wo: Vector,
+    /// Object and mesh triangle the vertex is on, for finding its light
+    object: usize,
+    primitive: usize,
+    /// Throughput of the subpath up to the vertex
+    beta: Color,
+    diffuse: Color,
+    specular: Color,
+    /// Radiance emitted towards `wo`, or from the front of a light
+    emission: Color,
+    /// Whether the subpath continued by mirror reflection or through glass
+    delta: bool,
+    /// Area densities of sampling the vertex from the previous vertex and from the next one
+    pdf_fwd: float,
+    pdf_rev: float,
+}
+
+impl Vertex {
+    fn new(kind: Kind, point: Point, normal: Vector) -> Self {
+        Self {
+            kind,
+            point,
+            normal,
+            shading_normal: normal,
+            error: Vector::ZERO,
+            wo: Vector::ZERO,
+            object: 0,
+            primitive: 0,
+            beta: Color::BLACK,
+            diffuse: Color::BLACK,
+            specular: Color::BLACK,
+            emission: Color::BLACK,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        self.kind != Kind::Camera
+    }
+
+    /// Probability of continuing by mirror reflection rather than diffusely
+    fn p_specular(&self) -> float {
+        let diffuse = self.diffuse.luminance();
+        let specular = self.specular.luminance();
+        if diffuse + specular > 0.0 {
+            specular / (diffuse + specular)
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether a connection can end at the vertex, which needs a diffuse lobe on surfaces
+    fn connectible(&self) -> bool {
+        self.kind != Kind::Surface || self.diffuse.luminance() > 0.0
+    }
+
+    /// Diffuse BSDF of a surface vertex for scattering between `wo` and `wi`
+    fn f(&self, wi: Vector, mode: Mode) -> Color {
+        if wi.dot(self.shading_normal) <= 0.0 || wi.dot(self.normal) <= 0.0 {
+            return Color::BLACK;
+        }
+        let f = self.diffuse * (1.0 / PI);
+        match mode {
+            Mode::Radiance => f,
+            Mode::Importance => f * self.shading_correction(wi),
+        }
+    }
+
+    /// Factor keeping light paths consistent with camera paths where shading normals differ
+    /// from geometric ones
+    /// https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/The_Path-Space_Measurement_Equation#x3-Non-SymmetryDuetoShadingNormals
+    fn shading_correction(&self, wi: Vector) -> float {
+        let numerator = self.wo.dot(self.shading_normal) * wi.dot(self.normal);
+        let denominator = self.wo.dot(self.normal) * wi.dot(self.shading_normal);
+        if denominator == 0.0 {
+            0.0
+        } else {
+            (numerator / denominator).abs()
+        }
+    }
+
+    /// Solid angle density of the diffuse lobe choosing `wi` when arriving from `wo`
+    fn bsdf_pdf(&self, wo: Vector, wi: Vector) -> float {
+        let cos_o = wo.dot(self.shading_normal);
+        let cos_i = wi.dot(self.shading_normal);
+        if cos_o * cos_i <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - self.p_specular()) * cos_i.abs() / PI
+    }
+
+    /// Area density at `next` from a solid angle density at the vertex
+    fn convert_density(&self, pdf: float, next: &Vertex) -> float {
+        let to_next = next.point - self.point;
+        let distance2 = to_next.len2();
+        if distance2 == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf / distance2;
+        if next.on_surface() {
+            pdf *= next.normal.dot(to_next).abs() / distance2.sqrt();
+        }
+        pdf
+    }
+
+    /// Area density of the vertex choosing `next`, having been reached from `previous`
+    fn pdf(&self, camera: &Camera, previous: Option<&Vertex>, next: &Vertex) -> float {
+        let to_next = (next.point - self.point).normalized();
+        let pdf = match self.kind {
+            Kind::Light => return self.pdf_light(next),
+            Kind::Camera => camera.pdf(to_next),
+            Kind::Surface => {
+                let wo = previous.map_or(self.wo, |p| (p.point - self.point).normalized());
+                self.bsdf_pdf(wo, to_next)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Area density of light leaving an emissive vertex towards `next`
+    fn pdf_light(&self, next: &Vertex) -> float {
+        let to_next = next.point - self.point;
+        let distance2 = to_next.len2();
+        if distance2 == 0.0 {
+            return 0.0;
+        }
+        let direction = to_next * (1.0 / distance2.sqrt());
+        let cos = direction.dot(self.normal);
+        if cos <= 0.0 {
+            return 0.0;
+        }
+        let mut pdf = cos / PI / distance2;
+        if next.on_surface() {
+            pdf *= next.normal.dot(direction).abs();
+        }
+        pdf
+    }
+}
+
+/// Radiance arriving at a uniformly chosen position in pixel `(x, y)`, from one camera subpath
+/// connected to one light subpath, for paths of up to `max_depth` bounces.
+/// Light reaching the film through light subpaths is added to `splats`, and like the returned
+/// radiance it has to be divided by the number of samples per pixel.
+pub fn sample(
+    scene: &Scene,
+    camera: &Camera,
+    x: u32,
+    y: u32,
+    max_depth: usize,
+    splats: &Splats,
+) -> Color {
+    let mut camera_path = Vec::with_capacity(max_depth + 2);
+    let mut radiance = camera_subpath(
+        scene,
+        camera,
+        x as float + sampling::random::<float>(),
+        y as float + sampling::random::<float>(),
+        max_depth,
+        &mut camera_path,
+    );
+    let mut light_path = Vec::with_capacity(max_depth + 1);
+    light_subpath(scene, max_depth, &mut light_path);
+
+    for vertex in camera_path.iter().skip(1).take(max_depth) {
+        radiance = radiance + sample_distant_lights(scene, vertex);
+    }
+
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len() {
+            let depth = s + t;
+            if depth < 2 || depth - 2 > max_depth || (s == 1 && t == 1) {
+                continue;
+            }
+            let contribution = connect(scene, camera, &light_path, &camera_path, s, t);
+            match contribution.raster {
+                Some((x, y)) => splats.add(x, y, contribution.color),
+                None => radiance = radiance + contribution.color,
+            }
+        }
+    }
+    radiance
+}
+
+/// Fills `path` with the camera and the surfaces a ray through film position `(x, y)` reaches,
+/// returning the environment and sun light where it leaves the scene
+fn camera_subpath(
+    scene: &Scene,
+    camera: &Camera,
+    x: float,
+    y: float,
+    max_depth: usize,
+    path: &mut Vec<Vertex>,
+) -> Color {
+    let (origin, direction, _) = camera.ray(x, y);
+    let mut start = Vertex::new(Kind::Camera, origin, Vector::ZERO);
+    start.beta = Color::WHITE;
+    path.push(start);
+    random_walk(
+        scene,
+        Ray::new(origin, direction),
+        Color::WHITE,
+        camera.pdf(direction),
+        max_depth + 2,
+        Mode::Radiance,
+        path,
+    )
+}
+
+/// Fills `path` with a point on an emissive object and the surfaces its light reaches
+fn light_subpath(scene: &Scene, max_depth: usize, path: &mut Vec<Vertex>) {
+    let emitter = match scene.lights.sample_emitter(random3()) {
+        Some(emitter) => emitter,
+        None => return,
+    };
+    let (direction, pdf_direction) = sampling::cosine_hemisphere(emitter.normal, random2());
+    if pdf_direction <= 0.0 {
+        return;
+    }
+
+    let mut start = Vertex::new(Kind::Light, emitter.point, emitter.normal);
+    start.object = emitter.object;
+    start.primitive = emitter.primitive;
+    start.emission = emitter.radiance;
+    start.beta = emitter.radiance * (1.0 / emitter.pdf);
+    start.pdf_fwd = emitter.pdf;
+    // Sampled points are not as exact as intersections
+    start.error = emitter.point.abs() * ray::gamma(16);
+    path.push(start);
+
+    let cos = direction.dot(emitter.normal);
+    let beta = emitter.radiance * (cos / (emitter.pdf * pdf_direction));
+    random_walk(
+        scene,
+        Ray::spawn(emitter.point, start.error, emitter.normal, direction),
+        beta,
+        pdf_direction,
+        max_depth + 1,
+        Mode::Importance,
+        path,
+    );
+}
+
+/// Extends a subpath until it has `max_vertices`, leaves the scene or is absorbed.
+/// Returns the environment and sun light reaching camera subpaths that leave the scene.
+fn random_walk(
+    scene: &Scene,
+    mut ray: Ray,
+    mut beta: Color,
+    pdf: float,
+    max_vertices: usize,
+    mode: Mode,
+    path: &mut Vec<Vertex>,
+) -> Color {
+    let mut pdf_fwd = pdf;
+    // Density of the last direction for weighting distant lights against sampling them,
+    // zero for camera rays and after mirror bounces
+    let mut bsdf_pdf = 0.0;
+
+    while path.len() < max_vertices {
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => {
+                if mode == Mode::Radiance {
+                    return beta * distant_light(scene, ray.direction, bsdf_pdf);
+                }
+                break;
+            }
+        };
+        let surface = SurfacePoint {
+            point: hit.point,
+            normal: hit.shading_normal,
+            uv: hit.uv,
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: hit.color,
+        };
+        let material = scene.material(&hit);
+        let (ambient, diffuse, specular) = match material {
+            Some(material) => (
+                material.ambient.color(&surface),
+                material.diffuse.color(&surface),
+                material.specular.color(&surface),
+            ),
+            // Default material
+            None => (Color::BLACK, Color::WHITE, Color::BLACK),
+        };
+        let glass = material.and_then(|material| material.glass);
+
+        let previous = path.len() - 1;
+        let mut vertex = Vertex {
+            shading_normal: hit.shading_normal,
+            error: hit.error,
+            wo: -ray.direction,
+            object: hit.object,
+            primitive: hit.primitive,
+            beta,
+            diffuse,
+            specular,
+            emission: if hit.front_face {
+                ambient
+            } else {
+                Color::BLACK
+            },
+            ..Vertex::new(Kind::Surface, hit.point, hit.normal)
+        };
+        vertex.pdf_fwd = path[previous].convert_density(pdf_fwd, &vertex);
+        path.push(vertex);
+        if path.len() == max_vertices
+            || glass.is_none() && diffuse.luminance() + specular.luminance() <= 0.0
+        {
+            break;
+        }
+
+        let p_specular = vertex.p_specular();
+        let (direction, pdf_rev, refracted) = if let Some(ior) = glass {
+            // Glass reflects or refracts by the Fresnel reflectance, which cancels out of the
+            // throughput, and like a mirror it cannot be connected to
+            let n = ior.at(Ior::REFERENCE);
+            let eta = if hit.front_face { n } else { 1.0 / n };
+            let (direction, refracted) =
+                scatter_glass(ray.direction, hit.shading_normal, eta, sampling::random());
+            pdf_fwd = 0.0;
+            bsdf_pdf = 0.0;
+            path[previous + 1].delta = true;
+            (direction, 0.0, refracted)
+        } else if sampling::random::<float>() < p_specular {
+            beta = beta * specular / p_specular;
+            pdf_fwd = 0.0;
+            bsdf_pdf = 0.0;
+            path[previous + 1].delta = true;
+            (ray.direction.reflect(hit.shading_normal), 0.0, false)
+        } else {
+            let (direction, pdf) = sampling::cosine_hemisphere(hit.shading_normal, random2());
+            beta = beta * diffuse / (1.0 - p_specular);
+            pdf_fwd = (1.0 - p_specular) * pdf;
+            bsdf_pdf = pdf_fwd;
+            (direction, vertex.bsdf_pdf(direction, vertex.wo), false)
+        };
+        // Directions to the wrong side of the surface would leak through it
+        let side = if refracted { -hit.normal } else { hit.normal };
+        if direction.dot(side) <= 0.0 {
+            break;
+        }
+        if mode == Mode::Importance {
+            beta = beta * vertex.shading_correction(direction);
+        }
+        path[previous].pdf_rev = vertex.convert_density(pdf_rev, &path[previous]);
+        ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+    }
+    Color::BLACK
+}
+
+/// Environment and sun light along a direction leaving the scene, weighted against sampling
+/// them directly at the last bounce
+fn distant_light(scene: &Scene, direction: Vector, bsdf_pdf: float) -> Color {
+    let weight = |light_pdf: float| {
+        if bsdf_pdf > 0.0 {
+            power_heuristic(1, bsdf_pdf, 1, light_pdf)
+        } else {
+            1.0
+        }
+    };
+    let mut radiance =
+        scene.environment.radiance(direction) * weight(scene.environment.pdf(direction));
+    if let Some(sun) = scene.sun {
+        radiance = radiance + sun.radiance(direction) * weight(sun.pdf(direction));
+    }
+    radiance
+}
+
+/// Environment and sun light sampled at a camera subpath vertex
+fn sample_distant_lights(scene: &Scene, vertex: &Vertex) -> Color {
+    if !vertex.connectible() {
+        return Color::BLACK;
+    }
+    let lights = [
+        scene.environment.sample(random2()),
+        scene.sun.map(|sun| sun.sample(random2())),
+    ];
+    let mut radiance = Color::BLACK;
+    for light in lights.iter().flatten() {
+        let f = vertex.f(light.direction, Mode::Radiance);
+        if f.luminance() <= 0.0 {
+            continue;
+        }
+        let shadow = Ray::spawn(vertex.point, vertex.error, vertex.normal, light.direction);
+        if scene.raycast(&shadow).is_some() {
+            continue;
+        }
+        let cos = light.direction.dot(vertex.shading_normal);
+        let weight = power_heuristic(1, light.pdf, 1, vertex.bsdf_pdf(vertex.wo, light.direction));
+        radiance = radiance + vertex.beta * f * light.radiance * (cos * weight / light.pdf);
+    }
+    radiance
+}
+
+/// Weighted light carried by a path made by one strategy
+struct Contribution {
+    color: Color,
+    /// Film position of light tracing strategies, which reach other pixels
+    raster: Option<(float, float)>,
+}
+
+impl Contribution {
+    const NONE: Self = Self {
+        color: Color::BLACK,
+        raster: None,
+    };
+}
+
+fn visible(scene: &Scene, from: &Vertex, to: Point) -> bool {
+    let ray = Ray::spawn_to(from.point, from.error, from.normal, to);
+    scene.raycast(&ray).is_none()
+}
+
+/// Path made of the first `s` vertices of the light subpath and the first `t` of the camera
+/// subpath. Strategies with `s == 1` sample a new point on a light, and those with `t == 1`
+/// connect to the camera.
+fn connect(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    s: usize,
+    t: usize,
+) -> Contribution {
+    let mut sampled = None;
+    let mut raster = None;
+
+    let color = if s == 0 {
+        // The camera subpath hit a light
+        let pt = &camera_path[t - 1];
+        pt.beta * pt.emission
+    } else if t == 1 {
+        let qs = &light_path[s - 1];
+        let origin = camera.transform.pos();
+        raster = camera.raster(qs.point);
+        if !qs.connectible() || raster.is_none() {
+            return Contribution::NONE;
+        }
+        let to_camera = origin - qs.point;
+        let distance2 = to_camera.len2();
+        let wi = to_camera * (1.0 / distance2.sqrt());
+        // Density of a pinhole camera being chosen from `qs`, in solid angle
+        let cos_camera = camera.pdf(-wi) / camera.importance(-wi);
+        let mut vertex = Vertex::new(Kind::Camera, origin, Vector::ZERO);
+        vertex.beta = Color::WHITE * (camera.importance(-wi) * cos_camera / distance2);
+
+        let color =
+            qs.beta * qs.f(wi, Mode::Importance) * vertex.beta * wi.dot(qs.shading_normal).abs();
+        if color.luminance() <= 0.0 || !visible(scene, qs, origin) {
+            return Contribution::NONE;
+        }
+        sampled = Some(vertex);
+        color
+    } else if s == 1 {
+        let pt = &camera_path[t - 1];
+        let emitter = match scene.lights.sample_emitter(random3()) {
+            Some(emitter) if pt.connectible() => emitter,
+            _ => return Contribution::NONE,
+        };
+        let to_light = emitter.point - pt.point;
+        let distance2 = to_light.len2();
+        let wi = to_light * (1.0 / distance2.sqrt());
+        let cos_light = -wi.dot(emitter.normal);
+        if cos_light <= 0.0 {
+            return Contribution::NONE;
+        }
+        let mut vertex = Vertex::new(Kind::Light, emitter.point, emitter.normal);
+        vertex.object = emitter.object;
+        vertex.primitive = emitter.primitive;
+        vertex.emission = emitter.radiance;
+        vertex.beta = emitter.radiance * (1.0 / emitter.pdf);
+        vertex.pdf_fwd = emitter.pdf;
+
+        let color = pt.beta
+            * pt.f(wi, Mode::Radiance)
+            * vertex.beta
+            * (wi.dot(pt.shading_normal).abs() * cos_light / distance2);
+        if color.luminance() <= 0.0 || !visible(scene, pt, emitter.point) {
+            return Contribution::NONE;
+        }
+        sampled = Some(vertex);
+        color
+    } else {
+        let qs = &light_path[s - 1];
+        let pt = &camera_path[t - 1];
+        if !qs.connectible() || !pt.connectible() {
+            return Contribution::NONE;
+        }
+        let to_pt = pt.point - qs.point;
+        let distance2 = to_pt.len2();
+        let w = to_pt * (1.0 / distance2.sqrt());
+        let geometry = w.dot(qs.shading_normal).abs() * w.dot(pt.shading_normal).abs() / distance2;
+        let color =
+            qs.beta * qs.f(w, Mode::Importance) * pt.f(-w, Mode::Radiance) * pt.beta * geometry;
+        if color.luminance() <= 0.0 || !visible(scene, pt, qs.point) {
+            return Contribution::NONE;
+        }
+        color
+    };
+
+    if color.luminance() <= 0.0 {
+        return Contribution::NONE;
+    }
+    let weight = mis_weight(scene, camera, light_path, camera_path, sampled, s, t);
+    Contribution {
+        color: color * weight,
+        raster,
+    }
+}
+
+/// Balance heuristic weight of a strategy among all the ones that could make the same path,
+/// from the ratios of their densities
+/// https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Bidirectional_Path_Tracing#MultipleImportanceSampling
+fn mis_weight(
+    scene: &Scene,
+    camera: &Camera,
+    light_path: &[Vertex],
+    camera_path: &[Vertex],
+    sampled: Option<Vertex>,
+    s: usize,
+    t: usize,
+) -> float {
+    if s + t == 2 {
+        return 1.0;
+    }
+
+    // Vertices of the path, with the sampled endpoint in place
+    let mut light: Vec<Vertex> = match sampled {
+        Some(vertex) if s == 1 => vec![vertex],
+        _ => light_path[..s].to_vec(),
+    };
+    let mut view: Vec<Vertex> = match sampled {
+        Some(vertex) if t == 1 => vec![vertex],
+        _ => camera_path[..t].to_vec(),
+    };
+
+    // Densities of sampling the connected vertices from the other side
+    let pt = &view[t - 1];
+    let pt_minus = t.checked_sub(2).map(|i| &view[i]);
+    let qs = s.checked_sub(1).map(|i| &light[i]);
+    let qs_minus = s.checked_sub(2).map(|i| &light[i]);
+    let pt_rev = match qs {
+        Some(qs) => qs.pdf(camera, qs_minus, pt),
+        None => scene.lights.emitter_pdf(pt.object, pt.primitive),
+    };
+    // Emitters that light subpaths do not start from are only found by camera subpaths
+    if s == 0 && pt_rev == 0.0 {
+        return 1.0;
+    }
+    let pt_minus_rev = pt_minus.map(|pt_minus| match qs {
+        Some(qs) => pt.pdf(camera, Some(qs), pt_minus),
+        None => pt.pdf_light(pt_minus),
+    });
+    let qs_rev = qs.map(|qs| pt.pdf(camera, pt_minus, qs));
+    let qs_minus_rev = qs_minus.map(|qs_minus| qs.unwrap().pdf(camera, Some(pt), qs_minus));
+
+    view[t - 1].pdf_rev = pt_rev;
+    view[t - 1].delta = false;
+    if let Some(pdf) = pt_minus_rev {
+        view[t - 2].pdf_rev = pdf;
+    }
+    if let Some(pdf) = qs_rev {
+        light[s - 1].pdf_rev = pdf;
+        light[s - 1].delta = false;
+    }
+    if let Some(pdf) = qs_minus_rev {
+        light[s - 2].pdf_rev = pdf;
+    }
+
+    // Mirror bounces have no density, and strategies connecting at them are skipped
+    let remap = |pdf: float| if pdf != 0.0 { pdf } else { 1.0 };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for i in (1..t).rev() {
+        ratio *= remap(view[i].pdf_rev) / remap(view[i].pdf_fwd);
+        if !view[i].delta && !view[i - 1].delta {
+            sum += ratio;
+        }
+    }
+    ratio = 1.0;
+    for i in (0..s).rev() {
+        ratio *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+        if !light[i].delta && !(i > 0 && light[i - 1].delta) {
+            sum += ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::test_scenes::{assert_furnace, furnace};
+
+    #[test]
+    fn furnace_matches_geometric_series() {
+        sampling::seed(1);
+        let (scene, camera) = furnace(Color::WHITE, 0.5);
+        let splats = Splats::new(camera.width, camera.height);
+        let samples = 32;
+        let max_depth = 2;
+
+        let mut total = Color::BLACK;
+        for y in 0..camera.height {
+            for x in 0..camera.width {
+                for _ in 0..samples {
+                    total = total + sample(&scene, &camera, x, y, max_depth, &splats);
+                }
+            }
+        }
+        let mut splatted = Color::BLACK;
+        for y in 0..camera.height {
+            for x in 0..camera.width {
+                splatted = splatted + splats.get(x, y);
+            }
+        }
+
+        let pixels = (camera.width * camera.height * samples) as float;
+        assert_furnace(
+            (total + splatted) / pixels,
+            Color::WHITE,
+            0.5,
+            max_depth,
+            0.03,
+        );
+        // Light tracing finds part of it
+        assert!(splatted.luminance() / pixels > 0.01);
+    }
+
+    #[test]
+    fn splats_accumulate_per_pixel() {
+        let mut splats = Splats::new(4, 2);
+        splats.add(1.5, 1.2, Color::WHITE);
+        splats.add(1.9, 1.9, Color::WHITE * 2.0);
+        splats.add(4.0, 0.0, Color::WHITE);
+        assert_eq!(splats.get(1, 1), Color::WHITE * 3.0);
+        assert_eq!(splats.get(3, 0), Color::BLACK);
+        splats.clear();
+        assert_eq!(splats.get(1, 1), Color::BLACK);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+/// Axis-aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Bounds {
+    /// Contains nothing, the identity of `union`
+    pub const EMPTY: Self = Self {
+        min: Vector {
+            x: float::INFINITY,
+            y: float::INFINITY,
+            z: float::INFINITY,
+        },
+        max: Vector {
+            x: float::NEG_INFINITY,
+            y: float::NEG_INFINITY,
+            z: float::NEG_INFINITY,
+        },
+    };
+
+    pub fn point(point: Point) -> Self {
+        Self {
+            min: point,
+            max: point,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    #[must_use]
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Empty if the boxes do not overlap
+    #[must_use]
+    pub fn intersection(self, other: Self) -> Self {
+        Self {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        }
+    }
+
+    #[must_use]
+    pub fn include(self, point: Point) -> Self {
+        self.union(Self::point(point))
+    }
+
+    pub fn centroid(self) -> Point {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn diagonal(self) -> Vector {
+        self.max - self.min
+    }
+
+    /// Axis along which the box is the longest
+    pub fn largest_axis(self) -> usize {
+        let d = self.diagonal();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn surface_area(self) -> float {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Center and radius of a sphere containing the box
+    pub fn bounding_sphere(self) -> (Point, float) {
+        (self.centroid(), self.diagonal().len() * 0.5)
+    }
+}
@@ -0,0 +1,412 @@
+use crate::bounds::Bounds;
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::raycast::RayHit;
+use crate::vector::{Point, Vector};
+
+use std::cell::Cell;
+
+/// Leaves with at most this many items are not split further
+const MAX_LEAF_ITEMS: usize = 4;
+
+/// Number of buckets the surface area heuristic evaluates splits between
+const SAH_BUCKETS: usize = 12;
+
+/// Bounding volume hierarchy over items with bounds, built with the surface area heuristic
+/// https://www.pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Item indices, leaves refer to ranges of this
+    items: Vec<usize>,
+    /// Items with infinite bounds, tested against every ray
+    unbounded: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BvhNode {
+    bounds: Bounds,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NodeKind {
+    Leaf {
+        start: usize,
+        count: usize,
+    },
+    /// The first child follows its parent
+    Interior {
+        second: usize,
+        axis: usize,
+    },
+}
+
+thread_local! {
+    /// Nodes and items rays were tested against on this thread, in all hierarchies including
+    /// those of instances and meshes
+    static TRAVERSAL_STEPS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Nodes and items the rays cast by this thread were tested against since the last call
+pub fn take_traversal_steps() -> usize {
+    TRAVERSAL_STEPS.with(|steps| steps.replace(0))
+}
+
+fn is_finite(bounds: &Bounds) -> bool {
+    [bounds.min, bounds.max]
+        .iter()
+        .all(|v| v.x.is_finite() && v.y.is_finite() && v.z.is_finite())
+}
+
+impl Bvh {
+    /// Hierarchy over items with the given bounds, referred to by their index
+    pub fn new(bounds: &[Bounds]) -> Self {
+        let (mut items, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..bounds.len()).partition(|&i| is_finite(&bounds[i]));
+
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(2 * items.len()),
+            items: Vec::new(),
+            unbounded,
+        };
+        if !items.is_empty() {
+            let centroids: Vec<Point> = bounds.iter().map(|b| b.centroid()).collect();
+            bvh.build(&mut items, 0, bounds, &centroids);
+        }
+        bvh.items = items;
+        bvh
+    }
+
+    /// Bounds of everything in the hierarchy, infinite if there are unbounded items
+    pub fn bounds(&self) -> Bounds {
+        if !self.unbounded.is_empty() {
+            return Bounds {
+                min: Bounds::EMPTY.max,
+                max: Bounds::EMPTY.min,
+            };
+        }
+        self.nodes.first().map_or(Bounds::EMPTY, |node| node.bounds)
+    }
+
+    fn build(
+        &mut self,
+        items: &mut [usize],
+        offset: usize,
+        bounds: &[Bounds],
+        centroids: &[Point],
+    ) -> usize {
+        let node_bounds = items.iter().fold(Bounds::EMPTY, |b, &i| b.union(bounds[i]));
+        let index = self.nodes.len();
+        let leaf = BvhNode {
+            bounds: node_bounds,
+            kind: NodeKind::Leaf {
+                start: offset,
+                count: items.len(),
+            },
+        };
+        self.nodes.push(leaf);
+        if items.len() <= MAX_LEAF_ITEMS {
+            return index;
+        }
+
+        let centroid_bounds = items
+            .iter()
+            .fold(Bounds::EMPTY, |b, &i| b.include(centroids[i]));
+        let axis = centroid_bounds.largest_axis();
+        let low = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - low;
+
+        let mid = if extent <= 0.0 {
+            // All centers coincide, any split is as good as another
+            items.len() / 2
+        } else {
+            let bucket_of = |i: usize| {
+                (((centroids[i][axis] - low) / extent * SAH_BUCKETS as float) as usize)
+                    .min(SAH_BUCKETS - 1)
+            };
+            let mut buckets = [(0, Bounds::EMPTY); SAH_BUCKETS];
+            for &i in items.iter() {
+                let bucket = &mut buckets[bucket_of(i)];
+                bucket.0 += 1;
+                bucket.1 = bucket.1.union(bounds[i]);
+            }
+
+            // Cost of splitting after each bucket, relative to intersecting an item
+            let mut best = (float::INFINITY, 0);
+            for split in 1..SAH_BUCKETS {
+                let side = |range: &[(usize, Bounds)]| {
+                    range
+                        .iter()
+                        .fold((0, Bounds::EMPTY), |(n, b), (count, bounds)| {
+                            (n + count, b.union(*bounds))
+                        })
+                };
+                let (n0, b0) = side(&buckets[..split]);
+                let (n1, b1) = side(&buckets[split..]);
+                if n0 == 0 || n1 == 0 {
+                    continue;
+                }
+                let cost = 0.125
+                    + (n0 as float * b0.surface_area() + n1 as float * b1.surface_area())
+                        / node_bounds.surface_area().max(float::MIN_POSITIVE);
+                if cost < best.0 {
+                    best = (cost, split);
+                }
+            }
+
+            if best.0 >= items.len() as float && items.len() <= 4 * MAX_LEAF_ITEMS {
+                return index;
+            }
+            if best.0.is_finite() {
+                partition(items, |i| bucket_of(i) < best.1)
+            } else {
+                items.len() / 2
+            }
+        };
+
+        if mid == items.len() / 2 {
+            // Median split, which also breaks up identical centers
+            items.sort_by(|&a, &b| {
+                centroids[a][axis]
+                    .partial_cmp(&centroids[b][axis])
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+        }
+
+        let (first, second) = items.split_at_mut(mid);
+        self.build(first, offset, bounds, centroids);
+        let second = self.build(second, offset + mid, bounds, centroids);
+        self.nodes[index].kind = NodeKind::Interior { second, axis };
+        index
+    }
+
+    /// Closest hit, where `intersect` tests the item with the given index against a ray,
+    /// which is shortened to end at the closest hit so far.
+    /// `object` of the returned hit is the item index.
+    pub fn raycast<F>(&self, ray: &Ray, mut intersect: F) -> Option<RayHit>
+    where
+        F: FnMut(usize, &Ray) -> Option<RayHit>,
+    {
+        let mut ray = *ray;
+        let mut closest: Option<RayHit> = None;
+        let mut steps = 0;
+        let mut test = |item: usize, ray: &mut Ray, closest: &mut Option<RayHit>| {
+            steps += 1;
+            if let Some(mut hit) = intersect(item, ray) {
+                hit.object = item;
+                ray.t_max = hit.distance;
+                *closest = Some(hit);
+            }
+        };
+
+        for &item in &self.unbounded {
+            test(item, &mut ray, &mut closest);
+        }
+        if self.nodes.is_empty() {
+            TRAVERSAL_STEPS.with(|total| total.set(total.get() + steps));
+            return closest;
+        }
+
+        let (from, direction) = (ray.origin, ray.direction);
+        let inverse = Vector {
+            x: 1.0 / direction.x,
+            y: 1.0 / direction.y,
+            z: 1.0 / direction.z,
+        };
+        let negative = [direction.x < 0.0, direction.y < 0.0, direction.z < 0.0];
+
+        // Splits that peel off a few items at a time can make the tree deep
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        let mut nodes = 0;
+        while let Some(index) = stack.pop() {
+            nodes += 1;
+            let node = &self.nodes[index];
+            if !hits_bounds(&node.bounds, from, inverse, ray.t_max) {
+                continue;
+            }
+
+            match node.kind {
+                NodeKind::Leaf { start, count } => {
+                    for &item in &self.items[start..start + count] {
+                        test(item, &mut ray, &mut closest);
+                    }
+                }
+                NodeKind::Interior { second, axis } => {
+                    // Visit the nearer child first, it is popped last pushed
+                    let first = index + 1;
+                    let (near, far) = if negative[axis] {
+                        (second, first)
+                    } else {
+                        (first, second)
+                    };
+                    stack.push(far);
+                    stack.push(near);
+                }
+            }
+        }
+
+        TRAVERSAL_STEPS.with(|total| total.set(total.get() + nodes + steps));
+        closest
+    }
+}
+
+/// Moves items matching the predicate to the front, returning their count
+fn partition(items: &mut [usize], predicate: impl Fn(usize) -> bool) -> usize {
+    let mut mid = 0;
+    for i in 0..items.len() {
+        if predicate(items[i]) {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Slab test against a box, for rays ending at `max_distance`
+fn hits_bounds(bounds: &Bounds, from: Point, inverse: Vector, max_distance: float) -> bool {
+    let mut near: float = 0.0;
+    // Slightly enlarged so that rounding does not miss hits on the box surface
+    let mut far = max_distance * (1.0 + 4.0 * float::EPSILON);
+    for axis in 0..3 {
+        let t0 = (bounds.min[axis] - from[axis]) * inverse[axis];
+        let t1 = (bounds.max[axis] - from[axis]) * inverse[axis];
+        // NaN from a zero direction inside the slab is ignored by min and max
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+        if near > far {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{Object, Shape};
+    use crate::raycast::{intersect_object, raycast};
+
+    #[test]
+    fn matches_linear_search() {
+        // Grid of spheres and a floor plane
+        let mut objects = Vec::new();
+        for i in 0..200 {
+            objects.push(Object {
+                shape: Shape::Sphere {
+                    center: Point {
+                        x: (i % 10) as float * 3.0,
+                        y: ((i / 10) % 5) as float * 3.0,
+                        z: (i / 50) as float * 3.0,
+                    },
+                    radius: 1.0 + (i % 3) as float * 0.3,
+                },
+                material_id: None,
+            });
+        }
+        objects.push(Object {
+            shape: Shape::Plane {
+                point: Point {
+                    x: 0.0,
+                    y: -1.5,
+                    z: 0.0,
+                },
+                normal: Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+            },
+            material_id: None,
+        });
+
+        let bounds: Vec<Bounds> = objects.iter().map(|o| o.shape.bounds()).collect();
+        let bvh = Bvh::new(&bounds);
+
+        for i in 0..500 {
+            let from = Point {
+                x: -10.0,
+                y: 5.0 + (i % 7) as float,
+                z: -10.0,
+            };
+            let direction = Vector {
+                x: 1.0,
+                y: (i % 13) as float * 0.1 - 0.8,
+                z: (i % 17) as float * 0.1,
+            }
+            .normalized();
+
+            let ray = Ray::new(from, direction);
+            let expected = raycast(&ray, &objects);
+            let found = bvh.raycast(&ray, |j, ray| intersect_object(&objects[j], ray));
+            match (expected, found) {
+                (Some(a), Some(b)) => {
+                    assert_eq!(a.object, b.object);
+                    assert!((a.distance - b.distance).abs() < 0.001);
+                }
+                (None, None) => (),
+                (a, b) => panic!("{:?} {:?}", a, b),
+            }
+        }
+    }
+
+    #[test]
+    fn deep_trees_are_traversed() {
+        // A chain of interior nodes, each with a single sphere as its second child, like the
+        // trees of splits that peel off one item at a time
+        let n = 100;
+        let objects: Vec<Object> = (0..n)
+            .map(|i| Object {
+                shape: Shape::Sphere {
+                    center: Point {
+                        x: i as float * 3.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    radius: 1.0,
+                },
+                material_id: None,
+            })
+            .collect();
+        let bounds: Vec<Bounds> = objects.iter().map(|o| o.shape.bounds()).collect();
+        let leaf = |i: usize| BvhNode {
+            bounds: bounds[i],
+            kind: NodeKind::Leaf { start: i, count: 1 },
+        };
+        let mut nodes: Vec<BvhNode> = (0..n - 1)
+            .map(|i| BvhNode {
+                bounds: bounds[i..].iter().fold(Bounds::EMPTY, |b, &c| b.union(c)),
+                kind: NodeKind::Interior {
+                    second: n + i,
+                    axis: 0,
+                },
+            })
+            .collect();
+        nodes.push(leaf(n - 1));
+        nodes.extend((0..n - 1).map(leaf));
+        let bvh = Bvh {
+            nodes,
+            items: (0..n).collect(),
+            unbounded: Vec::new(),
+        };
+
+        let ray = Ray::new(
+            Point {
+                x: -5.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        );
+        let hit = bvh
+            .raycast(&ray, |j, ray| intersect_object(&objects[j], ray))
+            .unwrap();
+        assert_eq!(hit.object, 0);
+        assert!((hit.distance - 4.0).abs() < 0.001);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::angle::Angle;
+use crate::matrix::Matrix;
+use crate::prelude::*;
+use crate::raycast::RayDifferential;
+use crate::vector::{Point, Vector};
+
+/// Pinhole camera looking along its local x axis
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    /// Camera to world transform
+    pub transform: Matrix,
+    /// Film size in pixels
+    pub width: u32,
+    pub height: u32,
+    /// Angle between the top and the bottom edge of the image
+    pub vertical_fov: Angle,
+}
+
+impl Camera {
+    /// Field of view where the image is as high as it is far from the eye
+    pub const DEFAULT_FOV: Angle = Angle {
+        radians: 0.927_295_2,
+    };
+
+    /// Half of the image width and height at unit distance
+    fn half_size(&self) -> (float, float) {
+        let half_height = (0.5 * self.vertical_fov.radians).tan();
+        (
+            half_height * self.width as float / self.height as float,
+            half_height,
+        )
+    }
+
+    /// Camera space direction through film position `(x, y)`, in pixels from the top left corner
+    fn direction(&self, x: float, y: float) -> Vector {
+        let (half_width, half_height) = self.half_size();
+        Vector {
+            z: (1.0 - 2.0 * x / self.width as float) * half_width,
+            y: (1.0 - 2.0 * y / self.height as float) * half_height,
+            x: 1.0, // affects fov calculation
+        }
+        .normalized()
+    }
+
+    /// World space ray through film position `(x, y)`,
+    /// with differentials towards the neighbouring pixels
+    pub fn ray(&self, x: float, y: float) -> (Point, Vector, RayDifferential) {
+        let origin = self.transform.pos();
+        let direction = self.transform.mul_rotate(self.direction(x, y));
+        let differential = RayDifferential {
+            rx_origin: origin,
+            rx_direction: self.transform.mul_rotate(self.direction(x + 1.0, y)),
+            ry_origin: origin,
+            ry_direction: self.transform.mul_rotate(self.direction(x, y + 1.0)),
+        };
+
+        (origin, direction, differential)
+    }
+
+    /// Film position that a world space point is seen at, `None` if it is outside of the image
+    pub fn raster(&self, point: Point) -> Option<(float, float)> {
+        let local = self.local_direction(point - self.transform.pos())?;
+        let (half_width, half_height) = self.half_size();
+        let x = (1.0 - local.z / (local.x * half_width)) * 0.5 * self.width as float;
+        let y = (1.0 - local.y / (local.x * half_height)) * 0.5 * self.height as float;
+        if x < 0.0 || y < 0.0 || x >= self.width as float || y >= self.height as float {
+            return None;
+        }
+        Some((x, y))
+    }
+
+    /// Solid angle density of `ray` choosing a world space direction,
+    /// for film positions uniformly distributed over the image
+    /// https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/The_Path-Space_Measurement_Equation#SamplingCameras
+    pub fn pdf(&self, direction: Vector) -> float {
+        match self.local_direction(direction) {
+            Some(local) if self.sees(local) => 1.0 / (self.film_area() * local.x.powi(3)),
+            _ => 0.0,
+        }
+    }
+
+    /// Importance the camera emits along a world space direction, normalized so that it
+    /// integrates to one over the image
+    pub fn importance(&self, direction: Vector) -> float {
+        match self.local_direction(direction) {
+            Some(local) if self.sees(local) => 1.0 / (self.film_area() * local.x.powi(4)),
+            _ => 0.0,
+        }
+    }
+
+    /// Area of the image at unit distance
+    fn film_area(&self) -> float {
+        let (half_width, half_height) = self.half_size();
+        4.0 * half_width * half_height
+    }
+
+    /// Normalized camera space direction, `None` if it points behind the camera
+    fn local_direction(&self, direction: Vector) -> Option<Vector> {
+        let local = self.transform.inverse()?.mul_rotate(direction);
+        if local.x <= 0.0 {
+            return None;
+        }
+        Some(local.normalized())
+    }
+
+    /// Whether a camera space direction is within the image
+    fn sees(&self, local: Vector) -> bool {
+        let (half_width, half_height) = self.half_size();
+        local.z.abs() <= local.x * half_width && local.y.abs() <= local.x * half_height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn raster_inverts_ray() {
+        let camera = Camera {
+            transform: Matrix::translation(Vector {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            }) * Matrix::rotation(
+                Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                Angle { radians: 0.7 },
+            ),
+            width: 40,
+            height: 30,
+            vertical_fov: Camera::DEFAULT_FOV,
+        };
+        for &(x, y) in [(0.5, 0.5), (20.0, 15.0), (39.5, 3.25)].iter() {
+            let (origin, direction, _) = camera.ray(x, y);
+            let (rx, ry) = camera.raster(origin + direction * 5.0).unwrap();
+            assert!((rx - x).abs() < 1e-3 && (ry - y).abs() < 1e-3);
+        }
+        let (origin, direction, _) = camera.ray(20.0, 15.0);
+        assert_eq!(camera.raster(origin - direction), None);
+        assert_eq!(camera.pdf(-direction), 0.0);
+
+        // The density integrates to one over the sphere of directions
+        let n = 400;
+        let mut total = 0.0;
+        for i in 0..n * n {
+            let z = 1.0 - 2.0 * ((i % n) as float + 0.5) / n as float;
+            let phi = 2.0 * PI * ((i / n) as float + 0.5) / n as float;
+            let r = (1.0 - z * z).sqrt();
+            let direction = Vector {
+                x: r * phi.cos(),
+                y: r * phi.sin(),
+                z,
+            };
+            total += camera.pdf(direction) * 4.0 * PI / (n * n) as float;
+        }
+        assert!((total - 1.0).abs() < 0.01, "{}", total);
+    }
+}
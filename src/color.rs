@@ -43,7 +43,7 @@ impl Color {
     #[must_use]
     pub fn darken(self, ratio: float) -> Self {
         assert!(
-            ratio >= 0.0 && ratio <= 1.001,
+            (0.0..=1.001).contains(&ratio),
             "Ratio out of range: {}",
             ratio
         );
@@ -60,7 +60,7 @@ impl Color {
     #[must_use]
     pub fn mix(self, other: Self, ratio: float) -> Self {
         assert!(
-            ratio >= 0.0 && ratio <= 1.001,
+            (0.0..=1.001).contains(&ratio),
             "Ratio out of range: {}",
             ratio
         );
@@ -74,6 +74,22 @@ impl Color {
         }
     }
 
+    /// Relative luminance of linear sRGB
+    /// https://en.wikipedia.org/wiki/Relative_luminance
+    pub fn luminance(self) -> float {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Linear sRGB from CIE XYZ tristimulus values
+    /// http://www.brucelindbloom.com/index.html?Eqn_RGB_XYZ_Matrix.html
+    pub fn from_xyz(x: float, y: float, z: float) -> Self {
+        Self {
+            r: 3.240_454 * x - 1.537_138_5 * y - 0.498_531_4 * z,
+            g: -0.969_266 * x + 1.876_010_8 * y + 0.041_556 * z,
+            b: 0.055_643_4 * x - 0.204_025_9 * y + 1.057_225_2 * z,
+        }
+    }
+
     pub fn to_pixel_color(self) -> [u8; 4] {
         [
             (self.r.clamp(0.0, 1.0) * (0xff as float)) as u8,
@@ -108,6 +124,18 @@ impl Mul for Color {
     }
 }
 
+impl Mul<float> for Color {
+    type Output = Self;
+
+    fn mul(self, rhs: float) -> Self {
+        Self {
+            r: (self.r * rhs),
+            g: (self.g * rhs),
+            b: (self.b * rhs),
+        }
+    }
+}
+
 impl Div<float> for Color {
     type Output = Self;
 
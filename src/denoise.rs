@@ -0,0 +1,380 @@
+//! Denoiser for renders with few samples per pixel: an edge-avoiding à-trous wavelet filter
+//! guided by the albedo, normal and depth of the surfaces the camera sees, which are free of
+//! noise and keep the filter from blurring across edges and textures
+//! https://jo.dreggn.org/home/2010_atrous.pdf
+
+use crate::color::Color;
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::raycast::RayDifferential;
+use crate::scene::Scene;
+use crate::texture::SurfacePoint;
+use crate::vector::{Point, Vector};
+
+use rayon::prelude::*;
+
+/// Weights of the B3 spline along each axis of the filter, the center in the middle
+const KERNEL: [float; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Albedo below which surfaces are treated as white, so that light they emit is filtered as
+/// it is instead of divided by almost nothing
+const DARK_ALBEDO: float = 0.01;
+
+/// Noise free properties of the surface seen through a pixel, which guide the denoiser
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// Color the surface reflects, white for glass and where nothing is hit
+    pub albedo: Color,
+    /// Shading normal facing the camera, against the view direction where nothing is hit
+    pub normal: Vector,
+    /// Distance from the camera, infinite where nothing is hit
+    pub depth: float,
+}
+
+impl Features {
+    /// Features of the first surface along a camera ray
+    pub fn trace(
+        scene: &Scene,
+        origin: Point,
+        direction: Vector,
+        differential: RayDifferential,
+    ) -> Self {
+        let ray = Ray::new(origin, direction);
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => {
+                return Self {
+                    albedo: Color::WHITE,
+                    normal: -ray.direction,
+                    depth: float::INFINITY,
+                }
+            }
+        };
+        let footprint = differential.footprint(&hit, hit.point);
+        let surface = SurfacePoint {
+            point: hit.point,
+            normal: hit.shading_normal,
+            uv: hit.uv,
+            duvdx: footprint.duvdx,
+            duvdy: footprint.duvdy,
+            color: hit.color,
+        };
+        let albedo = match scene.material(&hit) {
+            Some(material) if material.glass.is_none() => {
+                let albedo = material.diffuse.color(&surface) + material.specular.color(&surface);
+                Color {
+                    r: albedo.r.min(1.0),
+                    g: albedo.g.min(1.0),
+                    b: albedo.b.min(1.0),
+                }
+            }
+            _ => Color::WHITE,
+        };
+        Self {
+            albedo,
+            normal: hit.shading_normal,
+            depth: hit.distance,
+        }
+    }
+
+    /// Albedo the color of the pixel is divided by while it is filtered
+    fn demodulation(&self) -> Color {
+        if self.albedo.luminance() < DARK_ALBEDO {
+            Color::WHITE
+        } else {
+            Color {
+                r: self.albedo.r.max(DARK_ALBEDO),
+                g: self.albedo.g.max(DARK_ALBEDO),
+                b: self.albedo.b.max(DARK_ALBEDO),
+            }
+        }
+    }
+}
+
+/// Edge-avoiding à-trous filter, which blurs in passes of a 5 × 5 kernel with twice the spacing
+/// of the previous pass. Neighbours count less the more their features and their colors differ.
+/// Colors are divided by the albedo while they are filtered so that textures stay sharp.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Denoiser {
+    /// The filter reaches about `2^(passes + 1)` pixels away
+    pub passes: usize,
+    /// Difference of the colors divided by the albedo at which neighbours count about a third,
+    /// halved in each pass as the noise goes down
+    pub color_sigma: float,
+    /// Exponent of the cosine between the normals that weights neighbours
+    pub normal_power: i32,
+    /// Relative difference in depth per pixel of distance at which neighbours count about a
+    /// third
+    pub depth_sigma: float,
+    /// Difference of the albedos at which neighbours count about a third
+    pub albedo_sigma: float,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            passes: 5,
+            color_sigma: 1.0,
+            normal_power: 64,
+            depth_sigma: 0.05,
+            albedo_sigma: 0.1,
+        }
+    }
+}
+
+impl Denoiser {
+    /// Denoised image from the colors and features of its pixels, row by row from the top left
+    pub fn denoise(
+        &self,
+        width: u32,
+        height: u32,
+        colors: &[Color],
+        features: &[Features],
+    ) -> Vec<Color> {
+        let pixels = (width * height) as usize;
+        assert_eq!(colors.len(), pixels);
+        assert_eq!(features.len(), pixels);
+
+        let mut image: Vec<Color> = colors
+            .iter()
+            .zip(features)
+            .map(|(color, features)| divide(*color, features.demodulation()))
+            .collect();
+        let mut color_sigma = self.color_sigma;
+        for pass in 0..self.passes {
+            let step = 1 << pass;
+            image = (0..pixels)
+                .into_par_iter()
+                .map(|i| {
+                    let (x, y) = ((i as u32 % width) as i64, (i as u32 / width) as i64);
+                    let mut sum = Color::BLACK;
+                    let mut total = 0.0;
+                    for (ky, &wy) in KERNEL.iter().enumerate() {
+                        for (kx, &wx) in KERNEL.iter().enumerate() {
+                            let dx = (kx as i64 - 2) * step;
+                            let dy = (ky as i64 - 2) * step;
+                            let (qx, qy) = (x + dx, y + dy);
+                            if qx < 0 || qy < 0 || qx >= width as i64 || qy >= height as i64 {
+                                continue;
+                            }
+                            let j = (qy * width as i64 + qx) as usize;
+                            let distance = ((dx * dx + dy * dy) as float).sqrt();
+                            let weight = wx
+                                * wy
+                                * self.weight(&features[i], &features[j], distance)
+                                * color_weight(image[i], image[j], color_sigma);
+                            sum = sum + image[j] * weight;
+                            total += weight;
+                        }
+                    }
+                    // The pixel itself always counts
+                    sum / total
+                })
+                .collect();
+            color_sigma *= 0.5;
+        }
+
+        image
+            .iter()
+            .zip(features)
+            .map(|(color, features)| *color * features.demodulation())
+            .collect()
+    }
+
+    /// How much a neighbour `distance` pixels away counts by the difference of the features
+    fn weight(&self, pixel: &Features, neighbour: &Features, distance: float) -> float {
+        // The sky only blends with the sky and surfaces only with surfaces
+        let depth = match (pixel.depth.is_finite(), neighbour.depth.is_finite()) {
+            (true, true) => {
+                (pixel.depth - neighbour.depth).abs()
+                    / (self.depth_sigma * pixel.depth * distance.max(1.0))
+            }
+            (false, false) => 0.0,
+            _ => return 0.0,
+        };
+        let normal = pixel
+            .normal
+            .dot(neighbour.normal)
+            .max(0.0)
+            .powi(self.normal_power);
+        let albedo = difference2(pixel.albedo, neighbour.albedo) / (self.albedo_sigma.powi(2));
+        normal * (-depth - albedo).exp()
+    }
+}
+
+fn color_weight(pixel: Color, neighbour: Color, sigma: float) -> float {
+    (-difference2(pixel, neighbour) / (sigma * sigma)).exp()
+}
+
+/// Squared distance between two colors
+fn difference2(a: Color, b: Color) -> float {
+    let (r, g, b) = (a.r - b.r, a.g - b.g, a.b - b.b);
+    r * r + g * g + b * b
+}
+
+fn divide(color: Color, by: Color) -> Color {
+    Color {
+        r: color.r / by.r,
+        g: color.g / by.g,
+        b: color.b / by.b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::environment::EnvironmentLight;
+    use crate::integrator::{Integrator, PathTracer};
+    use crate::material::Material;
+    use crate::object::{Object, Shape};
+    use crate::sampling;
+    use crate::Matrix;
+
+    use std::sync::Arc;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    /// Red floor with a white sphere on it under a uniform sky
+    fn scene() -> Scene {
+        let floor = [
+            v(0.5, -1.0, -6.0),
+            v(12.0, -1.0, -6.0),
+            v(12.0, -1.0, 6.0),
+            v(0.5, -1.0, 6.0),
+        ];
+        let objects = vec![
+            Object {
+                shape: Shape::Triangle {
+                    corners: [floor[0], floor[2], floor[1]],
+                    uvs: Shape::BARYCENTRIC_UVS,
+                },
+                material_id: Some(0),
+            },
+            Object {
+                shape: Shape::Triangle {
+                    corners: [floor[0], floor[3], floor[2]],
+                    uvs: Shape::BARYCENTRIC_UVS,
+                },
+                material_id: Some(0),
+            },
+            Object {
+                shape: Shape::Sphere {
+                    center: v(5.0, 0.0, 0.0),
+                    radius: 1.0,
+                },
+                material_id: Some(1),
+            },
+        ];
+        let red = Color {
+            r: 0.7,
+            g: 0.3,
+            b: 0.2,
+        };
+        let materials = vec![
+            Material::diffuse("floor", Arc::new(red)),
+            Material::diffuse("sphere", Arc::new(Color::WHITE * 0.8)),
+        ];
+        Scene::new(
+            objects,
+            materials,
+            EnvironmentLight::uniform(Color::WHITE),
+            None,
+        )
+    }
+
+    /// Mean of `samples` path traced samples for each pixel, the same for the same `seed`
+    fn render(scene: &Scene, camera: &Camera, samples: usize, seed: u64) -> Vec<Color> {
+        let tracer = PathTracer { bounces: 3 };
+        (0..camera.width * camera.height)
+            .into_par_iter()
+            .map(|i| {
+                sampling::seed(seed.wrapping_add(i as u64));
+                let (x, y) = ((i % camera.width) as float, (i / camera.width) as float);
+                let (origin, direction, differential) = camera.ray(x, y);
+                let mut sum = Color::BLACK;
+                for _ in 0..samples {
+                    sum = sum + tracer.radiance(scene, origin, direction, Some(differential));
+                }
+                sum / samples as float
+            })
+            .collect()
+    }
+
+    fn mean_squared_error(image: &[Color], reference: &[Color]) -> float {
+        let sum: float = image
+            .iter()
+            .zip(reference)
+            .map(|(&a, &b)| difference2(a, b))
+            .sum();
+        sum / image.len() as float
+    }
+
+    #[test]
+    fn denoising_approaches_the_reference() {
+        let scene = scene();
+        let camera = Camera {
+            transform: Matrix::IDENTITY,
+            width: 64,
+            height: 48,
+            vertical_fov: Camera::DEFAULT_FOV,
+        };
+        let features: Vec<Features> = (0..camera.width * camera.height)
+            .map(|i| {
+                let (x, y) = ((i % camera.width) as float, (i / camera.width) as float);
+                let (origin, direction, differential) = camera.ray(x, y);
+                Features::trace(&scene, origin, direction, differential)
+            })
+            .collect();
+        assert_eq!(features[0].depth, float::INFINITY);
+
+        let noisy = render(&scene, &camera, 4, 1);
+        let reference = render(&scene, &camera, 256, 1 << 32);
+        let denoised = Denoiser::default().denoise(camera.width, camera.height, &noisy, &features);
+
+        let noisy_error = mean_squared_error(&noisy, &reference);
+        let denoised_error = mean_squared_error(&denoised, &reference);
+        assert!(
+            denoised_error < noisy_error / 4.0,
+            "{} {}",
+            denoised_error,
+            noisy_error
+        );
+
+        // Smooth images stay as they are
+        let denoised =
+            Denoiser::default().denoise(camera.width, camera.height, &reference, &features);
+        assert!(mean_squared_error(&denoised, &reference) < noisy_error / 10.0);
+    }
+
+    #[test]
+    fn sky_does_not_blend_with_surfaces() {
+        // Bright sky on the left of a darker wall facing the camera
+        let (width, height) = (64, 4);
+        let features: Vec<Features> = (0..width * height)
+            .map(|i| Features {
+                albedo: Color::WHITE,
+                normal: v(0.0, 0.0, 1.0),
+                depth: if i % width < width / 2 {
+                    float::INFINITY
+                } else {
+                    5.0
+                },
+            })
+            .collect();
+        let colors: Vec<Color> = features
+            .iter()
+            .map(|features| {
+                if features.depth.is_finite() {
+                    Color::WHITE * 0.5
+                } else {
+                    Color::WHITE
+                }
+            })
+            .collect();
+        let denoised = Denoiser::default().denoise(width, height, &colors, &features);
+        assert_eq!(denoised, colors);
+    }
+}
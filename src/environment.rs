@@ -0,0 +1,200 @@
+use crate::angle::Angle;
+use crate::color::Color;
+use crate::hdr;
+use crate::matrix::Matrix;
+use crate::mipmap::{Image, Wrap};
+use crate::prelude::*;
+use crate::sampling::Distribution2D;
+use crate::vector::Vector;
+
+use std::f32::consts::PI;
+use std::io;
+use std::path::Path;
+
+const UP: Vector = Vector {
+    x: 0.0,
+    y: 1.0,
+    z: 0.0,
+};
+
+/// Direction towards an environment light, chosen by `EnvironmentLight::sample`
+#[derive(Debug, Clone, Copy)]
+pub struct EnvironmentSample {
+    pub direction: Vector,
+    pub radiance: Color,
+    /// Solid angle density
+    pub pdf: float,
+}
+
+/// Infinitely distant light surrounding the scene, from an equirectangular image
+/// with the top row towards +y. Importance sampled by luminance.
+/// https://www.pbr-book.org/3ed-2018/Light_Sources/Infinite_Area_Lights
+#[derive(Debug, Clone)]
+pub struct EnvironmentLight {
+    image: Image,
+    distribution: Distribution2D,
+    to_world: Matrix,
+    to_map: Matrix,
+    intensity: float,
+}
+
+impl EnvironmentLight {
+    /// `rotation` turns the map about the y axis, `intensity` scales the radiance
+    pub fn new(image: Image, rotation: Angle, intensity: float) -> Self {
+        // Stretching near the poles makes rows there cover less solid angle
+        let mut func = Vec::with_capacity(image.width * image.height);
+        for y in 0..image.height {
+            let sin_theta = (PI * (y as float + 0.5) / image.height as float).sin();
+            for x in 0..image.width {
+                let luminance = image.pixels[y * image.width + x].luminance().max(0.0);
+                func.push(luminance * sin_theta);
+            }
+        }
+        let distribution = Distribution2D::new(&func, image.width, image.height);
+
+        Self {
+            image,
+            distribution,
+            to_world: Matrix::rotation(UP, rotation),
+            to_map: Matrix::rotation(
+                UP,
+                Angle {
+                    radians: -rotation.radians,
+                },
+            ),
+            intensity,
+        }
+    }
+
+    /// Loads a Radiance `.hdr`, `.pfm` or low dynamic range image
+    pub fn load<P: AsRef<Path>>(path: P, rotation: Angle, intensity: float) -> io::Result<Self> {
+        Ok(Self::new(hdr::load(path)?, rotation, intensity))
+    }
+
+    /// Same radiance from every direction
+    pub fn uniform(radiance: Color) -> Self {
+        let image = Image {
+            width: 1,
+            height: 1,
+            pixels: vec![radiance],
+        };
+        Self::new(image, Angle { radians: 0.0 }, 1.0)
+    }
+
+    /// Radiance arriving along `-direction`, i.e. seen when looking towards `direction`
+    pub fn radiance(&self, direction: Vector) -> Color {
+        let uv = direction_to_uv(self.to_map.mul_rotate(direction));
+        self.image.bilinear(uv, Wrap::Repeat) * self.intensity
+    }
+
+    /// Picks a direction proportionally to the map luminance, `u` uniform in 0..1
+    pub fn sample(&self, u: [float; 2]) -> Option<EnvironmentSample> {
+        let (uv, map_pdf) = self.distribution.sample_continuous(u);
+        if map_pdf == 0.0 {
+            return None;
+        }
+
+        let theta = uv[1] * PI;
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return None;
+        }
+
+        let direction = self.to_world.mul_rotate(uv_to_direction(uv));
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.image.bilinear(uv, Wrap::Repeat) * self.intensity,
+            pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    /// Solid angle density of `sample` choosing `direction`
+    pub fn pdf(&self, direction: Vector) -> float {
+        let uv = direction_to_uv(self.to_map.mul_rotate(direction));
+        let sin_theta = (uv[1] * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+/// Equirectangular coordinates, u around the y axis and v down from +y
+fn direction_to_uv(d: Vector) -> [float; 2] {
+    let theta = d.y.clamp(-1.0, 1.0).acos();
+    let phi = d.z.atan2(d.x).rem_euclid(2.0 * PI);
+    [phi / (2.0 * PI), theta / PI]
+}
+
+pub(crate) fn uv_to_direction(uv: [float; 2]) -> Vector {
+    let theta = uv[1] * PI;
+    let phi = uv[0] * 2.0 * PI;
+    Vector {
+        x: theta.sin() * phi.cos(),
+        y: theta.cos(),
+        z: theta.sin() * phi.sin(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uv_mapping_roundtrip() {
+        for i in 1..20 {
+            for j in 0..20 {
+                let uv = [j as float / 20.0, i as float / 20.0];
+                let back = direction_to_uv(uv_to_direction(uv));
+                assert!((uv[0] - back[0]).abs() < 0.001, "{:?} {:?}", uv, back);
+                assert!((uv[1] - back[1]).abs() < 0.001, "{:?} {:?}", uv, back);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_bright_region() {
+        // Dark map with a single bright texel
+        let (w, h) = (16, 8);
+        let mut pixels = vec![Color::WHITE * 0.01; w * h];
+        pixels[3 * w + 5] = Color::WHITE * 1000.0;
+        let light = EnvironmentLight::new(
+            Image {
+                width: w,
+                height: h,
+                pixels,
+            },
+            Angle { radians: 1.0 },
+            2.0,
+        );
+
+        let mut bright = 0;
+        for i in 0..100 {
+            let u = [
+                (i % 10) as float / 10.0 + 0.05,
+                (i / 10) as float / 10.0 + 0.05,
+            ];
+            let sample = light.sample(u).unwrap();
+            assert!(sample.pdf > 0.0);
+            let pdf = light.pdf(sample.direction);
+            assert!((pdf - sample.pdf).abs() / sample.pdf < 0.01);
+            if sample.radiance.r > 100.0 {
+                bright += 1;
+            }
+        }
+        assert!(bright > 90);
+    }
+
+    #[test]
+    fn uniform_pdf_follows_parameterization() {
+        let light = EnvironmentLight::uniform(Color::WHITE);
+        let d = Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        assert_eq!(light.radiance(d), Color::WHITE);
+        // Constant density over the map, which is stretched the least at the equator
+        assert!((light.pdf(d) - 1.0 / (2.0 * PI * PI)).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,316 @@
+//! Image that radiance samples accumulate into, with per-pixel variance estimates that let
+//! adaptive sampling spend more samples where the image is still noisy
+//! https://www.pbr-book.org/4ed/Sampling_and_Reconstruction/Image_Reconstruction
+
+use crate::color::Color;
+use crate::integrator::heat;
+use crate::prelude::*;
+
+use rayon::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Pixels along each side of the tiles that adaptive sampling decides about together
+const TILE: u32 = 8;
+
+/// Luminance below which the error is measured absolutely instead of relative to the pixel,
+/// since noise in darker pixels is too dark to see
+const DARK: float = 0.05;
+
+/// Samples of a pixel, with the running mean and squared deviations of their luminance
+/// https://en.wikipedia.org/wiki/Algorithms_for_calculating_variance#Welford's_online_algorithm
+#[derive(Debug, Clone, Copy)]
+struct Pixel {
+    sum: Color,
+    samples: u32,
+    mean: float,
+    squared_deviations: float,
+}
+
+impl Pixel {
+    const EMPTY: Self = Self {
+        sum: Color::BLACK,
+        samples: 0,
+        mean: 0.0,
+        squared_deviations: 0.0,
+    };
+
+    fn add(&mut self, color: Color) {
+        let luminance = color.luminance();
+        self.sum = self.sum + color;
+        self.samples += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.samples as float;
+        self.squared_deviations += delta * (luminance - self.mean);
+    }
+
+    /// Standard error of the mean luminance relative to it, infinite before there are two
+    /// samples to estimate it from
+    fn error(&self) -> float {
+        if self.samples < 2 {
+            return float::INFINITY;
+        }
+        let n = self.samples as float;
+        let variance = self.squared_deviations / (n - 1.0);
+        (variance / n).sqrt() / (self.mean.max(0.0) + DARK)
+    }
+}
+
+/// Radiance samples of each pixel and their number, which can differ between pixels
+#[derive(Debug, Clone)]
+pub struct Film {
+    width: u32,
+    height: u32,
+    pixels: Vec<Pixel>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![Pixel::EMPTY; (width * height) as usize],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Forgets all samples, for example when the camera moves
+    pub fn clear(&mut self) {
+        self.pixels.fill(Pixel::EMPTY);
+    }
+
+    pub fn add(&mut self, x: u32, y: u32, color: Color) {
+        self.pixels[(y * self.width + x) as usize].add(color);
+    }
+
+    /// Mean of the samples of a pixel, black without samples
+    pub fn color(&self, x: u32, y: u32) -> Color {
+        let pixel = &self.pixels[(y * self.width + x) as usize];
+        if pixel.samples == 0 {
+            return Color::BLACK;
+        }
+        pixel.sum / pixel.samples as float
+    }
+
+    pub fn samples(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize].samples
+    }
+
+    /// Estimated standard error of the mean luminance of a pixel, relative to that luminance
+    pub fn error(&self, x: u32, y: u32) -> float {
+        self.pixels[(y * self.width + x) as usize].error()
+    }
+
+    /// Mean colors of all pixels, row by row from the top left
+    pub fn colors(&self) -> Vec<Color> {
+        (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.color(x, y))
+            .collect()
+    }
+
+    /// Samples per pixel as colors from blue for the fewest to red for the most, row by row
+    /// from the top left
+    pub fn sample_map(&self) -> Vec<Color> {
+        let most = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.samples)
+            .max()
+            .unwrap_or(0);
+        self.pixels
+            .iter()
+            .map(|pixel| heat(pixel.samples as float / most.max(1) as float))
+            .collect()
+    }
+
+    fn tiles_x(&self) -> u32 {
+        self.width.div_ceil(TILE)
+    }
+
+    fn tiles(&self) -> u32 {
+        self.tiles_x() * self.height.div_ceil(TILE)
+    }
+
+    fn tile(&self, x: u32, y: u32) -> u32 {
+        y / TILE * self.tiles_x() + x / TILE
+    }
+
+    fn tile_pixels(&self, tile: u32) -> impl Iterator<Item = (u32, u32)> {
+        let x0 = tile % self.tiles_x() * TILE;
+        let y0 = tile / self.tiles_x() * TILE;
+        let (x1, y1) = ((x0 + TILE).min(self.width), (y0 + TILE).min(self.height));
+        (y0..y1).flat_map(move |y| (x0..x1).map(move |x| (x, y)))
+    }
+}
+
+/// Renders in rounds that add samples only to the tiles of the film that are still noisy,
+/// until all of them are below the error threshold or the time budget is used up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSampler {
+    /// Mean relative error of the pixels in a tile below which it gets no more samples
+    pub threshold: float,
+    /// Samples every pixel gets before its error estimate is trusted
+    pub min_samples: u32,
+    /// Samples added to each pixel of a noisy tile in a round
+    pub batch: u32,
+    /// Samples a pixel gets at most
+    pub max_samples: u32,
+    /// Time after which rendering stops even if tiles are still noisy
+    pub time_budget: Duration,
+}
+
+impl Default for AdaptiveSampler {
+    fn default() -> Self {
+        Self {
+            threshold: 0.02,
+            min_samples: 16,
+            batch: 16,
+            max_samples: 4096,
+            time_budget: Duration::from_secs(60),
+        }
+    }
+}
+
+impl AdaptiveSampler {
+    /// Whether a tile needs more samples
+    fn is_noisy(&self, film: &Film, tile: u32) -> bool {
+        let mut error = 0.0;
+        let mut pixels = 0;
+        let mut room = false;
+        for (x, y) in film.tile_pixels(tile) {
+            let samples = film.samples(x, y);
+            if samples < self.min_samples {
+                return true;
+            }
+            room |= samples < self.max_samples;
+            error += film.error(x, y);
+            pixels += 1;
+        }
+        room && error / pixels as float > self.threshold
+    }
+
+    /// Adds a batch of samples from `sample` to the pixels of the noisy tiles, in parallel.
+    /// Returns the number of noisy tiles, zero once the film has converged.
+    pub fn round<F>(&self, film: &mut Film, sample: F) -> usize
+    where
+        F: Fn(u32, u32) -> Color + Sync,
+    {
+        let noisy: Vec<bool> = (0..film.tiles())
+            .map(|tile| self.is_noisy(film, tile))
+            .collect();
+        let tiles: Vec<u32> = (0..film.width * film.height)
+            .map(|i| film.tile(i % film.width, i / film.width))
+            .collect();
+        let width = film.width;
+        film.pixels
+            .par_iter_mut()
+            .enumerate()
+            .filter(|(i, _)| noisy[tiles[*i] as usize])
+            .for_each(|(i, pixel)| {
+                let (x, y) = (i as u32 % width, i as u32 / width);
+                let wanted = if pixel.samples < self.min_samples {
+                    self.min_samples - pixel.samples
+                } else {
+                    self.batch
+                };
+                for _ in 0..wanted.min(self.max_samples.saturating_sub(pixel.samples)) {
+                    pixel.add(sample(x, y));
+                }
+            });
+        noisy.iter().filter(|&&noisy| noisy).count()
+    }
+
+    /// Renders rounds until the film converges or the time budget is used up, after at least
+    /// one round. Returns whether the film converged.
+    pub fn render<F>(&self, film: &mut Film, sample: F) -> bool
+    where
+        F: Fn(u32, u32) -> Color + Sync,
+    {
+        let start = Instant::now();
+        loop {
+            if self.round(film, &sample) == 0 {
+                return true;
+            }
+            if start.elapsed() >= self.time_budget {
+                return false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_track_the_variance_of_their_samples() {
+        let mut film = Film::new(2, 1);
+        assert_eq!(film.color(0, 0), Color::BLACK);
+        assert_eq!(film.error(0, 0), float::INFINITY);
+
+        // Luminance alternating between 0 and 2, a variance of 4 / 3 over 4 samples
+        for &value in [0.0, 2.0, 0.0, 2.0].iter() {
+            film.add(0, 0, Color::WHITE * value);
+        }
+        assert_eq!(film.samples(0, 0), 4);
+        assert!(approx_eq(film.color(0, 0).g, 1.0));
+        let expected = (4.0 / 3.0 / 4.0 as float).sqrt() / (1.0 + DARK);
+        assert!((film.error(0, 0) - expected).abs() < 1e-4);
+
+        for _ in 0..4 {
+            film.add(1, 0, Color::WHITE * 0.5);
+        }
+        assert!(approx_eq(film.error(1, 0), 0.0));
+    }
+
+    #[test]
+    fn noisy_tiles_get_more_samples() {
+        // The left tile is smooth and the right one noisy
+        let mut film = Film::new(16, 8);
+        let sampler = AdaptiveSampler {
+            threshold: 0.05,
+            time_budget: Duration::from_secs(600),
+            ..AdaptiveSampler::default()
+        };
+        let converged = sampler.render(&mut film, |x, _| {
+            if x < TILE {
+                Color::WHITE * 0.5
+            } else {
+                Color::WHITE * rand::random::<float>()
+            }
+        });
+        assert!(converged);
+
+        assert_eq!(film.samples(3, 3), sampler.min_samples);
+        assert!(film.samples(11, 3) > 4 * sampler.min_samples);
+        let error: float = film.tile_pixels(1).map(|(x, y)| film.error(x, y)).sum();
+        assert!(error / (TILE * TILE) as float <= sampler.threshold);
+        assert!(approx_eq(film.color(3, 3).r, 0.5));
+        assert!((film.color(11, 3).r - 0.5).abs() < 0.1);
+
+        let map = film.sample_map();
+        assert!(map[11].r > map[3].r && map[3].b > map[11].b);
+    }
+
+    #[test]
+    fn time_budget_stops_after_the_first_round() {
+        let mut film = Film::new(10, 10);
+        let sampler = AdaptiveSampler {
+            threshold: 0.0,
+            time_budget: Duration::ZERO,
+            ..AdaptiveSampler::default()
+        };
+        let converged = sampler.render(&mut film, |_, _| Color::WHITE * rand::random::<float>());
+        assert!(!converged);
+        // Tiles at the edges are cut off by the film
+        assert_eq!(film.samples(9, 9), sampler.min_samples);
+        assert_eq!(film.samples(0, 0), sampler.min_samples);
+    }
+}
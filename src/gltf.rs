@@ -0,0 +1,1272 @@
+//! Import of glTF 2.0 scenes, as `.gltf` with separate or embedded buffers, or as binary `.glb`
+//! https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+
+use crate::angle::Angle;
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::light::{self, PointLight};
+use crate::material::{Ior, Material};
+use crate::matrix::{Matrix, Transform};
+use crate::medium::Medium;
+use crate::mesh::{Mesh, MeshTriangle};
+use crate::mipmap::{Filter, Image, MipMap, Wrap};
+use crate::object::{Geometry, Object, Shape};
+use crate::prelude::*;
+use crate::sky::SunLight;
+use crate::texture::{Channel, ImageTexture, Mix, Multiply, TextureRef, VertexColor};
+use crate::vector::Vector;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Filter used for image textures of glTF materials
+const GLTF_TEXTURE_FILTER: Filter = Filter::Ewa {
+    max_anisotropy: 8.0,
+};
+
+/// Angular radius given to directional lights, that of the sun
+const DIRECTIONAL_LIGHT_RADIUS: Angle = Angle { radians: 0.004_65 };
+
+const GLB_MAGIC: &[u8] = b"glTF";
+const GLB_JSON_CHUNK: u32 = 0x4E4F_534A;
+const GLB_BIN_CHUNK: u32 = 0x004E_4942;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Perspective camera of a glTF node
+#[derive(Debug, Clone, Copy)]
+pub struct GltfCamera {
+    /// Camera to world, in the camera space of `Camera`
+    pub transform: Matrix,
+    pub vertical_fov: Angle,
+}
+
+impl GltfCamera {
+    /// Camera rendering a film of the given size. Its aspect ratio is used instead of the one in the file.
+    pub fn camera(&self, width: u32, height: u32) -> Camera {
+        Camera {
+            transform: self.transform,
+            width,
+            height,
+            vertical_fov: self.vertical_fov,
+        }
+    }
+}
+
+/// Contents of the default scene of a glTF file, in its coordinates and units
+#[derive(Debug, Clone)]
+pub struct GltfScene {
+    /// One instance for each node with a mesh, and an emissive sphere for each point or spot light
+    pub objects: Vec<Object>,
+    /// Materials of the file followed by those of the lights, referred to from `material_offset` on
+    pub materials: Vec<Material>,
+    pub cameras: Vec<GltfCamera>,
+    /// Directional lights
+    pub suns: Vec<SunLight>,
+    /// Parts of the file that were ignored or approximated
+    pub warnings: Vec<String>,
+}
+
+/// Loads a `.gltf` or `.glb` file. Material ids of the objects start from `material_offset`,
+/// so that the materials can be appended to those of another scene.
+pub fn load<P: AsRef<Path>>(path: P, material_offset: usize) -> io::Result<GltfScene> {
+    let path = path.as_ref();
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+    parse(&fs::read(path)?, directory, material_offset)
+}
+
+/// Reads a scene from the contents of a `.gltf` or `.glb` file,
+/// with external buffers and images relative to `directory`
+pub fn parse(bytes: &[u8], directory: &Path, material_offset: usize) -> io::Result<GltfScene> {
+    let (json, bin) = if bytes.starts_with(GLB_MAGIC) {
+        read_glb(bytes)?
+    } else {
+        (bytes, None)
+    };
+    let json: Value = serde_json::from_slice(json).map_err(|e| invalid(&e.to_string()))?;
+
+    let version = json["asset"]["version"].as_str().unwrap_or("");
+    if !version.starts_with("2.") {
+        return Err(invalid("Unsupported glTF version"));
+    }
+
+    let mut document = Document {
+        buffers: Vec::new(),
+        directory,
+        material_offset,
+        images: HashMap::new(),
+        geometries: HashMap::new(),
+        scene: GltfScene {
+            objects: Vec::new(),
+            materials: Vec::new(),
+            cameras: Vec::new(),
+            suns: Vec::new(),
+            warnings: Vec::new(),
+        },
+        json: &json,
+    };
+    document.load_buffers(bin)?;
+    document.check_extensions();
+
+    for material in array(&json, "materials") {
+        let material = document.material(material);
+        document.scene.materials.push(material);
+    }
+
+    let roots = match json["scenes"].get(json["scene"].as_u64().unwrap_or(0) as usize) {
+        Some(scene) => indices(scene, "nodes"),
+        // Without scenes, every node that is not a child of another one is a root
+        None => {
+            let children: Vec<usize> = array(&json, "nodes")
+                .iter()
+                .flat_map(|node| indices(node, "children"))
+                .collect();
+            (0..array(&json, "nodes").len())
+                .filter(|i| !children.contains(i))
+                .collect()
+        }
+    };
+    let mut visited = vec![false; array(&json, "nodes").len()];
+    let mut lights = Vec::new();
+    for root in roots {
+        document.node(root, Matrix::IDENTITY, &mut visited, &mut lights)?;
+    }
+    light::add_point_lights(
+        &lights,
+        &mut document.scene.objects,
+        &mut document.scene.materials,
+        material_offset,
+    );
+
+    Ok(document.scene)
+}
+
+/// JSON chunk and the optional binary chunk of a `.glb` file
+/// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#binary-gltf-layout
+fn read_glb(bytes: &[u8]) -> io::Result<(&[u8], Option<&[u8]>)> {
+    let word = |at: usize| -> io::Result<u32> {
+        bytes
+            .get(at..at + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| invalid("Truncated GLB file"))
+    };
+    if word(4)? != 2 {
+        return Err(invalid("Unsupported GLB version"));
+    }
+    let length = (word(8)? as usize).min(bytes.len());
+
+    let mut json = None;
+    let mut bin = None;
+    let mut at = 12;
+    while at + 8 <= length {
+        let chunk_length = word(at)? as usize;
+        let chunk_type = word(at + 4)?;
+        let data = bytes
+            .get(at + 8..at + 8 + chunk_length)
+            .ok_or_else(|| invalid("Truncated GLB chunk"))?;
+        match chunk_type {
+            GLB_JSON_CHUNK if json.is_none() => json = Some(data),
+            GLB_BIN_CHUNK if bin.is_none() => bin = Some(data),
+            // Unknown chunks are skipped
+            _ => {}
+        }
+        // Chunks are padded to four bytes
+        at += 8 + chunk_length.div_ceil(4) * 4;
+    }
+
+    Ok((
+        json.ok_or_else(|| invalid("GLB file has no JSON chunk"))?,
+        bin,
+    ))
+}
+
+/// Decodes standard base64, with or without padding
+fn decode_base64(text: &str) -> io::Result<Vec<u8>> {
+    let digit = |c: u8| match c {
+        b'A'..=b'Z' => Ok(c - b'A'),
+        b'a'..=b'z' => Ok(c - b'a' + 26),
+        b'0'..=b'9' => Ok(c - b'0' + 52),
+        b'+' => Ok(62),
+        b'/' => Ok(63),
+        _ => Err(invalid("Invalid base64 data")),
+    };
+
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for &c in text {
+        bits = (bits << 6) | digit(c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Elements of an array property, empty if it is missing
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value[key].as_array().map_or(&[], Vec::as_slice)
+}
+
+fn index(value: &Value, key: &str) -> Option<usize> {
+    value[key].as_u64().map(|i| i as usize)
+}
+
+fn indices(value: &Value, key: &str) -> Vec<usize> {
+    array(value, key)
+        .iter()
+        .filter_map(|i| i.as_u64().map(|i| i as usize))
+        .collect()
+}
+
+fn number(value: &Value, key: &str, default: float) -> float {
+    value[key].as_f64().map_or(default, |x| x as float)
+}
+
+/// Array of numbers, `None` unless it has exactly `N` elements
+fn numbers<const N: usize>(value: &Value, key: &str) -> Option<[float; N]> {
+    let values = array(value, key);
+    if values.len() != N {
+        return None;
+    }
+    let mut result = [0.0; N];
+    for (r, v) in result.iter_mut().zip(values) {
+        *r = v.as_f64()? as float;
+    }
+    Some(result)
+}
+
+fn color(rgb: [float; 3]) -> Color {
+    Color {
+        r: rgb[0],
+        g: rgb[1],
+        b: rgb[2],
+    }
+}
+
+/// Local transform of a node, given as a matrix or as translation, rotation and scale
+fn node_matrix(node: &Value) -> Matrix {
+    if let Some(m) = numbers::<16>(node, "matrix") {
+        // Column-major
+        let mut rows = [[0.0; 4]; 4];
+        for (i, &x) in m.iter().enumerate() {
+            rows[i % 4][i / 4] = x;
+        }
+        return Matrix::new(rows);
+    }
+
+    let [tx, ty, tz] = numbers(node, "translation").unwrap_or([0.0; 3]);
+    let [sx, sy, sz] = numbers(node, "scale").unwrap_or([1.0; 3]);
+    let [x, y, z, w] = numbers(node, "rotation").unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let length = (x * x + y * y + z * z + w * w).sqrt();
+    let (x, y, z, w) = if length > 0.0 {
+        (x / length, y / length, z / length, w / length)
+    } else {
+        (0.0, 0.0, 0.0, 1.0)
+    };
+
+    // https://en.wikipedia.org/wiki/Quaternions_and_spatial_rotation#Quaternion-derived_rotation_matrix
+    let rotation = Matrix::new([
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y - z * w),
+            2.0 * (x * z + y * w),
+            0.0,
+        ],
+        [
+            2.0 * (x * y + z * w),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z - x * w),
+            0.0,
+        ],
+        [
+            2.0 * (x * z - y * w),
+            2.0 * (y * z + x * w),
+            1.0 - 2.0 * (x * x + y * y),
+            0.0,
+        ],
+        [0.0, 0.0, 0.0, 1.0],
+    ]);
+    Matrix::translation(Vector {
+        x: tx,
+        y: ty,
+        z: tz,
+    }) * rotation
+        * Matrix::scale(Vector {
+            x: sx,
+            y: sy,
+            z: sz,
+        })
+}
+
+/// Camera to world transform of a camera node, without the scaling of the node
+fn camera_matrix(world: Matrix) -> Matrix {
+    let axis = |v: Vector| world.mul_rotate(v).normalized();
+    // glTF cameras look towards -Z with +X to the right, ours towards +X with +Z to the left
+    let forward = -axis(Vector {
+        x: 0.0,
+        y: 0.0,
+        z: 1.0,
+    });
+    let up = axis(Vector {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    });
+    let left = -axis(Vector {
+        x: 1.0,
+        y: 0.0,
+        z: 0.0,
+    });
+    let position = world.pos();
+    Matrix::new([
+        [forward.x, up.x, left.x, position.x],
+        [forward.y, up.y, left.y, position.y],
+        [forward.z, up.z, left.z, position.z],
+        [0.0, 0.0, 0.0, 1.0],
+    ])
+}
+
+/// Parsing state, with everything shared between nodes loaded only once
+struct Document<'a> {
+    json: &'a Value,
+    buffers: Vec<Vec<u8>>,
+    directory: &'a Path,
+    material_offset: usize,
+    /// Mipmaps by image and wrap mode, `None` for images that failed to load
+    images: HashMap<(usize, Wrap), Option<Arc<MipMap>>>,
+    /// Primitives of each mesh
+    geometries: HashMap<usize, Option<Arc<Geometry>>>,
+    scene: GltfScene,
+}
+
+impl<'a> Document<'a> {
+    fn warn(&mut self, warning: String) {
+        if !self.scene.warnings.contains(&warning) {
+            self.scene.warnings.push(warning);
+        }
+    }
+
+    fn check_extensions(&mut self) {
+        const SUPPORTED: &[&str] = &[
+            "KHR_lights_punctual",
+            "KHR_materials_emissive_strength",
+            "KHR_materials_ior",
+            "KHR_materials_transmission",
+            "KHR_materials_volume",
+        ];
+        for extension in array(self.json, "extensionsUsed") {
+            let name = extension.as_str().unwrap_or("");
+            if !SUPPORTED.contains(&name) {
+                self.warn(format!("Extension {} is ignored", name));
+            }
+        }
+        for (key, what) in [("skins", "Skins"), ("animations", "Animations")] {
+            if !array(self.json, key).is_empty() {
+                self.warn(format!(
+                    "{} are ignored, meshes are in their rest pose",
+                    what
+                ));
+            }
+        }
+    }
+
+    /// Data URIs, external files, or the binary chunk of a `.glb` file
+    fn load_buffers(&mut self, bin: Option<&[u8]>) -> io::Result<()> {
+        for (i, buffer) in array(self.json, "buffers").iter().enumerate() {
+            let data = match buffer["uri"].as_str() {
+                Some(uri) => self.read_uri(uri)?,
+                None if i == 0 => bin
+                    .ok_or_else(|| invalid("Buffer without URI outside of a GLB file"))?
+                    .to_vec(),
+                None => return Err(invalid("Buffer without URI")),
+            };
+            if data.len() < index(buffer, "byteLength").unwrap_or(0) {
+                return Err(invalid("Buffer is shorter than its byteLength"));
+            }
+            self.buffers.push(data);
+        }
+        Ok(())
+    }
+
+    fn read_uri(&self, uri: &str) -> io::Result<Vec<u8>> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            let (_, base64) = data
+                .split_once(";base64,")
+                .ok_or_else(|| invalid("Only base64 data URIs are supported"))?;
+            decode_base64(base64)
+        } else {
+            fs::read(self.directory.join(uri.replace("%20", " ")))
+        }
+    }
+
+    /// Bytes of a buffer view and its stride, if the elements in it are interleaved
+    fn buffer_view(&self, view: usize) -> io::Result<(&[u8], Option<usize>)> {
+        let view = self.json["bufferViews"]
+            .get(view)
+            .ok_or_else(|| invalid("Missing buffer view"))?;
+        let buffer = index(view, "buffer")
+            .and_then(|i| self.buffers.get(i))
+            .ok_or_else(|| invalid("Missing buffer"))?;
+        let offset = index(view, "byteOffset").unwrap_or(0);
+        let length = index(view, "byteLength").unwrap_or(0);
+        let bytes = buffer
+            .get(offset..offset + length)
+            .ok_or_else(|| invalid("Buffer view is out of bounds"))?;
+        Ok((bytes, index(view, "byteStride")))
+    }
+
+    /// Elements of an accessor as consecutive components. Doubles hold every 32 bit index exactly.
+    /// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#accessor-data-types
+    fn accessor(&self, accessor: usize) -> io::Result<(Vec<f64>, usize)> {
+        let accessor = self.json["accessors"]
+            .get(accessor)
+            .ok_or_else(|| invalid("Missing accessor"))?;
+        let components = match accessor["type"].as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") | Some("MAT2") => 4,
+            Some("MAT3") => 9,
+            Some("MAT4") => 16,
+            _ => return Err(invalid("Unknown accessor type")),
+        };
+        let count = index(accessor, "count").unwrap_or(0);
+        let component_type = index(accessor, "componentType").unwrap_or(0);
+        let normalized = accessor["normalized"].as_bool().unwrap_or(false);
+
+        let mut values = match index(accessor, "bufferView") {
+            Some(view) => {
+                let (bytes, stride) = self.buffer_view(view)?;
+                let offset = index(accessor, "byteOffset").unwrap_or(0);
+                read_components(
+                    bytes.get(offset..).unwrap_or(&[]),
+                    stride,
+                    count * components,
+                    components,
+                    component_type,
+                    normalized,
+                )?
+            }
+            // Zeros, unless replaced by sparse values
+            None => vec![0.0; count * components],
+        };
+
+        let sparse = &accessor["sparse"];
+        if sparse.is_object() {
+            let sparse_count = index(sparse, "count").unwrap_or(0);
+            let read = |part: &Value, components, component_type| -> io::Result<Vec<f64>> {
+                let (bytes, _) =
+                    self.buffer_view(index(part, "bufferView").unwrap_or(usize::MAX))?;
+                let offset = index(part, "byteOffset").unwrap_or(0);
+                read_components(
+                    bytes.get(offset..).unwrap_or(&[]),
+                    None,
+                    sparse_count * components,
+                    components,
+                    component_type,
+                    normalized,
+                )
+            };
+            let targets = read(
+                &sparse["indices"],
+                1,
+                index(&sparse["indices"], "componentType").unwrap_or(0),
+            )?;
+            let replacements = read(&sparse["values"], components, component_type)?;
+            for (&target, replacement) in targets.iter().zip(replacements.chunks(components)) {
+                let target = target as usize;
+                if target >= count {
+                    return Err(invalid("Sparse accessor index is out of bounds"));
+                }
+                values[target * components..(target + 1) * components].copy_from_slice(replacement);
+            }
+        }
+
+        Ok((values, components))
+    }
+
+    /// Optional vertex attribute of a primitive, which needs to have an element for every vertex
+    fn attribute(
+        &self,
+        primitive: &Value,
+        name: &str,
+        vertices: usize,
+    ) -> io::Result<Option<(Vec<float>, usize)>> {
+        let accessor = match index(&primitive["attributes"], name) {
+            Some(accessor) => accessor,
+            None => return Ok(None),
+        };
+        let (values, components) = self.accessor(accessor)?;
+        if values.len() != vertices * components {
+            return Err(invalid("Vertex attributes differ in length"));
+        }
+        Ok(Some((
+            values.into_iter().map(|x| x as float).collect(),
+            components,
+        )))
+    }
+
+    /// Triangles of a mesh primitive, with the vertex attributes used by the renderer
+    fn primitive(&mut self, primitive: &Value) -> io::Result<Option<Mesh>> {
+        let positions: Vec<float> = match index(&primitive["attributes"], "POSITION") {
+            Some(accessor) => self
+                .accessor(accessor)?
+                .0
+                .iter()
+                .map(|&x| x as float)
+                .collect(),
+            None => return Ok(None),
+        };
+        let vertices = positions.len() / 3;
+        let vector = |v: &[float]| Vector {
+            x: v[0],
+            y: v[1],
+            z: v[2],
+        };
+
+        let mode = index(primitive, "mode").unwrap_or(4);
+        let indices: Vec<u32> = match index(primitive, "indices") {
+            Some(accessor) => self
+                .accessor(accessor)?
+                .0
+                .iter()
+                .map(|&i| i as u32)
+                .collect(),
+            None => (0..vertices as u32).collect(),
+        };
+        if indices.len() > u32::MAX as usize || indices.iter().any(|&i| i as usize >= vertices) {
+            return Err(invalid("Vertex index is out of bounds"));
+        }
+        let corners: Vec<[u32; 3]> = match mode {
+            4 => indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect(),
+            // Every other triangle of a strip is turned around to keep the winding
+            5 => (0..indices.len().saturating_sub(2))
+                .map(|i| {
+                    let [a, b, c] = [indices[i], indices[i + 1], indices[i + 2]];
+                    if i % 2 == 0 {
+                        [a, b, c]
+                    } else {
+                        [b, a, c]
+                    }
+                })
+                .collect(),
+            6 => (1..indices.len().saturating_sub(1))
+                .map(|i| [indices[0], indices[i], indices[i + 1]])
+                .collect(),
+            _ => {
+                self.warn("Points and lines have no surface and are ignored".to_owned());
+                return Ok(None);
+            }
+        };
+
+        let normals = match self.attribute(primitive, "NORMAL", vertices)? {
+            Some((normals, _)) => normals.chunks_exact(3).map(vector).collect(),
+            None => Vec::new(),
+        };
+        // Texture space v points down in glTF
+        let uvs = match self.attribute(primitive, "TEXCOORD_0", vertices)? {
+            Some((uvs, _)) => uvs.chunks_exact(2).map(|uv| [uv[0], 1.0 - uv[1]]).collect(),
+            None => Vec::new(),
+        };
+        let material_id = index(primitive, "material").map(|m| m + self.material_offset);
+        let triangles = corners
+            .into_iter()
+            .map(|vertices| MeshTriangle {
+                vertices,
+                material_id,
+            })
+            .collect();
+        let mut mesh = Mesh::new(
+            positions.chunks_exact(3).map(vector).collect(),
+            normals,
+            uvs,
+            triangles,
+        );
+
+        // Colors with alpha have four components, the alpha is not used
+        if let Some((colors, components)) = self.attribute(primitive, "COLOR_0", vertices)? {
+            if components == 3 || components == 4 {
+                mesh = mesh.with_colors(
+                    colors
+                        .chunks_exact(components)
+                        .map(|c| color([c[0], c[1], c[2]]))
+                        .collect(),
+                );
+            }
+        }
+        if let Some((tangents, 4)) = self.attribute(primitive, "TANGENT", vertices)? {
+            mesh = mesh.with_tangents(
+                tangents
+                    .chunks_exact(4)
+                    .map(|t| [t[0], t[1], t[2], t[3]])
+                    .collect(),
+            );
+        }
+        Ok(Some(mesh))
+    }
+
+    /// All primitives of a mesh, shared by every node that refers to it
+    fn geometry(&mut self, mesh: usize) -> io::Result<Option<Arc<Geometry>>> {
+        if let Some(geometry) = self.geometries.get(&mesh) {
+            return Ok(geometry.clone());
+        }
+
+        let json = self.json;
+        let primitives = json["meshes"]
+            .get(mesh)
+            .map_or(&[][..], |mesh| array(mesh, "primitives"));
+        let mut objects = Vec::new();
+        for primitive in primitives {
+            if let Some(mesh) = self.primitive(primitive)? {
+                if !mesh.is_empty() {
+                    objects.push(Object {
+                        shape: Shape::Mesh {
+                            mesh: Arc::new(mesh),
+                        },
+                        material_id: None,
+                    });
+                }
+            }
+        }
+
+        let geometry = if objects.is_empty() {
+            None
+        } else {
+            Some(Arc::new(Geometry::new(objects)))
+        };
+        self.geometries.insert(mesh, geometry.clone());
+        Ok(geometry)
+    }
+
+    /// Adds whatever a node and its children hold, `parent` is the world transform of its parent
+    fn node(
+        &mut self,
+        node: usize,
+        parent: Matrix,
+        visited: &mut Vec<bool>,
+        lights: &mut Vec<PointLight>,
+    ) -> io::Result<()> {
+        let json = self.json;
+        let value = json["nodes"]
+            .get(node)
+            .ok_or_else(|| invalid("Missing node"))?;
+        // Nodes form a forest, so a node reached twice is part of a cycle
+        if std::mem::replace(&mut visited[node], true) {
+            return Err(invalid("Node hierarchy is not a tree"));
+        }
+        let world = parent * node_matrix(value);
+
+        if let Some(mesh) = index(value, "mesh") {
+            if let Some(geometry) = self.geometry(mesh)? {
+                match Transform::new(world) {
+                    Some(transform) => self.scene.objects.push(Object {
+                        shape: Shape::Instance {
+                            geometry,
+                            transform,
+                        },
+                        material_id: None,
+                    }),
+                    None => self.warn(format!(
+                        "Node {} with a singular transform is ignored",
+                        node
+                    )),
+                }
+            }
+        }
+
+        if let Some(camera) = index(value, "camera").and_then(|c| json["cameras"].get(c)) {
+            match camera["type"].as_str() {
+                Some("perspective") => self.scene.cameras.push(GltfCamera {
+                    transform: camera_matrix(world),
+                    vertical_fov: Angle {
+                        radians: number(
+                            &camera["perspective"],
+                            "yfov",
+                            Camera::DEFAULT_FOV.radians,
+                        ),
+                    },
+                }),
+                _ => self.warn("Orthographic cameras are ignored".to_owned()),
+            }
+        }
+
+        let light = index(&value["extensions"]["KHR_lights_punctual"], "light")
+            .and_then(|l| json["extensions"]["KHR_lights_punctual"]["lights"].get(l));
+        if let Some(light) = light {
+            self.light(light, world, lights);
+        }
+
+        for child in indices(value, "children") {
+            self.node(child, world, visited, lights)?;
+        }
+        Ok(())
+    }
+
+    /// Punctual light, with intensities in candela for point lights and in lux for directional ones
+    /// https://github.com/KhronosGroup/glTF/tree/main/extensions/2.0/Khronos/KHR_lights_punctual
+    fn light(&mut self, light: &Value, world: Matrix, lights: &mut Vec<PointLight>) {
+        let intensity =
+            color(numbers(light, "color").unwrap_or([1.0; 3])) * number(light, "intensity", 1.0);
+        match light["type"].as_str() {
+            Some("directional") => {
+                // Lights shine towards -Z, so the light is towards +Z
+                let direction = world
+                    .mul_rotate(Vector {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                    })
+                    .normalized();
+                // Irradiance of a disk of uniform radiance is `radiance * PI * sin^2(radius)`
+                let sin = DIRECTIONAL_LIGHT_RADIUS.radians.sin();
+                self.scene.suns.push(SunLight {
+                    direction,
+                    radius: DIRECTIONAL_LIGHT_RADIUS,
+                    radiance: intensity * (1.0 / (PI * sin * sin)),
+                });
+            }
+            Some(kind) => {
+                if kind == "spot" {
+                    self.warn("Spot lights shine in all directions".to_owned());
+                }
+                lights.push(PointLight {
+                    position: world.pos(),
+                    intensity,
+                });
+            }
+            None => self.warn("Light without a type is ignored".to_owned()),
+        }
+    }
+
+    /// Metallic-roughness material. Surfaces here are diffuse or perfect mirrors,
+    /// so metals become mirrors tinted by the base color and the roughness is ignored.
+    /// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#metallic-roughness-material
+    fn material(&mut self, material: &Value) -> Material {
+        let pbr = &material["pbrMetallicRoughness"];
+        let [r, g, b, _] = numbers(pbr, "baseColorFactor").unwrap_or([1.0; 4]);
+        let factor: TextureRef = Arc::new(color([r, g, b]));
+        let base = self.textured(
+            Arc::new(Multiply {
+                a: factor,
+                b: Arc::new(VertexColor),
+            }),
+            &pbr["baseColorTexture"],
+        );
+
+        // Metalness is in the blue channel, roughness in the green one
+        let metallic_factor: TextureRef = Arc::new(number(pbr, "metallicFactor", 1.0));
+        let metallic = match self.texture(&pbr["metallicRoughnessTexture"]) {
+            Some(texture) => Arc::new(Multiply {
+                a: metallic_factor,
+                b: Arc::new(Channel {
+                    texture,
+                    channel: 2,
+                }),
+            }),
+            None => metallic_factor,
+        };
+
+        let strength = number(
+            &material["extensions"]["KHR_materials_emissive_strength"],
+            "emissiveStrength",
+            1.0,
+        );
+        let emissive = color(numbers(material, "emissiveFactor").unwrap_or([0.0; 3])) * strength;
+        let ambient = self.textured(Arc::new(emissive), &material["emissiveTexture"]);
+
+        if material["normalTexture"].is_object() {
+            self.warn("Normal maps are ignored".to_owned());
+        }
+
+        // Mostly transmissive materials are clear glass, ignoring their tint and textures
+        let extensions = &material["extensions"];
+        let transmission = number(
+            &extensions["KHR_materials_transmission"],
+            "transmissionFactor",
+            0.0,
+        );
+        let glass = if transmission >= 0.5 {
+            let ior = number(&extensions["KHR_materials_ior"], "ior", 1.5);
+            Some(Ior::Constant(ior))
+        } else {
+            None
+        };
+        // Glass with a volume absorbs light down to the attenuation color over its distance
+        let volume = &extensions["KHR_materials_volume"];
+        let distance = number(volume, "attenuationDistance", float::INFINITY);
+        let medium = match glass {
+            Some(_) if distance.is_finite() && distance > 0.0 => {
+                let [r, g, b] = numbers(volume, "attenuationColor")
+                    .unwrap_or([1.0; 3])
+                    .map(|c| -c.max(1e-6).ln() / distance);
+                Some(Medium {
+                    absorption: Color { r, g, b },
+                    ..Medium::VACUUM
+                })
+            }
+            _ => None,
+        };
+
+        let black: TextureRef = Arc::new(Color::BLACK);
+        Material {
+            name: material["name"].as_str().unwrap_or("").to_owned(),
+            ambient,
+            diffuse: Arc::new(Mix {
+                a: base.clone(),
+                b: black.clone(),
+                amount: metallic.clone(),
+            }),
+            specular: Arc::new(Mix {
+                a: black,
+                b: base,
+                amount: metallic,
+            }),
+            glass,
+            medium,
+        }
+    }
+
+    /// `factor` multiplied by the texture of a texture info, if there is one
+    fn textured(&mut self, factor: TextureRef, info: &Value) -> TextureRef {
+        match self.texture(info) {
+            Some(texture) => Arc::new(Multiply {
+                a: factor,
+                b: texture,
+            }),
+            None => factor,
+        }
+    }
+
+    /// Image texture of a texture info. Textures that fail to load are reported and ignored.
+    fn texture(&mut self, info: &Value) -> Option<TextureRef> {
+        let json = self.json;
+        let texture = json["textures"].get(index(info, "index")?)?;
+        if index(info, "texCoord").unwrap_or(0) != 0 {
+            self.warn("Only the first texture coordinates are used".to_owned());
+        }
+        let image = index(texture, "source")?;
+
+        // Clamping only in both directions, repeating otherwise
+        let sampler = index(texture, "sampler").and_then(|s| json["samplers"].get(s));
+        let clamped = |key| sampler.and_then(|s| index(s, key)) == Some(33071);
+        let wrap = if clamped("wrapS") && clamped("wrapT") {
+            Wrap::Clamp
+        } else {
+            Wrap::Repeat
+        };
+
+        let mipmap = match self.images.get(&(image, wrap)) {
+            Some(mipmap) => mipmap.clone(),
+            None => {
+                let mipmap = match self.image(image) {
+                    Ok(image) => Some(Arc::new(MipMap::new(image, wrap))),
+                    Err(error) => {
+                        self.warn(format!("Failed to load image {}: {}", image, error));
+                        None
+                    }
+                };
+                self.images.insert((image, wrap), mipmap.clone());
+                mipmap
+            }
+        }?;
+        Some(Arc::new(ImageTexture {
+            mipmap,
+            filter: GLTF_TEXTURE_FILTER,
+        }))
+    }
+
+    /// Image from a URI or embedded in a buffer view
+    fn image(&self, image: usize) -> io::Result<Image> {
+        let image = self.json["images"]
+            .get(image)
+            .ok_or_else(|| invalid("Missing image"))?;
+        let bytes = match (image["uri"].as_str(), index(image, "bufferView")) {
+            (Some(uri), _) => self.read_uri(uri)?,
+            (None, Some(view)) => self.buffer_view(view)?.0.to_vec(),
+            (None, None) => return Err(invalid("Image has no data")),
+        };
+        Image::decode(&bytes).map_err(|e| invalid(&e.to_string()))
+    }
+}
+
+/// Reads `total` components, `components` per element, of the given component type
+fn read_components(
+    bytes: &[u8],
+    stride: Option<usize>,
+    total: usize,
+    components: usize,
+    component_type: usize,
+    normalized: bool,
+) -> io::Result<Vec<f64>> {
+    let size = match component_type {
+        5120 | 5121 => 1,
+        5122 | 5123 => 2,
+        5125 | 5126 => 4,
+        _ => return Err(invalid("Unknown accessor component type")),
+    };
+    let stride = stride.unwrap_or(components * size);
+    let count = total / components.max(1);
+    if count > 0 && (count - 1) * stride + components * size > bytes.len() {
+        return Err(invalid("Accessor is out of bounds"));
+    }
+
+    let mut values = Vec::with_capacity(total);
+    for element in 0..count {
+        for component in 0..components {
+            let at = element * stride + component * size;
+            let b = &bytes[at..at + size];
+            // Largest value of the integer types, which normalized integers map to 1
+            let (x, max) = match component_type {
+                5120 => (b[0] as i8 as f64, 127.0),
+                5121 => (b[0] as f64, 255.0),
+                5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+                5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+                5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+                _ => (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+            };
+            // Signed ones map to -1..1, where the smallest value is also -1
+            let value = if normalized { (x / max).max(-1.0) } else { x };
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+    use crate::texture::SurfacePoint;
+    use serde_json::json;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    fn floats(values: &[float]) -> Vec<u8> {
+        values.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    fn encode_base64(bytes: &[u8]) -> String {
+        const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        bytes
+            .chunks(3)
+            .flat_map(|chunk| {
+                let bits = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+                (0..=chunk.len()).map(move |i| DIGITS[(bits >> (18 - 6 * i) & 63) as usize] as char)
+            })
+            .collect()
+    }
+
+    fn glb(json: &Value, bin: &[u8]) -> Vec<u8> {
+        let mut json = json.to_string().into_bytes();
+        json.resize(json.len().div_ceil(4) * 4, b' ');
+        let mut bin = bin.to_vec();
+        bin.resize(bin.len().div_ceil(4) * 4, 0);
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+
+        let mut bytes = GLB_MAGIC.to_vec();
+        for word in [2, length as u32, json.len() as u32, GLB_JSON_CHUNK] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend(json);
+        bytes.extend((bin.len() as u32).to_le_bytes());
+        bytes.extend(GLB_BIN_CHUNK.to_le_bytes());
+        bytes.extend(bin);
+        bytes
+    }
+
+    fn white_point() -> SurfacePoint {
+        SurfacePoint {
+            point: Vector::ZERO,
+            normal: v(0.0, 0.0, 1.0),
+            uv: [0.0, 0.0],
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: Color::WHITE,
+        }
+    }
+
+    /// Triangle in the xy plane with red, green and blue corners, placed by two nodes
+    fn triangle_scene() -> Vec<u8> {
+        let mut bin = floats(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        bin.extend(floats(&[0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0]));
+        bin.extend(floats(&[0.0, 0.0, 1.0, 0.0, 0.0, 1.0]));
+        bin.extend([255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255]);
+        bin.extend([0u16, 1, 2].iter().flat_map(|i| i.to_le_bytes()));
+
+        let json = json!({
+            "asset": { "version": "2.0" },
+            "scene": 0,
+            "scenes": [{ "nodes": [0, 3, 4] }],
+            "nodes": [
+                { "children": [1, 2], "translation": [10.0, 0.0, 0.0] },
+                { "mesh": 0 },
+                { "mesh": 0, "translation": [0.0, 0.0, -5.0], "scale": [2.0, 2.0, 2.0] },
+                { "camera": 0, "translation": [0.0, 0.0, 5.0] },
+                {
+                    "translation": [0.0, 3.0, 0.0],
+                    "extensions": { "KHR_lights_punctual": { "light": 0 } }
+                }
+            ],
+            "meshes": [{
+                "primitives": [{
+                    "attributes": { "POSITION": 0, "NORMAL": 1, "TEXCOORD_0": 2, "COLOR_0": 3 },
+                    "indices": 4,
+                    "material": 0
+                }]
+            }],
+            "materials": [{
+                "name": "gray",
+                "pbrMetallicRoughness": { "baseColorFactor": [0.5, 0.5, 0.5, 1.0], "metallicFactor": 0.0 }
+            }],
+            "cameras": [{ "type": "perspective", "perspective": { "yfov": 0.8, "znear": 0.1 } }],
+            "extensionsUsed": ["KHR_lights_punctual"],
+            "extensions": {
+                "KHR_lights_punctual": { "lights": [{ "type": "point", "intensity": 10.0 }] }
+            },
+            "buffers": [{ "byteLength": bin.len() }],
+            "bufferViews": [
+                { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+                { "buffer": 0, "byteOffset": 36, "byteLength": 36 },
+                { "buffer": 0, "byteOffset": 72, "byteLength": 24 },
+                { "buffer": 0, "byteOffset": 96, "byteLength": 12 },
+                { "buffer": 0, "byteOffset": 108, "byteLength": 6 }
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" },
+                { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" },
+                { "bufferView": 2, "componentType": 5126, "count": 3, "type": "VEC2" },
+                { "bufferView": 3, "componentType": 5121, "normalized": true, "count": 3, "type": "VEC4" },
+                { "bufferView": 4, "componentType": 5123, "count": 3, "type": "SCALAR" }
+            ]
+        });
+        glb(&json, &bin)
+    }
+
+    #[test]
+    fn glb_with_instances_materials_lights_and_cameras() {
+        let scene = parse(&triangle_scene(), Path::new("."), 3).unwrap();
+        assert!(scene.warnings.is_empty(), "{:?}", scene.warnings);
+        assert_eq!(scene.objects.len(), 3);
+        assert_eq!(scene.materials.len(), 2);
+
+        // Both nodes share the triangles
+        let geometries: Vec<&Arc<Geometry>> = scene
+            .objects
+            .iter()
+            .filter_map(|o| match o.shape {
+                Shape::Instance { ref geometry, .. } => Some(geometry),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(geometries.len(), 2);
+        assert!(Arc::ptr_eq(geometries[0], geometries[1]));
+
+        let world = Geometry::new(scene.objects.clone());
+        let hit = world
+            .raycast(&Ray::new(v(10.25, 0.25, 10.0), v(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!(approx_eq(hit.distance, 10.0));
+        assert_eq!(hit.material_id, Some(3));
+        assert!((hit.color.r - 0.5).abs() < 0.01 && (hit.color.g - 0.25).abs() < 0.01);
+        // Flipped to the top of the texture
+        assert!((hit.uv[1] - 0.75).abs() < 0.01);
+
+        // Scaled copy behind the first one
+        let hit = world
+            .raycast(&Ray::new(v(11.5, 0.3, -1.0), v(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!(approx_eq(hit.distance, 4.0));
+
+        let gray = &scene.materials[0];
+        assert_eq!(gray.name, "gray");
+        let point = white_point();
+        assert!(approx_eq(gray.diffuse.color(&point).g, 0.5));
+        assert_eq!(gray.specular.color(&point), Color::BLACK);
+        assert_eq!(gray.ambient.color(&point), Color::BLACK);
+
+        // Point light as a small sphere with the same intensity
+        match scene.objects[2].shape {
+            Shape::Sphere { center, radius } => {
+                assert_eq!(center, v(0.0, 3.0, 0.0));
+                let radiance = scene.materials[1].ambient.color(&point);
+                assert!((radiance.r * PI * radius * radius - 10.0).abs() < 0.01);
+            }
+            ref shape => panic!("Light is a {:?}", shape),
+        }
+        assert_eq!(scene.objects[2].material_id, Some(4));
+
+        // Looking down -Z with +X to the right
+        assert_eq!(scene.cameras.len(), 1);
+        assert!(approx_eq(scene.cameras[0].vertical_fov.radians, 0.8));
+        let camera = scene.cameras[0].camera(4, 4);
+        let (origin, center, _) = camera.ray(2.0, 2.0);
+        assert_eq!(origin, v(0.0, 0.0, 5.0));
+        assert!((center - v(0.0, 0.0, -1.0)).len() < 1e-5);
+        let (_, left, _) = camera.ray(0.0, 2.0);
+        assert!(left.x < 0.0);
+        let (_, top, _) = camera.ray(2.0, 0.0);
+        assert!(top.y > 0.0);
+    }
+
+    #[test]
+    fn gltf_with_data_uri_interleaved_strip() {
+        // Positions with a padding float after each, as a strip of two triangles
+        let bin = floats(&[
+            0.0, 0.0, 0.0, 9.0, 1.0, 0.0, 0.0, 9.0, 0.0, 1.0, 0.0, 9.0, 1.0, 1.0, 0.0, 9.0,
+        ]);
+        let json = json!({
+            "asset": { "version": "2.0" },
+            "nodes": [
+                { "children": [1] },
+                {
+                    "mesh": 0,
+                    "matrix": [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 1.0]
+                }
+            ],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 }, "mode": 5 }] }],
+            "buffers": [{
+                "byteLength": bin.len(),
+                "uri": format!("data:application/octet-stream;base64,{}", encode_base64(&bin))
+            }],
+            "bufferViews": [{ "buffer": 0, "byteLength": bin.len(), "byteStride": 16 }],
+            "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3" }]
+        });
+        let scene = parse(json.to_string().as_bytes(), Path::new("."), 0).unwrap();
+        assert_eq!(scene.objects.len(), 1);
+
+        let mesh = match scene.objects[0].shape {
+            Shape::Instance { ref geometry, .. } => match geometry.objects[0].shape {
+                Shape::Mesh { ref mesh } => mesh.clone(),
+                _ => panic!("Primitive is not a mesh"),
+            },
+            _ => panic!("Node is not an instance"),
+        };
+        assert_eq!(mesh.len(), 2);
+        assert_eq!(mesh.positions[3], v(1.0, 1.0, 0.0));
+        // Both triangles wind the same way
+        let normal = |i| {
+            let [a, b, c] = mesh.corners(i);
+            (b - a).cross(c - a).z
+        };
+        assert!(normal(0) > 0.0 && normal(1) > 0.0);
+
+        let world = Geometry::new(scene.objects);
+        let hit = world
+            .raycast(&Ray::new(v(0.7, 0.7, 5.0), v(0.0, 0.0, -1.0)))
+            .unwrap();
+        assert!(approx_eq(hit.distance, 3.0));
+    }
+
+    #[test]
+    fn emissive_primitives_are_lights() {
+        // A gray triangle and, above it, a glowing one, in a node that doubles their size
+        let bin = floats(&[
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 1.0, 0.0, 1.0,
+            1.0,
+        ]);
+        let json = json!({
+            "asset": { "version": "2.0" },
+            "nodes": [{ "mesh": 0, "scale": [2.0, 2.0, 2.0] }],
+            "meshes": [{
+                "primitives": [
+                    { "attributes": { "POSITION": 0 }, "material": 0 },
+                    { "attributes": { "POSITION": 1 }, "material": 1 }
+                ]
+            }],
+            "materials": [
+                { "name": "gray" },
+                { "name": "glow", "emissiveFactor": [1.0, 1.0, 1.0] }
+            ],
+            "buffers": [{ "byteLength": bin.len() }],
+            "bufferViews": [
+                { "buffer": 0, "byteOffset": 0, "byteLength": 36 },
+                { "buffer": 0, "byteOffset": 36, "byteLength": 36 }
+            ],
+            "accessors": [
+                { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3" },
+                { "bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC3" }
+            ]
+        });
+        let scene = parse(&glb(&json, &bin), Path::new("."), 0).unwrap();
+        assert_eq!(scene.objects[0].material_id, None);
+
+        let lights = light::Lights::new(&scene.objects, &scene.materials);
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights.lights()[0].object, 0);
+
+        // Hits on the glowing triangle find its light
+        let world = Geometry::new(scene.objects);
+        let origin = v(0.5, 0.5, 5.0);
+        let hit = world.raycast(&Ray::new(origin, v(0.0, 0.0, -1.0))).unwrap();
+        assert!(approx_eq(hit.distance, 3.0));
+        assert_eq!(hit.material_id, Some(1));
+        assert_eq!(lights.lights()[0].primitive, hit.primitive);
+        assert!(approx_eq(lights.emitter_pdf(0, hit.primitive), 0.5));
+        let sample = lights
+            .sample(origin, Vector::ZERO, [0.5, 0.3, 0.6])
+            .unwrap();
+        let pdf = lights.pdf(
+            origin,
+            Vector::ZERO,
+            0,
+            hit.primitive,
+            sample.point,
+            sample.normal,
+        );
+        assert!((pdf - sample.pdf).abs() / sample.pdf < 0.001);
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let bytes = triangle_scene();
+        assert!(parse(&bytes[..bytes.len() - 8], Path::new("."), 0).is_err());
+        assert!(parse(b"{\"asset\": {\"version\": \"1.0\"}}", Path::new("."), 0).is_err());
+
+        let cycle = json!({
+            "asset": { "version": "2.0" },
+            "scenes": [{ "nodes": [0] }],
+            "nodes": [{ "children": [1] }, { "children": [0] }]
+        });
+        assert!(parse(cycle.to_string().as_bytes(), Path::new("."), 0).is_err());
+
+        let bin = floats(&[0.0; 9]);
+        let out_of_bounds = json!({
+            "asset": { "version": "2.0" },
+            "nodes": [{ "mesh": 0 }],
+            "meshes": [{ "primitives": [{ "attributes": { "POSITION": 0 } }] }],
+            "buffers": [{ "byteLength": bin.len() }],
+            "bufferViews": [{ "buffer": 0, "byteLength": bin.len() }],
+            "accessors": [{ "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3" }]
+        });
+        assert!(parse(&glb(&out_of_bounds, &bin), Path::new("."), 0).is_err());
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for length in 0..8 {
+            let bytes: Vec<u8> = (0..length).map(|i| (i * 37 + 200) as u8).collect();
+            let text = encode_base64(&bytes);
+            assert_eq!(decode_base64(&text).unwrap(), bytes);
+        }
+        assert!(decode_base64("a*b").is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! Readers for high dynamic range images
+
+use crate::color::Color;
+use crate::mipmap::Image;
+use crate::prelude::*;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Loads a Radiance `.hdr` or `.pfm` file based on its extension,
+/// or any other format supported by `Image::load` as low dynamic range data
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+
+    match extension.as_deref() {
+        Some("hdr") | Some("pic") => load_hdr(path),
+        Some("pfm") => load_pfm(path),
+        _ => Image::load(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+/// Radiance RGBE image, with flat or run-length encoded scanlines
+/// https://www.graphics.cornell.edu/~bjw/rgbe.html
+pub fn load_hdr<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    read_hdr(BufReader::new(File::open(path)?))
+}
+
+pub fn read_hdr<R: BufRead>(mut reader: R) -> io::Result<Image> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.starts_with("#?") {
+        return Err(invalid("Missing Radiance header"));
+    }
+
+    // Header variables, terminated by an empty line
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Unexpected end of header"));
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(invalid("Unsupported pixel format"));
+            }
+        }
+    }
+
+    // Resolution string, only the standard orientation is supported
+    line.clear();
+    reader.read_line(&mut line)?;
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let (height, width) = match parts.as_slice() {
+        ["-Y", h, "+X", w] => (
+            h.parse::<usize>().map_err(|_| invalid("Invalid height"))?,
+            w.parse::<usize>().map_err(|_| invalid("Invalid width"))?,
+        ),
+        _ => return Err(invalid("Unsupported resolution string")),
+    };
+    if width == 0 || height == 0 {
+        return Err(invalid("Empty image"));
+    }
+
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for _ in 0..height {
+        read_scanline(&mut reader, &mut scanline)?;
+        pixels.extend(scanline.iter().map(|&rgbe| rgbe_to_color(rgbe)));
+    }
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn read_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> io::Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    reader.read_exact(&mut first)?;
+
+    // New style run-length encoding stores each channel separately
+    let is_rle =
+        (8..0x8000).contains(&width) && first[0] == 2 && first[1] == 2 && first[2] & 0x80 == 0;
+    if !is_rle {
+        scanline[0] = first;
+        for pixel in scanline.iter_mut().skip(1) {
+            reader.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+
+    if ((first[2] as usize) << 8 | first[3] as usize) != width {
+        return Err(invalid("Scanline width mismatch"));
+    }
+
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let mut count = [0u8; 1];
+            reader.read_exact(&mut count)?;
+            let count = count[0] as usize;
+            if count > 128 {
+                // Run of a single value
+                let count = count - 128;
+                if x + count > width {
+                    return Err(invalid("Run past end of scanline"));
+                }
+                let mut value = [0u8; 1];
+                reader.read_exact(&mut value)?;
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = value[0];
+                }
+                x += count;
+            } else {
+                // Literal values
+                if count == 0 || x + count > width {
+                    return Err(invalid("Invalid literal run"));
+                }
+                let mut values = [0u8; 128];
+                reader.read_exact(&mut values[..count])?;
+                for (pixel, &value) in scanline[x..x + count].iter_mut().zip(values.iter()) {
+                    pixel[channel] = value;
+                }
+                x += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn rgbe_to_color(rgbe: [u8; 4]) -> Color {
+    if rgbe[3] == 0 {
+        return Color::BLACK;
+    }
+    // Mantissas are 8-bit fractions sharing the exponent
+    let scale = (2.0 as float).powi(rgbe[3] as i32 - (128 + 8));
+    Color {
+        r: rgbe[0] as float * scale,
+        g: rgbe[1] as float * scale,
+        b: rgbe[2] as float * scale,
+    }
+}
+
+/// Portable float map, color (`PF`) or grayscale (`Pf`)
+/// http://www.pauldebevec.com/Research/HDR/PFM/
+pub fn load_pfm<P: AsRef<Path>>(path: P) -> io::Result<Image> {
+    read_pfm(BufReader::new(File::open(path)?))
+}
+
+pub fn read_pfm<R: BufRead>(mut reader: R) -> io::Result<Image> {
+    // Header consists of three whitespace separated tokens after the magic
+    let mut tokens = Vec::new();
+    while tokens.len() < 4 {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("Unexpected end of header"));
+        }
+        tokens.extend(line.split_whitespace().map(str::to_owned));
+    }
+
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("Missing PFM header")),
+    };
+    let width: usize = tokens[1].parse().map_err(|_| invalid("Invalid width"))?;
+    let height: usize = tokens[2].parse().map_err(|_| invalid("Invalid height"))?;
+    let scale: float = tokens[3].parse().map_err(|_| invalid("Invalid scale"))?;
+    if width == 0 || height == 0 {
+        return Err(invalid("Empty image"));
+    }
+
+    // Negative scale means little endian
+    let little_endian = scale < 0.0;
+    let mut data = vec![0u8; width * height * channels * 4];
+    reader.read_exact(&mut data)?;
+    let values: Vec<float> = data
+        .chunks_exact(4)
+        .map(|b| {
+            let bytes = [b[0], b[1], b[2], b[3]];
+            if little_endian {
+                f32::from_le_bytes(bytes)
+            } else {
+                f32::from_be_bytes(bytes)
+            }
+        })
+        .collect();
+
+    // Rows are stored bottom to top
+    let mut pixels = Vec::with_capacity(width * height);
+    for row in values.chunks_exact(width * channels).rev() {
+        pixels.extend(row.chunks_exact(channels).map(|c| {
+            if channels == 3 {
+                Color {
+                    r: c[0],
+                    g: c[1],
+                    b: c[2],
+                }
+            } else {
+                Color::WHITE * c[0]
+            }
+        }));
+    }
+
+    Ok(Image {
+        width,
+        height,
+        pixels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hdr_flat_and_rle() {
+        let mut flat = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        flat.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+        let image = read_hdr(&flat[..]).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(
+            image.pixels[0],
+            Color {
+                r: 1.0,
+                g: 0.5,
+                b: 0.0
+            }
+        );
+        assert_eq!(image.pixels[1], Color::BLACK);
+
+        // Eight pixels, red as a run, green as literals, blue zero, shared exponent
+        let mut rle = b"#?RGBE\n\n-Y 1 +X 8\n".to_vec();
+        rle.extend_from_slice(&[2, 2, 0, 8]);
+        rle.extend_from_slice(&[128 + 8, 128]);
+        rle.extend_from_slice(&[8, 0, 32, 64, 96, 128, 160, 192, 224]);
+        rle.extend_from_slice(&[128 + 8, 0]);
+        rle.extend_from_slice(&[128 + 8, 130]);
+        let image = read_hdr(&rle[..]).unwrap();
+        assert_eq!(image.pixels.len(), 8);
+        for (i, p) in image.pixels.iter().enumerate() {
+            assert!(approx_eq(p.r, 2.0));
+            assert!(approx_eq(p.g, i as float * 0.5));
+            assert!(approx_eq(p.b, 0.0));
+        }
+    }
+
+    #[test]
+    fn pfm_bottom_to_top() {
+        let mut data = b"PF\n1 2\n-1.0\n".to_vec();
+        for v in [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0].iter() {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        let image = read_pfm(&data[..]).unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert_eq!(
+            image.pixels[0],
+            Color {
+                r: 4.0,
+                g: 5.0,
+                b: 6.0
+            }
+        );
+
+        let mut data = b"Pf 1 1 1.0\n".to_vec();
+        data.extend_from_slice(&0.25f32.to_be_bytes());
+        let image = read_pfm(&data[..]).unwrap();
+        assert_eq!(image.pixels[0], Color::WHITE * 0.25);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!(read_hdr(&b"P6\n1 1\n255\n"[..]).is_err());
+        assert!(read_pfm(&b"PX\n1 1\n1.0\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,1077 @@
+//! Colors seen along camera rays, from the light arriving along them or, for finding problems
+//! with the geometry, the materials and the acceleration structure, from the surfaces they hit
+
+use crate::bvh;
+use crate::color::Color;
+use crate::environment::EnvironmentSample;
+use crate::material::{scatter_glass, Ior};
+use crate::medium::Medium;
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::raycast::{Footprint, RayDifferential, RayHit};
+use crate::sampling::{self, power_heuristic};
+use crate::scene::Scene;
+use crate::spectrum::{SampledSpectrum, SampledWavelengths};
+use crate::texture::SurfacePoint;
+use crate::vector::{Point, Vector};
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::ops::{Add, Div, Mul};
+use std::str::FromStr;
+
+fn random2() -> [float; 2] {
+    [sampling::random(), sampling::random()]
+}
+
+fn random3() -> [float; 3] {
+    [sampling::random(), sampling::random(), sampling::random()]
+}
+
+pub trait Integrator: Sync {
+    /// Color seen along a camera ray, with differentials towards the neighbouring pixels
+    /// for filtering textures
+    fn radiance(
+        &self,
+        scene: &Scene,
+        origin: Point,
+        direction: Vector,
+        differential: Option<RayDifferential>,
+    ) -> Color;
+}
+
+/// Paths from the camera, sampling the emitters, the environment and the sun at each diffuse
+/// bounce, weighted against hitting them with multiple importance sampling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathTracer {
+    /// Reflections after the surface the camera ray hits
+    pub bounces: usize,
+}
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        origin: Point,
+        direction: Vector,
+        differential: Option<RayDifferential>,
+    ) -> Color {
+        path(
+            self.bounces,
+            scene,
+            &mut Rgb,
+            origin,
+            direction,
+            differential,
+        )
+    }
+}
+
+/// Path tracer carrying a few wavelengths per path instead of RGB, so that glass disperses light
+/// into its colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpectralPathTracer {
+    /// Reflections after the surface the camera ray hits
+    pub bounces: usize,
+}
+
+impl Integrator for SpectralPathTracer {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        origin: Point,
+        direction: Vector,
+        differential: Option<RayDifferential>,
+    ) -> Color {
+        let mut wavelengths = SampledWavelengths::sample_visible(sampling::random());
+        let radiance = path(
+            self.bounces,
+            scene,
+            &mut wavelengths,
+            origin,
+            direction,
+            differential,
+        );
+        wavelengths.to_color(radiance)
+    }
+}
+
+/// What paths carry light as: RGB colors, or spectra at sampled wavelengths
+trait Channels {
+    type Value: Copy
+        + Add<Output = Self::Value>
+        + Mul<Output = Self::Value>
+        + Mul<float, Output = Self::Value>
+        + Div<float, Output = Self::Value>;
+
+    const BLACK: Self::Value;
+    const WHITE: Self::Value;
+
+    fn reflectance(&self, rgb: Color) -> Self::Value;
+
+    fn emission(&self, rgb: Color) -> Self::Value;
+
+    /// Converts a medium coefficient, which unlike reflectances can be above one
+    fn coefficient(&self, rgb: Color) -> Self::Value;
+
+    fn average(value: Self::Value) -> float;
+
+    fn max(value: Self::Value) -> float;
+
+    /// Index of refraction of glass for the light the path carries
+    fn ior(&mut self, ior: Ior) -> float;
+}
+
+struct Rgb;
+
+impl Channels for Rgb {
+    type Value = Color;
+
+    const BLACK: Color = Color::BLACK;
+    const WHITE: Color = Color::WHITE;
+
+    fn reflectance(&self, rgb: Color) -> Color {
+        rgb
+    }
+
+    fn emission(&self, rgb: Color) -> Color {
+        rgb
+    }
+
+    fn coefficient(&self, rgb: Color) -> Color {
+        rgb
+    }
+
+    fn average(value: Color) -> float {
+        (value.r + value.g + value.b) / 3.0
+    }
+
+    fn max(value: Color) -> float {
+        value.r.max(value.g).max(value.b)
+    }
+
+    fn ior(&mut self, ior: Ior) -> float {
+        ior.at(Ior::REFERENCE)
+    }
+}
+
+impl Channels for SampledWavelengths {
+    type Value = SampledSpectrum;
+
+    const BLACK: SampledSpectrum = SampledSpectrum::ZERO;
+    const WHITE: SampledSpectrum = SampledSpectrum::ONE;
+
+    fn reflectance(&self, rgb: Color) -> SampledSpectrum {
+        SampledWavelengths::reflectance(self, rgb)
+    }
+
+    fn emission(&self, rgb: Color) -> SampledSpectrum {
+        SampledWavelengths::emission(self, rgb)
+    }
+
+    fn coefficient(&self, rgb: Color) -> SampledSpectrum {
+        self.unbounded(rgb)
+    }
+
+    fn average(value: SampledSpectrum) -> float {
+        value.average()
+    }
+
+    fn max(value: SampledSpectrum) -> float {
+        value.max()
+    }
+
+    /// Refraction bends each wavelength differently, so only the hero wavelength continues
+    fn ior(&mut self, ior: Ior) -> float {
+        if ior.is_dispersive() {
+            self.terminate_secondary();
+        }
+        ior.at(self.lambda[0])
+    }
+}
+
+/// Crossings of invisible medium boundaries a ray makes at most between two bounces
+const MAX_CROSSINGS: usize = 64;
+
+/// Light arriving along a camera ray, in the channels of `channels`
+fn path<C: Channels>(
+    bounces: usize,
+    scene: &Scene,
+    channels: &mut C,
+    from: Point,
+    direction: Vector,
+    mut differential: Option<RayDifferential>,
+) -> C::Value {
+    let mut ray = Ray::new(from, direction);
+    // Camera rays start outside of the closed surfaces with media
+    let mut medium = scene.atmosphere.as_ref();
+
+    let mut mask_color = C::WHITE; // Surfaces only reflect their own color
+    let mut acc_color = C::BLACK; // Total color
+
+    // Density of the direction chosen at the last diffuse bounce, zero after mirror bounces
+    let mut bsdf_pdf: float = 0.0;
+    let mut bounce_point = ray.origin;
+    let mut bounce_normal = Vector::ZERO;
+
+    let mut bounce = 0;
+    let mut crossings = 0;
+    while bounce <= bounces && crossings <= MAX_CROSSINGS {
+        let hit = scene.raycast(&ray);
+
+        // Scattering in the medium before reaching the surface
+        if let Some(medium) = medium {
+            let distance = hit.as_ref().map_or(float::INFINITY, |hit| hit.distance);
+            let (collision, emitted) = delta_track(channels, medium, &ray, distance);
+            acc_color = acc_color + mask_color * emitted;
+            match collision {
+                Collision::Absorbed => break,
+                Collision::Passed { weight } => mask_color = mask_color * weight,
+                Collision::Scattered { t, weight } => {
+                    mask_color = mask_color * weight;
+                    let point = ray.at(t);
+                    let (direction, pdf) = medium.sample_phase(ray.direction, random2());
+
+                    // Sample the lights directly, weighted against finding them by scattering
+                    let phase = |to_light: Vector| medium.phase(ray.direction, to_light);
+                    for (light, shadow) in light_samples(scene, point, Vector::ZERO, Vector::ZERO) {
+                        let phase = phase(light.direction);
+                        if phase > 0.0 {
+                            let weight = power_heuristic(1, light.pdf, 1, phase);
+                            let transmittance =
+                                transmittance(scene, channels, shadow, Some(medium));
+                            acc_color = acc_color
+                                + mask_color
+                                    * transmittance
+                                    * channels.emission(light.radiance)
+                                    * (phase * weight / light.pdf);
+                        }
+                    }
+
+                    differential = None;
+                    // The phase function is its own sampling density
+                    bsdf_pdf = pdf;
+                    bounce_point = point;
+                    bounce_normal = Vector::ZERO;
+                    ray = Ray::new(point, direction);
+                    bounce += 1;
+                    crossings = 0;
+                    continue;
+                }
+            }
+        }
+
+        let hit = match hit {
+            Some(hit) => hit,
+            None => {
+                // Environment, weighted against sampling it directly at the last bounce
+                let weight = if bsdf_pdf > 0.0 {
+                    power_heuristic(1, bsdf_pdf, 1, scene.environment.pdf(ray.direction))
+                } else {
+                    1.0
+                };
+                let environment = channels.emission(scene.environment.radiance(ray.direction));
+                acc_color = acc_color + environment * mask_color * weight;
+
+                if let Some(sun) = scene.sun {
+                    let weight = if bsdf_pdf > 0.0 {
+                        power_heuristic(1, bsdf_pdf, 1, sun.pdf(ray.direction))
+                    } else {
+                        1.0
+                    };
+                    let sun = channels.emission(sun.radiance(ray.direction));
+                    acc_color = acc_color + sun * mask_color * weight;
+                }
+
+                break;
+            }
+        };
+
+        let hit_point = hit.point;
+        let footprint = differential
+            .map(|d| d.footprint(&hit, hit_point))
+            .unwrap_or(Footprint::ZERO);
+        let surface = SurfacePoint {
+            point: hit_point,
+            normal: hit.shading_normal,
+            uv: hit.uv,
+            duvdx: footprint.duvdx,
+            duvdy: footprint.duvdy,
+            color: hit.color,
+        };
+
+        let material = scene.material(&hit);
+        let (ambient, diffuse, specular) = match material {
+            Some(material) => (
+                material.ambient.color(&surface),
+                material.diffuse.color(&surface),
+                material.specular.color(&surface),
+            ),
+            // Default material
+            None => (Color::BLACK, Color::WHITE, Color::BLACK),
+        };
+        let glass = material.and_then(|material| material.glass);
+        let interior = material.and_then(|material| material.medium.as_ref());
+        // Medium on the other side of the surface
+        let beyond = if hit.front_face {
+            interior
+        } else {
+            scene.atmosphere.as_ref()
+        };
+
+        // Emission, weighted against sampling the light directly at the last bounce
+        if hit.front_face {
+            let weight = if bsdf_pdf > 0.0 {
+                let light_pdf = scene.lights.pdf(
+                    bounce_point,
+                    bounce_normal,
+                    hit.object,
+                    hit.primitive,
+                    hit_point,
+                    hit.normal,
+                );
+                power_heuristic(1, bsdf_pdf, 1, light_pdf)
+            } else {
+                1.0
+            };
+            acc_color = acc_color + channels.emission(ambient) * mask_color * weight;
+        }
+
+        // Boundaries of media that are not glass let rays through unchanged
+        if glass.is_none() && interior.is_some() {
+            medium = beyond;
+            ray = Ray::spawn(hit_point, hit.error, hit.normal, ray.direction);
+            crossings += 1;
+            continue;
+        }
+        bounce += 1;
+        crossings = 0;
+
+        // Glass reflects or refracts by the Fresnel reflectance, which cancels out of the
+        // weight. Refracted texture footprints are not tracked.
+        if let Some(ior) = glass {
+            let n = channels.ior(ior);
+            let eta = if hit.front_face { n } else { 1.0 / n };
+            let (direction, refracted) =
+                scatter_glass(ray.direction, hit.shading_normal, eta, sampling::random());
+            if refracted {
+                differential = None;
+                medium = beyond;
+            } else {
+                differential =
+                    differential.map(|d| d.reflect(ray.direction, &hit, hit_point, &footprint));
+            }
+            bsdf_pdf = 0.0;
+
+            ray = Ray::spawn(hit_point, hit.error, hit.normal, direction);
+            continue;
+        }
+
+        // Pick the mirror or the diffuse lobe by their brightness
+        let diffuse_weight = diffuse.luminance();
+        let specular_weight = specular.luminance();
+        if diffuse_weight + specular_weight <= 0.0 {
+            break;
+        }
+        let p_specular = specular_weight / (diffuse_weight + specular_weight);
+        let diffuse = channels.reflectance(diffuse);
+
+        // Sample the emitters, the environment and the sun directly for the diffuse lobe
+        if diffuse_weight > 0.0 {
+            for (light, shadow) in light_samples(scene, hit_point, hit.error, hit.normal) {
+                let cos = light.direction.dot(hit.shading_normal);
+                if cos > 0.0 {
+                    let light_bsdf_pdf = (1.0 - p_specular) * cos / PI;
+                    let weight = power_heuristic(1, light.pdf, 1, light_bsdf_pdf);
+                    let transmittance = transmittance(scene, channels, shadow, medium);
+                    acc_color = acc_color
+                        + mask_color
+                            * diffuse
+                            * transmittance
+                            * channels.emission(light.radiance)
+                            * (cos / PI * weight / light.pdf);
+                }
+            }
+        }
+
+        if sampling::random::<float>() < p_specular {
+            let reflection = ray.direction.reflect(hit.shading_normal);
+            differential =
+                differential.map(|d| d.reflect(ray.direction, &hit, hit_point, &footprint));
+            mask_color = mask_color * channels.reflectance(specular) / p_specular;
+            bsdf_pdf = 0.0;
+
+            ray = Ray::spawn(hit_point, hit.error, hit.normal, reflection);
+        } else {
+            let (bounce, pdf) = sampling::cosine_hemisphere(hit.shading_normal, random2());
+            differential = None;
+            mask_color = mask_color * diffuse / (1.0 - p_specular);
+            bsdf_pdf = (1.0 - p_specular) * pdf;
+            bounce_point = hit_point;
+            bounce_normal = hit.shading_normal;
+
+            ray = Ray::spawn(hit_point, hit.error, hit.normal, bounce);
+        }
+    }
+
+    acc_color
+}
+
+/// Direct light samples at a point from an emitter, the environment and the sun, with shadow
+/// rays towards them
+fn light_samples(
+    scene: &Scene,
+    point: Point,
+    error: Vector,
+    normal: Vector,
+) -> impl Iterator<Item = (EnvironmentSample, Ray)> {
+    let emitter = scene.lights.sample(point, normal, random3()).map(|light| {
+        let shadow = Ray::spawn_to(point, error, normal, light.point);
+        let light = EnvironmentSample {
+            direction: light.direction,
+            radiance: light.radiance,
+            pdf: light.pdf,
+        };
+        (light, shadow)
+    });
+    let distant = [
+        scene.environment.sample(random2()),
+        scene.sun.map(|sun| sun.sample(random2())),
+    ];
+    let distant = IntoIterator::into_iter(distant)
+        .flatten()
+        .map(move |light| {
+            let shadow = Ray::spawn(point, error, normal, light.direction);
+            (light, shadow)
+        });
+    emitter.into_iter().chain(distant)
+}
+
+/// Event where delta tracking ended a ray segment in a medium
+enum Collision<V> {
+    Absorbed,
+    Scattered {
+        t: float,
+        weight: V,
+    },
+    /// The ray reached the end of the segment
+    Passed {
+        weight: V,
+    },
+}
+
+/// Samples where light along a ray segment of `distance` through a medium last interacted with
+/// it, together with the light the medium emits towards the start of the segment. Tentative
+/// collisions are sampled by the majorant of the densest channel, and the others are weighted
+/// for the collisions that are null for them.
+/// https://www.pbr-book.org/4ed/Volume_Scattering/Volume_Scattering_Processes#DeltaTracking
+fn delta_track<C: Channels>(
+    channels: &C,
+    medium: &Medium,
+    ray: &Ray,
+    distance: float,
+) -> (Collision<C::Value>, C::Value) {
+    let absorption = channels.coefficient(medium.absorption);
+    let scattering = channels.coefficient(medium.scattering);
+    let emission = if medium.emission == Color::BLACK {
+        None
+    } else {
+        Some(channels.emission(medium.emission))
+    };
+    let densest = C::max(absorption + scattering);
+
+    let mut weight = C::WHITE;
+    let mut emitted = C::BLACK;
+    if densest <= 0.0 {
+        return (Collision::Passed { weight }, emitted);
+    }
+    for segment in medium.majorants(ray, distance) {
+        let majorant = densest * segment.majorant;
+        if majorant <= 0.0 {
+            continue;
+        }
+        let mut t = segment.t_min;
+        loop {
+            t -= (1.0 - sampling::random::<float>()).ln() / majorant;
+            if t >= segment.t_max {
+                break;
+            }
+            let point = ray.at(t);
+            let density = medium.density(point);
+            let absorption = absorption * density;
+            let scattering = scattering * density;
+            if let Some(emission) = emission {
+                let scale = medium.emission_scale(point) / majorant;
+                emitted = emitted + weight * absorption * emission * scale;
+            }
+
+            let p_absorb = C::average(absorption) / majorant;
+            let p_scatter = C::average(scattering) / majorant;
+            let u = sampling::random::<float>();
+            if u < p_absorb {
+                return (Collision::Absorbed, emitted);
+            }
+            if u < p_absorb + p_scatter {
+                let weight = weight * scattering / (p_scatter * majorant);
+                return (Collision::Scattered { t, weight }, emitted);
+            }
+            let null = C::WHITE * majorant + (absorption + scattering) * -1.0;
+            weight = weight * null / ((1.0 - p_absorb - p_scatter) * majorant);
+        }
+    }
+    (Collision::Passed { weight }, emitted)
+}
+
+/// Fraction of light passing a ray segment of `distance` through a medium, estimated by
+/// ratio tracking
+/// https://www.pbr-book.org/4ed/Light_Transport_II_Volume_Rendering/Volume_Scattering_Integrator#RatioTracking
+fn ratio_track<C: Channels>(channels: &C, medium: &Medium, ray: &Ray, distance: float) -> C::Value {
+    // Beyond this the estimate continues by Russian roulette
+    const ROULETTE: float = 0.1;
+
+    let extinction = channels.coefficient(medium.extinction());
+    let densest = C::max(extinction);
+    let mut transmittance = C::WHITE;
+    if densest <= 0.0 {
+        return transmittance;
+    }
+    for segment in medium.majorants(ray, distance) {
+        let majorant = densest * segment.majorant;
+        if majorant <= 0.0 {
+            continue;
+        }
+        let mut t = segment.t_min;
+        loop {
+            t -= (1.0 - sampling::random::<float>()).ln() / majorant;
+            if t >= segment.t_max {
+                break;
+            }
+            let density = medium.density(ray.at(t));
+            let null = C::WHITE + extinction * (-density / majorant);
+            transmittance = transmittance * null;
+            let max = C::max(transmittance);
+            if max < ROULETTE {
+                let survival = max / ROULETTE;
+                if sampling::random::<float>() >= survival {
+                    return C::BLACK;
+                }
+                transmittance = transmittance / survival;
+            }
+        }
+    }
+    transmittance
+}
+
+/// Fraction of light arriving along a shadow ray that starts in `medium`, through media and
+/// their invisible boundaries, and none if a surface blocks it
+fn transmittance<'a, C: Channels>(
+    scene: &'a Scene,
+    channels: &C,
+    mut ray: Ray,
+    mut medium: Option<&'a Medium>,
+) -> C::Value {
+    let end = ray.at(ray.t_max);
+    let mut transmittance = C::WHITE;
+    for _ in 0..=MAX_CROSSINGS {
+        let hit = scene.raycast(&ray);
+        if let Some(medium) = medium {
+            let distance = hit.as_ref().map_or(ray.t_max, |hit| hit.distance);
+            transmittance = transmittance * ratio_track(channels, medium, &ray, distance);
+        }
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return transmittance,
+        };
+        match scene.material(&hit) {
+            Some(material) if material.glass.is_none() && material.medium.is_some() => {
+                medium = if hit.front_face {
+                    material.medium.as_ref()
+                } else {
+                    scene.atmosphere.as_ref()
+                };
+                ray = if ray.t_max.is_finite() {
+                    Ray::spawn_to(hit.point, hit.error, hit.normal, end)
+                } else {
+                    Ray::spawn(hit.point, hit.error, hit.normal, ray.direction)
+                };
+            }
+            _ => return C::BLACK,
+        }
+    }
+    C::BLACK
+}
+
+/// Property of the surface a camera ray hits, shown instead of the light arriving from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    ShadingNormal,
+    GeometricNormal,
+    /// Nearer surfaces brighter
+    Depth,
+    /// Fractional parts of the texture coordinates as red and green
+    Uv,
+    /// Weights of the triangle corners as red, green and blue
+    Barycentric,
+    /// Color for each material, grey for the default material
+    MaterialId,
+    /// Color for each top level object
+    ObjectId,
+    /// Whether a cosine distributed direction leaves without hitting anything nearby
+    AmbientOcclusion,
+    /// Bounding volume nodes and items the camera ray was tested against, from blue to red
+    TraversalCost,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 9] = [
+        DebugView::ShadingNormal,
+        DebugView::GeometricNormal,
+        DebugView::Depth,
+        DebugView::Uv,
+        DebugView::Barycentric,
+        DebugView::MaterialId,
+        DebugView::ObjectId,
+        DebugView::AmbientOcclusion,
+        DebugView::TraversalCost,
+    ];
+
+    /// Name on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            DebugView::ShadingNormal => "shading-normal",
+            DebugView::GeometricNormal => "geometric-normal",
+            DebugView::Depth => "depth",
+            DebugView::Uv => "uv",
+            DebugView::Barycentric => "barycentric",
+            DebugView::MaterialId => "material-id",
+            DebugView::ObjectId => "object-id",
+            DebugView::AmbientOcclusion => "ambient-occlusion",
+            DebugView::TraversalCost => "traversal-cost",
+        }
+    }
+}
+
+impl fmt::Display for DebugView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DebugView::ALL
+            .iter()
+            .copied()
+            .find(|view| view.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = DebugView::ALL.iter().map(|view| view.name()).collect();
+                format!("unknown view {:?}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// Traversal steps shown in the hottest color
+const HOTTEST_TRAVERSAL: float = 200.0;
+
+/// Integrator showing a debug view of the scene
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DebugIntegrator {
+    pub view: DebugView,
+    /// Distance over which depth fades out, and within which surfaces occlude
+    pub distance: float,
+}
+
+impl DebugIntegrator {
+    /// Debug view with distances relative to the size of the scene
+    pub fn new(view: DebugView, scene: &Scene) -> Self {
+        let bounds = scene.finite_bounds();
+        let size = if bounds.is_empty() {
+            1.0
+        } else {
+            bounds.diagonal().len().max(float::EPSILON)
+        };
+        let distance = match view {
+            DebugView::AmbientOcclusion => 0.1 * size,
+            _ => size,
+        };
+        Self { view, distance }
+    }
+}
+
+impl Integrator for DebugIntegrator {
+    fn radiance(
+        &self,
+        scene: &Scene,
+        origin: Point,
+        direction: Vector,
+        _differential: Option<RayDifferential>,
+    ) -> Color {
+        let ray = Ray::new(origin, direction);
+        if self.view == DebugView::TraversalCost {
+            bvh::take_traversal_steps();
+            scene.raycast(&ray);
+            return heat(bvh::take_traversal_steps() as float / HOTTEST_TRAVERSAL);
+        }
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => return Color::BLACK,
+        };
+        match self.view {
+            DebugView::ShadingNormal => unit_vector_color(hit.shading_normal),
+            DebugView::GeometricNormal => unit_vector_color(hit.normal),
+            DebugView::Depth => Color::WHITE * (1.0 - hit.distance / self.distance).max(0.0),
+            DebugView::Uv => Color {
+                r: hit.uv[0] - hit.uv[0].floor(),
+                g: hit.uv[1] - hit.uv[1].floor(),
+                b: 0.0,
+            },
+            DebugView::Barycentric => {
+                let [r, g, b] = hit.barycentric;
+                Color { r, g, b }
+            }
+            DebugView::MaterialId => hit
+                .material_id
+                .map_or(Color::WHITE * 0.5, |id| id_color(id as u64)),
+            DebugView::ObjectId => id_color(hit.object as u64),
+            DebugView::AmbientOcclusion => self.ambient_occlusion(scene, &hit),
+            DebugView::TraversalCost => unreachable!(),
+        }
+    }
+}
+
+impl DebugIntegrator {
+    fn ambient_occlusion(&self, scene: &Scene, hit: &RayHit) -> Color {
+        let (direction, _) = sampling::cosine_hemisphere(hit.shading_normal, random2());
+        let occluder = Ray {
+            t_max: self.distance,
+            ..Ray::spawn(hit.point, hit.error, hit.normal, direction)
+        };
+        if scene.raycast(&occluder).is_some() {
+            Color::BLACK
+        } else {
+            Color::WHITE
+        }
+    }
+}
+
+/// Components of a unit vector mapped from -1..1 to 0..1
+fn unit_vector_color(v: Vector) -> Color {
+    Color {
+        r: 0.5 + 0.5 * v.x,
+        g: 0.5 + 0.5 * v.y,
+        b: 0.5 + 0.5 * v.z,
+    }
+}
+
+/// Bright color that tells apart nearby indices
+fn id_color(id: u64) -> Color {
+    // Bits of the fractional part of the golden ratio spread consecutive ids apart
+    let hash = (id + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    let channel = |shift: u32| 0.2 + 0.8 * ((hash >> shift) & 0xff) as float / 255.0;
+    Color {
+        r: channel(56),
+        g: channel(48),
+        b: channel(40),
+    }
+}
+
+/// Blue through green and yellow to red as `t` goes from 0 to 1
+pub(crate) fn heat(t: float) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    Color {
+        r: (2.0 * t - 0.5).clamp(0.0, 1.0),
+        g: (1.5 - (4.0 * t - 2.0).abs()).clamp(0.0, 1.0),
+        b: (1.5 - 4.0 * t).clamp(0.0, 1.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bounds::Bounds;
+    use crate::environment::EnvironmentLight;
+    use crate::material::{Ior, Material};
+    use crate::object::{Object, Shape};
+    use crate::volume::{Grid, Volume};
+    use crate::Matrix;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    fn scene() -> Scene {
+        let objects = vec![
+            Object {
+                shape: Shape::Triangle {
+                    corners: [v(0.0, -1.0, -1.0), v(0.0, 1.0, -1.0), v(0.0, 0.0, 1.0)],
+                    uvs: Shape::BARYCENTRIC_UVS,
+                },
+                material_id: Some(0),
+            },
+            Object {
+                shape: Shape::Sphere {
+                    center: v(5.0, 0.0, 0.0),
+                    radius: 1.0,
+                },
+                material_id: None,
+            },
+        ];
+        Scene::new(
+            objects,
+            vec![Material::diffuse(
+                "grey",
+                std::sync::Arc::new(Color::WHITE * 0.5),
+            )],
+            EnvironmentLight::uniform(Color::WHITE),
+            None,
+        )
+    }
+
+    #[test]
+    fn views_parse_from_their_names() {
+        for &view in DebugView::ALL.iter() {
+            assert_eq!(view.name().parse(), Ok(view));
+        }
+        assert!("normals".parse::<DebugView>().is_err());
+    }
+
+    #[test]
+    fn debug_views_show_the_hit() {
+        let scene = scene();
+        let origin = v(-1.0, 0.0, 0.0);
+        let forward = v(1.0, 0.0, 0.0);
+        let show = |view: DebugView, direction: Vector| {
+            DebugIntegrator::new(view, &scene).radiance(&scene, origin, direction, None)
+        };
+
+        let normal = show(DebugView::GeometricNormal, forward);
+        assert!((normal.r - 0.0).abs() < 1e-5 && (normal.g - 0.5).abs() < 1e-5);
+        let weights = show(DebugView::Barycentric, forward);
+        assert!((weights.r + weights.g + weights.b - 1.0).abs() < 1e-5);
+        assert_eq!(show(DebugView::MaterialId, forward), id_color(0));
+        assert_eq!(show(DebugView::ObjectId, forward), id_color(0));
+        assert_ne!(id_color(0), id_color(1));
+
+        // Depth fades with distance and misses are black
+        let near = show(DebugView::Depth, forward).r;
+        let beside = v(1.0, 1.0, 0.0);
+        assert!(near > 0.0 && near < 1.0);
+        assert_eq!(show(DebugView::Depth, beside), Color::BLACK);
+
+        // Rays that travel through the hierarchy cost something, and the count resets
+        bvh::take_traversal_steps();
+        scene.raycast(&Ray::new(origin, forward));
+        assert!(bvh::take_traversal_steps() > 0);
+        assert_eq!(bvh::take_traversal_steps(), 0);
+        assert_ne!(heat(0.0), heat(1.0));
+    }
+
+    #[test]
+    fn path_tracer_sees_a_uniform_environment() {
+        let scene = Scene::new(
+            Vec::new(),
+            Vec::new(),
+            EnvironmentLight::uniform(Color::WHITE * 0.25),
+            None,
+        );
+        let tracer = PathTracer { bounces: 3 };
+        let color = tracer.radiance(&scene, Vector::ZERO, v(0.0, 0.0, 1.0), None);
+        assert!((color.g - 0.25).abs() < 1e-5);
+    }
+
+    /// Mean color seen through a glass sphere in a uniform environment, which glass neither
+    /// absorbs nor adds to
+    fn through_glass(integrator: &dyn Integrator, ior: Ior) -> Color {
+        let scene = Scene::new(
+            vec![Object {
+                shape: Shape::Sphere {
+                    center: Vector::ZERO,
+                    radius: 1.0,
+                },
+                material_id: Some(0),
+            }],
+            vec![Material::glass("glass", ior)],
+            EnvironmentLight::uniform(Color::WHITE * 0.25),
+            None,
+        );
+        let n = 16000;
+        let mut sum = Color::BLACK;
+        for i in 0..n {
+            let offset = (i as float / n as float - 0.5) * 1.8;
+            let color = integrator.radiance(&scene, v(-3.0, offset, 0.0), v(1.0, 0.0, 0.0), None);
+            sum = sum + color;
+        }
+        sum / n as float
+    }
+
+    #[test]
+    fn glass_neither_absorbs_nor_emits() {
+        let rgb = through_glass(&PathTracer { bounces: 30 }, Ior::Constant(1.5));
+        let spectral = through_glass(&SpectralPathTracer { bounces: 30 }, Ior::BK7);
+        for color in [rgb, spectral] {
+            for c in [color.r, color.g, color.b] {
+                assert!((c - 0.25).abs() < 0.015, "{:?}", color);
+            }
+        }
+    }
+
+    #[test]
+    fn glass_disperses_blue_more_than_red() {
+        assert!((Ior::BK7.at(Ior::REFERENCE) - 1.5168).abs() < 1e-3);
+        assert!(Ior::BK7.at(400.0) > Ior::BK7.at(700.0));
+        assert!(
+            Ior::SF11.at(400.0) - Ior::SF11.at(700.0) > Ior::BK7.at(400.0) - Ior::BK7.at(700.0)
+        );
+        assert_eq!(Ior::Constant(1.5).at(400.0), 1.5);
+
+        let cauchy = Ior::Cauchy { a: 1.5, b: 0.004 };
+        assert!(approx_eq(cauchy.at(1000.0), 1.504));
+
+        // Only the hero wavelength continues through dispersive glass
+        let mut wavelengths = SampledWavelengths::sample_visible(0.5);
+        wavelengths.ior(Ior::Constant(1.5));
+        assert!(!wavelengths.secondary_terminated());
+        let n = wavelengths.ior(Ior::BK7);
+        assert!(wavelengths.secondary_terminated());
+        assert_eq!(n, Ior::BK7.at(wavelengths.lambda[0]));
+    }
+
+    /// Unit sphere whose inside is `medium`, in a uniform environment
+    fn medium_sphere(medium: Medium, environment: Color) -> Scene {
+        let mut boundary = Material::diffuse("boundary", std::sync::Arc::new(Color::BLACK));
+        boundary.medium = Some(medium);
+        Scene::new(
+            vec![Object {
+                shape: Shape::Sphere {
+                    center: Vector::ZERO,
+                    radius: 1.0,
+                },
+                material_id: Some(0),
+            }],
+            vec![boundary],
+            EnvironmentLight::uniform(environment),
+            None,
+        )
+    }
+
+    #[test]
+    fn absorbing_media_follow_beer_lambert() {
+        sampling::seed(1);
+        let absorption = Color {
+            r: 0.25,
+            g: 0.5,
+            b: 1.0,
+        };
+        let scene = medium_sphere(
+            Medium {
+                absorption,
+                ..Medium::VACUUM
+            },
+            Color::WHITE,
+        );
+        let tracer = PathTracer { bounces: 3 };
+        let n = 4000;
+        let mut sum = Color::BLACK;
+        for _ in 0..n {
+            sum = sum + tracer.radiance(&scene, v(-3.0, 0.0, 0.0), v(1.0, 0.0, 0.0), None);
+        }
+        let mean = sum / n as float;
+        for (c, a) in [
+            (mean.r, absorption.r),
+            (mean.g, absorption.g),
+            (mean.b, absorption.b),
+        ] {
+            let expected = (-2.0 * a).exp();
+            assert!((c - expected).abs() < 0.03, "{:?}", mean);
+        }
+
+        // Shadow rays see the same transmittance
+        let medium = scene.materials[0].medium.clone().unwrap();
+        let ray = Ray::new(v(-1.0, 0.0, 0.0), v(1.0, 0.0, 0.0));
+        let mut sum = Color::BLACK;
+        for _ in 0..n {
+            sum = sum + ratio_track(&Rgb, &medium, &ray, 2.0);
+        }
+        let mean = sum / n as float;
+        assert!((mean.r - (-0.5 as float).exp()).abs() < 0.02, "{:?}", mean);
+        assert!((mean.b - (-2.0 as float).exp()).abs() < 0.02, "{:?}", mean);
+    }
+
+    #[test]
+    fn scattering_media_neither_absorb_nor_emit() {
+        sampling::seed(1);
+        let scene = medium_sphere(Medium::fog(0.5, 1.0, 0.3), Color::WHITE * 0.25);
+        let tracer = PathTracer { bounces: 50 };
+        let n = 4000;
+        let mut sum = 0.0;
+        for i in 0..n {
+            let offset = (i as float / n as float - 0.5) * 1.8;
+            let color = tracer.radiance(&scene, v(-3.0, offset, 0.0), v(1.0, 0.0, 0.0), None);
+            sum += color.g;
+        }
+        assert!(
+            (sum / n as float - 0.25).abs() < 0.01,
+            "{}",
+            sum / n as float
+        );
+    }
+
+    #[test]
+    fn grid_media_glow_and_absorb_by_their_optical_depth() {
+        sampling::seed(1);
+        // Density rising along x through the box around the sphere
+        let values: Vec<float> = (0..16).map(|i| i as float / 8.0).collect();
+        let volume = Volume::new(
+            Grid::new([16, 1, 1], &values),
+            None,
+            Bounds {
+                min: v(-1.0, -1.0, -1.0),
+                max: v(1.0, 1.0, 1.0),
+            },
+            Matrix::IDENTITY,
+        )
+        .unwrap();
+        let medium = Medium {
+            absorption: Color::WHITE * 0.5,
+            emission: Color::WHITE,
+            volume: Some(std::sync::Arc::new(volume)),
+            ..Medium::VACUUM
+        };
+        let steps = 1000;
+        let depth: float = (0..steps)
+            .map(|i| {
+                let x = -1.0 + 2.0 * (i as float + 0.5) / steps as float;
+                medium.density(v(x, 0.0, 0.0)) * 0.5 * 2.0 / steps as float
+            })
+            .sum();
+
+        // What the medium emits along a ray is what it would absorb of its own radiance
+        let scene = medium_sphere(medium.clone(), Color::BLACK);
+        let tracer = PathTracer { bounces: 3 };
+        let n = 4000;
+        let mut glow = 0.0;
+        let mut transmittance = 0.0;
+        let ray = Ray::new(v(-1.0, 0.0, 0.0), v(1.0, 0.0, 0.0));
+        for _ in 0..n {
+            glow += tracer
+                .radiance(&scene, v(-3.0, 0.0, 0.0), v(1.0, 0.0, 0.0), None)
+                .g;
+            transmittance += ratio_track(&Rgb, &medium, &ray, 2.0).g;
+        }
+        let (glow, transmittance) = (glow / n as float, transmittance / n as float);
+        assert!(
+            (glow - (1.0 - (-depth).exp())).abs() < 0.03,
+            "{} {}",
+            glow,
+            depth
+        );
+        assert!(
+            (transmittance - (-depth).exp()).abs() < 0.02,
+            "{} {}",
+            transmittance,
+            depth
+        );
+    }
+}
@@ -1,4 +1,42 @@
 pub mod prelude;
+
+mod angle;
+pub mod bdpt;
+pub mod bounds;
+pub mod bvh;
+pub mod camera;
+mod color;
+pub mod denoise;
+pub mod environment;
+pub mod film;
+pub mod gltf;
+pub mod hdr;
+pub mod integrator;
+pub mod light;
+pub mod material;
+mod matrix;
+pub mod medium;
+pub mod mesh;
+pub mod mipmap;
+pub mod mlt;
+pub mod noise;
+pub mod object;
+pub mod pbrt;
+pub mod photon;
+pub mod ply;
+pub mod ray;
+pub mod raycast;
+pub mod sampling;
+pub mod scene;
+pub mod sdf;
+pub mod sky;
+pub mod spectrum;
+pub mod stl;
+pub mod texture;
 mod vector;
+pub mod volume;
 
+pub use crate::angle::Angle;
+pub use crate::color::Color;
+pub use crate::matrix::{Matrix, Transform};
 pub use crate::vector::{Point, Vector};
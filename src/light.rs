@@ -0,0 +1,1017 @@
+//! Sampling of emissive objects
+
+use crate::bounds::Bounds;
+use crate::color::Color;
+use crate::material::Material;
+use crate::matrix::Transform;
+use crate::object::{Geometry, Object, Shape, ShapeSample};
+use crate::prelude::*;
+use crate::sampling::AliasTable;
+use crate::texture::{SurfacePoint, TextureRef};
+use crate::vector::{Point, Vector};
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Radius of the spheres standing in for point lights, relative to the size of the scene
+const POINT_LIGHT_RADIUS: float = 0.001;
+
+/// Bounds the directions light leaves a group of emitters in: their normals are within
+/// `normal_angle` of `axis`, and light leaves at most `emission_angle` away from a normal
+#[derive(Debug, Clone, Copy)]
+struct DirectionCone {
+    axis: Vector,
+    normal_angle: float,
+    emission_angle: float,
+}
+
+impl DirectionCone {
+    /// Smallest cone containing both
+    /// https://fpsunflower.github.io/ckulla/data/many-lights-hpg2018.pdf
+    fn union(self, other: Self) -> Self {
+        let (a, b) = if other.normal_angle > self.normal_angle {
+            (other, self)
+        } else {
+            (self, other)
+        };
+        let emission_angle = a.emission_angle.max(b.emission_angle);
+
+        let between = a.axis.dot(b.axis).clamp(-1.0, 1.0).acos();
+        if (between + b.normal_angle).min(PI) <= a.normal_angle {
+            return Self {
+                emission_angle,
+                ..a
+            };
+        }
+
+        let normal_angle = (a.normal_angle + between + b.normal_angle) * 0.5;
+        if normal_angle >= PI {
+            return Self {
+                axis: a.axis,
+                normal_angle: PI,
+                emission_angle,
+            };
+        }
+
+        // Turn the axis of `a` towards `b` so that the new cone just covers both
+        let turn = normal_angle - a.normal_angle;
+        let towards = b.axis - a.axis * a.axis.dot(b.axis);
+        let towards = if towards.len2() > 1e-12 {
+            towards.normalized()
+        } else {
+            a.axis.coordinate_system().0
+        };
+        Self {
+            axis: (a.axis * turn.cos() + towards * turn.sin()).normalized(),
+            normal_angle,
+            emission_angle,
+        }
+    }
+}
+
+/// Emissive object or mesh triangle, which emits from its front side only
+#[derive(Debug, Clone)]
+pub struct AreaLight {
+    /// Index of the object in the scene
+    pub object: usize,
+    /// Triangle of a mesh, zero for other shapes, like `RayHit::primitive`
+    pub primitive: usize,
+    shape: Shape,
+    emission: TextureRef,
+    area: float,
+    /// Estimate of the total emitted power
+    pub power: float,
+    bounds: Bounds,
+    cone: DirectionCone,
+}
+
+impl AreaLight {
+    /// `None` if the shape does not emit light
+    pub fn new(
+        object: usize,
+        primitive: usize,
+        shape: &Shape,
+        emission: TextureRef,
+    ) -> Option<Self> {
+        // The area of an instance and the points sampled on it are only uniform when it is
+        // scaled uniformly. Other instances are only found by hitting them, which is unbiased as
+        // they have no light density.
+        if let Shape::Instance { ref transform, .. } = *shape {
+            if !transform.scales_uniformly() {
+                return None;
+            }
+        }
+        let area = shape.area();
+        if area <= 0.0 || !area.is_finite() {
+            return None;
+        }
+
+        // Average the emission over a few points to catch textured emitters
+        let n = 4;
+        let mut luminance = 0.0;
+        for i in 0..n * n {
+            let u = [
+                ((i % n) as float + 0.5) / n as float,
+                ((i / n) as float + 0.5) / n as float,
+            ];
+            luminance += emitted(&emission, shape.sample(u)).luminance().max(0.0);
+        }
+        let power = luminance / (n * n) as float * area * PI;
+        if power <= 0.0 {
+            return None;
+        }
+
+        let cone = match *shape {
+            // Flat shapes face a single direction
+            Shape::Triangle { .. }
+            | Shape::Plane { .. }
+            | Shape::Rectangle { .. }
+            | Shape::Disk { .. } => DirectionCone {
+                axis: shape.sample([0.5, 0.5]).normal,
+                normal_angle: 0.0,
+                emission_angle: PI / 2.0,
+            },
+            Shape::Sphere { .. }
+            | Shape::AxisBox { .. }
+            | Shape::Cylinder { .. }
+            | Shape::Cone { .. }
+            | Shape::Torus { .. }
+            | Shape::BilinearPatch { .. }
+            | Shape::Mesh { .. }
+            | Shape::Instance { .. }
+            | Shape::Csg { .. }
+            | Shape::Sdf { .. } => DirectionCone {
+                axis: Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+                normal_angle: PI,
+                emission_angle: PI / 2.0,
+            },
+        };
+
+        Some(Self {
+            object,
+            primitive,
+            shape: shape.clone(),
+            emission,
+            area,
+            power,
+            bounds: shape.bounds(),
+            cone,
+        })
+    }
+}
+
+/// Where a shape is in the scene, for finding the lights in it
+#[derive(Debug)]
+struct Part {
+    object: usize,
+    /// Primitive number of the first triangle of the shape in hits on `object`
+    first_primitive: usize,
+    /// Material of the enclosing objects, for surfaces without one
+    material_id: Option<usize>,
+    /// Placement of instanced shapes
+    to_world: Option<Transform>,
+}
+
+impl Part {
+    fn add_lights(&self, shape: &Shape, materials: &[Material], lights: &mut Vec<AreaLight>) {
+        let emission = |material_id: Option<usize>| material_id.map(|id| &materials[id].ambient);
+        let place = |corners: [Point; 3]| match self.to_world {
+            Some(to_world) => [
+                to_world.point(corners[0]),
+                to_world.point(corners[1]),
+                to_world.point(corners[2]),
+            ],
+            None => corners,
+        };
+        match *shape {
+            Shape::Mesh { ref mesh } => {
+                for (primitive, triangle) in mesh.triangles.iter().enumerate() {
+                    let emission = match emission(triangle.material_id.or(self.material_id)) {
+                        Some(emission) => emission,
+                        None => continue,
+                    };
+                    let triangle = Shape::Triangle {
+                        corners: place(mesh.corners(primitive)),
+                        uvs: mesh.corner_uvs(primitive),
+                    };
+                    lights.extend(AreaLight::new(
+                        self.object,
+                        self.first_primitive + primitive,
+                        &triangle,
+                        emission.clone(),
+                    ));
+                }
+            }
+            Shape::Instance {
+                ref geometry,
+                ref transform,
+            } => {
+                let to_world = match self.to_world {
+                    Some(outer) => match Transform::new(outer.to_world * transform.to_world) {
+                        Some(to_world) => to_world,
+                        None => return,
+                    },
+                    None => *transform,
+                };
+                for (i, object) in geometry.objects.iter().enumerate() {
+                    let part = Part {
+                        object: self.object,
+                        first_primitive: self.first_primitive + geometry.first_primitive(i),
+                        material_id: object.material_id.or(self.material_id),
+                        to_world: Some(to_world),
+                    };
+                    part.add_lights(&object.shape, materials, lights);
+                }
+            }
+            _ => {
+                let emission = match emission(self.material_id) {
+                    Some(emission) => emission.clone(),
+                    None => return,
+                };
+                let light = match (shape, self.to_world) {
+                    // Triangles stay exact under any transform
+                    (&Shape::Triangle { corners, uvs }, _) => AreaLight::new(
+                        self.object,
+                        self.first_primitive,
+                        &Shape::Triangle {
+                            corners: place(corners),
+                            uvs,
+                        },
+                        emission,
+                    ),
+                    (_, Some(to_world)) => AreaLight::new(
+                        self.object,
+                        self.first_primitive,
+                        &Shape::Instance {
+                            geometry: Arc::new(Geometry::new(vec![Object {
+                                shape: shape.clone(),
+                                material_id: None,
+                            }])),
+                            transform: to_world,
+                        },
+                        emission,
+                    ),
+                    (_, None) => AreaLight::new(self.object, self.first_primitive, shape, emission),
+                };
+                lights.extend(light);
+            }
+        }
+    }
+}
+
+/// Point light of an imported scene, with its radiant intensity in each direction
+#[derive(Debug, Clone, Copy)]
+pub struct PointLight {
+    pub position: Point,
+    pub intensity: Color,
+}
+
+/// Adds small emissive spheres standing in for point lights, as only lights with an area are
+/// sampled. Their size follows the bounds of `objects`, and their materials are appended to
+/// `materials`, which start from `material_offset` in the scene.
+pub fn add_point_lights(
+    lights: &[PointLight],
+    objects: &mut Vec<Object>,
+    materials: &mut Vec<Material>,
+    material_offset: usize,
+) {
+    let bounds = objects
+        .iter()
+        .fold(Bounds::EMPTY, |b, o| b.union(o.shape.bounds()));
+    let bounds = lights.iter().fold(bounds, |b, l| b.include(l.position));
+    let radius = (bounds.diagonal().len() * POINT_LIGHT_RADIUS).max(1e-4);
+
+    for light in lights {
+        // A sphere of radiance L has the intensity L * PI * r^2 in every direction
+        let radiance = light.intensity * (1.0 / (PI * radius * radius));
+        materials.push(Material {
+            name: "point light".to_owned(),
+            ambient: Arc::new(radiance),
+            diffuse: Arc::new(Color::BLACK),
+            specular: Arc::new(Color::BLACK),
+            glass: None,
+            medium: None,
+        });
+        objects.push(Object {
+            shape: Shape::Sphere {
+                center: light.position,
+                radius,
+            },
+            material_id: Some(material_offset + materials.len() - 1),
+        });
+    }
+}
+
+fn emitted(emission: &TextureRef, at: ShapeSample) -> Color {
+    emission.color(&SurfacePoint {
+        point: at.point,
+        normal: at.normal,
+        uv: at.uv,
+        duvdx: [0.0; 2],
+        duvdy: [0.0; 2],
+        color: Color::WHITE,
+    })
+}
+
+/// Point on a light chosen by `Lights::sample`
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+    pub object: usize,
+    pub primitive: usize,
+    pub point: Point,
+    pub normal: Vector,
+    /// Unit vector from the shading point towards the light
+    pub direction: Vector,
+    pub distance: float,
+    pub radiance: Color,
+    /// Solid angle density, including the probability of choosing the light
+    pub pdf: float,
+}
+
+/// Point on a light chosen by `Lights::sample_emitter`, where light paths start
+#[derive(Debug, Clone, Copy)]
+pub struct EmitterSample {
+    pub object: usize,
+    pub primitive: usize,
+    pub point: Point,
+    /// Facing the side that emits
+    pub normal: Vector,
+    pub radiance: Color,
+    /// Area density, including the probability of choosing the light
+    pub pdf: float,
+}
+
+/// How `Lights` chooses the light to sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightSelection {
+    /// Proportionally to power, the same everywhere
+    Power,
+    /// By estimated contribution to the shading point, traversing a light BVH
+    Bvh,
+}
+
+#[derive(Debug, Clone)]
+struct LightNode {
+    bounds: Bounds,
+    cone: DirectionCone,
+    power: float,
+    kind: NodeKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NodeKind {
+    /// Index to `lights`
+    Leaf(usize),
+    /// The first child follows its parent, this is the index of the second
+    Interior(usize),
+}
+
+impl LightNode {
+    /// Conservative estimate of the light reaching a point, whose surface faces `normal`
+    /// or any direction if the normal is zero
+    fn importance(&self, point: Point, normal: Vector) -> float {
+        let (center, radius) = self.bounds.bounding_sphere();
+        let to_point = point - center;
+        let distance2 = to_point.len2();
+        if distance2 <= radius * radius {
+            // Inside the bounds, any direction is possible
+            return self.power / (radius * radius).max(float::EPSILON);
+        }
+
+        let distance = distance2.sqrt();
+        let wi = to_point * (1.0 / distance);
+        let bounds_angle = (radius / distance).clamp(-1.0, 1.0).asin();
+
+        // Smallest angle between an emitter normal and the direction towards the point
+        let angle = self.cone.axis.dot(wi).clamp(-1.0, 1.0).acos();
+        let angle = (angle - self.cone.normal_angle - bounds_angle).max(0.0);
+        if angle >= self.cone.emission_angle {
+            return 0.0;
+        }
+        let mut importance = self.power * angle.cos().max(0.0) / distance2;
+
+        if normal != Vector::ZERO {
+            let incident = normal.dot(-wi).clamp(-1.0, 1.0).acos();
+            importance *= (incident - bounds_angle).max(0.0).cos().max(0.0);
+        }
+
+        importance
+    }
+}
+
+/// Emissive objects of a scene, sampled for next event estimation
+/// https://www.pbr-book.org/4ed/Light_Sources/Light_Sampling
+#[derive(Debug, Clone)]
+pub struct Lights {
+    lights: Vec<AreaLight>,
+    by_power: Option<AliasTable>,
+    nodes: Vec<LightNode>,
+    /// Path from the root to each light, a set bit picks the second child at that depth
+    trails: Vec<u64>,
+    /// Light index of each emissive object and mesh triangle in the scene
+    primitive_lights: HashMap<(usize, usize), usize>,
+    pub selection: LightSelection,
+}
+
+impl Lights {
+    /// Every object whose material has emission. Meshes, also those of instances, are split
+    /// into a light for each emissive triangle, so that each can be chosen by where it faces and
+    /// by its own material.
+    pub fn new(objects: &[Object], materials: &[Material]) -> Self {
+        let mut lights = Vec::new();
+        for (i, object) in objects.iter().enumerate() {
+            let part = Part {
+                object: i,
+                first_primitive: 0,
+                material_id: object.material_id,
+                to_world: None,
+            };
+            part.add_lights(&object.shape, materials, &mut lights);
+        }
+        let primitive_lights = lights
+            .iter()
+            .enumerate()
+            .map(|(index, light)| ((light.object, light.primitive), index))
+            .collect();
+
+        let by_power = if lights.is_empty() {
+            None
+        } else {
+            let powers: Vec<float> = lights.iter().map(|l| l.power).collect();
+            Some(AliasTable::new(&powers))
+        };
+
+        let mut result = Self {
+            trails: vec![0; lights.len()],
+            lights,
+            by_power,
+            nodes: Vec::new(),
+            primitive_lights,
+            selection: LightSelection::Bvh,
+        };
+        let mut indices: Vec<usize> = (0..result.lights.len()).collect();
+        if !indices.is_empty() {
+            result.build(&mut indices, 0, 0);
+        }
+        result
+    }
+
+    /// Splits at the median along the axis where light centers spread the most
+    fn build(&mut self, indices: &mut [usize], trail: u64, depth: u32) -> usize {
+        let index = self.nodes.len();
+        if let [light_index] = *indices {
+            let light = &self.lights[light_index];
+            self.nodes.push(LightNode {
+                bounds: light.bounds,
+                cone: light.cone,
+                power: light.power,
+                kind: NodeKind::Leaf(light_index),
+            });
+            self.trails[light_index] = trail;
+            return index;
+        }
+        assert!(depth < 64, "Light BVH too deep");
+
+        let centers = indices.iter().fold(Bounds::EMPTY, |b, &i| {
+            b.include(self.lights[i].bounds.centroid())
+        });
+        let axis = centers.largest_axis();
+        indices.sort_by(|&a, &b| {
+            let a = self.lights[a].bounds.centroid()[axis];
+            let b = self.lights[b].bounds.centroid()[axis];
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // Placeholder until the children are known
+        self.nodes.push(LightNode {
+            bounds: Bounds::EMPTY,
+            cone: self.lights[indices[0]].cone,
+            power: 0.0,
+            kind: NodeKind::Leaf(0),
+        });
+        let (first, second) = indices.split_at_mut(indices.len() / 2);
+        let first = self.build(first, trail, depth + 1);
+        let second = self.build(second, trail | 1 << depth, depth + 1);
+
+        let (a, b) = (&self.nodes[first], &self.nodes[second]);
+        self.nodes[index] = LightNode {
+            bounds: a.bounds.union(b.bounds),
+            cone: a.cone.union(b.cone),
+            power: a.power + b.power,
+            kind: NodeKind::Interior(second),
+        };
+        index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn lights(&self) -> &[AreaLight] {
+        &self.lights
+    }
+
+    /// Light for lighting a point with surface normal `normal`, and its probability
+    pub fn select(&self, point: Point, normal: Vector, u: float) -> Option<(usize, float)> {
+        match self.selection {
+            LightSelection::Power => self.by_power.as_ref().map(|table| {
+                let (index, pmf, _) = table.sample(u);
+                (index, pmf)
+            }),
+            LightSelection::Bvh => self.select_bvh(point, normal, u),
+        }
+    }
+
+    fn select_bvh(&self, point: Point, normal: Vector, mut u: float) -> Option<(usize, float)> {
+        let mut node = 0;
+        let mut pmf = 1.0;
+        loop {
+            match self.nodes.get(node)?.kind {
+                NodeKind::Leaf(light) => {
+                    if self.nodes[node].importance(point, normal) > 0.0 {
+                        return Some((light, pmf));
+                    }
+                    return None;
+                }
+                NodeKind::Interior(second) => {
+                    let a = self.nodes[node + 1].importance(point, normal);
+                    let b = self.nodes[second].importance(point, normal);
+                    if a + b <= 0.0 {
+                        return None;
+                    }
+                    let p_first = a / (a + b);
+                    if u < p_first {
+                        u = (u / p_first).min(1.0 - float::EPSILON);
+                        pmf *= p_first;
+                        node += 1;
+                    } else {
+                        u = ((u - p_first) / (1.0 - p_first)).min(1.0 - float::EPSILON);
+                        pmf *= 1.0 - p_first;
+                        node = second;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Probability of `select` choosing a light
+    pub fn pmf(&self, point: Point, normal: Vector, light: usize) -> float {
+        match self.selection {
+            LightSelection::Power => self.by_power.as_ref().map_or(0.0, |t| t.pmf(light)),
+            LightSelection::Bvh => {
+                let trail = self.trails[light];
+                let mut node = 0;
+                let mut pmf = 1.0;
+                for depth in 0.. {
+                    match self.nodes[node].kind {
+                        NodeKind::Leaf(_) => {
+                            if self.nodes[node].importance(point, normal) <= 0.0 {
+                                return 0.0;
+                            }
+                            break;
+                        }
+                        NodeKind::Interior(second) => {
+                            let a = self.nodes[node + 1].importance(point, normal);
+                            let b = self.nodes[second].importance(point, normal);
+                            if a + b <= 0.0 {
+                                return 0.0;
+                            }
+                            if trail & (1 << depth) == 0 {
+                                pmf *= a / (a + b);
+                                node += 1;
+                            } else {
+                                pmf *= b / (a + b);
+                                node = second;
+                            }
+                        }
+                    }
+                }
+                pmf
+            }
+        }
+    }
+
+    /// Point on a light for lighting `point`, `u` uniform in 0..1
+    pub fn sample(&self, point: Point, normal: Vector, u: [float; 3]) -> Option<LightSample> {
+        let (index, pmf) = self.select(point, normal, u[0])?;
+        let light = &self.lights[index];
+        let at = light.shape.sample([u[1], u[2]]);
+
+        let to_light = at.point - point;
+        let distance2 = to_light.len2();
+        if distance2 == 0.0 {
+            return None;
+        }
+        let distance = distance2.sqrt();
+        let direction = to_light * (1.0 / distance);
+
+        let cos_light = -direction.dot(at.normal);
+        if cos_light <= 0.0 {
+            return None;
+        }
+
+        Some(LightSample {
+            object: light.object,
+            primitive: light.primitive,
+            point: at.point,
+            normal: at.normal,
+            direction,
+            distance,
+            radiance: emitted(&light.emission, at),
+            pdf: pmf * distance2 / (cos_light * light.area),
+        })
+    }
+
+    /// Solid angle density of `sample` choosing `light_point` on `primitive` of `object` when
+    /// lighting `point`
+    pub fn pdf(
+        &self,
+        point: Point,
+        normal: Vector,
+        object: usize,
+        primitive: usize,
+        light_point: Point,
+        light_normal: Vector,
+    ) -> float {
+        let index = match self.primitive_lights.get(&(object, primitive)) {
+            Some(&index) => index,
+            None => return 0.0,
+        };
+
+        let to_light = light_point - point;
+        let distance2 = to_light.len2();
+        if distance2 == 0.0 {
+            return 0.0;
+        }
+        let cos_light = to_light.normalized().dot(light_normal).abs();
+        if cos_light == 0.0 {
+            return 0.0;
+        }
+
+        self.pmf(point, normal, index) * distance2 / (cos_light * self.lights[index].area)
+    }
+
+    /// Point on a light chosen proportionally to power, independently of where it is seen from
+    pub fn sample_emitter(&self, u: [float; 3]) -> Option<EmitterSample> {
+        let (index, pmf, _) = self.by_power.as_ref()?.sample(u[0]);
+        let light = &self.lights[index];
+        let at = light.shape.sample([u[1], u[2]]);
+        Some(EmitterSample {
+            object: light.object,
+            primitive: light.primitive,
+            point: at.point,
+            normal: at.normal,
+            radiance: emitted(&light.emission, at),
+            pdf: pmf / light.area,
+        })
+    }
+
+    /// Area density of `sample_emitter` choosing a point on `primitive` of `object`
+    pub fn emitter_pdf(&self, object: usize, primitive: usize) -> float {
+        match (
+            self.primitive_lights.get(&(object, primitive)),
+            &self.by_power,
+        ) {
+            (Some(&index), Some(table)) => table.pmf(index) / self.lights[index].area,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix::Matrix;
+    use crate::mesh::{Mesh, MeshTriangle};
+    use std::sync::Arc;
+
+    fn triangle_light(center: Point, facing: Vector, size: float) -> Object {
+        let (t, b) = facing.coordinate_system();
+        Object {
+            shape: Shape::Triangle {
+                corners: [center, center + t * size, center + b * size],
+                uvs: Shape::BARYCENTRIC_UVS,
+            },
+            material_id: Some(0),
+        }
+    }
+
+    fn scene() -> (Vec<Object>, Vec<Material>) {
+        let mut material = Material::diffuse("light", Arc::new(Color::BLACK));
+        material.ambient = Arc::new(Color::WHITE * 5.0);
+
+        let mut objects = Vec::new();
+        for i in 0..20 {
+            let center = Point {
+                x: (i % 5) as float * 10.0,
+                y: 10.0 + (i / 5) as float,
+                z: (i * 7 % 11) as float,
+            };
+            // Alternate between facing down and up
+            let facing = Vector {
+                x: 0.1,
+                y: if i % 2 == 0 { -1.0 } else { 1.0 },
+                z: 0.0,
+            }
+            .normalized();
+            objects.push(triangle_light(center, facing, 1.0 + i as float * 0.1));
+        }
+        objects.push(Object {
+            shape: Shape::Sphere {
+                center: Point {
+                    x: 20.0,
+                    y: 5.0,
+                    z: 5.0,
+                },
+                radius: 1.0,
+            },
+            material_id: Some(0),
+        });
+        (objects, vec![material])
+    }
+
+    #[test]
+    fn cone_union_covers_both() {
+        let cone = |axis: Vector, normal_angle| DirectionCone {
+            axis: axis.normalized(),
+            normal_angle,
+            emission_angle: PI / 2.0,
+        };
+        let a = cone(
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            0.1,
+        );
+        let b = cone(
+            Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            0.2,
+        );
+        let c = a.union(b);
+        for inner in [a, b].iter() {
+            let between = c.axis.dot(inner.axis).clamp(-1.0, 1.0).acos();
+            assert!(between + inner.normal_angle <= c.normal_angle + 0.001);
+        }
+        assert!(c.normal_angle < PI / 2.0);
+
+        let opposite = cone(
+            Vector {
+                x: -1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            0.0,
+        );
+        assert!(approx_eq(
+            cone(a.axis, 0.0).union(opposite).normal_angle,
+            PI / 2.0
+        ));
+    }
+
+    #[test]
+    fn bvh_pmf_matches_selection() {
+        let (objects, materials) = scene();
+        let lights = Lights::new(&objects, &materials);
+        assert_eq!(lights.len(), 21);
+
+        let point = Point {
+            x: 12.0,
+            y: 3.0,
+            z: 4.0,
+        };
+        let normal = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+
+        let total: float = (0..lights.len())
+            .map(|i| lights.pmf(point, normal, i))
+            .sum();
+        assert!(approx_eq(total, 1.0), "{}", total);
+
+        for i in 0..200 {
+            let u = (i as float + 0.5) / 200.0;
+            let (light, pmf) = lights.select(point, normal, u).unwrap();
+            assert!(approx_eq(pmf, lights.pmf(point, normal, light)));
+        }
+    }
+
+    #[test]
+    fn bvh_skips_lights_facing_away() {
+        let (objects, materials) = scene();
+        let lights = Lights::new(&objects, &materials);
+        let point = Point {
+            x: 20.0,
+            y: 0.0,
+            z: 5.0,
+        };
+        let up = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+
+        // Lights above facing up cannot light the point below them
+        for (i, object) in objects.iter().enumerate().take(20) {
+            let facing_up = i % 2 == 1;
+            let pmf = lights.pmf(point, up, i);
+            if facing_up {
+                assert_eq!(pmf, 0.0, "{:?}", object);
+            } else {
+                assert!(pmf > 0.0);
+            }
+        }
+
+        // Facing down, nothing above is visible
+        assert!(lights.select(point, -up, 0.5).is_none());
+    }
+
+    #[test]
+    fn sample_pdf_matches_lookup() {
+        let (objects, materials) = scene();
+        let mut lights = Lights::new(&objects, &materials);
+        let point = Point {
+            x: 5.0,
+            y: 6.0,
+            z: 2.0,
+        };
+        let normal = Vector::ZERO;
+
+        for selection in [LightSelection::Power, LightSelection::Bvh].iter() {
+            lights.selection = *selection;
+            let mut found = 0;
+            for i in 0..100 {
+                let u = [
+                    (i as float + 0.5) / 100.0,
+                    (i % 7) as float / 7.0,
+                    (i % 3) as float / 3.0,
+                ];
+                if let Some(sample) = lights.sample(point, normal, u) {
+                    found += 1;
+                    assert!(sample.direction.is_normalized());
+                    let pdf = lights.pdf(
+                        point,
+                        normal,
+                        sample.object,
+                        sample.primitive,
+                        sample.point,
+                        sample.normal,
+                    );
+                    assert!((pdf - sample.pdf).abs() / sample.pdf < 0.001);
+                }
+            }
+            // About half of the power faces away from the point
+            assert!(found > 25, "{}", found);
+        }
+    }
+
+    #[test]
+    fn emitter_pdf_matches_sample() {
+        let (objects, materials) = scene();
+        let lights = Lights::new(&objects, &materials);
+        let mut total = 0.0;
+        for i in 0..100 {
+            let u = [(i as float + 0.5) / 100.0, 0.3, 0.6];
+            let sample = lights.sample_emitter(u).unwrap();
+            assert!(approx_eq(
+                sample.pdf,
+                lights.emitter_pdf(sample.object, sample.primitive)
+            ));
+            assert_eq!(sample.radiance, Color::WHITE * 5.0);
+        }
+        // Densities times areas are the probabilities of choosing each light
+        for light in lights.lights() {
+            total += lights.emitter_pdf(light.object, light.primitive) * light.area;
+        }
+        assert!(approx_eq(total, 1.0));
+        assert_eq!(lights.emitter_pdf(objects.len(), 0), 0.0);
+    }
+
+    #[test]
+    fn only_uniformly_scaled_instances_are_sampled() {
+        let mut material = Material::diffuse("light", Arc::new(Color::BLACK));
+        material.ambient = Arc::new(Color::WHITE);
+        let geometry = Arc::new(Geometry::new(vec![Object {
+            shape: Shape::Sphere {
+                center: Point::ZERO,
+                radius: 1.0,
+            },
+            material_id: None,
+        }]));
+        let instance = |scale: Vector| Object {
+            shape: Shape::Instance {
+                geometry: geometry.clone(),
+                transform: Transform::new(Matrix::scale(scale)).unwrap(),
+            },
+            material_id: Some(0),
+        };
+        let objects = vec![
+            instance(Vector {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0,
+            }),
+            instance(Vector {
+                x: 1.0,
+                y: 1.0,
+                z: 3.0,
+            }),
+        ];
+        let lights = Lights::new(&objects, &[material]);
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights.lights()[0].object, 0);
+        assert!((lights.lights()[0].area - 16.0 * PI).abs() < 0.01);
+        assert_eq!(lights.emitter_pdf(1, 0), 0.0);
+    }
+
+    #[test]
+    fn mesh_triangles_are_lights_of_their_own() {
+        let mut light = Material::diffuse("light", Arc::new(Color::BLACK));
+        light.ambient = Arc::new(Color::WHITE);
+        let materials = vec![Material::diffuse("wall", Arc::new(Color::WHITE)), light];
+        // A wall and, above it, a light facing down
+        let positions = vec![
+            Point::ZERO,
+            Point {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Point {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            Point {
+                x: 0.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            Point {
+                x: 1.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            Point {
+                x: 0.0,
+                y: 2.0,
+                z: 1.0,
+            },
+        ];
+        let triangles = vec![
+            MeshTriangle {
+                vertices: [0, 2, 1],
+                material_id: None,
+            },
+            MeshTriangle {
+                vertices: [3, 4, 5],
+                material_id: Some(1),
+            },
+        ];
+        let objects = vec![Object {
+            shape: Shape::Mesh {
+                mesh: Arc::new(Mesh::new(positions, Vec::new(), Vec::new(), triangles)),
+            },
+            material_id: Some(0),
+        }];
+        let lights = Lights::new(&objects, &materials);
+        assert_eq!(lights.len(), 1);
+        let light = &lights.lights()[0];
+        assert_eq!((light.object, light.primitive), (0, 1));
+        assert!(approx_eq(light.area, 0.5));
+        assert_eq!(light.cone.normal_angle, 0.0);
+        assert!(light.cone.axis.y < -0.99);
+
+        let point = Point {
+            x: 0.2,
+            y: 1.0,
+            z: 0.2,
+        };
+        let sample = lights.sample(point, Vector::ZERO, [0.5, 0.3, 0.6]).unwrap();
+        assert_eq!((sample.object, sample.primitive), (0, 1));
+        let pdf = lights.pdf(point, Vector::ZERO, 0, 1, sample.point, sample.normal);
+        assert!((pdf - sample.pdf).abs() / sample.pdf < 0.001);
+        assert_eq!(
+            lights.pdf(point, Vector::ZERO, 0, 0, sample.point, sample.normal),
+            0.0
+        );
+        assert_eq!(lights.emitter_pdf(0, 0), 0.0);
+        assert!(approx_eq(lights.emitter_pdf(0, 1), 2.0));
+    }
+}
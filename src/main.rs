@@ -1,25 +1,36 @@
 #![feature(const_fn_floating_point_arithmetic)]
 
-pub mod prelude;
-
-mod angle;
-mod color;
-mod matrix;
-mod object;
-mod raycast;
-mod vector;
-
-pub use crate::angle::Angle;
-pub use crate::color::Color;
-pub use crate::matrix::Matrix;
-pub use crate::vector::{Point, Vector};
-
-use crate::object::{Object, Shape};
-use crate::prelude::float;
-use crate::raycast::raycast;
+use raytracer::bdpt::{self, Splats};
+use raytracer::bounds::Bounds;
+use raytracer::camera::Camera;
+use raytracer::denoise::{Denoiser, Features};
+use raytracer::environment::EnvironmentLight;
+use raytracer::film::{AdaptiveSampler, Film};
+use raytracer::gltf;
+use raytracer::integrator::{
+    DebugIntegrator, DebugView, Integrator, PathTracer, SpectralPathTracer,
+};
+use raytracer::material::Material;
+use raytracer::medium::Medium;
+use raytracer::mesh::Mesh;
+use raytracer::mlt::Metropolis;
+use raytracer::object::{Geometry, Object, Shape};
+use raytracer::pbrt;
+use raytracer::photon::{self, PhotonMap, ProgressivePhotonMapping};
+use raytracer::prelude::float;
+use raytracer::sampling;
+use raytracer::scene::Scene;
+use raytracer::sdf::{Sdf, SphereTracing};
+use raytracer::sky::PhysicalSky;
+use raytracer::texture::{Mapping, Marble};
+use raytracer::volume::{self, Volume};
+use raytracer::{Angle, Color, Matrix, Point, Transform, Vector};
 
 use rayon::prelude::*;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
@@ -36,74 +47,237 @@ const HEIGHT: u32 = 48*2;
 
 const BOUNCES: usize = 3;
 
-fn random_nudge(vector: Vector, weigth: float) -> Vector {
-    (vector + Vector::random_spherepoint() * weigth).normalized()
+/// Light transport algorithm rendering the frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    /// Paths from the camera, sampling the lights at each bounce
+    Path,
+    /// Path tracing with a few wavelengths per path instead of RGB, so that glass disperses light
+    Spectral,
+    /// Paths from the camera connected with paths from the emissive objects
+    Bidirectional,
+    /// Direct light from the camera paths, indirect light from photons gathered on diffuse
+    /// surfaces
+    PhotonMapping,
+    /// Photon mapping with new photons every frame and shrinking gather radii
+    ProgressivePhotonMapping,
+    /// Mutations of path tracer random numbers, exploring paths that carry much light
+    Metropolis,
 }
 
-fn raytrace(
-    mut from: Point,
-    mut direction: Vector,
-    objects: &[object::Object],
-    materials: &[tobj::Material],
-    sun: Vector,
-) -> Color {
-    direction = direction.normalized();
-
-    let mut any_hits = false;
-    let mut mask_color = Color::WHITE; // Surfaces only reflect their own color
-    let mut acc_color = Color::BLACK; // Total color
-
-    for _ in 0..=BOUNCES {
-        if let Some(hit) = raycast(from, direction, objects) {
-            any_hits = true;
-
-            let hit_point: Point = from + direction * hit.distance;
-            if let Some(material_id) = objects[hit.object].material_id {
-                let material = &materials[material_id];
-
-                let w = (-direction).dot(hit.normal);
-                assert!(w >= 0.0);
-                acc_color = acc_color + (Color::from(material.ambient) * mask_color).darken(w);
-                mask_color = mask_color * Color::from(material.diffuse).darken(w);
-            } else {
-                // Default material
-                let w = (-direction).dot(hit.normal);
-                mask_color = mask_color * Color::WHITE.darken(w);
-            }
+impl Algorithm {
+    const ALL: [Algorithm; 6] = [
+        Algorithm::Path,
+        Algorithm::Spectral,
+        Algorithm::Bidirectional,
+        Algorithm::PhotonMapping,
+        Algorithm::ProgressivePhotonMapping,
+        Algorithm::Metropolis,
+    ];
+
+    /// Name on the command line
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Path => "path",
+            Algorithm::Spectral => "spectral",
+            Algorithm::Bidirectional => "bidirectional",
+            Algorithm::PhotonMapping => "photon-mapping",
+            Algorithm::ProgressivePhotonMapping => "progressive-photon-mapping",
+            Algorithm::Metropolis => "metropolis",
+        }
+    }
+}
 
-            let reflection = direction.reflect(hit.normal);
-
-            // let reflection = if reflect_mode {
-            //     direction.reflect(hit.normal)
-            // } else {
-            //     Vector::random_spherepoint().normalized()
-                // random_nudge(hit.normal, 0.8)
-            // };
-
-            // Epsilon hack to avoid self-collision
-            direction = (reflection + hit.normal * 1.0001).normalized();
-            from = hit_point;
-        } else {
-            // No hit, check for sun
-            let s = (-sun).dot(direction);
-            if s > 0.0 && any_hits {
-                acc_color = acc_color + (Color::WHITE * mask_color).darken(s);
-            }
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|algorithm| algorithm.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Algorithm::ALL.iter().map(|a| a.name()).collect();
+                format!(
+                    "unknown algorithm {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Photon paths traced for photon mapping, per frame for the progressive variant
+const PHOTONS: usize = 100_000;
+/// Radius photons are gathered within, the initial one for progressive photon mapping
+const PHOTON_RADIUS: float = 0.1;
+
+// Daylight used when there is no environment map
+const SUN_ELEVATION: Angle = Angle { radians: 1.35 };
+const SUN_AZIMUTH: Angle = Angle { radians: 2.7 };
+const TURBIDITY: float = 3.0;
+
+/// Settings from the command line
+#[derive(Debug)]
+struct Options {
+    /// Light transport algorithm, from `--algorithm <name>`
+    algorithm: Algorithm,
+    /// Debug view to start in, from `--view <name>`
+    view: Option<DebugView>,
+    /// Whether to start with the denoiser on, from `--denoise`
+    denoise: bool,
+    /// Where the image shown last is saved when the window closes, from `--output <path>`
+    output: Option<PathBuf>,
+    /// Equirectangular `.hdr`, `.pfm` or LDR image lighting the scene, from
+    /// `--environment <path>`
+    environment: Option<PathBuf>,
+    /// `.gltf` or `.glb` scene added to the built in one, its first camera replaces the default
+    /// one, from `--gltf <path>`
+    gltf: Option<PathBuf>,
+    /// `.pbrt` scene replacing the built in one, with its camera and lights, from `--pbrt <path>`
+    pbrt: Option<PathBuf>,
+    /// `.ply` or `.stl` mesh added to the built in scene, and the scale it is loaded at, from
+    /// `--mesh <path> <scale>`
+    mesh: Option<(PathBuf, float)>,
+    /// Fog filling the scene, as the mean distance light travels before scattering and the mean
+    /// cosine of the scattering angle, from `--fog <distance> <cosine>`. Only the path tracers
+    /// see it.
+    fog: Option<(float, float)>,
+    /// Mitsuba `.vol` density grid filling its bounding box with white smoke instead of the fog,
+    /// and the mean distance light travels before scattering where the density is one, from
+    /// `--smoke <path> <distance>`
+    smoke: Option<(PathBuf, float)>,
+    /// Mean relative pixel error that the path tracers and photon mapping render towards while
+    /// the camera stands still, adding samples to the noisy tiles every frame until the time
+    /// budget in seconds is used up, from `--adaptive <error> <seconds>`. `None` renders a fixed
+    /// number of rays every frame.
+    adaptive: Option<(float, u64)>,
+}
 
-            // Skybox
-            acc_color = acc_color + (Color::WHITE * mask_color).darken(0.4);
+/// Next argument parsed as the value of an option, with `needs` saying what was expected
+fn option_value<T: FromStr>(
+    args: &mut impl Iterator<Item = String>,
+    needs: &str,
+) -> Result<T, String> {
+    let arg = args.next().ok_or_else(|| needs.to_owned())?;
+    arg.parse().map_err(|_| format!("{}, not {:?}", needs, arg))
+}
 
-            break;
+fn options_from_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        algorithm: Algorithm::Path,
+        view: None,
+        denoise: false,
+        output: None,
+        environment: None,
+        gltf: None,
+        pbrt: None,
+        mesh: None,
+        fog: None,
+        smoke: None,
+        adaptive: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--algorithm" => {
+                let name = args
+                    .next()
+                    .ok_or("--algorithm needs the name of an algorithm")?;
+                options.algorithm = name.parse()?;
+            }
+            "--view" => {
+                let name = args.next().ok_or("--view needs the name of a view")?;
+                options.view = Some(name.parse()?);
+            }
+            "--denoise" => options.denoise = true,
+            "--output" => {
+                let path = args.next().ok_or("--output needs the path of an image")?;
+                options.output = Some(PathBuf::from(path));
+            }
+            "--environment" => {
+                let needs = "--environment needs the path of an image";
+                options.environment = Some(option_value(&mut args, needs)?);
+            }
+            "--gltf" => {
+                let needs = "--gltf needs the path of a glTF scene";
+                options.gltf = Some(option_value(&mut args, needs)?);
+            }
+            "--pbrt" => {
+                let needs = "--pbrt needs the path of a pbrt scene";
+                options.pbrt = Some(option_value(&mut args, needs)?);
+            }
+            "--mesh" => {
+                let path = option_value(&mut args, "--mesh needs the path of a mesh")?;
+                let scale = option_value(&mut args, "--mesh needs a scale after the path")?;
+                options.mesh = Some((path, scale));
+            }
+            "--fog" => {
+                let needs = "--fog needs the mean distance between scatterings";
+                let visibility = option_value(&mut args, needs)?;
+                let needs = "--fog needs the mean scattering cosine after the distance";
+                let g = option_value(&mut args, needs)?;
+                options.fog = Some((visibility, g));
+            }
+            "--smoke" => {
+                let path = option_value(&mut args, "--smoke needs the path of a .vol grid")?;
+                let needs = "--smoke needs the mean distance between scatterings after the path";
+                let visibility = option_value(&mut args, needs)?;
+                options.smoke = Some((path, visibility));
+            }
+            "--adaptive" => {
+                let needs = "--adaptive needs the mean relative error to stop at";
+                let threshold = option_value(&mut args, needs)?;
+                let needs = "--adaptive needs the time budget in seconds after the error";
+                let seconds = option_value(&mut args, needs)?;
+                options.adaptive = Some((threshold, seconds));
+            }
+            _ => return Err(format!("unknown argument {:?}", arg)),
         }
     }
+    Ok(options)
+}
 
-    acc_color
+/// One mesh object for each model in an OBJ file, keeping the material of the model.
+/// Quads become bilinear patches when `quads_as_patches` is set.
+fn obj_objects(models: &[tobj::Model], scale: f32, quads_as_patches: bool) -> Vec<Object> {
+    let mut objects = Vec::new();
+    for model in models.iter() {
+        let surfaces = Mesh::from_obj(&model.mesh, scale, quads_as_patches);
+        if surfaces.warnings > 0 {
+            eprintln!(
+                "{} faces of {:?} were skipped or are not simple polygons",
+                surfaces.warnings, model.name
+            );
+        }
+        let material_id = model.mesh.material_id;
+        if !surfaces.mesh.is_empty() {
+            objects.push(Object {
+                shape: Shape::Mesh {
+                    mesh: Arc::new(surfaces.mesh),
+                },
+                material_id,
+            });
+        }
+        objects.extend(
+            surfaces
+                .patches
+                .into_iter()
+                .map(|shape| Object { shape, material_id }),
+        );
+    }
+    objects
 }
 
 fn main() -> Result<(), Error> {
+    let options = options_from_args().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(2);
+    });
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
-    let time_start = Instant::now();
+    let _time_start = Instant::now();
     let window = {
         let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
         WindowBuilder::new()
@@ -120,102 +294,416 @@ fn main() -> Result<(), Error> {
         Pixels::new(WIDTH, HEIGHT, surface_texture)?
     };
 
-    let mut camera = Matrix::translation(Vector {
-        x: 0.0,
-        y: 0.0,
-        z: 0.0,
-    }) * Matrix::rotation(
-        Vector {
-            x: 1.0,
+    let mut camera = Camera {
+        transform: Matrix::translation(Vector {
+            x: 0.0,
             y: 0.0,
             z: 0.0,
-        },
-        Angle { radians: 0.0 },
-    );
-
-    let sun = (Vector {
-        x: 0.1,
-        y: -1.0,
-        z: 0.2,
-    })
-    .normalized();
+        }) * Matrix::rotation(
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            Angle { radians: 0.0 },
+        ),
+        width: WIDTH,
+        height: HEIGHT,
+        vertical_fov: Camera::DEFAULT_FOV,
+    };
 
     let mut objects = Vec::new();
 
-    let (models, materials) =
-        tobj::load_obj("objs/cornell_box.obj", true).expect("Failed to load file");
+    let obj_path = Path::new("objs/cornell_box.obj");
+    let (models, obj_materials) = tobj::load_obj(obj_path, false).expect("Failed to load file");
+    let obj_dir = obj_path.parent().unwrap_or_else(|| Path::new("."));
+    let mut obj_warnings = Vec::new();
+    let mut materials: Vec<Material> = obj_materials
+        .iter()
+        .map(|m| Material::from_obj(m, obj_dir, &mut obj_warnings))
+        .collect();
+    for warning in obj_warnings.iter() {
+        eprintln!("{}: {}", obj_path.display(), warning);
+    }
 
     const SCALE: f32 = 1.0 / 4.0;
     // const SCALE: f32 = 1.0;
 
-    for model in models.iter() {
-        let mesh = &model.mesh;
-        let mut next_face = 0;
-        for f in 0..mesh.num_face_indices.len() {
-            let end = next_face + mesh.num_face_indices[f] as usize;
-            let face_indices: Vec<_> = mesh.indices[next_face..end].iter().collect();
-
-            assert_eq!(face_indices.len(), 3);
-            let mut tri = [Vector::ZERO; 3];
-            for i in 0..3 {
-                let k = *face_indices[i] as usize;
-                tri[i] = Vector {
-                    x: mesh.positions[3 * k + 0] * SCALE,
-                    y: mesh.positions[3 * k + 1] * SCALE,
-                    z: mesh.positions[3 * k + 2] * SCALE,
-                };
-            }
-            objects.push(Object {
-                shape: Shape::Triangle { corners: tri },
-                material_id: mesh.material_id,
+    objects.extend(obj_objects(&models, SCALE, true));
+
+    // Teapots sharing one copy of the triangles
+    let (teapot_models, _) =
+        tobj::load_obj("objs/utah_teapot.obj", false).expect("Failed to load file");
+    let teapot = Arc::new(Geometry::new(obj_objects(&teapot_models, 1.0, false)));
+    let up = Vector {
+        x: 0.0,
+        y: 1.0,
+        z: 0.0,
+    };
+    let placements = [
+        (
+            Vector {
+                x: 46.0,
+                y: 41.25,
+                z: 42.0,
+            },
+            0.6,
+            6.0,
+        ),
+        (
+            Vector {
+                x: 30.0,
+                y: 0.0,
+                z: 100.0,
+            },
+            -0.9,
+            4.0,
+        ),
+    ];
+    for &(position, angle, scale) in placements.iter() {
+        let to_world = Matrix::translation(position)
+            * Matrix::rotation(up, Angle { radians: angle })
+            * Matrix::scale(Vector {
+                x: scale,
+                y: scale,
+                z: scale,
             });
+        objects.push(Object {
+            shape: Shape::Instance {
+                geometry: teapot.clone(),
+                transform: Transform::new(to_world).expect("Instance transform is singular"),
+            },
+            material_id: None,
+        });
+    }
+
+    materials.push(Material::diffuse(
+        "marble",
+        Arc::new(Marble {
+            base: Color {
+                r: 0.9,
+                g: 0.88,
+                b: 0.85,
+            },
+            vein: Color {
+                r: 0.2,
+                g: 0.25,
+                b: 0.3,
+            },
+            frequency: 0.1,
+            turbulence: 4.0,
+            octaves: 4,
+            mapping: Mapping::Solid,
+        }),
+    ));
+    objects.push(Object {
+        shape: Shape::Sphere {
+            center: Point {
+                x: 90.0,
+                y: 20.0,
+                z: 40.0,
+            },
+            radius: 20.0,
+        },
+        material_id: Some(materials.len() - 1),
+    });
+
+    // Twisted column traced as a distance field
+    let column = Point {
+        x: 122.0,
+        y: 14.0,
+        z: 45.0,
+    };
+    let half_size = Vector {
+        x: 7.0,
+        y: 14.0,
+        z: 7.0,
+    };
+    objects.push(Object {
+        shape: Shape::Sdf {
+            sdf: Arc::new(Sdf::Translate {
+                offset: column,
+                sdf: Box::new(Sdf::Twist {
+                    rate: 0.08,
+                    sdf: Box::new(Sdf::RoundBox {
+                        half_size,
+                        radius: 1.5,
+                    }),
+                }),
+            }),
+            bounds: Bounds {
+                min: column - half_size * 1.5,
+                max: column + half_size * 1.5,
+            },
+            tracing: SphereTracing {
+                step_scale: 0.7,
+                ..SphereTracing::default()
+            },
+        },
+        material_id: Some(materials.len() - 1),
+    });
+
+    if let Some((path, scale)) = options.mesh {
+        let mesh = Mesh::load(&path, scale).expect("Failed to load mesh");
+        materials.push(Material::vertex_colored(&path.to_string_lossy()));
+        objects.push(Object {
+            shape: Shape::Mesh {
+                mesh: Arc::new(mesh),
+            },
+            material_id: Some(materials.len() - 1),
+        });
+    }
+
+    let mut gltf_suns = Vec::new();
+    if let Some(path) = options.gltf {
+        let gltf = gltf::load(&path, materials.len()).expect("Failed to load glTF scene");
+        for warning in gltf.warnings.iter() {
+            eprintln!("{}: {}", path.display(), warning);
+        }
+        if let Some(gltf_camera) = gltf.cameras.first() {
+            camera = gltf_camera.camera(WIDTH, HEIGHT);
+        }
+        objects.extend(gltf.objects);
+        materials.extend(gltf.materials);
+        gltf_suns = gltf.suns;
+    }
 
-            next_face = end;
+    let mut atmosphere = options
+        .fog
+        .map(|(visibility, g)| Medium::fog(visibility, 1.0, g));
+    if let Some((path, visibility)) = options.smoke {
+        let (density, bounds) = volume::load_vol(path).expect("Failed to load volume");
+        let volume = Volume::new(density, None, bounds, Matrix::IDENTITY).expect("Flat volume");
+        atmosphere = Some(Medium {
+            volume: Some(Arc::new(volume)),
+            ..Medium::fog(visibility, 1.0, 0.0)
+        });
+    }
+    let mut pbrt_lights = None;
+    if let Some(path) = options.pbrt {
+        let pbrt = pbrt::load(&path, 0).expect("Failed to load pbrt scene");
+        for warning in pbrt.warnings.iter() {
+            eprintln!("{}: {}", path.display(), warning);
         }
+        camera = Camera {
+            width: WIDTH,
+            height: HEIGHT,
+            ..pbrt.camera
+        };
+        objects = pbrt.objects;
+        materials = pbrt.materials;
+        pbrt_lights = Some((pbrt.environment, pbrt.suns));
+        atmosphere = pbrt.atmosphere;
     }
 
+    let (environment, sun) = match options.environment {
+        Some(path) => (
+            EnvironmentLight::load(path, Angle { radians: 0.0 }, 1.0)
+                .expect("Failed to load environment map"),
+            None,
+        ),
+        None => {
+            let sky = PhysicalSky::new(SUN_ELEVATION, SUN_AZIMUTH, TURBIDITY, 1.0);
+            (sky.environment(256, 128), Some(sky.sun()))
+        }
+    };
+
+    // Directional lights of the glTF scene replace the sun of the sky
+    let sun = gltf_suns.first().copied().or(sun);
+    // Lights of a pbrt scene replace all others, and its sky is black without an infinite light
+    let (environment, sun) = match pbrt_lights {
+        Some((pbrt_environment, pbrt_suns)) => (
+            pbrt_environment.unwrap_or_else(|| EnvironmentLight::uniform(Color::BLACK)),
+            pbrt_suns.first().copied(),
+        ),
+        None => (environment, sun),
+    };
+    let mut scene = Scene::new(objects, materials, environment, sun);
+    scene.atmosphere = atmosphere;
+
+    let algorithm = options.algorithm;
+    let mut splats = Splats::new(WIDTH, HEIGHT);
+    let photons = if algorithm == Algorithm::PhotonMapping {
+        PhotonMap::trace(&scene, PHOTONS, BOUNCES + 1)
+    } else {
+        PhotonMap::new(Vec::new())
+    };
+    let mut progressive = ProgressivePhotonMapping::new(WIDTH, HEIGHT, PHOTON_RADIUS);
+    let mut progressive_view = camera.transform;
+    let path_tracer: Box<dyn Integrator> = match algorithm {
+        Algorithm::Spectral => Box::new(SpectralPathTracer { bounces: BOUNCES }),
+        _ => Box::new(PathTracer { bounces: BOUNCES }),
+    };
+    // Debug views replace the light transport algorithm while they are shown
+    let mut debug = options.view.map(|view| DebugIntegrator::new(view, &scene));
+
+    let sampler = options
+        .adaptive
+        .map(|(threshold, seconds)| AdaptiveSampler {
+            threshold,
+            time_budget: Duration::from_secs(seconds),
+            ..AdaptiveSampler::default()
+        });
+    // Samples of the adaptive sampler and features of the denoiser since the camera last moved
+    let mut film = Film::new(WIDTH, HEIGHT);
+    let mut features: Vec<Features> = Vec::new();
+    let mut film_view = camera.transform;
+    let mut film_start = Instant::now();
+    let mut converged = false;
+    // Shows the samples per pixel of the adaptive sampler instead of the image
+    let mut show_samples = false;
+    // Filters the noise out of the image of the light transport algorithm
+    let mut denoise = options.denoise;
+    let output = options.output;
+
     event_loop.run(move |event, _, control_flow| {
         // Draw the current frame
         if let Event::RedrawRequested(_) = event {
             // draw
 
+            let rays = 16;
+            splats.clear();
+            if camera.transform != film_view {
+                film.clear();
+                features.clear();
+                film_view = camera.transform;
+                film_start = Instant::now();
+                converged = false;
+            }
+            if debug.is_none() && algorithm == Algorithm::ProgressivePhotonMapping {
+                // The estimate only converges for a fixed view
+                if camera.transform != progressive_view {
+                    progressive = ProgressivePhotonMapping::new(WIDTH, HEIGHT, PHOTON_RADIUS);
+                    progressive_view = camera.transform;
+                }
+                progressive.iterate(&scene, &camera, PHOTONS, BOUNCES + 1);
+            }
+            let metropolis = if debug.is_none() && algorithm == Algorithm::Metropolis {
+                Metropolis {
+                    mutations_per_pixel: rays,
+                    ..Metropolis::default()
+                }
+                .render(&scene, &camera, BOUNCES + 1)
+            } else {
+                Vec::new()
+            };
+            // One sample of the light through a pixel, for the algorithms that trace from the
+            // camera pixel by pixel
+            let camera_sample = |x: u32, y: u32| {
+                if algorithm == Algorithm::PhotonMapping {
+                    let (origin, direction, _) = camera.ray(
+                        x as float + sampling::random::<float>(),
+                        y as float + sampling::random::<float>(),
+                    );
+                    photon::radiance(
+                        &scene,
+                        &photons,
+                        PHOTONS,
+                        PHOTON_RADIUS,
+                        origin,
+                        direction,
+                        BOUNCES + 1,
+                    )
+                } else {
+                    let (origin, direction, differential) = camera.ray(x as float, y as float);
+                    path_tracer.radiance(&scene, origin, direction, Some(differential))
+                }
+            };
+            let adaptive = match sampler {
+                Some(sampler)
+                    if debug.is_none()
+                        && matches!(
+                            algorithm,
+                            Algorithm::Path | Algorithm::Spectral | Algorithm::PhotonMapping
+                        ) =>
+                {
+                    Some(sampler)
+                }
+                _ => None,
+            };
+
+            let mut colors: Vec<Color> = if let Some(sampler) = adaptive {
+                if !converged && film_start.elapsed() < sampler.time_budget {
+                    converged = sampler.round(&mut film, camera_sample) == 0;
+                }
+                if show_samples {
+                    film.sample_map()
+                } else {
+                    film.colors()
+                }
+            } else {
+                let sums: Vec<Color> = (0..(WIDTH * HEIGHT) as usize)
+                    .into_par_iter()
+                    .map(|i| {
+                        let x = i as u32 % WIDTH;
+                        let y = i as u32 / WIDTH;
+
+                        let mut sum = Color::BLACK;
+                        if let Some(debug) = &debug {
+                            let (origin, direction, differential) =
+                                camera.ray(x as float, y as float);
+                            for _ in 0..rays {
+                                sum = sum
+                                    + debug.radiance(&scene, origin, direction, Some(differential));
+                            }
+                            return sum;
+                        }
+                        match algorithm {
+                            Algorithm::Path | Algorithm::Spectral | Algorithm::PhotonMapping => {
+                                for _ in 0..rays {
+                                    sum = sum + camera_sample(x, y);
+                                }
+                            }
+                            Algorithm::Bidirectional => {
+                                // The path tracer also samples lights at its last bounce
+                                for _ in 0..rays {
+                                    sum = sum
+                                        + bdpt::sample(&scene, &camera, x, y, BOUNCES + 1, &splats);
+                                }
+                            }
+                            Algorithm::ProgressivePhotonMapping => {
+                                // Already averaged over the frames
+                                sum = progressive.radiance(x, y) * rays as float;
+                            }
+                            Algorithm::Metropolis => {
+                                sum = metropolis[i] * rays as float;
+                            }
+                        }
+                        sum
+                    })
+                    .collect();
+
+                // Light subpaths of all pixels add to the splats, as many as there are rays
+                sums.iter()
+                    .enumerate()
+                    .map(|(i, sum)| {
+                        let x = i as u32 % WIDTH;
+                        let y = i as u32 / WIDTH;
+                        (*sum + splats.get(x, y)) / (rays as float)
+                    })
+                    .collect()
+            };
+            if denoise && debug.is_none() && !(adaptive.is_some() && show_samples) {
+                // The features are free of noise, so they are traced once for each view
+                if features.is_empty() {
+                    features = (0..WIDTH * HEIGHT)
+                        .into_par_iter()
+                        .map(|i| {
+                            let (origin, direction, differential) =
+                                camera.ray((i % WIDTH) as float, (i / WIDTH) as float);
+                            Features::trace(&scene, origin, direction, differential)
+                        })
+                        .collect();
+                }
+                colors = Denoiser::default().denoise(WIDTH, HEIGHT, &colors, &features);
+            }
+
             let frame = pixels.get_frame();
 
             frame
                 .par_chunks_exact_mut(4)
-                .enumerate()
-                .for_each(|(i, pixel)| {
-                    let x = (i % WIDTH as usize) as u16;
-                    let y = (i / WIDTH as usize) as u16;
-
-                    let aspect_ratio = (WIDTH as f32) / (HEIGHT as f32);
-
-                    let cx = 0.5 * (WIDTH as f32);
-                    let cy = 0.5 * (HEIGHT as f32);
-
-                    let p = (Vector {
-                        z: (cx - x as f32) / (WIDTH as f32) * aspect_ratio,
-                        y: (cy - y as f32) / (HEIGHT as f32),
-                        x: 1.0f32, // affects fov calculation
-                    })
-                    .normalized();
-
-                    let rays = 1;
-
-                    let mut sum = Color::BLACK;
-                    for i in 0..rays {
-                        sum = sum
-                            + raytrace(
-                                camera.pos(),
-                                camera.mul_rotate(p),
-                                &objects,
-                                &materials,
-                                sun,
-                            );
-                    }
-
-                    let color = sum / (rays as float);
-
+                .zip(colors.par_iter())
+                .for_each(|(pixel, color)| {
                     let c = color.to_pixel_color();
 
                     // let c = [
@@ -235,12 +723,24 @@ fn main() -> Result<(), Error> {
         if input.update(&event) {
             // Close events
             if input.key_pressed(VirtualKeyCode::Escape) || input.quit() {
+                if let Some(path) = &output {
+                    let saved = image::save_buffer(
+                        path,
+                        pixels.get_frame(),
+                        WIDTH,
+                        HEIGHT,
+                        image::ColorType::Rgba8,
+                    );
+                    if let Err(error) = saved {
+                        eprintln!("Could not save {}: {}", path.display(), error);
+                    }
+                }
                 *control_flow = ControlFlow::Exit;
                 return;
             }
 
             if input.key_pressed(VirtualKeyCode::Q) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::translation(Vector {
                         x: 0.0,
                         y: 0.1,
@@ -249,7 +749,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::E) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::translation(Vector {
                         x: 0.0,
                         y: -0.1,
@@ -258,7 +758,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::W) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::translation(Vector {
                         x: 0.1,
                         y: 0.0,
@@ -267,7 +767,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::S) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::translation(Vector {
                         x: -0.1,
                         y: 0.0,
@@ -276,7 +776,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::A) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::rotation(
                         Vector {
                             x: 0.0,
@@ -288,7 +788,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::D) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::rotation(
                         Vector {
                             x: 0.0,
@@ -300,7 +800,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::Z) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::rotation(
                         Vector {
                             x: 1.0,
@@ -312,7 +812,7 @@ fn main() -> Result<(), Error> {
             }
 
             if input.key_pressed(VirtualKeyCode::X) {
-                camera = camera
+                camera.transform = camera.transform
                     * Matrix::rotation(
                         Vector {
                             x: 1.0,
@@ -323,6 +823,37 @@ fn main() -> Result<(), Error> {
                     );
             }
 
+            if input.key_pressed(VirtualKeyCode::M) {
+                show_samples = !show_samples;
+            }
+
+            if input.key_pressed(VirtualKeyCode::N) {
+                denoise = !denoise;
+            }
+
+            // Number keys switch between the light transport algorithm and the debug views
+            if input.key_pressed(VirtualKeyCode::Key1) {
+                debug = None;
+                window.set_title("Raytracer test");
+            }
+            let view_keys = [
+                VirtualKeyCode::Key2,
+                VirtualKeyCode::Key3,
+                VirtualKeyCode::Key4,
+                VirtualKeyCode::Key5,
+                VirtualKeyCode::Key6,
+                VirtualKeyCode::Key7,
+                VirtualKeyCode::Key8,
+                VirtualKeyCode::Key9,
+                VirtualKeyCode::Key0,
+            ];
+            for (&key, &view) in view_keys.iter().zip(DebugView::ALL.iter()) {
+                if input.key_pressed(key) {
+                    debug = Some(DebugIntegrator::new(view, &scene));
+                    window.set_title(&format!("Raytracer test: {}", view));
+                }
+            }
+
             // Resize the window
             if let Some(size) = input.window_resized() {
                 pixels.resize(size.width, size.height);
@@ -0,0 +1,236 @@
+use crate::color::Color;
+use crate::medium::Medium;
+use crate::mipmap::{Filter, MipMap, Wrap};
+use crate::prelude::*;
+use crate::texture::{ImageTexture, Multiply, TextureRef, VertexColor};
+use crate::vector::Vector;
+
+use std::path::Path;
+use std::sync::Arc;
+
+/// Filter used for image textures referenced by OBJ materials
+const OBJ_TEXTURE_FILTER: Filter = Filter::Ewa {
+    max_anisotropy: 8.0,
+};
+
+/// Surface appearance. Every parameter is a texture,
+/// so constant colors and procedural patterns can be used interchangeably.
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub name: String,
+    /// Light emitted by the surface
+    pub ambient: TextureRef,
+    /// Color of diffusely reflected light
+    pub diffuse: TextureRef,
+    /// Color of mirror reflected light
+    pub specular: TextureRef,
+    /// Makes the surface clear glass, which reflects or refracts by the Fresnel reflectance
+    pub glass: Option<Ior>,
+    /// Medium inside closed surfaces with this material, seen by the path tracers.
+    /// Surfaces with a medium that are not glass are only its boundary, and invisible.
+    pub medium: Option<Medium>,
+}
+
+impl Material {
+    /// Non-emissive material with the given diffuse texture
+    pub fn diffuse(name: &str, diffuse: TextureRef) -> Self {
+        Self {
+            name: name.to_owned(),
+            ambient: Arc::new(Color::BLACK),
+            diffuse,
+            specular: Arc::new(Color::BLACK),
+            glass: None,
+            medium: None,
+        }
+    }
+
+    /// Clear glass with the given index of refraction
+    pub fn glass(name: &str, ior: Ior) -> Self {
+        Self {
+            glass: Some(ior),
+            ..Self::diffuse(name, Arc::new(Color::BLACK))
+        }
+    }
+
+    /// Material for meshes from files without materials, such as PLY and STL:
+    /// white like the default material, unless the mesh has vertex colors
+    pub fn vertex_colored(name: &str) -> Self {
+        Self::diffuse(name, Arc::new(VertexColor))
+    }
+}
+
+impl Material {
+    /// Converts an OBJ material, loading its texture maps relative to `directory`.
+    /// Texture maps that fail to load add to `warnings` and are left out.
+    pub fn from_obj(
+        material: &tobj::Material,
+        directory: &Path,
+        warnings: &mut Vec<String>,
+    ) -> Self {
+        let mut texture = |color, map: &str| obj_texture(color, map, directory, warnings);
+        Self {
+            name: material.name.clone(),
+            ambient: texture(material.ambient, &material.ambient_texture),
+            diffuse: texture(material.diffuse, &material.diffuse_texture),
+            specular: texture(material.specular, &material.specular_texture),
+            // Illumination models 4, 6 and 7 refract
+            glass: match material.illumination_model {
+                Some(4) | Some(6) | Some(7) if material.optical_density > 1.0 => {
+                    Some(Ior::Constant(material.optical_density))
+                }
+                _ => None,
+            },
+            medium: None,
+        }
+    }
+}
+
+/// Index of refraction of glass, which depends on the wavelength of light.
+/// Only the spectral path tracer disperses light, others use the index at `Ior::REFERENCE`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ior {
+    Constant(float),
+    /// `a + b / λ²` with λ in micrometers
+    Cauchy {
+        a: float,
+        b: float,
+    },
+    /// `n² = 1 + Σ b λ² / (λ² - c)` with λ in micrometers
+    Sellmeier {
+        b: [float; 3],
+        c: [float; 3],
+    },
+}
+
+impl Ior {
+    /// Wavelength of the sodium D line in nanometers, which glass catalogs quote indices at
+    pub const REFERENCE: float = 589.3;
+
+    /// Borosilicate crown glass, the common optical glass
+    pub const BK7: Self = Self::Sellmeier {
+        b: [1.039_612, 0.231_792_34, 1.010_469_5],
+        c: [0.006_000_699, 0.020_017_914, 103.560_65],
+    };
+
+    /// Dense flint glass, which disperses light strongly
+    pub const SF11: Self = Self::Sellmeier {
+        b: [1.737_597, 0.313_747_35, 1.898_781],
+        c: [0.013_188_707, 0.062_306_814, 155.236_3],
+    };
+
+    /// Index at a wavelength in nanometers
+    pub fn at(self, wavelength: float) -> float {
+        let micrometers = wavelength / 1000.0;
+        let l2 = micrometers * micrometers;
+        match self {
+            Self::Constant(n) => n,
+            Self::Cauchy { a, b } => a + b / l2,
+            Self::Sellmeier { b, c } => {
+                let sum: float = b.iter().zip(c.iter()).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    /// Whether the index depends on the wavelength
+    pub fn is_dispersive(self) -> bool {
+        !matches!(self, Self::Constant(_))
+    }
+}
+
+/// Fraction of unpolarized light a dielectric reflects, for the cosine of the angle of
+/// incidence and `eta` as in `Vector::refract`
+/// https://www.pbr-book.org/4ed/Reflection_Models/Specular_Reflection_and_Transmission#FresnelReflectance
+pub fn fresnel_dielectric(cos_i: float, eta: float) -> float {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+/// Direction of a ray arriving along `direction` at glass whose unit shading `normal` faces
+/// against it, reflected with the Fresnel reflectance and refracted otherwise, as chosen by `u`
+/// in [0, 1). `eta` is as in `Vector::refract`. Also returns whether the ray was refracted.
+pub fn scatter_glass(direction: Vector, normal: Vector, eta: float, u: float) -> (Vector, bool) {
+    let cos_i = -direction.normalized().dot(normal);
+    match direction.refract(normal, eta) {
+        Some(refraction) if u >= fresnel_dielectric(cos_i, eta) => (refraction, true),
+        _ => (direction.reflect(normal), false),
+    }
+}
+
+/// Constant color, multiplied by the texture map if one is given.
+/// A texture that fails to load adds to `warnings` and is ignored.
+fn obj_texture(
+    color: [f32; 3],
+    map: &str,
+    directory: &Path,
+    warnings: &mut Vec<String>,
+) -> TextureRef {
+    let color: TextureRef = Arc::new(Color::from(color));
+    if map.is_empty() {
+        return color;
+    }
+
+    let path = directory.join(map);
+    match MipMap::load(&path, Wrap::Repeat) {
+        Ok(mipmap) => Arc::new(Multiply {
+            a: color,
+            b: Arc::new(ImageTexture {
+                mipmap: Arc::new(mipmap),
+                filter: OBJ_TEXTURE_FILTER,
+            }),
+        }),
+        Err(error) => {
+            warnings.push(format!("Failed to load texture {:?}: {}", path, error));
+            color
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::SurfacePoint;
+
+    #[test]
+    fn missing_obj_textures_are_warned_about() {
+        let material = tobj::Material {
+            name: "missing".to_owned(),
+            ambient: [0.0; 3],
+            diffuse: [0.5; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            dissolve: 1.0,
+            optical_density: 1.0,
+            ambient_texture: String::new(),
+            diffuse_texture: "missing.png".to_owned(),
+            specular_texture: String::new(),
+            normal_texture: String::new(),
+            shininess_texture: String::new(),
+            dissolve_texture: String::new(),
+            illumination_model: None,
+            unknown_param: Default::default(),
+        };
+        let mut warnings = Vec::new();
+        let material = Material::from_obj(&material, Path::new("does/not/exist"), &mut warnings);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("missing.png"), "{:?}", warnings);
+        // The constant color is kept
+        let at = SurfacePoint {
+            point: Vector::ZERO,
+            normal: Vector::ZERO,
+            uv: [0.5, 0.5],
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: Color::WHITE,
+        };
+        let color = material.diffuse.color(&at);
+        assert_eq!(color, Color::WHITE * 0.5);
+    }
+}
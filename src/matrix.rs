@@ -1,15 +1,29 @@
 use crate::angle::Angle;
+use crate::bounds::Bounds;
 use crate::prelude::float;
+use crate::ray::gamma;
 use crate::vector::{Point, Vector};
 
 use std::fmt;
 use std::ops::Mul;
 
 /// 4x4 transformation matrix, outer array is rows, inner columns
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Matrix([[float; 4]; 4]);
 
 impl Matrix {
+    pub const IDENTITY: Self = Self([
+        [1.0, 0.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0, 0.0],
+        [0.0, 0.0, 0.0, 1.0],
+    ]);
+
+    /// Matrix from its rows
+    pub fn new(rows: [[float; 4]; 4]) -> Self {
+        Self(rows)
+    }
+
     /// https://en.wikipedia.org/wiki/Transformation_matrix#Rotation_2
     pub fn rotation(about: Vector, angle: Angle) -> Self {
         assert!(about.is_normalized());
@@ -96,6 +110,182 @@ impl Matrix {
             z: self.0[2][0] * rhs.x + self.0[2][1] * rhs.y + self.0[2][2] * rhs.z,
         }
     }
+
+    /// Transforms a surface normal, when `self` is the inverse of the transform applied to points.
+    /// Multiplying by the transpose of the inverse keeps normals perpendicular under non-uniform scaling.
+    /// https://www.pbr-book.org/3ed-2018/Geometry_and_Transformations/Applying_Transformations#Normals
+    pub fn mul_normal(self, rhs: Vector) -> Vector {
+        Vector {
+            x: self.0[0][0] * rhs.x + self.0[1][0] * rhs.y + self.0[2][0] * rhs.z,
+            y: self.0[0][1] * rhs.x + self.0[1][1] * rhs.y + self.0[2][1] * rhs.z,
+            z: self.0[0][2] * rhs.x + self.0[1][2] * rhs.y + self.0[2][2] * rhs.z,
+        }
+    }
+
+    #[must_use]
+    pub fn transpose(self) -> Self {
+        let mut result = [[0.0; 4]; 4];
+        for (row, result_row) in result.iter_mut().enumerate() {
+            for (col, cell) in result_row.iter_mut().enumerate() {
+                *cell = self.0[col][row];
+            }
+        }
+        Self(result)
+    }
+
+    /// Determinant of the upper left 3x3 part, how much volumes are scaled
+    pub fn determinant3(self) -> float {
+        let m = self.0;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting, `None` for singular matrices
+    pub fn inverse(self) -> Option<Self> {
+        let mut m = self.0;
+        let mut inverse = Self::IDENTITY.0;
+
+        for col in 0..4 {
+            let pivot = (col..4)
+                .max_by(|&a, &b| m[a][col].abs().partial_cmp(&m[b][col].abs()).unwrap())
+                .unwrap();
+            if m[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            m.swap(col, pivot);
+            inverse.swap(col, pivot);
+
+            let scale = 1.0 / m[col][col];
+            for i in 0..4 {
+                m[col][i] *= scale;
+                inverse[col][i] *= scale;
+            }
+
+            for row in 0..4 {
+                if row != col {
+                    let factor = m[row][col];
+                    for i in 0..4 {
+                        m[row][i] -= factor * m[col][i];
+                        inverse[row][i] -= factor * inverse[col][i];
+                    }
+                }
+            }
+        }
+
+        Some(Self(inverse))
+    }
+}
+
+/// Object to world transform together with its inverse
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub to_world: Matrix,
+    pub to_object: Matrix,
+}
+
+impl Transform {
+    /// `None` if the matrix can not be inverted
+    pub fn new(to_world: Matrix) -> Option<Self> {
+        Some(Self {
+            to_world,
+            to_object: to_world.inverse()?,
+        })
+    }
+
+    pub fn point(&self, point: Point) -> Point {
+        self.to_world.mul_translate(point)
+    }
+
+    pub fn vector(&self, vector: Vector) -> Vector {
+        self.to_world.mul_rotate(vector)
+    }
+
+    /// Transformed point together with a bound on its error, given the error of the original point
+    /// https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error#TransformingPointsandVectorswithErrors
+    pub fn point_with_error(&self, point: Point, error: Vector) -> (Point, Vector) {
+        let m = self.to_world.0;
+        let row = |i: usize| Vector {
+            x: m[i][0].abs(),
+            y: m[i][1].abs(),
+            z: m[i][2].abs(),
+        };
+        let bound = |i: usize| {
+            let abs_row = row(i);
+            (1.0 + gamma(3)) * abs_row.dot(error)
+                + gamma(3) * (abs_row.dot(point.abs()) + m[i][3].abs())
+        };
+        (
+            self.point(point),
+            Vector {
+                x: bound(0),
+                y: bound(1),
+                z: bound(2),
+            },
+        )
+    }
+
+    /// Whether the transform scales lengths the same in every direction, so that it keeps angles
+    /// and scales areas evenly
+    pub fn scales_uniformly(&self) -> bool {
+        let axes = [
+            self.vector(Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }),
+            self.vector(Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }),
+            self.vector(Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+        ];
+        let scale2 = axes[0].len2();
+        let tolerance = 1e-4 * scale2;
+        (0..3).all(|i| {
+            (axes[i].len2() - scale2).abs() <= tolerance
+                && axes[i].dot(axes[(i + 1) % 3]).abs() <= tolerance
+        })
+    }
+
+    /// Not normalized
+    pub fn normal(&self, normal: Vector) -> Vector {
+        self.to_object.mul_normal(normal)
+    }
+
+    /// World space box around the transformed corners of object space bounds
+    pub fn bounds(&self, bounds: Bounds) -> Bounds {
+        if bounds.is_empty() {
+            return bounds;
+        }
+        let finite = |v: Vector| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        if !finite(bounds.min) || !finite(bounds.max) {
+            return Bounds {
+                min: Bounds::EMPTY.max,
+                max: Bounds::EMPTY.min,
+            };
+        }
+
+        (0..8).fold(Bounds::EMPTY, |result, corner| {
+            let pick = |bit: usize, axis: usize| {
+                if corner & bit == 0 {
+                    bounds.min[axis]
+                } else {
+                    bounds.max[axis]
+                }
+            };
+            result.include(self.point(Point {
+                x: pick(1, 0),
+                y: pick(2, 1),
+                z: pick(4, 2),
+            }))
+        })
+    }
 }
 
 impl Mul for Matrix {
@@ -103,13 +293,13 @@ impl Mul for Matrix {
 
     fn mul(self, rhs: Self) -> Self {
         let mut result = [[0.0; 4]; 4];
-        for row in 0..4 {
-            for col in 0..4 {
+        for (row, result_row) in result.iter_mut().enumerate() {
+            for (col, cell) in result_row.iter_mut().enumerate() {
                 let mut s = 0.0;
                 for i in 0..4 {
                     s += self.0[row][i] * rhs.0[i][col];
                 }
-                result[row][col] = s;
+                *cell = s;
             }
         }
         Self(result)
@@ -129,10 +319,101 @@ impl fmt::Display for Matrix {
             }
             write!(f, "]")?;
             if row < 3 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
         }
-        write!(f, "]\n")?;
+        writeln!(f, "]")?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inverse_undoes_transform() {
+        let m = Matrix::translation(Vector {
+            x: 1.0,
+            y: -2.0,
+            z: 3.0,
+        }) * Matrix::rotation(
+            Vector {
+                x: 0.0,
+                y: 0.6,
+                z: 0.8,
+            },
+            Angle { radians: 0.7 },
+        ) * Matrix::scale(Vector {
+            x: 2.0,
+            y: 0.5,
+            z: 3.0,
+        });
+        let inverse = m.inverse().unwrap();
+        let p = Point {
+            x: 0.3,
+            y: 4.0,
+            z: -1.0,
+        };
+        assert!((inverse.mul_translate(m.mul_translate(p)) - p).len() < 0.0001);
+
+        let product = m * inverse;
+        for row in 0..4 {
+            for col in 0..4 {
+                let expected = if row == col { 1.0 } else { 0.0 };
+                assert!((product.0[row][col] - expected).abs() < 0.0001);
+            }
+        }
+
+        assert!(Matrix::scale(Vector::ZERO).inverse().is_none());
+    }
+
+    #[test]
+    fn normals_stay_perpendicular() {
+        let transform = Transform::new(Matrix::scale(Vector {
+            x: 4.0,
+            y: 1.0,
+            z: 1.0,
+        }))
+        .unwrap();
+        // Surface sloping 45 degrees in xy
+        let tangent = Vector {
+            x: 1.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let normal = Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        let dot = transform.vector(tangent).dot(transform.normal(normal));
+        assert!(dot.abs() < 0.0001);
+    }
+
+    #[test]
+    fn uniform_scaling_is_recognized() {
+        let rotation = Matrix::rotation(
+            Vector {
+                x: 0.0,
+                y: 0.6,
+                z: 0.8,
+            },
+            Angle { radians: 0.7 },
+        );
+        let scale = |x: float, y: float, z: float| {
+            Transform::new(rotation * Matrix::scale(Vector { x, y, z })).unwrap()
+        };
+        assert!(scale(2.0, 2.0, 2.0).scales_uniformly());
+        assert!(scale(-0.5, 0.5, 0.5).scales_uniformly());
+        assert!(!scale(2.0, 2.0, 3.0).scales_uniformly());
+        // Shearing keeps the lengths of the axes
+        let shear = Matrix::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.6, 0.8, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        assert!(!Transform::new(shear).unwrap().scales_uniformly());
+    }
+}
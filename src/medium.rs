@@ -0,0 +1,150 @@
+//! Participating media such as fog and smoke, which absorb and scatter light along rays
+//! instead of at surfaces.
+//! https://www.pbr-book.org/4ed/Volume_Scattering
+
+use crate::color::Color;
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::vector::{Point, Vector};
+use crate::volume::{Majorants, Volume};
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Medium filling the scene or the inside of closed surfaces
+#[derive(Debug, Clone, PartialEq)]
+pub struct Medium {
+    /// Fraction of light absorbed per unit of distance, for each channel
+    pub absorption: Color,
+    /// Fraction of light scattered into other directions per unit of distance
+    pub scattering: Color,
+    /// Henyey–Greenstein asymmetry, the mean cosine of the scattering angle: positive values
+    /// scatter forwards, negative ones backwards and zero evenly
+    pub g: float,
+    /// Radiance of the light the absorbing particles emit, as in fire. Like in pbrt the light
+    /// emitted per unit of distance is this times the absorption.
+    pub emission: Color,
+    /// Density scaling the coefficients, the same everywhere without a volume
+    pub volume: Option<Arc<Volume>>,
+}
+
+impl Medium {
+    /// Medium that does not interact with light, for boundaries of media that are not surfaces
+    pub const VACUUM: Self = Self {
+        absorption: Color::BLACK,
+        scattering: Color::BLACK,
+        g: 0.0,
+        emission: Color::BLACK,
+        volume: None,
+    };
+
+    /// Gray medium where light travels `visibility` on average before it is scattered, with
+    /// `albedo` the fraction of it scattered rather than absorbed
+    pub fn fog(visibility: float, albedo: float, g: float) -> Self {
+        let extinction = Color::WHITE * (1.0 / visibility);
+        Self {
+            absorption: extinction * (1.0 - albedo),
+            scattering: extinction * albedo,
+            g,
+            ..Self::VACUUM
+        }
+    }
+
+    /// Fraction of light lost per unit of distance, by absorption or scattering
+    pub fn extinction(&self) -> Color {
+        self.absorption + self.scattering
+    }
+
+    /// Density at a point, which scales the coefficients
+    pub fn density(&self, point: Point) -> float {
+        match &self.volume {
+            Some(volume) => volume.density(point),
+            None => 1.0,
+        }
+    }
+
+    /// Scale of the emission at a point
+    pub fn emission_scale(&self, point: Point) -> float {
+        match &self.volume {
+            Some(volume) => volume.emission(point),
+            None => 1.0,
+        }
+    }
+
+    /// Parts of a ray up to `distance` with bounds on the density along them
+    pub fn majorants(&self, ray: &Ray, distance: float) -> Majorants<'_> {
+        match &self.volume {
+            Some(volume) => volume.majorants(ray, distance),
+            None => Majorants::uniform(distance),
+        }
+    }
+
+    /// Density of scattering from `direction` into `scattered`, both directions of travel
+    pub fn phase(&self, direction: Vector, scattered: Vector) -> float {
+        henyey_greenstein(direction.dot(scattered), self.g)
+    }
+
+    /// Scattered direction for a unit direction of travel, distributed like the phase function
+    pub fn sample_phase(&self, direction: Vector, u: [float; 2]) -> (Vector, float) {
+        let g = self.g;
+        let cos = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u[0]
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u[0]);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        let phi = 2.0 * PI * u[1];
+        let (t1, t2) = direction.coordinate_system();
+        let scattered = t1 * (sin * phi.cos()) + t2 * (sin * phi.sin()) + direction * cos;
+        (scattered, henyey_greenstein(cos, g))
+    }
+}
+
+/// Henyey–Greenstein phase function of the cosine between the directions of travel before and
+/// after scattering
+fn henyey_greenstein(cos: float, g: float) -> float {
+    let denominator = 1.0 + g * g - 2.0 * g * cos;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.max(1e-12).sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_sampling_matches_the_phase_function() {
+        let direction = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        for &g in [-0.6, 0.0, 0.3, 0.9].iter() {
+            let medium = Medium {
+                g,
+                ..Medium::VACUUM
+            };
+
+            // The phase function integrates to one over the sphere
+            let n = 4000;
+            let mut total = 0.0;
+            for i in 0..n {
+                let cos = -1.0 + 2.0 * (i as float + 0.5) / n as float;
+                total += henyey_greenstein(cos, g) * 2.0 * PI * 2.0 / n as float;
+            }
+            assert!((total - 1.0).abs() < 0.02, "g = {}: {}", g, total);
+
+            // Sampled directions have the asymmetry as their mean cosine
+            let n = 20_000;
+            let mut sum = 0.0;
+            for i in 0..n {
+                let u = [(i as float + 0.5) / n as float, rand::random()];
+                let (scattered, pdf) = medium.sample_phase(direction, u);
+                assert!((scattered.len() - 1.0).abs() < 1e-3);
+                assert!((pdf - medium.phase(direction, scattered)).abs() < 1e-3 * pdf.max(1.0));
+                sum += scattered.z;
+            }
+            assert!((sum / n as float - g).abs() < 0.02, "g = {}", g);
+        }
+    }
+}
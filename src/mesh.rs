@@ -0,0 +1,630 @@
+//! Indexed triangle meshes
+
+use crate::bounds::Bounds;
+use crate::bvh::Bvh;
+use crate::color::Color;
+use crate::matrix::Transform;
+use crate::object::{Shape, ShapeSample};
+use crate::ply;
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::raycast::{ray_triangle_barycentric, triangle_hit, triangle_normal, RayHit};
+use crate::stl;
+use crate::vector::{Point, Vector};
+
+use std::io;
+use std::path::Path;
+
+/// Corners of a mesh triangle as indices to the vertices of the mesh
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshTriangle {
+    pub vertices: [u32; 3],
+    /// Material of this triangle, the object's material when `None`
+    pub material_id: Option<usize>,
+}
+
+/// Triangles sharing vertices, with their own acceleration structure.
+/// Vertex attributes other than the position are either missing or given for every vertex.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub positions: Vec<Point>,
+    /// For smooth shading, triangles are flat shaded without
+    pub normals: Vec<Vector>,
+    pub uvs: Vec<[float; 2]>,
+    pub colors: Vec<Color>,
+    /// Direction of increasing u, with the sign of the bitangent as the fourth component
+    pub tangents: Vec<[float; 4]>,
+    pub triangles: Vec<MeshTriangle>,
+    /// Unit normal of each triangle, zero for degenerate ones which are never hit
+    face_normals: Vec<Vector>,
+    bvh: Bvh,
+    /// Running total of triangle areas, for sampling points by area
+    area_cdf: Vec<float>,
+}
+
+impl PartialEq for Mesh {
+    fn eq(&self, other: &Self) -> bool {
+        self.positions == other.positions
+            && self.normals == other.normals
+            && self.uvs == other.uvs
+            && self.colors == other.colors
+            && self.tangents == other.tangents
+            && self.triangles == other.triangles
+    }
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Point>,
+        normals: Vec<Vector>,
+        uvs: Vec<[float; 2]>,
+        triangles: Vec<MeshTriangle>,
+    ) -> Self {
+        let mut mesh = Self {
+            positions,
+            normals,
+            uvs,
+            colors: Vec::new(),
+            tangents: Vec::new(),
+            triangles,
+            face_normals: Vec::new(),
+            bvh: Bvh::default(),
+            area_cdf: Vec::new(),
+        };
+        let vertices = mesh.positions.len();
+        assert!(mesh.normals.is_empty() || mesh.normals.len() == vertices);
+        assert!(mesh.uvs.is_empty() || mesh.uvs.len() == vertices);
+        assert!(
+            mesh.triangles
+                .iter()
+                .all(|t| t.vertices.iter().all(|&i| (i as usize) < vertices)),
+            "Mesh triangle refers to a missing vertex"
+        );
+
+        let mut total = 0.0;
+        let mut bounds = Vec::with_capacity(mesh.triangles.len());
+        for i in 0..mesh.triangles.len() {
+            let corners = mesh.corners(i);
+            mesh.face_normals
+                .push(triangle_normal(corners).unwrap_or(Vector::ZERO));
+            total += (corners[1] - corners[0])
+                .cross(corners[2] - corners[0])
+                .len()
+                * 0.5;
+            mesh.area_cdf.push(total);
+            bounds.push(
+                Bounds::point(corners[0])
+                    .include(corners[1])
+                    .include(corners[2]),
+            );
+        }
+        mesh.bvh = Bvh::new(&bounds);
+        mesh
+    }
+
+    /// Polygons of an OBJ mesh scaled by `scale`, all with the material of the object.
+    /// With `quads_as_patches`, four sided faces become bilinear patches instead of triangles.
+    pub fn from_obj(mesh: &tobj::Mesh, scale: float, quads_as_patches: bool) -> ObjSurfaces {
+        let vector = |values: &[float], k: usize, scale: float| Vector {
+            x: values[3 * k] * scale,
+            y: values[3 * k + 1] * scale,
+            z: values[3 * k + 2] * scale,
+        };
+        let positions: Vec<Point> = (0..mesh.positions.len() / 3)
+            .map(|k| vector(&mesh.positions, k, scale))
+            .collect();
+        let normals: Vec<Vector> = (0..mesh.normals.len() / 3)
+            .map(|k| vector(&mesh.normals, k, 1.0))
+            .collect();
+        let uvs: Vec<[float; 2]> = (0..mesh.texcoords.len() / 2)
+            .map(|k| [mesh.texcoords[2 * k], mesh.texcoords[2 * k + 1]])
+            .collect();
+
+        // Faces are all triangles when their sizes are not given
+        let sizes: Vec<usize> = if mesh.num_face_indices.is_empty() {
+            vec![3; mesh.indices.len() / 3]
+        } else {
+            mesh.num_face_indices.iter().map(|&n| n as usize).collect()
+        };
+
+        let mut triangles = Vec::new();
+        let mut patches = Vec::new();
+        let mut warnings = 0;
+        let mut start = 0;
+        for size in sizes {
+            // Vertices share all attributes, so one index refers to each of them
+            let face = &mesh.indices[start..start + size];
+            start += size;
+
+            if size < 3 {
+                // Lines and points have no surface
+                warnings += 1;
+                continue;
+            }
+            if size == 4 && quads_as_patches {
+                // Around the quad, so the last corner is across from the first
+                let order = [face[0], face[1], face[3], face[2]];
+                patches.push(Shape::BilinearPatch {
+                    corners: order.map(|i| positions[i as usize]),
+                    uvs: if uvs.is_empty() {
+                        Shape::PATCH_UVS
+                    } else {
+                        order.map(|i| uvs[i as usize])
+                    },
+                });
+                continue;
+            }
+
+            let corners: Vec<Point> = face.iter().map(|&i| positions[i as usize]).collect();
+            let (polygon_triangles, simple) = triangulate(&corners);
+            if !simple {
+                warnings += 1;
+            }
+            for [a, b, c] in polygon_triangles {
+                triangles.push(MeshTriangle {
+                    vertices: [face[a], face[b], face[c]],
+                    material_id: None,
+                });
+            }
+        }
+
+        ObjSurfaces {
+            mesh: Self::new(positions, normals, uvs, triangles),
+            patches,
+            warnings,
+        }
+    }
+
+    /// Loads a PLY or STL file based on its extension, scaled by `scale`
+    pub fn load<P: AsRef<Path>>(path: P, scale: float) -> io::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match extension.as_deref() {
+            Some("ply") => ply::load(path, scale),
+            Some("stl") => stl::load(path, scale),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unknown mesh file extension",
+            )),
+        }
+    }
+
+    /// Adds a color for each vertex
+    pub fn with_colors(mut self, colors: Vec<Color>) -> Self {
+        assert_eq!(colors.len(), self.positions.len());
+        self.colors = colors;
+        self
+    }
+
+    /// Adds a tangent for each vertex
+    pub fn with_tangents(mut self, tangents: Vec<[float; 4]>) -> Self {
+        assert_eq!(tangents.len(), self.positions.len());
+        self.tangents = tangents;
+        self
+    }
+
+    /// Copy moved by `transform`, with the winding of the triangles reversed if `reverse_winding`
+    pub fn transformed(&self, transform: &Transform, reverse_winding: bool) -> Self {
+        let triangles = self
+            .triangles
+            .iter()
+            .map(|t| {
+                let [a, b, c] = t.vertices;
+                MeshTriangle {
+                    vertices: if reverse_winding {
+                        [a, c, b]
+                    } else {
+                        [a, b, c]
+                    },
+                    ..*t
+                }
+            })
+            .collect();
+        let mut mesh = Self::new(
+            self.positions.iter().map(|&p| transform.point(p)).collect(),
+            self.normals
+                .iter()
+                .map(|&n| transform.normal(n).normalized())
+                .collect(),
+            self.uvs.clone(),
+            triangles,
+        );
+        mesh.colors = self.colors.clone();
+        mesh.tangents = self
+            .tangents
+            .iter()
+            .map(|&[x, y, z, w]| {
+                let t = transform.vector(Vector { x, y, z });
+                [t.x, t.y, t.z, w]
+            })
+            .collect();
+        mesh
+    }
+
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    pub fn corners(&self, primitive: usize) -> [Point; 3] {
+        let [a, b, c] = self.triangles[primitive].vertices;
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+
+    /// Texture coordinates of the corners of a triangle, barycentric if it has none
+    pub fn corner_uvs(&self, primitive: usize) -> [[float; 2]; 3] {
+        if self.uvs.is_empty() {
+            return Shape::BARYCENTRIC_UVS;
+        }
+        let [a, b, c] = self.triangles[primitive].vertices;
+        [
+            self.uvs[a as usize],
+            self.uvs[b as usize],
+            self.uvs[c as usize],
+        ]
+    }
+
+    /// Closest hit, `primitive` of the hit is the index of the triangle.
+    /// Object is filled back later.
+    pub fn raycast(&self, ray: &Ray) -> Option<RayHit> {
+        let mut hit = self.bvh.raycast(ray, |i, ray| {
+            let normal = self.face_normals[i];
+            if normal == Vector::ZERO {
+                return None;
+            }
+            let corners = self.corners(i);
+            let (distance, barycentric) =
+                ray_triangle_barycentric(ray.origin, ray.direction, corners)?;
+            let mut hit = triangle_hit(
+                ray.direction,
+                distance,
+                barycentric,
+                corners,
+                self.corner_uvs(i),
+                normal,
+            );
+            hit.primitive = i;
+            hit.material_id = self.triangles[i].material_id;
+            if !self.colors.is_empty() {
+                let [a, b, c] = self.triangles[i].vertices;
+                hit.color = self.colors[a as usize] * barycentric[0]
+                    + self.colors[b as usize] * barycentric[1]
+                    + self.colors[c as usize] * barycentric[2];
+            }
+            if let Some(shading_normal) = self.shading_normal(i, barycentric) {
+                // Same side as the geometric normal, which already faces the ray
+                hit.shading_normal = if shading_normal.dot(hit.normal) < 0.0 {
+                    -shading_normal
+                } else {
+                    shading_normal
+                };
+            }
+            Some(hit)
+        })?;
+        hit.object = 0;
+        Some(hit)
+    }
+
+    /// Interpolated vertex normal, `None` for flat shaded triangles
+    fn shading_normal(&self, primitive: usize, barycentric: [float; 3]) -> Option<Vector> {
+        if self.normals.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.triangles[primitive].vertices;
+        let normal = self.normals[a as usize] * barycentric[0]
+            + self.normals[b as usize] * barycentric[1]
+            + self.normals[c as usize] * barycentric[2];
+        if normal.len2() > 0.0 {
+            Some(normal.normalized())
+        } else {
+            None
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self.bvh.bounds()
+    }
+
+    pub fn area(&self) -> float {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Uniformly distributed point on all of the triangles
+    pub fn sample(&self, u: [float; 2]) -> ShapeSample {
+        let target = u[0] * self.area();
+        let i = self
+            .area_cdf
+            .partition_point(|&total| total <= target)
+            .min(self.triangles.len() - 1);
+        let start = if i == 0 { 0.0 } else { self.area_cdf[i - 1] };
+        let remapped =
+            ((target - start) / (self.area_cdf[i] - start)).clamp(0.0, 1.0 - float::EPSILON);
+        Shape::Triangle {
+            corners: self.corners(i),
+            uvs: self.corner_uvs(i),
+        }
+        .sample([remapped, u[1]])
+    }
+}
+
+/// Surfaces of the polygons of an OBJ mesh
+#[derive(Debug, Clone)]
+pub struct ObjSurfaces {
+    pub mesh: Mesh,
+    /// Quads kept as bilinear patches
+    pub patches: Vec<Shape>,
+    /// Faces that were skipped or that were not simple polygons, whose triangles may overlap
+    pub warnings: usize,
+}
+
+/// Splits a polygon into triangles of its corner indices by ear clipping, in the same winding.
+/// Handles concave polygons, and also returns whether the polygon was simple; triangles of
+/// self-intersecting polygons may overlap.
+/// https://www.geometrictools.com/Documentation/TriangulationByEarClipping.pdf
+pub fn triangulate(points: &[Point]) -> (Vec<[usize; 3]>, bool) {
+    let n = points.len();
+    if n < 3 {
+        return (Vec::new(), false);
+    }
+    if n == 3 {
+        return (vec![[0, 1, 2]], true);
+    }
+
+    // Newell's normal is robust for nonplanar and concave polygons
+    let mut normal = Vector::ZERO;
+    for i in 0..n {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        normal = normal
+            + Vector {
+                x: (a.y - b.y) * (a.z + b.z),
+                y: (a.z - b.z) * (a.x + b.x),
+                z: (a.x - b.x) * (a.y + b.y),
+            };
+    }
+
+    // Project away the axis the polygon faces the most, keeping the polygon counterclockwise
+    let abs = normal.abs();
+    let axis = if abs.x > abs.y && abs.x > abs.z {
+        0
+    } else if abs.y > abs.z {
+        1
+    } else {
+        2
+    };
+    let flip = if normal[axis] < 0.0 { -1.0 } else { 1.0 };
+    let flat: Vec<[float; 2]> = points
+        .iter()
+        .map(|p| [p[(axis + 1) % 3], p[(axis + 2) % 3] * flip])
+        .collect();
+    let turn = |a: usize, b: usize, c: usize| {
+        let (a, b, c) = (flat[a], flat[b], flat[c]);
+        (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+    let mut simple = normal != Vector::ZERO;
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let corners = |i: usize| {
+            (
+                remaining[(i + m - 1) % m],
+                remaining[i],
+                remaining[(i + 1) % m],
+            )
+        };
+        let is_ear = |i: usize| {
+            let (a, b, c) = corners(i);
+            turn(a, b, c) > 0.0
+                && remaining.iter().all(|&p| {
+                    // Corners repeated elsewhere in the polygon do not block the ear
+                    [a, b, c].contains(&p)
+                        || [a, b, c].iter().any(|&q| flat[q] == flat[p])
+                        || turn(a, b, p) < 0.0
+                        || turn(b, c, p) < 0.0
+                        || turn(c, a, p) < 0.0
+                })
+        };
+
+        // A proper ear, else a vertex in a straight line which leaves a degenerate triangle,
+        // else the polygon is not simple and the most convex vertex is cut off anyway
+        let clip = (0..m)
+            .find(|&i| is_ear(i))
+            .or_else(|| {
+                (0..m).find(|&i| {
+                    let (a, b, c) = corners(i);
+                    turn(a, b, c) == 0.0
+                })
+            })
+            .unwrap_or_else(|| {
+                simple = false;
+                (0..m)
+                    .max_by(|&i, &j| {
+                        let (a, b, c) = corners(i);
+                        let (d, e, f) = corners(j);
+                        turn(a, b, c)
+                            .partial_cmp(&turn(d, e, f))
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                    })
+                    .unwrap_or(0)
+            });
+        let (a, b, c) = corners(clip);
+        triangles.push([a, b, c]);
+        remaining.remove(clip);
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    (triangles, simple)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Object;
+    use crate::raycast::raycast;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    /// Square grid of `n` by `n` quads in the xz plane, bent up along x
+    fn grid(n: u32) -> (Vec<Point>, Vec<MeshTriangle>) {
+        let mut positions = Vec::new();
+        for i in 0..=n {
+            for j in 0..=n {
+                let (x, z) = (i as float / n as float, j as float / n as float);
+                positions.push(v(x, x * x, z));
+            }
+        }
+        let mut triangles = Vec::new();
+        let at = |i: u32, j: u32| i * (n + 1) + j;
+        for i in 0..n {
+            for j in 0..n {
+                for &vertices in [
+                    [at(i, j), at(i, j + 1), at(i + 1, j + 1)],
+                    [at(i, j), at(i + 1, j + 1), at(i + 1, j)],
+                ]
+                .iter()
+                {
+                    triangles.push(MeshTriangle {
+                        vertices,
+                        material_id: Some((i % 2) as usize),
+                    });
+                }
+            }
+        }
+        (positions, triangles)
+    }
+
+    #[test]
+    fn matches_separate_triangles() {
+        let (positions, triangles) = grid(8);
+        let mesh = Mesh::new(positions, Vec::new(), Vec::new(), triangles);
+        let separate: Vec<Object> = (0..mesh.len())
+            .map(|i| Object {
+                shape: Shape::Triangle {
+                    corners: mesh.corners(i),
+                    uvs: Shape::BARYCENTRIC_UVS,
+                },
+                material_id: mesh.triangles[i].material_id,
+            })
+            .collect();
+        let total: float = separate.iter().map(|o| o.shape.area()).sum();
+        assert!(approx_eq(mesh.area(), total));
+
+        for i in 0..100 {
+            let from = v(
+                (i % 10) as float / 9.0 * 1.2 - 0.1,
+                3.0,
+                (i / 10) as float / 9.0 * 1.2 - 0.1,
+            );
+            let ray = Ray::new(from, v(0.1, -1.0, 0.05));
+            let (a, b) = (mesh.raycast(&ray), raycast(&ray, &separate));
+            assert_eq!(a.is_some(), b.is_some(), "{}", i);
+            if let (Some(a), Some(b)) = (a, b) {
+                assert_eq!(a.primitive, b.object);
+                assert_eq!(a.material_id, b.material_id);
+                assert!((a.distance - b.distance).abs() < 0.0001);
+                assert!((a.normal - b.normal).len() < 0.0001);
+            }
+        }
+
+        // Indices and face normals take less than one triangle object per face
+        let per_face = std::mem::size_of::<MeshTriangle>() + std::mem::size_of::<Vector>();
+        assert!(per_face * 3 < std::mem::size_of::<Object>());
+    }
+
+    #[test]
+    fn interpolates_vertex_normals() {
+        let up = v(0.0, 1.0, 0.0);
+        let tilted = v(1.0, 1.0, 0.0).normalized();
+        let mesh = Mesh::new(
+            vec![v(0.0, 0.0, 0.0), v(0.0, 0.0, 1.0), v(1.0, 0.0, 0.0)],
+            vec![up, up, tilted],
+            Vec::new(),
+            vec![MeshTriangle {
+                vertices: [0, 1, 2],
+                material_id: None,
+            }],
+        );
+        let from_above = mesh
+            .raycast(&Ray::new(v(0.5, 1.0, 0.25), v(0.0, -1.0, 0.0)))
+            .unwrap();
+        assert_eq!(from_above.normal, up);
+        let expected = (up * 0.5 + tilted * 0.5).normalized();
+        assert!((from_above.shading_normal - expected).len() < 0.001);
+
+        // Flipped together with the geometric normal
+        let from_below = mesh
+            .raycast(&Ray::new(v(0.5, -1.0, 0.25), v(0.0, 1.0, 0.0)))
+            .unwrap();
+        assert!(!from_below.front_face);
+        assert!((from_below.shading_normal + expected).len() < 0.001);
+    }
+
+    #[test]
+    fn triangulates_concave_polygons() {
+        // Rectangle with a notch in the bottom, in a tilted plane
+        let points: Vec<Point> = [(0.0, 0.0), (2.0, 1.0), (4.0, 0.0), (4.0, 3.0), (0.0, 3.0)]
+            .iter()
+            .map(|&(x, y)| v(x, y * 0.6, y * 0.8))
+            .collect();
+        let (triangles, simple) = triangulate(&points);
+        assert!(simple);
+        assert_eq!(triangles.len(), 3);
+        let facing = v(0.0, -0.8, 0.6);
+        let mut area = 0.0;
+        for &[a, b, c] in triangles.iter() {
+            let cross = (points[b] - points[a]).cross(points[c] - points[a]);
+            // Same winding as the polygon
+            assert!(cross.dot(facing) > 0.0);
+            area += cross.len() * 0.5;
+        }
+        assert!(approx_eq(area, 10.0));
+
+        // Bow tie crosses itself
+        let bow_tie = [
+            v(0.0, 0.0, 0.0),
+            v(1.0, 1.0, 0.0),
+            v(1.0, 0.0, 0.0),
+            v(0.0, 1.0, 0.0),
+        ];
+        let (triangles, simple) = triangulate(&bow_tie);
+        assert_eq!(triangles.len(), 2);
+        assert!(!simple);
+        assert!(!triangulate(&bow_tie[..2]).1);
+    }
+
+    #[test]
+    fn polygons_from_obj() {
+        let mut mesh = tobj::Mesh::empty();
+        // Square, the square with a notch in the top, and a line
+        mesh.positions = vec![
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.5, 0.5, 0.0,
+        ];
+        mesh.indices = vec![0, 1, 2, 3, 0, 1, 2, 4, 3, 1, 2];
+        mesh.num_face_indices = vec![4, 5, 2];
+
+        let surfaces = Mesh::from_obj(&mesh, 2.0, false);
+        assert_eq!(surfaces.warnings, 1);
+        assert!(surfaces.patches.is_empty());
+        assert_eq!(surfaces.mesh.len(), 2 + 3);
+        assert!(approx_eq(surfaces.mesh.area(), 4.0 * 1.75));
+
+        let surfaces = Mesh::from_obj(&mesh, 1.0, true);
+        assert_eq!(surfaces.patches.len(), 1);
+        assert_eq!(surfaces.mesh.len(), 3);
+        let total = surfaces.mesh.area() + surfaces.patches[0].area();
+        assert!(approx_eq(total, 1.75));
+    }
+}
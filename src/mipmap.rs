@@ -0,0 +1,416 @@
+use crate::color::Color;
+use crate::prelude::*;
+
+use std::path::Path;
+
+/// Behavior of lookups outside of the 0..1 texture coordinate range
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+}
+
+/// Reconstruction filter used for texture lookups
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// Single texel of the finest level, aliases at a distance
+    Nearest,
+    /// Bilinear interpolation of the finest level
+    Bilinear,
+    /// Isotropic filter, blends bilinear lookups of the two closest levels
+    Trilinear,
+    /// Elliptically weighted average, follows the exact footprint shape.
+    /// Eccentricity of the footprint ellipse is limited to `max_anisotropy`.
+    Ewa { max_anisotropy: float },
+}
+
+/// Single level of a mipmap
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// Row-major, top row first
+    pub pixels: Vec<Color>,
+}
+
+impl Image {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, image::ImageError> {
+        Ok(Self::from_rgb(image::open(path)?))
+    }
+
+    /// Image from the contents of a file in any format the `image` crate supports
+    pub fn decode(bytes: &[u8]) -> Result<Self, image::ImageError> {
+        Ok(Self::from_rgb(image::load_from_memory(bytes)?))
+    }
+
+    fn from_rgb(image: image::DynamicImage) -> Self {
+        let rgb = image.to_rgb8();
+        let (width, height) = rgb.dimensions();
+        let pixels = rgb
+            .pixels()
+            .map(|p| Color {
+                r: p[0] as float / 255.0,
+                g: p[1] as float / 255.0,
+                b: p[2] as float / 255.0,
+            })
+            .collect();
+
+        Self {
+            width: width as usize,
+            height: height as usize,
+            pixels,
+        }
+    }
+
+    /// Texel at integer coordinates
+    pub fn texel(&self, x: i64, y: i64, wrap: Wrap) -> Color {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let (x, y) = match wrap {
+            Wrap::Repeat => (x.rem_euclid(w), y.rem_euclid(h)),
+            Wrap::Clamp => (x.clamp(0, w - 1), y.clamp(0, h - 1)),
+        };
+        self.pixels[(y * w + x) as usize]
+    }
+
+    /// Bilinear interpolation of the four closest texels,
+    /// with `st` in 0..1 spanning the whole image
+    pub fn bilinear(&self, st: [float; 2], wrap: Wrap) -> Color {
+        let s = st[0] * self.width as float - 0.5;
+        let t = st[1] * self.height as float - 0.5;
+        let (s0, t0) = (s.floor(), t.floor());
+        let (ds, dt) = (s - s0, t - t0);
+        let (x, y) = (s0 as i64, t0 as i64);
+
+        self.texel(x, y, wrap) * ((1.0 - ds) * (1.0 - dt))
+            + self.texel(x + 1, y, wrap) * (ds * (1.0 - dt))
+            + self.texel(x, y + 1, wrap) * ((1.0 - ds) * dt)
+            + self.texel(x + 1, y + 1, wrap) * (ds * dt)
+    }
+
+    /// Half resolution copy using a 2x2 box filter
+    fn downsample(&self) -> Self {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = Color::BLACK;
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                    let sx = (2 * x + dx).min(self.width - 1);
+                    let sy = (2 * y + dy).min(self.height - 1);
+                    sum = sum + self.pixels[sy * self.width + sx];
+                }
+                pixels.push(sum / 4.0);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            pixels,
+        }
+    }
+}
+
+/// Width of the gaussian used by EWA filtering
+const EWA_ALPHA: float = 2.0;
+
+/// Image pyramid, each level half the resolution of the previous one
+/// https://www.pbr-book.org/3ed-2018/Texture/Image_Texture
+#[derive(Debug, Clone)]
+pub struct MipMap {
+    /// Finest level first, down to a single texel
+    levels: Vec<Image>,
+    wrap: Wrap,
+}
+
+impl MipMap {
+    pub fn new(image: Image, wrap: Wrap) -> Self {
+        assert!(image.width > 0 && image.height > 0);
+        assert_eq!(image.pixels.len(), image.width * image.height);
+
+        let mut levels = vec![image];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width == 1 && last.height == 1 {
+                break;
+            }
+            let next = last.downsample();
+            levels.push(next);
+        }
+
+        Self { levels, wrap }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, wrap: Wrap) -> Result<Self, image::ImageError> {
+        Ok(Self::new(Image::load(path)?, wrap))
+    }
+
+    pub fn levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    /// Texel at integer coordinates, wrapped according to the wrap mode
+    pub fn texel(&self, level: usize, x: i64, y: i64) -> Color {
+        self.level(level).texel(x, y, self.wrap)
+    }
+
+    /// Closest texel of the given level
+    pub fn nearest(&self, level: usize, st: [float; 2]) -> Color {
+        let image = self.level(level);
+        let x = (st[0] * image.width as float).floor() as i64;
+        let y = (st[1] * image.height as float).floor() as i64;
+        self.texel(level, x, y)
+    }
+
+    /// Bilinear interpolation of the four closest texels of the given level
+    pub fn bilinear(&self, level: usize, st: [float; 2]) -> Color {
+        self.level(level).bilinear(st, self.wrap)
+    }
+
+    fn level(&self, level: usize) -> &Image {
+        &self.levels[level.min(self.levels.len() - 1)]
+    }
+
+    /// Isotropic lookup of a square footprint `width` wide in texture coordinates
+    pub fn trilinear(&self, st: [float; 2], width: float) -> Color {
+        let level = self.lod(width);
+        if level <= 0.0 {
+            self.bilinear(0, st)
+        } else if level >= (self.levels.len() - 1) as float {
+            self.texel(self.levels.len() - 1, 0, 0)
+        } else {
+            let base = level.floor();
+            let d = level - base;
+            let base = base as usize;
+            self.bilinear(base, st) * (1.0 - d) + self.bilinear(base + 1, st) * d
+        }
+    }
+
+    /// Anisotropic lookup of the ellipse with axes `dst0` and `dst1` in texture coordinates
+    pub fn ewa(
+        &self,
+        st: [float; 2],
+        mut dst0: [float; 2],
+        mut dst1: [float; 2],
+        max_anisotropy: float,
+    ) -> Color {
+        let len2 = |v: [float; 2]| v[0] * v[0] + v[1] * v[1];
+
+        if len2(dst0) < len2(dst1) {
+            std::mem::swap(&mut dst0, &mut dst1);
+        }
+        let major = len2(dst0).sqrt();
+        let mut minor = len2(dst1).sqrt();
+
+        // Clamp the eccentricity, trading some blur for a bounded number of texels
+        if minor * max_anisotropy < major && minor > 0.0 {
+            let scale = major / (minor * max_anisotropy);
+            dst1 = [dst1[0] * scale, dst1[1] * scale];
+            minor *= scale;
+        }
+
+        if minor == 0.0 {
+            return self.bilinear(0, st);
+        }
+
+        let level = self.lod(minor).max(0.0);
+        let base = level.floor();
+        let d = level - base;
+        let base = base as usize;
+        self.ewa_level(base, st, dst0, dst1) * (1.0 - d)
+            + self.ewa_level(base + 1, st, dst0, dst1) * d
+    }
+
+    /// Level of detail for a footprint `width` wide in texture coordinates
+    fn lod(&self, width: float) -> float {
+        (self.levels.len() - 1) as float + width.max(1e-8).log2()
+    }
+
+    fn ewa_level(&self, level: usize, st: [float; 2], dst0: [float; 2], dst1: [float; 2]) -> Color {
+        if level >= self.levels.len() {
+            return self.texel(self.levels.len() - 1, 0, 0);
+        }
+
+        let image = &self.levels[level];
+        let (w, h) = (image.width as float, image.height as float);
+
+        // Convert to texel coordinates of this level
+        let s = st[0] * w - 0.5;
+        let t = st[1] * h - 0.5;
+        let dst0 = [dst0[0] * w, dst0[1] * h];
+        let dst1 = [dst1[0] * w, dst1[1] * h];
+
+        // Implicit ellipse equation coefficients
+        let mut a = dst0[1] * dst0[1] + dst1[1] * dst1[1] + 1.0;
+        let mut b = -2.0 * (dst0[0] * dst0[1] + dst1[0] * dst1[1]);
+        let mut c = dst0[0] * dst0[0] + dst1[0] * dst1[0] + 1.0;
+        let inv_f = 1.0 / (a * c - b * b * 0.25);
+        a *= inv_f;
+        b *= inv_f;
+        c *= inv_f;
+
+        // Bounding box of the ellipse
+        let det = -b * b + 4.0 * a * c;
+        let inv_det = 1.0 / det;
+        let u_sqrt = (det * c).sqrt();
+        let v_sqrt = (a * det).sqrt();
+        let s0 = (s - 2.0 * inv_det * u_sqrt).ceil() as i64;
+        let s1 = (s + 2.0 * inv_det * u_sqrt).floor() as i64;
+        let t0 = (t - 2.0 * inv_det * v_sqrt).ceil() as i64;
+        let t1 = (t + 2.0 * inv_det * v_sqrt).floor() as i64;
+
+        let mut sum = Color::BLACK;
+        let mut weight_sum = 0.0;
+        for it in t0..=t1 {
+            let tt = it as float - t;
+            for is in s0..=s1 {
+                let ss = is as float - s;
+                let r2 = a * ss * ss + b * ss * tt + c * tt * tt;
+                if r2 < 1.0 {
+                    let weight = (-EWA_ALPHA * r2).exp() - (-EWA_ALPHA).exp();
+                    sum = sum + self.texel(level, is, it) * weight;
+                    weight_sum += weight;
+                }
+            }
+        }
+
+        if weight_sum > 0.0 {
+            sum / weight_sum
+        } else {
+            self.bilinear(level, st)
+        }
+    }
+
+    /// Filtered lookup with the footprint given by the texture coordinate derivatives
+    pub fn lookup(
+        &self,
+        filter: Filter,
+        st: [float; 2],
+        dst0: [float; 2],
+        dst1: [float; 2],
+    ) -> Color {
+        match filter {
+            Filter::Nearest => self.nearest(0, st),
+            Filter::Bilinear => self.bilinear(0, st),
+            Filter::Trilinear => {
+                let width = 2.0
+                    * dst0[0]
+                        .abs()
+                        .max(dst0[1].abs())
+                        .max(dst1[0].abs())
+                        .max(dst1[1].abs());
+                self.trilinear(st, width)
+            }
+            Filter::Ewa { max_anisotropy } => self.ewa(st, dst0, dst1, max_anisotropy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One pixel wide black and white stripes
+    fn stripes(size: usize) -> Image {
+        let pixels = (0..size * size)
+            .map(|i| {
+                if i % 2 == 0 {
+                    Color::WHITE
+                } else {
+                    Color::BLACK
+                }
+            })
+            .collect();
+        Image {
+            width: size,
+            height: size,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn pyramid_down_to_one_texel() {
+        let mipmap = MipMap::new(stripes(16), Wrap::Repeat);
+        assert_eq!(mipmap.levels(), 5);
+
+        let mipmap = MipMap::new(
+            Image {
+                width: 5,
+                height: 2,
+                pixels: vec![Color::WHITE; 10],
+            },
+            Wrap::Clamp,
+        );
+        assert_eq!(mipmap.levels(), 3);
+        assert_eq!(mipmap.texel(2, 0, 0), Color::WHITE);
+    }
+
+    #[test]
+    fn wide_footprint_averages() {
+        let mipmap = MipMap::new(stripes(64), Wrap::Repeat);
+        let st = [0.3, 0.7];
+
+        // Point lookup sees a single stripe
+        let c = mipmap.lookup(Filter::Nearest, st, [0.0, 0.0], [0.0, 0.0]);
+        assert!(c == Color::WHITE || c == Color::BLACK);
+
+        for &filter in [
+            Filter::Trilinear,
+            Filter::Ewa {
+                max_anisotropy: 8.0,
+            },
+        ]
+        .iter()
+        {
+            let c = mipmap.lookup(filter, st, [0.1, 0.0], [0.0, 0.1]);
+            assert!((c.r - 0.5).abs() < 0.05, "{:?}: {:?}", filter, c);
+        }
+    }
+
+    #[test]
+    fn anisotropic_footprint_keeps_detail() {
+        // Horizontal stripes, varying only along t
+        let size = 64;
+        let pixels = (0..size * size)
+            .map(|i| {
+                if (i / size) % 8 < 4 {
+                    Color::WHITE
+                } else {
+                    Color::BLACK
+                }
+            })
+            .collect();
+        let image = Image {
+            width: size,
+            height: size,
+            pixels,
+        };
+        let mipmap = MipMap::new(image, Wrap::Repeat);
+
+        // Footprint long along the stripes, narrow across them
+        let st = [0.5, 1.0 / 64.0];
+        let dst0 = [0.25, 0.0];
+        let dst1 = [0.0, 0.5 / 64.0];
+
+        let ewa = mipmap.lookup(
+            Filter::Ewa {
+                max_anisotropy: 64.0,
+            },
+            st,
+            dst0,
+            dst1,
+        );
+        let trilinear = mipmap.lookup(Filter::Trilinear, st, dst0, dst1);
+        assert!(ewa.r > 0.9, "{:?}", ewa);
+        assert!(trilinear.r < ewa.r, "{:?}", trilinear);
+    }
+}
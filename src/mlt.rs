@@ -0,0 +1,412 @@
+//! Primary sample space Metropolis light transport, which mutates the random numbers a path
+//! tracer consumes so that Markov chains linger on paths carrying much light, such as light
+//! reaching the camera through a narrow gap.
+//! https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Metropolis_Light_Transport
+
+use crate::bdpt::Splats;
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::material::{scatter_glass, Ior};
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::sampling::{self, AliasTable, ONE_MINUS_EPSILON};
+use crate::scene::Scene;
+use crate::texture::SurfacePoint;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::StandardNormal;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+/// Settings of the Metropolis integrator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metropolis {
+    /// Independent paths estimating the brightness of the image, and the chains start from
+    pub bootstrap_samples: usize,
+    /// Markov chains run in parallel
+    pub chains: usize,
+    /// Mutations of all chains together, per pixel
+    pub mutations_per_pixel: usize,
+    /// Standard deviation of a small step mutation of one random number
+    pub sigma: float,
+    /// Probability of a mutation replacing all random numbers, so chains do not get stuck
+    pub large_step_probability: float,
+}
+
+impl Default for Metropolis {
+    fn default() -> Self {
+        Self {
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            mutations_per_pixel: 100,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+        }
+    }
+}
+
+impl Metropolis {
+    /// Renders the image seen by `camera`, with paths of up to `max_depth` bounces, as rows of
+    /// pixels from the top left corner
+    pub fn render(&self, scene: &Scene, camera: &Camera, max_depth: usize) -> Vec<Color> {
+        let pixels = (camera.width * camera.height) as usize;
+        let seed: u64 = sampling::random();
+        let new_sampler = |index: usize| {
+            MltSampler::new(
+                seed.wrapping_add(index as u64),
+                self.sigma,
+                self.large_step_probability,
+            )
+        };
+
+        // Mean contribution of independent paths normalizes the image, and chains start from
+        // paths chosen in proportion to it so that they need no burn-in
+        let contributions: Vec<float> = (0..self.bootstrap_samples.max(1))
+            .into_par_iter()
+            .map(|index| {
+                let (radiance, _) = path(scene, camera, &mut new_sampler(index), max_depth);
+                contribution(radiance)
+            })
+            .collect();
+        let brightness = contributions.iter().sum::<float>() / contributions.len() as float;
+        if brightness.is_nan() || brightness <= 0.0 {
+            return vec![Color::BLACK; pixels];
+        }
+        let starts = AliasTable::new(&contributions);
+
+        let chains = self.chains.max(1);
+        let mutations_per_chain = (self.mutations_per_pixel * pixels).div_ceil(chains);
+        let splats = Splats::new(camera.width, camera.height);
+        let chain_seed: u64 = sampling::random();
+        (0..chains).into_par_iter().for_each(|chain| {
+            sampling::seed(chain_seed.wrapping_add(chain as u64));
+            let (index, _, _) = starts.sample(sampling::random());
+            let mut sampler = new_sampler(index);
+            let (mut current, mut current_raster) = path(scene, camera, &mut sampler, max_depth);
+            for _ in 0..mutations_per_chain {
+                sampler.start_iteration();
+                let (proposed, raster) = path(scene, camera, &mut sampler, max_depth);
+
+                // Both paths are recorded, weighted by how likely the chain moves to each
+                let accept = match contribution(current) {
+                    c if c > 0.0 => (contribution(proposed) / c).min(1.0),
+                    _ => 1.0,
+                };
+                if accept > 0.0 {
+                    let weight = accept / contribution(proposed);
+                    splats.add(raster.0, raster.1, proposed * weight);
+                }
+                if accept < 1.0 {
+                    let weight = (1.0 - accept) / contribution(current);
+                    splats.add(current_raster.0, current_raster.1, current * weight);
+                }
+
+                if sampling::random::<float>() < accept {
+                    current = proposed;
+                    current_raster = raster;
+                    sampler.accept();
+                } else {
+                    sampler.reject();
+                }
+            }
+        });
+
+        // Chains visit pixels in proportion to their contribution over the brightness
+        let scale = brightness * pixels as float / (mutations_per_chain * chains) as float;
+        (0..pixels as u32)
+            .map(|i| splats.get(i % camera.width, i / camera.width) * scale)
+            .collect()
+    }
+}
+
+/// How much the chains favour a path carrying `radiance`
+fn contribution(radiance: Color) -> float {
+    radiance.luminance().max(0.0)
+}
+
+/// Random number in the unit hypercube that the chain mutates, lazily as it is used
+#[derive(Debug, Clone, Copy, Default)]
+struct PrimarySample {
+    value: float,
+    /// Iteration the value was last mutated at
+    last_modification: usize,
+    value_backup: float,
+    modification_backup: usize,
+}
+
+impl PrimarySample {
+    fn backup(&mut self) {
+        self.value_backup = self.value;
+        self.modification_backup = self.last_modification;
+    }
+
+    fn restore(&mut self) {
+        self.value = self.value_backup;
+        self.last_modification = self.modification_backup;
+    }
+}
+
+/// Random numbers of one path, where each iteration either perturbs them slightly or replaces
+/// them all, and rejecting the proposed path restores the previous ones
+#[derive(Debug, Clone)]
+struct MltSampler {
+    rng: StdRng,
+    sigma: float,
+    large_step_probability: float,
+    samples: Vec<PrimarySample>,
+    iteration: usize,
+    large_step: bool,
+    last_large_step: usize,
+    /// Next random number of the current path
+    index: usize,
+}
+
+impl MltSampler {
+    /// Sampler whose first path uses independent uniform random numbers, determined by `seed`
+    fn new(seed: u64, sigma: float, large_step_probability: float) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            sigma,
+            large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        }
+    }
+
+    /// Starts proposing a new path
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<float>() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn next(&mut self) -> float {
+        if self.index == self.samples.len() {
+            // Uniform like the values of the last large step would have been
+            self.samples.push(PrimarySample {
+                value: self.rng.gen(),
+                last_modification: self.last_large_step,
+                ..PrimarySample::default()
+            });
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+
+        // Catch up with the last large step, which only replaced the values used back then
+        if sample.last_modification < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modification = self.last_large_step;
+        }
+
+        sample.backup();
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // Small steps since the last use add up to one with a wider normal distribution
+            let steps = (self.iteration - sample.last_modification) as float;
+            let normal: float = self.rng.sample(StandardNormal);
+            let value = sample.value + normal * self.sigma * steps.sqrt();
+            sample.value = (value - value.floor()).min(ONE_MINUS_EPSILON);
+        }
+        sample.last_modification = self.iteration;
+        sample.value
+    }
+
+    fn next2(&mut self) -> [float; 2] {
+        [self.next(), self.next()]
+    }
+
+    fn next3(&mut self) -> [float; 3] {
+        [self.next(), self.next(), self.next()]
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        let iteration = self.iteration;
+        for sample in self.samples.iter_mut() {
+            if sample.last_modification == iteration {
+                sample.restore();
+            }
+        }
+        self.iteration -= 1;
+    }
+}
+
+/// Radiance along a path traced with the random numbers of `sampler`, sampling the lights at
+/// diffuse bounces, and the film position it passes through.
+/// Every bounce uses the same number of random numbers, so that a mutation of one changes the
+/// same decision on the proposed path.
+fn path(
+    scene: &Scene,
+    camera: &Camera,
+    sampler: &mut MltSampler,
+    max_depth: usize,
+) -> (Color, (float, float)) {
+    let [u, v] = sampler.next2();
+    let raster = (u * camera.width as float, v * camera.height as float);
+    let (origin, direction, _) = camera.ray(raster.0, raster.1);
+    let mut ray = Ray::new(origin, direction);
+    let mut beta = Color::WHITE;
+    let mut radiance = Color::BLACK;
+    // Emitters hit after a diffuse bounce were sampled already
+    let mut specular_bounce = true;
+
+    for _ in 0..max_depth {
+        let lobe = sampler.next();
+        let light = sampler.next3();
+        let environment = sampler.next2();
+        let sun = sampler.next2();
+        let bounce = sampler.next2();
+
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => {
+                if specular_bounce {
+                    radiance = radiance + beta * scene.environment.radiance(ray.direction);
+                    if let Some(sun) = scene.sun {
+                        radiance = radiance + beta * sun.radiance(ray.direction);
+                    }
+                }
+                break;
+            }
+        };
+        let surface = SurfacePoint {
+            point: hit.point,
+            normal: hit.shading_normal,
+            uv: hit.uv,
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: hit.color,
+        };
+        let material = scene.material(&hit);
+        if hit.front_face && specular_bounce {
+            if let Some(material) = material {
+                radiance = radiance + beta * material.ambient.color(&surface);
+            }
+        }
+
+        // Glass reflects or refracts by the Fresnel reflectance, chosen by the lobe number
+        if let Some(ior) = material.and_then(|material| material.glass) {
+            let n = ior.at(Ior::REFERENCE);
+            let eta = if hit.front_face { n } else { 1.0 / n };
+            let (direction, _) = scatter_glass(ray.direction, hit.shading_normal, eta, lobe);
+            specular_bounce = true;
+            ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+            continue;
+        }
+
+        let (diffuse, specular) = match material {
+            Some(material) => (
+                material.diffuse.color(&surface),
+                material.specular.color(&surface),
+            ),
+            None => (Color::WHITE, Color::BLACK),
+        };
+        let diffuse_weight = diffuse.luminance();
+        let specular_weight = specular.luminance();
+        if diffuse_weight + specular_weight <= 0.0 {
+            break;
+        }
+        let p_specular = specular_weight / (diffuse_weight + specular_weight);
+        let direction = if lobe < p_specular {
+            beta = beta * specular / p_specular;
+            specular_bounce = true;
+            ray.direction.reflect(hit.shading_normal)
+        } else {
+            beta = beta * diffuse / (1.0 - p_specular);
+            specular_bounce = false;
+
+            if let Some(light) = scene.lights.sample(hit.point, hit.shading_normal, light) {
+                let cos = light.direction.dot(hit.shading_normal);
+                let shadow = Ray::spawn_to(hit.point, hit.error, hit.normal, light.point);
+                if cos > 0.0 && scene.raycast(&shadow).is_none() {
+                    radiance = radiance + beta * light.radiance * (cos / PI / light.pdf);
+                }
+            }
+            let distant = [
+                scene.environment.sample(environment),
+                scene.sun.map(|s| s.sample(sun)),
+            ];
+            for light in distant.iter().flatten() {
+                let cos = light.direction.dot(hit.shading_normal);
+                let shadow = Ray::spawn(hit.point, hit.error, hit.normal, light.direction);
+                if cos > 0.0 && scene.raycast(&shadow).is_none() {
+                    radiance = radiance + beta * light.radiance * (cos / PI / light.pdf);
+                }
+            }
+
+            sampling::cosine_hemisphere(hit.shading_normal, bounce).0
+        };
+        ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+    }
+
+    let radiance = if radiance.luminance().is_finite() {
+        radiance
+    } else {
+        Color::BLACK
+    };
+    (radiance, raster)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::test_scenes::{assert_furnace, furnace};
+
+    #[test]
+    fn rejected_mutations_restore_the_random_numbers() {
+        let mut sampler = MltSampler::new(7, 0.01, 0.3);
+        let first: Vec<float> = (0..5).map(|_| sampler.next()).collect();
+        assert!(first.iter().all(|&u| (0.0..1.0).contains(&u)));
+
+        for _ in 0..100 {
+            sampler.start_iteration();
+            let proposed: Vec<float> = (0..5).map(|_| sampler.next()).collect();
+            assert!(proposed.iter().all(|&u| (0.0..1.0).contains(&u)));
+            if !sampler.large_step {
+                // Small steps stay close, up to wrapping around
+                for (a, b) in first.iter().zip(proposed.iter()) {
+                    let distance = (a - b).abs();
+                    assert!(distance.min(1.0 - distance) < 0.1);
+                }
+            }
+            sampler.reject();
+        }
+
+        // Every proposal was rejected
+        let restored: Vec<float> = sampler.samples.iter().map(|s| s.value).collect();
+        assert_eq!(restored, first);
+    }
+
+    #[test]
+    fn furnace_matches_geometric_series() {
+        sampling::seed(1);
+        let emission = Color {
+            r: 2.0,
+            g: 1.0,
+            b: 0.5,
+        };
+        let (scene, camera) = furnace(emission, 0.5);
+        let metropolis = Metropolis {
+            bootstrap_samples: 20_000,
+            chains: 64,
+            mutations_per_pixel: 200,
+            ..Metropolis::default()
+        };
+        let image = metropolis.render(&scene, &camera, 2);
+
+        let mean = image.iter().fold(Color::BLACK, |sum, &c| sum + c) / image.len() as float;
+        assert_furnace(mean, emission, 0.5, 2, 0.05);
+        // Every pixel is visited
+        assert!(image.iter().all(|c| c.luminance() > 0.0));
+    }
+}
@@ -0,0 +1,346 @@
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+/// Ken Perlin's reference permutation
+/// https://mrl.cs.nyu.edu/~perlin/noise/
+const PERMUTATION: [u8; 256] = [
+    151, 160, 137, 91, 90, 15, 131, 13, 201, 95, 96, 53, 194, 233, 7, 225, 140, 36, 103, 30, 69,
+    142, 8, 99, 37, 240, 21, 10, 23, 190, 6, 148, 247, 120, 234, 75, 0, 26, 197, 62, 94, 252, 219,
+    203, 117, 35, 11, 32, 57, 177, 33, 88, 237, 149, 56, 87, 174, 20, 125, 136, 171, 168, 68, 175,
+    74, 165, 71, 134, 139, 48, 27, 166, 77, 146, 158, 231, 83, 111, 229, 122, 60, 211, 133, 230,
+    220, 105, 92, 41, 55, 46, 245, 40, 244, 102, 143, 54, 65, 25, 63, 161, 1, 216, 80, 73, 209, 76,
+    132, 187, 208, 89, 18, 169, 200, 196, 135, 130, 116, 188, 159, 86, 164, 100, 109, 198, 173,
+    186, 3, 64, 52, 217, 226, 250, 124, 123, 5, 202, 38, 147, 118, 126, 255, 82, 85, 212, 207, 206,
+    59, 227, 47, 16, 58, 17, 182, 189, 28, 42, 223, 183, 170, 213, 119, 248, 152, 2, 44, 154, 163,
+    70, 221, 153, 101, 155, 167, 43, 172, 9, 129, 22, 39, 253, 19, 98, 108, 110, 79, 113, 224, 232,
+    178, 185, 112, 104, 218, 246, 97, 228, 251, 34, 242, 193, 238, 210, 144, 12, 191, 179, 162,
+    241, 81, 51, 145, 235, 249, 14, 239, 107, 49, 192, 214, 31, 181, 199, 106, 157, 184, 84, 204,
+    176, 115, 121, 50, 45, 127, 4, 150, 254, 138, 236, 205, 93, 222, 114, 67, 29, 24, 72, 243, 141,
+    128, 195, 78, 66, 215, 61, 156, 180,
+];
+
+/// Edge midpoints of a cube, used as simplex gradients
+const GRAD3: [[float; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+fn perm(i: i32) -> i32 {
+    PERMUTATION[(i & 255) as usize] as i32
+}
+
+/// Hash of an integer lattice point, in range 0..256
+fn hash(x: i32, y: i32, z: i32) -> i32 {
+    perm(perm(perm(x) + y) + z)
+}
+
+fn fade(t: float) -> float {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: float, a: float, b: float) -> float {
+    a + t * (b - a)
+}
+
+fn grad(hash: i32, x: float, y: float, z: float) -> float {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Improved Perlin noise, roughly in range -1..1 and zero on integer lattice points
+/// https://mrl.cs.nyu.edu/~perlin/noise/
+pub fn perlin(p: Point) -> float {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (fx as i32, fy as i32, fz as i32);
+    let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let aa = hash(xi, yi, zi);
+    let ab = hash(xi, yi + 1, zi);
+    let ba = hash(xi + 1, yi, zi);
+    let bb = hash(xi + 1, yi + 1, zi);
+    let aa1 = hash(xi, yi, zi + 1);
+    let ab1 = hash(xi, yi + 1, zi + 1);
+    let ba1 = hash(xi + 1, yi, zi + 1);
+    let bb1 = hash(xi + 1, yi + 1, zi + 1);
+
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(aa, x, y, z), grad(ba, x - 1.0, y, z)),
+            lerp(u, grad(ab, x, y - 1.0, z), grad(bb, x - 1.0, y - 1.0, z)),
+        ),
+        lerp(
+            v,
+            lerp(u, grad(aa1, x, y, z - 1.0), grad(ba1, x - 1.0, y, z - 1.0)),
+            lerp(
+                u,
+                grad(ab1, x, y - 1.0, z - 1.0),
+                grad(bb1, x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+/// 3D simplex noise, roughly in range -1..1
+/// https://weber.itn.liu.se/~stegu/simplexnoise/simplexnoise.pdf
+pub fn simplex(p: Point) -> float {
+    const F3: float = 1.0 / 3.0;
+    const G3: float = 1.0 / 6.0;
+
+    // Skew the input space to find the containing simplex cell
+    let s = (p.x + p.y + p.z) * F3;
+    let i = (p.x + s).floor();
+    let j = (p.y + s).floor();
+    let k = (p.z + s).floor();
+    let t = (i + j + k) * G3;
+
+    let x0 = p.x - (i - t);
+    let y0 = p.y - (j - t);
+    let z0 = p.z - (k - t);
+
+    // Offsets of the second and third corners, in skewed coordinates
+    let (i1, j1, k1, i2, j2, k2) = if x0 >= y0 {
+        if y0 >= z0 {
+            (1, 0, 0, 1, 1, 0)
+        } else if x0 >= z0 {
+            (1, 0, 0, 1, 0, 1)
+        } else {
+            (0, 0, 1, 1, 0, 1)
+        }
+    } else if y0 < z0 {
+        (0, 0, 1, 0, 1, 1)
+    } else if x0 < z0 {
+        (0, 1, 0, 0, 1, 1)
+    } else {
+        (0, 1, 0, 1, 1, 0)
+    };
+
+    let corners = [
+        (0, 0, 0, x0, y0, z0),
+        (
+            i1,
+            j1,
+            k1,
+            x0 - i1 as float + G3,
+            y0 - j1 as float + G3,
+            z0 - k1 as float + G3,
+        ),
+        (
+            i2,
+            j2,
+            k2,
+            x0 - i2 as float + 2.0 * G3,
+            y0 - j2 as float + 2.0 * G3,
+            z0 - k2 as float + 2.0 * G3,
+        ),
+        (
+            1,
+            1,
+            1,
+            x0 - 1.0 + 3.0 * G3,
+            y0 - 1.0 + 3.0 * G3,
+            z0 - 1.0 + 3.0 * G3,
+        ),
+    ];
+
+    let (i, j, k) = (i as i32, j as i32, k as i32);
+    let mut sum = 0.0;
+    for &(di, dj, dk, x, y, z) in corners.iter() {
+        let t = 0.6 - x * x - y * y - z * z;
+        if t > 0.0 {
+            let g = GRAD3[(hash(i + di, j + dj, k + dk) % 12) as usize];
+            sum += t.powi(4) * (g[0] * x + g[1] * y + g[2] * z);
+        }
+    }
+
+    32.0 * sum
+}
+
+/// Distances to the closest and second closest feature points,
+/// and a hash of the closest cell in range 0..1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Worley {
+    pub f1: float,
+    pub f2: float,
+    pub cell: float,
+}
+
+/// Cellular noise with one jittered feature point per unit cell
+/// https://en.wikipedia.org/wiki/Worley_noise
+pub fn worley(p: Point) -> Worley {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (xi, yi, zi) = (fx as i32, fy as i32, fz as i32);
+
+    let mut result = Worley {
+        f1: float::INFINITY,
+        f2: float::INFINITY,
+        cell: 0.0,
+    };
+
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let h = hash(xi + dx, yi + dy, zi + dz);
+                let feature = Point {
+                    x: fx + dx as float + perm(h) as float / 255.0,
+                    y: fy + dy as float + perm(h + 1) as float / 255.0,
+                    z: fz + dz as float + perm(h + 2) as float / 255.0,
+                };
+
+                let d = (feature - p).len();
+                if d < result.f1 {
+                    result.f2 = result.f1;
+                    result.f1 = d;
+                    result.cell = h as float / 256.0;
+                } else if d < result.f2 {
+                    result.f2 = d;
+                }
+            }
+        }
+    }
+
+    result
+}
+
+/// Fractional Brownian motion: sum of `octaves` layers of `noise`,
+/// each `lacunarity` times the frequency and `gain` times the amplitude of the previous one.
+/// Normalized so that the result has the same range as `noise`.
+pub fn fbm(
+    noise: impl Fn(Point) -> float,
+    p: Point,
+    octaves: usize,
+    lacunarity: float,
+    gain: float,
+) -> float {
+    let mut sum = 0.0;
+    let mut total = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * noise(p * frequency);
+        total += amplitude;
+        amplitude *= gain;
+        frequency *= lacunarity;
+    }
+
+    if total > 0.0 {
+        sum / total
+    } else {
+        0.0
+    }
+}
+
+/// Like `fbm`, but sums absolute values of each octave, producing creases. In range 0..1.
+pub fn turbulence(
+    noise: impl Fn(Point) -> float,
+    p: Point,
+    octaves: usize,
+    lacunarity: float,
+    gain: float,
+) -> float {
+    fbm(|q| noise(q).abs(), p, octaves, lacunarity, gain)
+}
+
+/// Offsets used to decorrelate vector-valued noise components
+const DECORRELATE: [Vector; 2] = [
+    Vector {
+        x: 31.416,
+        y: -47.853,
+        z: 12.679,
+    },
+    Vector {
+        x: -93.989,
+        y: 67.345,
+        z: 54.921,
+    },
+];
+
+/// Vector-valued noise, each component roughly in range -1..1
+pub fn perlin_vector(p: Point) -> Vector {
+    Vector {
+        x: perlin(p),
+        y: perlin(p + DECORRELATE[0]),
+        z: perlin(p + DECORRELATE[1]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_points() -> impl Iterator<Item = Point> {
+        (0..1000).map(|i| {
+            let i = i as float;
+            Point {
+                x: (i * 0.137).sin() * 10.0,
+                y: (i * 0.291).cos() * 7.0 + i * 0.01,
+                z: i * 0.0731 - 30.0,
+            }
+        })
+    }
+
+    #[test]
+    fn perlin_zero_on_lattice() {
+        for x in -3..3 {
+            for y in -3..3 {
+                let p = Point {
+                    x: x as float,
+                    y: y as float,
+                    z: 5.0,
+                };
+                assert!(perlin(p).abs() < 0.000_01);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_in_range() {
+        for p in sample_points() {
+            let n = perlin(p);
+            assert!((-1.0..=1.0).contains(&n), "perlin {}", n);
+            let n = simplex(p);
+            assert!((-1.0..=1.0).contains(&n), "simplex {}", n);
+            let n = turbulence(simplex, p, 4, 2.0, 0.5);
+            assert!((0.0..=1.0).contains(&n), "turbulence {}", n);
+        }
+    }
+
+    #[test]
+    fn worley_ordering() {
+        for p in sample_points() {
+            let w = worley(p);
+            assert!(w.f1 <= w.f2);
+            assert!(w.f1 < float::sqrt(3.0));
+            assert!(w.cell >= 0.0 && w.cell < 1.0);
+        }
+    }
+
+    #[test]
+    fn noise_is_deterministic() {
+        for p in sample_points().take(10) {
+            assert_eq!(perlin(p), perlin(p));
+            assert_eq!(simplex(p), simplex(p));
+            assert_eq!(worley(p), worley(p));
+        }
+    }
+}
@@ -1,5 +1,16 @@
+use crate::bounds::Bounds;
+use crate::bvh::Bvh;
+use crate::matrix::Transform;
+use crate::mesh::Mesh;
 use crate::prelude::*;
+use crate::ray::Ray;
+use crate::raycast::{intersect_object, RayHit};
+use crate::sdf::{Sdf, SphereTracing};
 use crate::Point;
+use crate::Vector;
+
+use std::f32::consts::PI;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Object {
@@ -9,6 +20,692 @@ pub struct Object {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Shape {
-    Sphere { center: Point, radius: float },
-    Triangle { corners: [Point; 3] },
+    Sphere {
+        center: Point,
+        radius: float,
+    },
+    Triangle {
+        corners: [Point; 3],
+        /// Texture coordinates of each corner
+        uvs: [[float; 2]; 3],
+    },
+    /// Infinite plane through `point`, facing `normal`
+    Plane {
+        point: Point,
+        normal: Vector,
+    },
+    /// Parallelogram spanned by two edges from a corner, facing `edges[0] × edges[1]`
+    Rectangle {
+        corner: Point,
+        edges: [Vector; 2],
+    },
+    /// Quad of four corners at uv (0, 0), (1, 0), (0, 1) and (1, 1), curved if they are not
+    /// in a plane, facing `(corners[1] - corners[0]) × (corners[2] - corners[0])`
+    BilinearPatch {
+        corners: [Point; 4],
+        uvs: [[float; 2]; 4],
+    },
+    Disk {
+        center: Point,
+        normal: Vector,
+        radius: float,
+    },
+    /// Axis-aligned box
+    AxisBox {
+        min: Point,
+        max: Point,
+    },
+    /// Capped cylinder from `base` to `base + axis`
+    Cylinder {
+        base: Point,
+        axis: Vector,
+        radius: float,
+    },
+    /// Cone with its base disk at `base` and apex at `base + axis`
+    Cone {
+        base: Point,
+        axis: Vector,
+        radius: float,
+    },
+    /// Ring around `axis`, `major_radius` from `center` to the middle of the tube
+    Torus {
+        center: Point,
+        axis: Vector,
+        major_radius: float,
+        minor_radius: float,
+    },
+    /// Triangles with shared vertices, possibly with materials of their own
+    Mesh {
+        mesh: Arc<Mesh>,
+    },
+    /// Shared geometry placed in the world by an object to world transform
+    Instance {
+        geometry: Arc<Geometry>,
+        transform: Transform,
+    },
+    /// Boolean combination of two closed shapes.
+    /// Has no area, so it is not sampled as a light.
+    Csg {
+        operation: CsgOperation,
+        operands: Box<[Object; 2]>,
+    },
+    /// Implicit surface of a signed distance field, only traced inside `bounds`.
+    /// Has no area, so it is not sampled as a light.
+    Sdf {
+        sdf: Arc<Sdf>,
+        bounds: Bounds,
+        tracing: SphereTracing,
+    },
+}
+
+/// How the solids of the two operands of a CSG node are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    /// First operand with the second one cut away
+    Difference,
+}
+
+impl CsgOperation {
+    /// Whether a point is in the combined solid, given whether it is inside each operand
+    pub fn contains(self, inside: [bool; 2]) -> bool {
+        match self {
+            CsgOperation::Union => inside[0] || inside[1],
+            CsgOperation::Intersection => inside[0] && inside[1],
+            CsgOperation::Difference => inside[0] && !inside[1],
+        }
+    }
+}
+
+/// Objects that can be placed many times as instances, with their own acceleration structure
+#[derive(Debug, Clone)]
+pub struct Geometry {
+    pub objects: Vec<Object>,
+    bvh: Bvh,
+    /// Running total of object areas, for sampling points by area
+    area_cdf: Vec<float>,
+    /// Primitive number of the first part of each object, see `first_primitive`
+    first_primitives: Vec<usize>,
+    primitives: usize,
+}
+
+impl PartialEq for Geometry {
+    fn eq(&self, other: &Self) -> bool {
+        self.objects == other.objects
+    }
+}
+
+impl Geometry {
+    pub fn new(objects: Vec<Object>) -> Self {
+        let bounds: Vec<Bounds> = objects.iter().map(|o| o.shape.bounds()).collect();
+        let area_cdf = objects
+            .iter()
+            .scan(0.0, |total, o| {
+                *total += o.shape.area();
+                Some(*total)
+            })
+            .collect();
+        let mut primitives = 0;
+        let first_primitives = objects
+            .iter()
+            .map(|o| {
+                let first = primitives;
+                primitives += o.shape.primitives();
+                first
+            })
+            .collect();
+        Self {
+            bvh: Bvh::new(&bounds),
+            objects,
+            area_cdf,
+            first_primitives,
+            primitives,
+        }
+    }
+
+    /// Number of the first triangle of an object in the primitives of hits on instances, which
+    /// number the parts of all objects one after another
+    pub fn first_primitive(&self, object: usize) -> usize {
+        self.first_primitives[object]
+    }
+
+    /// Parts of all objects, counted like `Shape::primitives`
+    pub fn primitives(&self) -> usize {
+        self.primitives
+    }
+
+    /// Closest hit in object space, `object` of the hit is the index in `objects`
+    pub fn raycast(&self, ray: &Ray) -> Option<RayHit> {
+        self.bvh
+            .raycast(ray, |i, ray| intersect_object(&self.objects[i], ray))
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self.bvh.bounds()
+    }
+
+    pub fn area(&self) -> float {
+        self.area_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    /// Uniformly distributed point on all of the objects
+    pub fn sample(&self, u: [float; 2]) -> ShapeSample {
+        let target = u[0] * self.area();
+        let i = self
+            .area_cdf
+            .partition_point(|&total| total <= target)
+            .min(self.objects.len() - 1);
+        let start = if i == 0 { 0.0 } else { self.area_cdf[i - 1] };
+        let remapped =
+            ((target - start) / (self.area_cdf[i] - start)).clamp(0.0, 1.0 - float::EPSILON);
+        self.objects[i].shape.sample([remapped, u[1]])
+    }
+}
+
+/// Point chosen uniformly on the surface of a shape
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeSample {
+    pub point: Point,
+    /// Outward facing, towards the side that emits light
+    pub normal: Vector,
+    pub uv: [float; 2],
+}
+
+/// Orthonormal basis of a shape, with `y` along its axis
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Frame {
+    pub origin: Point,
+    pub x: Vector,
+    pub y: Vector,
+    pub z: Vector,
+}
+
+impl Frame {
+    pub fn new(origin: Point, axis: Vector) -> Self {
+        let y = axis.normalized();
+        let (z, x) = y.coordinate_system();
+        Self { origin, x, y, z }
+    }
+
+    pub fn to_local(self, point: Point) -> Point {
+        self.dir_to_local(point - self.origin)
+    }
+
+    pub fn dir_to_local(self, v: Vector) -> Vector {
+        Vector {
+            x: v.dot(self.x),
+            y: v.dot(self.y),
+            z: v.dot(self.z),
+        }
+    }
+
+    pub fn to_world(self, point: Point) -> Point {
+        self.origin + self.dir_to_world(point)
+    }
+
+    pub fn dir_to_world(self, v: Vector) -> Vector {
+        self.x * v.x + self.y * v.y + self.z * v.z
+    }
+}
+
+/// Unit vector along a coordinate axis
+pub(crate) fn axis_vector(axis: usize) -> Vector {
+    match axis {
+        0 => Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        },
+        1 => Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        },
+        _ => Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        },
+    }
+}
+
+/// Picks one of several parts by weight, returning its index and the remapped sample
+fn pick(weights: &[float], u: float) -> (usize, float) {
+    let total: float = weights.iter().sum();
+    let mut start = 0.0;
+    for (i, w) in weights.iter().enumerate() {
+        let end = start + w / total;
+        if u < end || i == weights.len() - 1 {
+            let remapped = if end > start {
+                ((u - start) / (end - start)).clamp(0.0, 1.0 - float::EPSILON)
+            } else {
+                0.0
+            };
+            return (i, remapped);
+        }
+        start = end;
+    }
+    unreachable!()
+}
+
+/// Uniform point on the unit disk in the local xz plane, with its uv
+fn disk_sample(u: [float; 2]) -> (float, float, [float; 2]) {
+    let r = u[0].sqrt();
+    let phi = 2.0 * PI * u[1];
+    (r * phi.cos(), r * phi.sin(), [u[1], r])
+}
+
+impl Shape {
+    /// Texture coordinates for triangles without any, so that UVs are the barycentric coordinates
+    pub const BARYCENTRIC_UVS: [[float; 2]; 3] = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+
+    /// Texture coordinates for bilinear patches without any, so that UVs are the patch parameters
+    pub const PATCH_UVS: [[float; 2]; 4] = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0], [1.0, 1.0]];
+
+    pub fn bounds(&self) -> Bounds {
+        let round = |frame: Frame, height: float, radius: float| {
+            // Bounds of the end disks, which contain the whole shape
+            let mut bounds = Bounds::EMPTY;
+            for &y in [0.0, height].iter() {
+                for axis in 0..3 {
+                    let a = axis_vector(axis);
+                    // Extent of a disk perpendicular to y along a world axis
+                    let extent = radius * (1.0 - frame.y[axis].powi(2)).max(0.0).sqrt();
+                    let center = frame.origin + frame.y * y;
+                    bounds = bounds
+                        .include(center + a * extent)
+                        .include(center - a * extent);
+                }
+            }
+            bounds
+        };
+
+        match *self {
+            Shape::Sphere { center, radius } => {
+                let r = Vector {
+                    x: radius,
+                    y: radius,
+                    z: radius,
+                };
+                Bounds {
+                    min: center - r,
+                    max: center + r,
+                }
+            }
+            Shape::Triangle { corners, .. } => Bounds::point(corners[0])
+                .include(corners[1])
+                .include(corners[2]),
+            Shape::Plane { .. } => Bounds {
+                min: Bounds::EMPTY.max,
+                max: Bounds::EMPTY.min,
+            },
+            // Within the convex hull of the corners
+            Shape::BilinearPatch { corners, .. } => Bounds::point(corners[0])
+                .include(corners[1])
+                .include(corners[2])
+                .include(corners[3]),
+            Shape::Rectangle { corner, edges } => Bounds::point(corner)
+                .include(corner + edges[0])
+                .include(corner + edges[1])
+                .include(corner + edges[0] + edges[1]),
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+            } => round(Frame::new(center, normal), 0.0, radius),
+            Shape::AxisBox { min, max } => Bounds { min, max },
+            Shape::Cylinder { base, axis, radius } | Shape::Cone { base, axis, radius } => {
+                round(Frame::new(base, axis), axis.len(), radius)
+            }
+            Shape::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+            } => {
+                let frame = Frame::new(center - axis.normalized() * minor_radius, axis);
+                round(frame, 2.0 * minor_radius, major_radius + minor_radius)
+            }
+            Shape::Mesh { ref mesh } => mesh.bounds(),
+            Shape::Instance {
+                ref geometry,
+                ref transform,
+            } => transform.bounds(geometry.bounds()),
+            Shape::Csg {
+                operation,
+                ref operands,
+            } => {
+                let a = operands[0].shape.bounds();
+                let b = operands[1].shape.bounds();
+                match operation {
+                    CsgOperation::Union => a.union(b),
+                    CsgOperation::Intersection => a.intersection(b),
+                    CsgOperation::Difference => a,
+                }
+            }
+            Shape::Sdf { bounds, .. } => bounds,
+        }
+    }
+
+    /// Number of parts that hits tell apart by `RayHit::primitive`, the triangles of meshes and
+    /// all the parts of instanced geometry
+    pub fn primitives(&self) -> usize {
+        match *self {
+            Shape::Mesh { ref mesh } => mesh.len(),
+            Shape::Instance { ref geometry, .. } => geometry.primitives(),
+            _ => 1,
+        }
+    }
+
+    /// Surface area, infinite for planes
+    pub fn area(&self) -> float {
+        match *self {
+            Shape::Sphere { radius, .. } => 4.0 * PI * radius * radius,
+            Shape::Triangle { corners, .. } => {
+                (corners[1] - corners[0])
+                    .cross(corners[2] - corners[0])
+                    .len()
+                    * 0.5
+            }
+            Shape::Plane { .. } => float::INFINITY,
+            Shape::Rectangle { edges, .. } => edges[0].cross(edges[1]).len(),
+            // Midpoint rule, exact for parallelograms
+            Shape::BilinearPatch { corners, .. } => {
+                let n = 8;
+                let mut area = 0.0;
+                for i in 0..n * n {
+                    let u = ((i % n) as float + 0.5) / n as float;
+                    let v = ((i / n) as float + 0.5) / n as float;
+                    area += patch_point(corners, u, v).1.len();
+                }
+                area / (n * n) as float
+            }
+            Shape::Disk { radius, .. } => PI * radius * radius,
+            Shape::AxisBox { min, max } => Bounds { min, max }.surface_area(),
+            Shape::Cylinder { axis, radius, .. } => {
+                2.0 * PI * radius * axis.len() + 2.0 * PI * radius * radius
+            }
+            Shape::Cone { axis, radius, .. } => {
+                let slant = (axis.len2() + radius * radius).sqrt();
+                PI * radius * slant + PI * radius * radius
+            }
+            Shape::Torus {
+                major_radius,
+                minor_radius,
+                ..
+            } => 4.0 * PI * PI * major_radius * minor_radius,
+            Shape::Mesh { ref mesh } => mesh.area(),
+            // Exact for uniform scaling
+            Shape::Instance {
+                ref geometry,
+                ref transform,
+            } => geometry.area() * transform.to_world.determinant3().abs().powf(2.0 / 3.0),
+            Shape::Csg { .. } | Shape::Sdf { .. } => 0.0,
+        }
+    }
+
+    /// Uniformly distributed point on the surface, `u` uniform in 0..1.
+    /// Bilinear patches are sampled uniformly in their parameters, which is uniform by area
+    /// for parallelograms only.
+    /// Planes have no uniform distribution and always give their reference point,
+    /// CSG nodes give a point on their first operand and fields the center of their bounds.
+    pub fn sample(&self, u: [float; 2]) -> ShapeSample {
+        match *self {
+            Shape::Sphere { center, radius } => {
+                let y = 1.0 - 2.0 * u[0];
+                let ring = (1.0 - y * y).max(0.0).sqrt();
+                let phi = 2.0 * PI * u[1];
+                let normal = Vector {
+                    x: ring * phi.cos(),
+                    y,
+                    z: ring * phi.sin(),
+                };
+                ShapeSample {
+                    point: center + normal * radius,
+                    normal,
+                    // Same parameterization as `ray_sphere`
+                    uv: [
+                        0.5 + normal.z.atan2(normal.x) / (2.0 * PI),
+                        (-y).clamp(-1.0, 1.0).acos() / PI,
+                    ],
+                }
+            }
+            Shape::Triangle { corners, uvs } => {
+                // Uniform barycentric coordinates
+                let s = u[0].sqrt();
+                let b1 = 1.0 - s;
+                let b2 = u[1] * s;
+                let b0 = 1.0 - b1 - b2;
+                ShapeSample {
+                    point: corners[0] * b0 + corners[1] * b1 + corners[2] * b2,
+                    normal: (corners[1] - corners[0])
+                        .cross(corners[2] - corners[0])
+                        .normalized(),
+                    uv: [
+                        b0 * uvs[0][0] + b1 * uvs[1][0] + b2 * uvs[2][0],
+                        b0 * uvs[0][1] + b1 * uvs[1][1] + b2 * uvs[2][1],
+                    ],
+                }
+            }
+            Shape::Plane { point, normal } => ShapeSample {
+                point,
+                normal: normal.normalized(),
+                uv: [0.0, 0.0],
+            },
+            Shape::Rectangle { corner, edges } => ShapeSample {
+                point: corner + edges[0] * u[0] + edges[1] * u[1],
+                normal: edges[0].cross(edges[1]).normalized(),
+                uv: u,
+            },
+            Shape::BilinearPatch { corners, uvs } => {
+                let (point, normal) = patch_point(corners, u[0], u[1]);
+                let lerp = |a: float, b: float, t: float| a + (b - a) * t;
+                let uv_at = |axis: usize| {
+                    lerp(
+                        lerp(uvs[0][axis], uvs[1][axis], u[0]),
+                        lerp(uvs[2][axis], uvs[3][axis], u[0]),
+                        u[1],
+                    )
+                };
+                ShapeSample {
+                    point,
+                    normal: if normal.len2() > 0.0 {
+                        normal.normalized()
+                    } else {
+                        (corners[1] - corners[0])
+                            .cross(corners[2] - corners[0])
+                            .normalized()
+                    },
+                    uv: [uv_at(0), uv_at(1)],
+                }
+            }
+            Shape::Disk {
+                center,
+                normal,
+                radius,
+            } => {
+                let frame = Frame::new(center, normal);
+                let (x, z, uv) = disk_sample(u);
+                ShapeSample {
+                    point: frame.to_world(Vector {
+                        x: x * radius,
+                        y: 0.0,
+                        z: z * radius,
+                    }),
+                    normal: frame.y,
+                    uv,
+                }
+            }
+            Shape::AxisBox { min, max } => {
+                let d = max - min;
+                let areas = [
+                    d.y * d.z,
+                    d.y * d.z,
+                    d.x * d.z,
+                    d.x * d.z,
+                    d.x * d.y,
+                    d.x * d.y,
+                ];
+                let (face, remapped) = pick(&areas, u[0]);
+                let axis = face / 2;
+                let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+                let normal = axis_vector(axis) * if face % 2 == 0 { -1.0 } else { 1.0 };
+                let mut point =
+                    min + axis_vector(a) * (remapped * d[a]) + axis_vector(b) * (u[1] * d[b]);
+                if face % 2 == 1 {
+                    point = point + axis_vector(axis) * d[axis];
+                }
+                ShapeSample {
+                    point,
+                    normal,
+                    uv: [remapped, u[1]],
+                }
+            }
+            Shape::Cylinder { base, axis, radius } => {
+                let frame = Frame::new(base, axis);
+                let height = axis.len();
+                let side = 2.0 * PI * radius * height;
+                let cap = PI * radius * radius;
+                match pick(&[side, cap, cap], u[0]) {
+                    (0, v) => {
+                        let phi = 2.0 * PI * u[1];
+                        let normal = Vector {
+                            x: phi.cos(),
+                            y: 0.0,
+                            z: phi.sin(),
+                        };
+                        ShapeSample {
+                            point: frame.to_world(normal * radius + frame_y(v * height)),
+                            normal: frame.dir_to_world(normal),
+                            uv: [u[1], v],
+                        }
+                    }
+                    (part, v) => {
+                        let (x, z, uv) = disk_sample([v, u[1]]);
+                        let top = part == 2;
+                        ShapeSample {
+                            point: frame.to_world(Vector {
+                                x: x * radius,
+                                y: if top { height } else { 0.0 },
+                                z: z * radius,
+                            }),
+                            normal: if top { frame.y } else { -frame.y },
+                            uv,
+                        }
+                    }
+                }
+            }
+            Shape::Cone { base, axis, radius } => {
+                let frame = Frame::new(base, axis);
+                let height = axis.len();
+                let slant = (height * height + radius * radius).sqrt();
+                let side = PI * radius * slant;
+                let cap = PI * radius * radius;
+                match pick(&[side, cap], u[0]) {
+                    (0, v) => {
+                        // Area grows linearly towards the base
+                        let y = height * (1.0 - v.sqrt());
+                        let r = radius * (1.0 - y / height);
+                        let phi = 2.0 * PI * u[1];
+                        let normal = Vector {
+                            x: phi.cos() * height,
+                            y: radius,
+                            z: phi.sin() * height,
+                        }
+                        .normalized();
+                        ShapeSample {
+                            point: frame.to_world(Vector {
+                                x: r * phi.cos(),
+                                y,
+                                z: r * phi.sin(),
+                            }),
+                            normal: frame.dir_to_world(normal),
+                            uv: [u[1], y / height],
+                        }
+                    }
+                    (_, v) => {
+                        let (x, z, uv) = disk_sample([v, u[1]]);
+                        ShapeSample {
+                            point: frame.to_world(Vector {
+                                x: x * radius,
+                                y: 0.0,
+                                z: z * radius,
+                            }),
+                            normal: -frame.y,
+                            uv,
+                        }
+                    }
+                }
+            }
+            Shape::Torus {
+                center,
+                axis,
+                major_radius,
+                minor_radius,
+            } => {
+                let frame = Frame::new(center, axis);
+                // Outer side of the tube has more area, invert its cdf (theta + k sin theta) / 2pi
+                let k = minor_radius / major_radius;
+                let target = 2.0 * PI * u[0];
+                let mut theta = target;
+                for _ in 0..8 {
+                    let f = theta + k * theta.sin() - target;
+                    theta -= f / (1.0 + k * theta.cos());
+                    theta = theta.clamp(0.0, 2.0 * PI);
+                }
+                let phi = 2.0 * PI * u[1];
+                let normal = Vector {
+                    x: theta.cos() * phi.cos(),
+                    y: theta.sin(),
+                    z: theta.cos() * phi.sin(),
+                };
+                let ring = Vector {
+                    x: phi.cos(),
+                    y: 0.0,
+                    z: phi.sin(),
+                };
+                ShapeSample {
+                    point: frame.to_world(ring * major_radius + normal * minor_radius),
+                    normal: frame.dir_to_world(normal),
+                    uv: [u[1], theta / (2.0 * PI)],
+                }
+            }
+            Shape::Mesh { ref mesh } => mesh.sample(u),
+            Shape::Instance {
+                ref geometry,
+                ref transform,
+            } => {
+                let sample = geometry.sample(u);
+                ShapeSample {
+                    point: transform.point(sample.point),
+                    normal: transform.normal(sample.normal).normalized(),
+                    uv: sample.uv,
+                }
+            }
+            // Not uniform and not necessarily on the combined surface
+            Shape::Csg { ref operands, .. } => operands[0].shape.sample(u),
+            Shape::Sdf { bounds, .. } => ShapeSample {
+                point: bounds.centroid(),
+                normal: axis_vector(1),
+                uv: [0.0, 0.0],
+            },
+        }
+    }
+}
+
+/// Point on a bilinear patch and the cross product of its derivatives, which is its area density
+fn patch_point(corners: [Point; 4], u: float, v: float) -> (Point, Vector) {
+    let lerp = |a: Point, b: Point, t: float| a + (b - a) * t;
+    let [p00, p10, p01, p11] = corners;
+    let dpdu = lerp(p10, p11, v) - lerp(p00, p01, v);
+    let dpdv = lerp(p01, p11, u) - lerp(p00, p10, u);
+    (
+        lerp(lerp(p00, p10, u), lerp(p01, p11, u), v),
+        dpdu.cross(dpdv),
+    )
+}
+
+fn frame_y(y: float) -> Vector {
+    Vector { x: 0.0, y, z: 0.0 }
 }
@@ -0,0 +1,1484 @@
+//! Import of the parts of the pbrt-v3 and pbrt-v4 scene format used by common test scenes.
+//! Directives and parameters that can not be represented are skipped with a warning.
+//! https://pbrt.org/fileformat-v4
+
+use crate::angle::Angle;
+use crate::bounds::Bounds;
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::environment::EnvironmentLight;
+use crate::light::{self, PointLight};
+use crate::material::{Ior, Material};
+use crate::matrix::{Matrix, Transform};
+use crate::medium::Medium;
+use crate::mesh::{Mesh, MeshTriangle};
+use crate::object::{Geometry, Object, Shape};
+use crate::ply;
+use crate::prelude::*;
+use crate::sky::SunLight;
+use crate::vector::{Point, Vector};
+use crate::volume::{self, Grid, Volume};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Angular radius given to distant lights, that of the sun
+const DISTANT_LIGHT_RADIUS: Angle = Angle { radians: 0.004_65 };
+
+/// Film size when the scene does not set one, the default of pbrt-v4
+const DEFAULT_RESOLUTION: (u32, u32) = (1280, 720);
+
+/// Files included by included files at most, to stop runaway chains of includes
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Contents of a pbrt scene, in its coordinates and units
+#[derive(Debug, Clone)]
+pub struct PbrtScene {
+    /// Shapes, object instances, and emissive spheres for point lights
+    pub objects: Vec<Object>,
+    /// Materials of the shapes, referred to from `material_offset` on
+    pub materials: Vec<Material>,
+    /// Camera with the film resolution of the scene
+    pub camera: Camera,
+    pub samples_per_pixel: usize,
+    /// Infinite light, if there is one
+    pub environment: Option<EnvironmentLight>,
+    /// Distant lights
+    pub suns: Vec<SunLight>,
+    /// Medium the camera is in
+    pub atmosphere: Option<Medium>,
+    /// Directives and parameters that were ignored or approximated
+    pub warnings: Vec<String>,
+}
+
+/// Loads a pbrt scene file, with included files, meshes and images relative to its directory.
+/// Material ids of the objects start from `material_offset`.
+pub fn load<P: AsRef<Path>>(path: P, material_offset: usize) -> io::Result<PbrtScene> {
+    let path = path.as_ref();
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+    parse(&fs::read_to_string(path)?, directory, material_offset)
+}
+
+/// Reads a scene from the contents of a pbrt file
+pub fn parse(text: &str, directory: &Path, material_offset: usize) -> io::Result<PbrtScene> {
+    let mut document = Document {
+        tokens: tokenize(text)?,
+        at: 0,
+        directory: directory.to_owned(),
+        includes: Vec::new(),
+        material_offset,
+        state: State {
+            transform: Matrix::IDENTITY,
+            material: material_offset,
+            area_light: None,
+            reverse_orientation: false,
+            inside: None,
+            outside: None,
+        },
+        attributes: Vec::new(),
+        transforms: Vec::new(),
+        coordinate_systems: HashMap::new(),
+        named_materials: HashMap::new(),
+        media: Vec::new(),
+        named_media: HashMap::new(),
+        object: None,
+        instances: HashMap::new(),
+        camera: None,
+        resolution: DEFAULT_RESOLUTION,
+        lights: Vec::new(),
+        scene: PbrtScene {
+            objects: Vec::new(),
+            materials: Vec::new(),
+            camera: Camera {
+                transform: Matrix::IDENTITY,
+                width: DEFAULT_RESOLUTION.0,
+                height: DEFAULT_RESOLUTION.1,
+                vertical_fov: Camera::DEFAULT_FOV,
+            },
+            samples_per_pixel: 16,
+            environment: None,
+            suns: Vec::new(),
+            atmosphere: None,
+            warnings: Vec::new(),
+        },
+    };
+    // pbrt's default material
+    document
+        .scene
+        .materials
+        .push(Material::diffuse("default", Arc::new(Color::WHITE * 0.5)));
+
+    while let Some(token) = document.next() {
+        match token {
+            Token::Word(directive) => document.directive(&directive)?,
+            _ => return Err(invalid("Expected a directive")),
+        }
+    }
+    Ok(document.finish())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Directive names and the values `true` and `false`
+    Word(String),
+    /// Quoted string
+    Text(String),
+    Number(float),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> io::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|&(_, c)| c != '\n').is_some() {},
+            '[' => tokens.push(Token::Open),
+            ']' => tokens.push(Token::Close),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => text.push(c),
+                        None => return Err(invalid("Unterminated string")),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || "[]\"#".contains(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                let word = &text[start..end];
+                tokens.push(match word.parse() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => Token::Word(word.to_owned()),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parameter of a directive, such as `"float radius" [ 2 ]`
+#[derive(Debug, Clone)]
+struct Param {
+    kind: String,
+    name: String,
+    numbers: Vec<float>,
+    /// Strings, and booleans as "true" or "false"
+    strings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Params(Vec<Param>);
+
+impl Params {
+    fn get(&self, name: &str) -> Option<&Param> {
+        self.0.iter().find(|p| p.name == name)
+    }
+
+    fn numbers(&self, name: &str) -> Option<&[float]> {
+        self.get(name).map(|p| p.numbers.as_slice())
+    }
+
+    fn float(&self, name: &str, default: float) -> float {
+        self.numbers(name)
+            .and_then(|n| n.first().copied())
+            .unwrap_or(default)
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        self.get(name)
+            .and_then(|p| p.strings.first())
+            .map(String::as_str)
+    }
+
+    fn points(&self, names: &[&str]) -> Vec<Vector> {
+        names
+            .iter()
+            .find_map(|name| self.numbers(name))
+            .unwrap_or(&[])
+            .chunks_exact(3)
+            .map(|p| Vector {
+                x: p[0],
+                y: p[1],
+                z: p[2],
+            })
+            .collect()
+    }
+}
+
+/// Attributes saved by `AttributeBegin`
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// Current transformation matrix, from object space to world space
+    transform: Matrix,
+    /// Index in the scene materials
+    material: usize,
+    /// Radiance emitted by the shapes that follow
+    area_light: Option<Color>,
+    reverse_orientation: bool,
+    /// Indices in the media of `MediumInterface`, `None` for vacuum
+    inside: Option<usize>,
+    outside: Option<usize>,
+}
+
+struct Document {
+    tokens: Vec<Token>,
+    at: usize,
+    directory: PathBuf,
+    /// Files whose tokens are being read, with the index just past their last token
+    includes: Vec<(PathBuf, usize)>,
+    material_offset: usize,
+    state: State,
+    attributes: Vec<State>,
+    /// Transforms saved by the `TransformBegin` of pbrt-v3
+    transforms: Vec<Matrix>,
+    coordinate_systems: HashMap<String, Matrix>,
+    named_materials: HashMap<String, usize>,
+    media: Vec<Medium>,
+    named_media: HashMap<String, usize>,
+    /// Name and shapes of the object being defined
+    object: Option<(String, Vec<Object>)>,
+    /// Defined objects, `None` if they are empty
+    instances: HashMap<String, Option<Arc<Geometry>>>,
+    /// Camera to world transform and field of view of the shorter image axis
+    camera: Option<(Matrix, Angle)>,
+    resolution: (u32, u32),
+    lights: Vec<PointLight>,
+    scene: PbrtScene,
+}
+
+impl Document {
+    fn warn(&mut self, warning: String) {
+        if !self.scene.warnings.contains(&warning) {
+            self.scene.warnings.push(warning);
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        match self.next() {
+            Some(Token::Text(text)) => Ok(text),
+            _ => Err(invalid("Expected a string")),
+        }
+    }
+
+    /// `n` numbers, with or without brackets around them
+    fn numbers(&mut self, n: usize) -> io::Result<Vec<float>> {
+        let bracketed = self.peek() == Some(&Token::Open);
+        if bracketed {
+            self.at += 1;
+        }
+        let mut numbers = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.next() {
+                Some(Token::Number(x)) => numbers.push(x),
+                _ => return Err(invalid("Expected a number")),
+            }
+        }
+        if bracketed && self.next() != Some(Token::Close) {
+            return Err(invalid("Expected a closing bracket"));
+        }
+        Ok(numbers)
+    }
+
+    /// Parameter list following the arguments of a directive
+    fn params(&mut self) -> io::Result<Params> {
+        let mut params = Vec::new();
+        while let Some(Token::Text(declaration)) = self.peek().cloned() {
+            self.at += 1;
+            let mut words = declaration.split_whitespace();
+            let (kind, name) = match (words.next(), words.next()) {
+                (Some(kind), Some(name)) => (kind.to_owned(), name.to_owned()),
+                _ => return Err(invalid("Invalid parameter declaration")),
+            };
+            let mut param = Param {
+                kind,
+                name,
+                numbers: Vec::new(),
+                strings: Vec::new(),
+            };
+
+            let bracketed = self.peek() == Some(&Token::Open);
+            if bracketed {
+                self.at += 1;
+            }
+            loop {
+                match self.peek().cloned() {
+                    Some(Token::Number(x)) => param.numbers.push(x),
+                    Some(Token::Text(text)) if bracketed || param.strings.is_empty() => {
+                        param.strings.push(text)
+                    }
+                    Some(Token::Word(word)) if word == "true" || word == "false" => {
+                        param.strings.push(word)
+                    }
+                    Some(Token::Close) if bracketed => {
+                        self.at += 1;
+                        break;
+                    }
+                    _ if bracketed => return Err(invalid("Unterminated parameter list")),
+                    _ => break,
+                }
+                self.at += 1;
+                if !bracketed {
+                    break;
+                }
+            }
+            params.push(param);
+        }
+        Ok(Params(params))
+    }
+
+    /// Skips the arguments of a directive that is not supported
+    fn skip(&mut self, directive: &str) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Word(word) if word != "true" && word != "false" => break,
+                _ => self.at += 1,
+            }
+        }
+        self.warn(format!("{} is not supported and was skipped", directive));
+    }
+
+    fn directive(&mut self, directive: &str) -> io::Result<()> {
+        match directive {
+            "Identity" => self.state.transform = Matrix::IDENTITY,
+            "Translate" => {
+                let t = self.numbers(3)?;
+                self.concat(Matrix::translation(Vector {
+                    x: t[0],
+                    y: t[1],
+                    z: t[2],
+                }));
+            }
+            "Scale" => {
+                let s = self.numbers(3)?;
+                self.concat(Matrix::scale(Vector {
+                    x: s[0],
+                    y: s[1],
+                    z: s[2],
+                }));
+            }
+            "Rotate" => {
+                let r = self.numbers(4)?;
+                let axis = Vector {
+                    x: r[1],
+                    y: r[2],
+                    z: r[3],
+                };
+                if axis.len2() > 0.0 {
+                    self.concat(Matrix::rotation(
+                        axis.normalized(),
+                        Angle {
+                            radians: r[0].to_radians(),
+                        },
+                    ));
+                }
+            }
+            "LookAt" => {
+                let v = self.numbers(9)?;
+                let point = |i: usize| Vector {
+                    x: v[i],
+                    y: v[i + 1],
+                    z: v[i + 2],
+                };
+                match look_at(point(0), point(3), point(6)).and_then(Matrix::inverse) {
+                    Some(world_to_camera) => self.concat(world_to_camera),
+                    None => self.warn("Degenerate LookAt was ignored".to_owned()),
+                }
+            }
+            "Transform" => self.state.transform = column_major(&self.numbers(16)?),
+            "ConcatTransform" => {
+                let matrix = column_major(&self.numbers(16)?);
+                self.concat(matrix);
+            }
+            "CoordinateSystem" => {
+                let name = self.string()?;
+                self.coordinate_systems.insert(name, self.state.transform);
+            }
+            "CoordSysTransform" => {
+                let name = self.string()?;
+                match self.coordinate_systems.get(&name) {
+                    Some(&transform) => self.state.transform = transform,
+                    None => self.warn(format!("Unknown coordinate system {}", name)),
+                }
+            }
+            "ReverseOrientation" => {
+                self.state.reverse_orientation = !self.state.reverse_orientation
+            }
+
+            "Camera" => {
+                let kind = self.string()?;
+                let params = self.params()?;
+                if kind != "perspective" {
+                    self.warn(format!("{} camera was replaced by a perspective one", kind));
+                }
+                let fov = Angle {
+                    radians: params.float("fov", 90.0).to_radians(),
+                };
+                self.scene.atmosphere = self.state.outside.map(|medium| self.media[medium].clone());
+                match self.state.transform.inverse() {
+                    Some(camera_to_world) => {
+                        self.camera = Some((camera_to_world, fov));
+                        self.coordinate_systems
+                            .insert("camera".to_owned(), camera_to_world);
+                    }
+                    None => self.warn("Camera with a singular transform was ignored".to_owned()),
+                }
+            }
+            "Film" => {
+                self.string()?;
+                let params = self.params()?;
+                let (width, height) = self.resolution;
+                self.resolution = (
+                    params.float("xresolution", width as float) as u32,
+                    params.float("yresolution", height as float) as u32,
+                );
+            }
+            "Sampler" => {
+                self.string()?;
+                let params = self.params()?;
+                self.scene.samples_per_pixel = params.float("pixelsamples", 16.0).max(1.0) as usize;
+            }
+            "WorldBegin" => {
+                self.state.transform = Matrix::IDENTITY;
+                self.coordinate_systems
+                    .insert("world".to_owned(), Matrix::IDENTITY);
+            }
+            "WorldEnd" => {}
+
+            "AttributeBegin" => self.attributes.push(self.state),
+            "AttributeEnd" => match self.attributes.pop() {
+                Some(state) => self.state = state,
+                None => return Err(invalid("Unmatched AttributeEnd")),
+            },
+            "TransformBegin" => self.transforms.push(self.state.transform),
+            "TransformEnd" => match self.transforms.pop() {
+                Some(transform) => self.state.transform = transform,
+                None => return Err(invalid("Unmatched TransformEnd")),
+            },
+
+            "Material" => {
+                let kind = self.string()?;
+                let params = self.params()?;
+                let material = self.material("material", &kind, &params);
+                self.scene.materials.push(material);
+                self.state.material = self.material_offset + self.scene.materials.len() - 1;
+            }
+            "MakeNamedMaterial" => {
+                let name = self.string()?;
+                let params = self.params()?;
+                let kind = params.string("type").unwrap_or("diffuse").to_owned();
+                let material = self.material(&name, &kind, &params);
+                self.scene.materials.push(material);
+                self.named_materials
+                    .insert(name, self.material_offset + self.scene.materials.len() - 1);
+            }
+            "NamedMaterial" => {
+                let name = self.string()?;
+                match self.named_materials.get(&name) {
+                    Some(&material) => self.state.material = material,
+                    None => self.warn(format!("Unknown material {}", name)),
+                }
+            }
+
+            "MakeNamedMedium" => {
+                let name = self.string()?;
+                let params = self.params()?;
+                let medium = match params.string("type") {
+                    Some("homogeneous") => Some(self.medium(&params)),
+                    Some("uniformgrid") => self.grid_medium(&params),
+                    kind => {
+                        self.warn(format!(
+                            "{} media are not supported",
+                            kind.unwrap_or("Untyped")
+                        ));
+                        None
+                    }
+                };
+                if let Some(medium) = medium {
+                    self.media.push(medium);
+                    self.named_media.insert(name, self.media.len() - 1);
+                }
+            }
+            "MediumInterface" => {
+                let inside = self.string()?;
+                let outside = match self.peek() {
+                    Some(Token::Text(_)) => self.string()?,
+                    _ => inside.clone(),
+                };
+                self.state.inside = self.named_medium(&inside);
+                self.state.outside = self.named_medium(&outside);
+            }
+
+            "LightSource" => {
+                let kind = self.string()?;
+                let params = self.params()?;
+                self.light(&kind, &params)?;
+            }
+            "AreaLightSource" => {
+                let kind = self.string()?;
+                let params = self.params()?;
+                if kind != "diffuse" {
+                    self.warn(format!("{} area lights are not supported", kind));
+                }
+                if params.string("twosided") == Some("true") {
+                    self.warn("Area lights emit from their front side only".to_owned());
+                }
+                let radiance = self.color(&params, "L", Color::WHITE) * self.scale(&params);
+                self.state.area_light = Some(radiance);
+            }
+
+            "Shape" => {
+                let kind = self.string()?;
+                let params = self.params()?;
+                self.shape(&kind, &params)?;
+            }
+            "ObjectBegin" => {
+                let name = self.string()?;
+                self.attributes.push(self.state);
+                self.object = Some((name, Vec::new()));
+            }
+            "ObjectEnd" => {
+                if let Some((name, objects)) = self.object.take() {
+                    let geometry = if objects.is_empty() {
+                        None
+                    } else {
+                        Some(Arc::new(Geometry::new(objects)))
+                    };
+                    self.instances.insert(name, geometry);
+                }
+                match self.attributes.pop() {
+                    Some(state) => self.state = state,
+                    None => return Err(invalid("Unmatched ObjectEnd")),
+                }
+            }
+            "ObjectInstance" => {
+                let name = self.string()?;
+                match self.instances.get(&name).cloned() {
+                    Some(Some(geometry)) => match Transform::new(self.state.transform) {
+                        Some(transform) => self.scene.objects.push(Object {
+                            shape: Shape::Instance {
+                                geometry,
+                                transform,
+                            },
+                            material_id: None,
+                        }),
+                        None => self.warn(format!("Instance of {} is singular", name)),
+                    },
+                    Some(None) => {}
+                    None => self.warn(format!("Unknown object {}", name)),
+                }
+            }
+
+            "Include" | "Import" => {
+                // Files that ended before this directive are no longer being read
+                let position = self.at - 1;
+                while matches!(self.includes.last(), Some(&(_, end)) if end <= position) {
+                    self.includes.pop();
+                }
+                let name = self.string()?;
+                let path = self.directory.join(&name);
+                let path = path.canonicalize().unwrap_or(path);
+                if self.includes.iter().any(|(included, _)| *included == path) {
+                    return Err(invalid(&format!("{} includes itself", name)));
+                }
+                if self.includes.len() >= MAX_INCLUDE_DEPTH {
+                    return Err(invalid("Includes are nested too deeply"));
+                }
+                let tokens = tokenize(&fs::read_to_string(&path)?)?;
+                let count = tokens.len();
+                self.tokens.splice(self.at..self.at, tokens);
+                for (_, end) in self.includes.iter_mut() {
+                    *end += count;
+                }
+                self.includes.push((path, self.at + count));
+            }
+
+            // Rendering settings of pbrt itself
+            "Integrator" | "PixelFilter" | "Accelerator" | "SurfaceIntegrator"
+            | "VolumeIntegrator" | "Renderer" => {
+                self.string()?;
+                self.params()?;
+                self.warn(format!("{} settings are ignored", directive));
+            }
+            _ => self.skip(directive),
+        }
+        Ok(())
+    }
+
+    fn concat(&mut self, matrix: Matrix) {
+        self.state.transform = self.state.transform * matrix;
+    }
+
+    /// Adds a shape with the current material, area light and medium inside
+    fn add(&mut self, shape: Shape) {
+        let mut material_id = match self.state.area_light {
+            Some(radiance) => {
+                let material = &self.scene.materials[self.state.material - self.material_offset];
+                let emissive = Material {
+                    name: format!("{} emitting", material.name),
+                    ambient: Arc::new(radiance),
+                    ..material.clone()
+                };
+                self.scene.materials.push(emissive);
+                self.material_offset + self.scene.materials.len() - 1
+            }
+            None => self.state.material,
+        };
+        // Only glass and interfaces let light into the medium
+        if let Some(inside) = self.state.inside {
+            let material = &self.scene.materials[material_id - self.material_offset];
+            if material.glass.is_some() || material.medium.is_some() {
+                let bounding = Material {
+                    name: format!("{} around medium", material.name),
+                    medium: Some(self.media[inside].clone()),
+                    ..material.clone()
+                };
+                self.scene.materials.push(bounding);
+                material_id = self.material_offset + self.scene.materials.len() - 1;
+            } else {
+                self.warn("Media inside opaque shapes are ignored".to_owned());
+            }
+        }
+        let object = Object {
+            shape,
+            material_id: Some(material_id),
+        };
+        match self.object {
+            Some((_, ref mut objects)) => objects.push(object),
+            None => self.scene.objects.push(object),
+        }
+    }
+
+    fn shape(&mut self, kind: &str, params: &Params) -> io::Result<()> {
+        let transform = match Transform::new(self.state.transform) {
+            Some(transform) => transform,
+            None => {
+                self.warn("Shapes with a singular transform were skipped".to_owned());
+                return Ok(());
+            }
+        };
+        // Mirroring transforms flip the facing of the winding, like reversing the orientation
+        let reverse_winding =
+            self.state.reverse_orientation != (self.state.transform.determinant3() < 0.0);
+
+        match kind {
+            "sphere" => {
+                if params.get("zmin").is_some()
+                    || params.get("zmax").is_some()
+                    || params.get("phimax").is_some()
+                {
+                    self.warn("Partial spheres are rendered whole".to_owned());
+                }
+                let scales: Vec<float> = [0.0, 1.0, 2.0]
+                    .iter()
+                    .map(|&i| {
+                        let axis = Vector {
+                            x: (i == 0.0) as u8 as float,
+                            y: (i == 1.0) as u8 as float,
+                            z: (i == 2.0) as u8 as float,
+                        };
+                        transform.vector(axis).len()
+                    })
+                    .collect();
+                let scale = (scales[0] + scales[1] + scales[2]) / 3.0;
+                if scales.iter().any(|&s| (s - scale).abs() > scale * 1e-3) {
+                    self.warn("Spheres are scaled uniformly".to_owned());
+                }
+                self.add(Shape::Sphere {
+                    center: transform.point(Vector::ZERO),
+                    radius: params.float("radius", 1.0) * scale,
+                });
+            }
+            "trianglemesh" => {
+                let positions = params.points(&["P"]);
+                let mut indices: Vec<u32> = params
+                    .numbers("indices")
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|&i| i as u32)
+                    .collect();
+                if indices.is_empty() && positions.len() == 3 {
+                    indices = vec![0, 1, 2];
+                }
+                if !indices.chunks_exact(3).remainder().is_empty()
+                    || indices.iter().any(|&i| i as usize >= positions.len())
+                {
+                    return Err(invalid("Invalid triangle mesh indices"));
+                }
+                let mut normals = params.points(&["N"]);
+                if normals.len() != positions.len() {
+                    normals.clear();
+                }
+                let mut uvs: Vec<[float; 2]> = ["uv", "st"]
+                    .iter()
+                    .find_map(|name| params.numbers(name))
+                    .unwrap_or(&[])
+                    .chunks_exact(2)
+                    .map(|uv| [uv[0], uv[1]])
+                    .collect();
+                if uvs.len() != positions.len() {
+                    uvs.clear();
+                }
+                let triangles = indices
+                    .chunks_exact(3)
+                    .map(|t| MeshTriangle {
+                        vertices: [t[0], t[1], t[2]],
+                        material_id: None,
+                    })
+                    .collect();
+                let mesh = Mesh::new(positions, normals, uvs, triangles);
+                self.add_mesh(mesh, &transform, reverse_winding);
+            }
+            "plymesh" => {
+                let name = params
+                    .string("filename")
+                    .ok_or_else(|| invalid("plymesh without a filename"))?;
+                let mesh = ply::load(self.directory.join(name), 1.0)?;
+                self.add_mesh(mesh, &transform, reverse_winding);
+            }
+            _ => self.warn(format!("{} shapes are not supported", kind)),
+        }
+        Ok(())
+    }
+
+    fn add_mesh(&mut self, mesh: Mesh, transform: &Transform, reverse_winding: bool) {
+        if !mesh.is_empty() {
+            self.add(Shape::Mesh {
+                mesh: Arc::new(mesh.transformed(transform, reverse_winding)),
+            });
+        }
+    }
+
+    fn light(&mut self, kind: &str, params: &Params) -> io::Result<()> {
+        let scale = self.scale(params);
+        let point =
+            |name: &str, default: Point| params.points(&[name]).first().copied().unwrap_or(default);
+        if params.get("power").is_some() {
+            self.warn("Light power is ignored, the intensity is used".to_owned());
+        }
+
+        match kind {
+            "point" | "spot" => {
+                if kind == "spot" {
+                    self.warn("Spot lights shine in all directions".to_owned());
+                }
+                let intensity = self.color(params, "I", Color::WHITE) * scale;
+                self.lights.push(PointLight {
+                    position: self
+                        .state
+                        .transform
+                        .mul_translate(point("from", Vector::ZERO)),
+                    intensity,
+                });
+            }
+            "distant" => {
+                let to = point(
+                    "to",
+                    Vector {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 1.0,
+                    },
+                );
+                let direction = self
+                    .state
+                    .transform
+                    .mul_rotate(point("from", Vector::ZERO) - to);
+                if direction.len2() == 0.0 {
+                    self.warn("Distant light without a direction was ignored".to_owned());
+                    return Ok(());
+                }
+                // `L` is the irradiance, that of a disk of uniform radiance is `radiance * PI * sin^2(radius)`
+                let sin = DISTANT_LIGHT_RADIUS.radians.sin();
+                let irradiance = self.color(params, "L", Color::WHITE) * scale;
+                self.scene.suns.push(SunLight {
+                    direction: direction.normalized(),
+                    radius: DISTANT_LIGHT_RADIUS,
+                    radiance: irradiance * (1.0 / (std::f32::consts::PI * sin * sin)),
+                });
+            }
+            "infinite" => {
+                if self.scene.environment.is_some() {
+                    self.warn("Only the last infinite light is used".to_owned());
+                }
+                self.scene.environment = Some(match params.string("filename") {
+                    Some(name) => {
+                        self.warn(
+                            "Environment maps are read as equirectangular, without their transform"
+                                .to_owned(),
+                        );
+                        EnvironmentLight::load(
+                            self.directory.join(name),
+                            Angle { radians: 0.0 },
+                            scale.luminance(),
+                        )?
+                    }
+                    None => {
+                        EnvironmentLight::uniform(self.color(params, "L", Color::WHITE) * scale)
+                    }
+                });
+            }
+            _ => self.warn(format!("{} lights are not supported", kind)),
+        }
+        Ok(())
+    }
+
+    /// RGB value of a parameter. Spectra given as wavelength and value pairs are averaged to gray,
+    /// other spectra and textures are replaced by `default` with a warning.
+    fn color(&mut self, params: &Params, name: &str, default: Color) -> Color {
+        let param = match params.get(name) {
+            Some(param) => param,
+            None => return default,
+        };
+        let n = &param.numbers;
+        match param.kind.as_str() {
+            "rgb" | "color" if n.len() == 3 => Color {
+                r: n[0],
+                g: n[1],
+                b: n[2],
+            },
+            "float" if n.len() == 1 => Color::WHITE * n[0],
+            "spectrum" if n.len() >= 2 && n.len() % 2 == 0 => {
+                let values: Vec<float> = n.iter().skip(1).step_by(2).copied().collect();
+                Color::WHITE * (values.iter().sum::<float>() / values.len() as float)
+            }
+            kind => {
+                self.warn(format!("{} values of {} are not supported", kind, name));
+                default
+            }
+        }
+    }
+
+    /// Homogeneous medium, with pbrt's defaults
+    fn medium(&mut self, params: &Params) -> Medium {
+        let absorption = Color {
+            r: 0.0011,
+            g: 0.0024,
+            b: 0.014,
+        };
+        let scattering = Color {
+            r: 2.55,
+            g: 3.21,
+            b: 3.77,
+        };
+        if params.get("preset").is_some() {
+            self.warn("Medium presets are replaced by the default coefficients".to_owned());
+        }
+        self.coefficients(params, absorption, scattering)
+    }
+
+    /// Medium whose density is given by a grid of voxels filling the box from `p0` to `p1`,
+    /// optionally emitting light scaled by a second grid in `Lescale`
+    fn grid_medium(&mut self, params: &Params) -> Option<Medium> {
+        let resolution = [
+            params.float("nx", 1.0) as usize,
+            params.float("ny", 1.0) as usize,
+            params.float("nz", 1.0) as usize,
+        ];
+        let voxels = volume::voxel_count(resolution).unwrap_or(0);
+        let grid = |name: &str| match params.numbers(name) {
+            Some(values) if voxels > 0 && values.len() == voxels => {
+                Some(Grid::new(resolution, values))
+            }
+            _ => None,
+        };
+        let density = match grid("density") {
+            Some(density) => density,
+            None => {
+                self.warn("Grid medium density does not match its resolution".to_owned());
+                return None;
+            }
+        };
+        if params.get("temperature").is_some() {
+            self.warn("Temperature grids are not supported".to_owned());
+        }
+
+        let mut medium = self.coefficients(params, Color::WHITE, Color::WHITE);
+        let emission = grid("Lescale");
+        if emission.is_some() {
+            // The grid replaces the uniform scale read with the coefficients
+            medium.emission = self.color(params, "Le", Color::BLACK);
+        }
+        let point =
+            |name: &str, default: Point| params.points(&[name]).first().copied().unwrap_or(default);
+        let bounds = Bounds {
+            min: point("p0", Vector::ZERO),
+            max: point(
+                "p1",
+                Vector {
+                    x: 1.0,
+                    y: 1.0,
+                    z: 1.0,
+                },
+            ),
+        };
+        match Volume::new(density, emission, bounds, self.state.transform) {
+            Some(volume) => {
+                medium.volume = Some(Arc::new(volume));
+                Some(medium)
+            }
+            None => {
+                self.warn("Grid medium fills a flat box".to_owned());
+                None
+            }
+        }
+    }
+
+    /// Coefficients, phase function and emission of a medium, with default coefficients
+    fn coefficients(&mut self, params: &Params, absorption: Color, scattering: Color) -> Medium {
+        let scale = params.float("scale", 1.0);
+        Medium {
+            absorption: self.color(params, "sigma_a", absorption) * scale,
+            scattering: self.color(params, "sigma_s", scattering) * scale,
+            g: params.float("g", 0.0),
+            emission: self.color(params, "Le", Color::BLACK) * params.float("Lescale", 1.0),
+            volume: None,
+        }
+    }
+
+    /// Index in the media of a name in `MediumInterface`, `None` for the empty name of vacuum
+    fn named_medium(&mut self, name: &str) -> Option<usize> {
+        if name.is_empty() {
+            return None;
+        }
+        let medium = self.named_media.get(name).copied();
+        if medium.is_none() {
+            self.warn(format!("Unknown medium {}", name));
+        }
+        medium
+    }
+
+    /// Index of refraction of a dielectric, a number or one of the named glass spectra
+    fn ior(&mut self, params: &Params) -> Ior {
+        let name = if params.get("eta").is_some() {
+            "eta"
+        } else {
+            "index"
+        };
+        match params.get(name) {
+            Some(param) if param.kind == "spectrum" && !param.strings.is_empty() => {
+                match param.strings[0].as_str() {
+                    "glass-BK7" => Ior::BK7,
+                    "glass-F11" => Ior::SF11,
+                    spectrum => {
+                        self.warn(format!("Glass spectrum {} is replaced by BK7", spectrum));
+                        Ior::BK7
+                    }
+                }
+            }
+            _ => Ior::Constant(params.float(name, 1.5)),
+        }
+    }
+
+    /// Scale factor of a light, a float in pbrt-v4 and a color in pbrt-v3
+    fn scale(&mut self, params: &Params) -> Color {
+        self.color(params, "scale", Color::WHITE)
+    }
+
+    /// Closest material this renderer has: diffuse, mirror, or a mix of the two
+    fn material(&mut self, name: &str, kind: &str, params: &Params) -> Material {
+        if params.0.iter().any(|p| p.kind == "texture") {
+            self.warn("Textures are not supported, their parameters use defaults".to_owned());
+        }
+        let gray = |x: float| Color::WHITE * x;
+        let mirror = |color: Color| Material {
+            name: name.to_owned(),
+            ambient: Arc::new(Color::BLACK),
+            diffuse: Arc::new(Color::BLACK),
+            specular: Arc::new(color),
+            glass: None,
+            medium: None,
+        };
+
+        match kind {
+            "diffuse" => {
+                Material::diffuse(name, Arc::new(self.color(params, "reflectance", gray(0.5))))
+            }
+            "matte" => Material::diffuse(name, Arc::new(self.color(params, "Kd", gray(0.5)))),
+            "conductor" | "metal" => {
+                if params.get("roughness").is_some() || params.get("uroughness").is_some() {
+                    self.warn("Rough conductors are rendered as mirrors".to_owned());
+                }
+                let reflectance = match params.get("reflectance") {
+                    Some(_) => self.color(params, "reflectance", Color::WHITE),
+                    None => self.conductor_reflectance(params),
+                };
+                mirror(reflectance)
+            }
+            "mirror" => mirror(self.color(params, "Kr", gray(0.9))),
+            // Boundary of a medium without a surface
+            "interface" => Material {
+                medium: Some(Medium::VACUUM),
+                ..Material::diffuse(name, Arc::new(Color::BLACK))
+            },
+            "dielectric" | "glass" | "thindielectric" => {
+                if kind == "thindielectric" {
+                    self.warn("Thin dielectrics are rendered as solid glass".to_owned());
+                }
+                Material::glass(name, self.ior(params))
+            }
+            "coateddiffuse" | "plastic" | "substrate" | "uber" => {
+                self.warn(format!("{} materials are rendered as diffuse", kind));
+                let reflectance = match params.get("reflectance") {
+                    Some(_) => self.color(params, "reflectance", gray(0.5)),
+                    None => self.color(params, "Kd", gray(0.5)),
+                };
+                Material::diffuse(name, Arc::new(reflectance))
+            }
+            _ => {
+                self.warn(format!(
+                    "{} materials are rendered as the default one",
+                    kind
+                ));
+                Material::diffuse(name, Arc::new(gray(0.5)))
+            }
+        }
+    }
+
+    /// Reflectance at normal incidence from the complex index of refraction, copper by default
+    /// https://en.wikipedia.org/wiki/Fresnel_equations#Complex_amplitude_reflection_and_transmission_coefficients
+    fn conductor_reflectance(&mut self, params: &Params) -> Color {
+        let named = ["eta", "k"].iter().find_map(|name| params.string(name));
+        if let Some(spectrum) = named {
+            return match named_metal(spectrum) {
+                Some(color) => color,
+                None => {
+                    self.warn(format!("Unknown spectrum {}", spectrum));
+                    COPPER
+                }
+            };
+        }
+        if params.get("eta").is_none() && params.get("k").is_none() {
+            return COPPER;
+        }
+        let eta = self.color(params, "eta", Color::WHITE);
+        let k = self.color(params, "k", Color::BLACK);
+        let channel =
+            |n: float, k: float| ((n - 1.0).powi(2) + k * k) / ((n + 1.0).powi(2) + k * k);
+        Color {
+            r: channel(eta.r, k.r),
+            g: channel(eta.g, k.g),
+            b: channel(eta.b, k.b),
+        }
+    }
+
+    fn finish(mut self) -> PbrtScene {
+        if self.object.is_some() {
+            self.warn("Object definition without ObjectEnd was dropped".to_owned());
+        }
+        light::add_point_lights(
+            &self.lights,
+            &mut self.scene.objects,
+            &mut self.scene.materials,
+            self.material_offset,
+        );
+
+        let (width, height) = self.resolution;
+        let (camera_to_world, fov) = self.camera.unwrap_or((
+            Matrix::IDENTITY,
+            Angle {
+                radians: (90.0 as float).to_radians(),
+            },
+        ));
+        // The field of view is that of the shorter axis
+        let vertical_fov = if width >= height {
+            fov
+        } else {
+            Angle {
+                radians: 2.0
+                    * ((0.5 * fov.radians).tan() * height as float / width as float).atan(),
+            }
+        };
+        // pbrt cameras look towards +Z with +X to the right, ours towards +X with +Z to the left
+        let axes = Matrix::new([
+            [0.0, 0.0, -1.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        self.scene.camera = Camera {
+            transform: camera_to_world * axes,
+            width,
+            height,
+            vertical_fov,
+        };
+        self.scene
+    }
+}
+
+/// Camera to world transform looking from `eye` towards `target`, as pbrt builds it
+fn look_at(eye: Point, target: Point, up: Vector) -> Option<Matrix> {
+    let direction = target - eye;
+    if direction.len2() == 0.0 || up.len2() == 0.0 {
+        return None;
+    }
+    let direction = direction.normalized();
+    let right = up.normalized().cross(direction);
+    if right.len2() == 0.0 {
+        return None;
+    }
+    let right = right.normalized();
+    let up = direction.cross(right);
+    Some(Matrix::new([
+        [right.x, up.x, direction.x, eye.x],
+        [right.y, up.y, direction.y, eye.y],
+        [right.z, up.z, direction.z, eye.z],
+        [0.0, 0.0, 0.0, 1.0],
+    ]))
+}
+
+/// Matrix of the `Transform` directives, which list the columns
+fn column_major(m: &[float]) -> Matrix {
+    let mut rows = [[0.0; 4]; 4];
+    for (i, &x) in m.iter().enumerate() {
+        rows[i % 4][i / 4] = x;
+    }
+    Matrix::new(rows)
+}
+
+const COPPER: Color = Color {
+    r: 0.955,
+    g: 0.638,
+    b: 0.538,
+};
+
+/// Reflectance at normal incidence of the metals pbrt has spectra for
+fn named_metal(spectrum: &str) -> Option<Color> {
+    let metal = spectrum
+        .strip_prefix("metal-")?
+        .trim_end_matches("-eta")
+        .trim_end_matches("-k");
+    let (r, g, b) = match metal {
+        "Cu" => return Some(COPPER),
+        "Au" => (1.0, 0.766, 0.336),
+        "Ag" => (0.972, 0.960, 0.915),
+        "Al" => (0.913, 0.922, 0.924),
+        "CuZn" => (0.910, 0.778, 0.423),
+        "MgO" => (0.07, 0.07, 0.07),
+        "TiO2" => (0.19, 0.2, 0.21),
+        _ => return None,
+    };
+    Some(Color { r, g, b })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"
+# Camera on the -Z side looking at the origin
+LookAt 0 0 -5  0 0 0  0 1 0
+Camera "perspective" "float fov" [ 45 ]
+Film "rgb" "integer xresolution" [ 200 ] "integer yresolution" 100
+Sampler "halton" "integer pixelsamples" 64
+Integrator "volpath"
+ColorSpace "srgb"
+
+WorldBegin
+LightSource "distant" "point3 from" [0 10 0] "point3 to" [0 0 0] "blackbody L" [5500]
+AttributeBegin
+  Translate 0 3 0
+  LightSource "point" "rgb I" [10 10 10]
+AttributeEnd
+
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [ 4 4 4 ]
+  Translate 0 2 0
+  Shape "trianglemesh" "point3 P" [ -1 0 -1  1 0 -1  1 0 1  -1 0 1 ]
+      "integer indices" [ 0 1 2  0 2 3 ]
+AttributeEnd
+
+Material "conductor" "spectrum eta" "metal-Au-eta" "spectrum k" "metal-Au-k"
+AttributeBegin
+  Translate 2 0 0
+  Scale 2 2 2
+  Shape "sphere" "float radius" 0.5
+AttributeEnd
+
+MakeNamedMaterial "glass" "string type" "thindielectric" "float eta" 1.5
+NamedMaterial "glass"
+ObjectBegin "triangle"
+  Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ]
+ObjectEnd
+AttributeBegin
+  Translate -3 0 0
+  ObjectInstance "triangle"
+AttributeEnd
+Shape "curve" "point3 P" [ 0 0 0  1 1 1  2 2 2  3 3 3 ]
+MakeNamedMedium "smoke" "string type" "nanovdb"
+"#;
+
+    #[test]
+    fn media_fill_the_camera_space_and_closed_shapes() {
+        let scene = parse(
+            r#"
+MakeNamedMedium "haze" "string type" "homogeneous"
+    "rgb sigma_a" [ 0.1 0.2 0.3 ] "rgb sigma_s" [ 1 1 1 ] "float scale" 2 "float g" 0.5
+MediumInterface "" "haze"
+Camera "perspective"
+WorldBegin
+MakeNamedMaterial "boundary" "string type" "interface"
+AttributeBegin
+  MediumInterface "haze" ""
+  NamedMaterial "boundary"
+  Shape "sphere"
+AttributeEnd
+AttributeBegin
+  MediumInterface "haze" ""
+  Material "diffuse"
+  Shape "sphere"
+AttributeEnd
+"#,
+            Path::new("."),
+            0,
+        )
+        .unwrap();
+
+        let haze = scene.atmosphere.clone().unwrap();
+        assert!(approx_eq(haze.absorption.b, 0.6));
+        assert_eq!(haze.scattering, Color::WHITE * 2.0);
+        assert_eq!(haze.g, 0.5);
+
+        let material = |i: usize| &scene.materials[scene.objects[i].material_id.unwrap()];
+        assert_eq!(material(0).medium, Some(haze));
+        assert_eq!(material(1).medium, None);
+        assert!(scene.warnings.iter().any(|w| w.contains("opaque")));
+    }
+
+    #[test]
+    fn grid_media_fill_their_box() {
+        let scene = parse(
+            r#"
+AttributeBegin
+  Translate 10 0 0
+  MakeNamedMedium "fire" "string type" "uniformgrid"
+      "integer nx" 2 "integer ny" 1 "integer nz" 1 "float density" [ 0 4 ]
+      "point3 p0" [ 0 0 0 ] "point3 p1" [ 2 1 1 ] "rgb Le" [ 1 0.5 0 ] "float Lescale" [ 1 3 ]
+AttributeEnd
+MakeNamedMedium "broken" "string type" "uniformgrid" "integer nx" 2 "float density" [ 1 ]
+MediumInterface "" "fire"
+Camera "perspective"
+WorldBegin
+"#,
+            Path::new("."),
+            0,
+        )
+        .unwrap();
+
+        let fire = scene.atmosphere.unwrap();
+        let at = |x: float| Point { x, y: 0.5, z: 0.5 };
+        assert!(approx_eq(fire.density(at(11.5)), 4.0));
+        assert!(approx_eq(fire.density(at(10.5)), 0.0));
+        assert!(approx_eq(fire.density(at(1.5)), 0.0));
+        assert!(approx_eq(fire.emission_scale(at(11.5)), 3.0));
+        assert_eq!(fire.absorption, Color::WHITE);
+        assert_eq!(fire.emission.g, 0.5);
+        assert!(scene.warnings.iter().any(|w| w.contains("resolution")));
+    }
+
+    #[test]
+    fn scene_subset() {
+        let scene = parse(SCENE, Path::new("."), 10).unwrap();
+        assert_eq!(scene.samples_per_pixel, 64);
+        assert_eq!((scene.camera.width, scene.camera.height), (200, 100));
+
+        // Area light quad, sphere, instance and the point light sphere
+        assert_eq!(scene.objects.len(), 4);
+        let material = |object: &Object| &scene.materials[object.material_id.unwrap() - 10];
+
+        let point = crate::texture::SurfacePoint {
+            point: Vector::ZERO,
+            normal: Vector::ZERO,
+            uv: [0.0; 2],
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: Color::WHITE,
+        };
+        let quad = &scene.objects[0];
+        match quad.shape {
+            Shape::Mesh { ref mesh } => {
+                assert_eq!(mesh.len(), 2);
+                assert!(mesh.positions.iter().all(|p| p.y == 2.0));
+            }
+            ref shape => panic!("Area light is a {:?}", shape),
+        }
+        assert_eq!(material(quad).ambient.color(&point), Color::WHITE * 4.0);
+        assert!(approx_eq(material(quad).diffuse.color(&point).r, 0.5));
+
+        match scene.objects[1].shape {
+            Shape::Sphere { center, radius } => {
+                assert_eq!(
+                    center,
+                    Vector {
+                        x: 2.0,
+                        y: 0.0,
+                        z: 0.0
+                    }
+                );
+                assert!(approx_eq(radius, 1.0));
+            }
+            ref shape => panic!("Sphere is a {:?}", shape),
+        }
+        let gold = material(&scene.objects[1]).specular.color(&point);
+        assert!(gold.r > gold.b && gold.r > 0.9);
+
+        match scene.objects[2].shape {
+            Shape::Instance { ref transform, .. } => {
+                assert_eq!(transform.point(Vector::ZERO).x, -3.0)
+            }
+            ref shape => panic!("Instance is a {:?}", shape),
+        }
+        assert_eq!(scene.objects[2].material_id, None);
+        assert!(scene
+            .materials
+            .iter()
+            .any(|m| m.name == "glass" && m.glass == Some(Ior::Constant(1.5))));
+
+        match scene.objects[3].shape {
+            Shape::Sphere { center, .. } => assert_eq!(center.y, 3.0),
+            ref shape => panic!("Point light is a {:?}", shape),
+        }
+
+        assert_eq!(scene.suns.len(), 1);
+        assert!((scene.suns[0].direction.y - 1.0).abs() < 1e-6);
+
+        // Looking towards +Z, with +X on the right as in pbrt
+        let (origin, center, _) = scene.camera.ray(100.0, 50.0);
+        assert_eq!(
+            origin,
+            Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -5.0
+            }
+        );
+        assert!(
+            (center
+                - Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0
+                })
+            .len()
+                < 1e-5
+        );
+        let (_, right, _) = scene.camera.ray(200.0, 50.0);
+        assert!(right.x > 0.0);
+        let (_, top, _) = scene.camera.ray(100.0, 0.0);
+        assert!(approx_eq(top.y / top.z, (22.5 as float).to_radians().tan()));
+
+        for expected in [
+            "Integrator",
+            "ColorSpace",
+            "blackbody",
+            "Thin dielectrics",
+            "curve",
+            "nanovdb media",
+        ] {
+            assert!(
+                scene.warnings.iter().any(|w| w.contains(expected)),
+                "No warning about {} in {:?}",
+                expected,
+                scene.warnings
+            );
+        }
+    }
+
+    #[test]
+    fn mirrored_transforms_keep_facing() {
+        let text = r#"
+Scale -1 1 1
+Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ]
+ReverseOrientation
+Shape "trianglemesh" "point3 P" [ 0 0 0  1 0 0  0 1 0 ]
+"#;
+        let scene = parse(text, Path::new("."), 0).unwrap();
+        let normal = |i: usize| match scene.objects[i].shape {
+            Shape::Mesh { ref mesh } => {
+                let [a, b, c] = mesh.corners(0);
+                (b - a).cross(c - a).z
+            }
+            _ => panic!("Not a mesh"),
+        };
+        // Same facing as before the mirroring, and the opposite one when reversed
+        assert!(normal(0) > 0.0);
+        assert!(normal(1) < 0.0);
+    }
+
+    #[test]
+    fn includes_splice_files_without_cycles() {
+        let directory = std::env::temp_dir().join(format!("pbrt-includes-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let write = |name: &str, text: &str| fs::write(directory.join(name), text).unwrap();
+        write("sphere.pbrt", "Shape \"sphere\"");
+        write(
+            "spheres.pbrt",
+            "Include \"sphere.pbrt\" Translate 2 0 0 Include \"sphere.pbrt\"",
+        );
+        write("self.pbrt", "Shape \"sphere\" Include \"self.pbrt\"");
+        write(
+            "loop.pbrt",
+            "Include \"spheres.pbrt\" Include \"loop.pbrt\"",
+        );
+
+        // The same file included repeatedly, and by an included file
+        let scene = parse(
+            "WorldBegin Include \"spheres.pbrt\" Include \"sphere.pbrt\"",
+            &directory,
+            0,
+        )
+        .unwrap();
+        assert_eq!(scene.objects.len(), 3);
+
+        assert!(parse("Include \"self.pbrt\"", &directory, 0).is_err());
+        assert!(parse("WorldBegin Include \"loop.pbrt\"", &directory, 0).is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn malformed_scenes_are_errors() {
+        assert!(parse("Translate 1 2", Path::new("."), 0).is_err());
+        assert!(parse("AttributeEnd", Path::new("."), 0).is_err());
+        assert!(parse("Shape \"sphere", Path::new("."), 0).is_err());
+        assert!(parse(
+            "Shape \"trianglemesh\" \"point3 P\" [0 0 0 1 0 0 0 1 0] \"integer indices\" [0 1 5]",
+            Path::new("."),
+            0
+        )
+        .is_err());
+        assert!(parse(
+            "Shape \"plymesh\" \"string filename\" \"missing.ply\"",
+            Path::new("."),
+            0
+        )
+        .is_err());
+    }
+}
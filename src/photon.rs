@@ -0,0 +1,781 @@
+//! Photon mapping, estimating the light reaching diffuse surfaces from the density of photons
+//! traced from the lights. It resolves caustics, light focused by mirrors and glass onto diffuse
+//! surfaces, which paths from the camera rarely find.
+//! https://www.pbr-book.org/3ed-2018/Light_Transport_III_Bidirectional_Methods/Stochastic_Progressive_Photon_Mapping
+
+use crate::bounds::Bounds;
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::material::{scatter_glass, Ior};
+use crate::prelude::*;
+use crate::ray::{self, Ray};
+use crate::sampling;
+use crate::scene::Scene;
+use crate::texture::SurfacePoint;
+use crate::vector::{Point, Vector};
+
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::f32::consts::PI;
+
+fn random2() -> [float; 2] {
+    [sampling::random(), sampling::random()]
+}
+
+fn random3() -> [float; 3] {
+    [sampling::random(), sampling::random(), sampling::random()]
+}
+
+/// Light arriving at a diffuse surface along a photon path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Photon {
+    pub point: Point,
+    /// Unit vector towards where the photon came from
+    pub direction: Vector,
+    /// Power of the whole path, to be divided by the number of paths traced
+    pub power: Color,
+}
+
+/// Photons in a balanced kd-tree, where the median of each range splits it in two
+/// https://graphics.stanford.edu/papers/photongfx/
+#[derive(Debug, Clone, Default)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    /// Axis splitting the range whose median is at the same index
+    axes: Vec<u8>,
+}
+
+impl PhotonMap {
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+
+    /// Traces `paths` photon paths from the lights, the environment and the sun, keeping
+    /// the photons that reach diffuse surfaces after at least one bounce, as light arriving
+    /// directly is sampled instead. Paths have up to `max_depth` bounces, counting the
+    /// surface the photons are found on.
+    pub fn trace(scene: &Scene, paths: usize, max_depth: usize) -> Self {
+        let emitters = Emitters::new(scene);
+        let seed: u64 = sampling::random();
+        let photons = (0..paths)
+            .into_par_iter()
+            .flat_map_iter(|path| {
+                sampling::seed(seed.wrapping_add(path as u64));
+                trace_photon(scene, &emitters, max_depth)
+            })
+            .collect();
+        Self::new(photons)
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Calls `f` with every photon within `radius` of `point`
+    pub fn for_each_within<F: FnMut(&Photon)>(&self, point: Point, radius: float, mut f: F) {
+        within(&self.photons, &self.axes, point, radius * radius, &mut f);
+    }
+}
+
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let bounds = photons
+        .iter()
+        .fold(Bounds::EMPTY, |b, photon| b.include(photon.point));
+    let axis = bounds.largest_axis();
+    let median = photons.len() / 2;
+    photons.select_nth_unstable_by(median, |a, b| {
+        a.point[axis]
+            .partial_cmp(&b.point[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[median] = axis as u8;
+
+    let (below, above) = photons.split_at_mut(median);
+    let (below_axes, above_axes) = axes.split_at_mut(median);
+    build(below, below_axes);
+    build(&mut above[1..], &mut above_axes[1..]);
+}
+
+fn within<F: FnMut(&Photon)>(
+    photons: &[Photon],
+    axes: &[u8],
+    point: Point,
+    radius2: float,
+    f: &mut F,
+) {
+    if photons.is_empty() {
+        return;
+    }
+    let median = photons.len() / 2;
+    let photon = &photons[median];
+    if (photon.point - point).len2() <= radius2 {
+        f(photon);
+    }
+
+    let axis = axes[median] as usize;
+    let offset = point[axis] - photon.point[axis];
+    let below = (&photons[..median], &axes[..median]);
+    let above = (&photons[median + 1..], &axes[median + 1..]);
+    let (near, far) = if offset < 0.0 {
+        (below, above)
+    } else {
+        (above, below)
+    };
+    within(near.0, near.1, point, radius2, f);
+    if offset * offset <= radius2 {
+        within(far.0, far.1, point, radius2, f);
+    }
+}
+
+/// Where photon paths start, chosen by their estimated power
+struct Emitters {
+    /// Sphere containing the finite objects, which light from afar has to cross
+    center: Point,
+    radius: float,
+    /// Probabilities of starting on an emissive object and in the environment,
+    /// the rest starts in the sun
+    p_area: float,
+    p_environment: float,
+}
+
+impl Emitters {
+    fn new(scene: &Scene) -> Self {
+        let bounds = scene.finite_bounds();
+        let (center, radius) = if bounds.is_empty() {
+            (Vector::ZERO, 1.0)
+        } else {
+            bounds.bounding_sphere()
+        };
+        let disk = PI * radius * radius;
+
+        let area: float = scene.lights.lights().iter().map(|light| light.power).sum();
+        // Average radiance over a grid of directions uniform over the sphere
+        let n = 16;
+        let mut environment = 0.0;
+        for i in 0..n * n {
+            let z = 1.0 - 2.0 * ((i % n) as float + 0.5) / n as float;
+            let phi = 2.0 * PI * ((i / n) as float + 0.5) / n as float;
+            let r = (1.0 - z * z).sqrt();
+            let direction = Vector {
+                x: r * phi.cos(),
+                y: z,
+                z: r * phi.sin(),
+            };
+            environment += scene.environment.radiance(direction).luminance();
+        }
+        let environment = environment / (n * n) as float * 4.0 * PI * disk;
+        let sun = scene.sun.map_or(0.0, |sun| {
+            let one_minus_cos = 2.0 * (0.5 * sun.radius.radians).sin().powi(2);
+            sun.radiance.luminance() * 2.0 * PI * one_minus_cos * disk
+        });
+
+        let total = area + environment + sun;
+        let (p_area, p_environment) = if total > 0.0 {
+            (area / total, environment / total)
+        } else {
+            (0.0, 0.0)
+        };
+        Self {
+            center,
+            radius,
+            p_area,
+            p_environment,
+        }
+    }
+
+    /// Start of a photon path, with its power
+    fn sample(&self, scene: &Scene) -> Option<(Ray, Color)> {
+        let u: float = sampling::random();
+        if u < self.p_area {
+            let emitter = scene.lights.sample_emitter(random3())?;
+            let (direction, pdf) = sampling::cosine_hemisphere(emitter.normal, random2());
+            if pdf <= 0.0 {
+                return None;
+            }
+            // Sampled points are not as exact as intersections
+            let error = emitter.point.abs() * ray::gamma(16);
+            let cos = direction.dot(emitter.normal);
+            return Some((
+                Ray::spawn(emitter.point, error, emitter.normal, direction),
+                emitter.radiance * (cos / (self.p_area * emitter.pdf * pdf)),
+            ));
+        }
+
+        let (light, p_choice) = if u < self.p_area + self.p_environment {
+            (scene.environment.sample(random2())?, self.p_environment)
+        } else {
+            let sun = scene.sun?;
+            (
+                sun.sample(random2()),
+                1.0 - self.p_area - self.p_environment,
+            )
+        };
+        if light.pdf <= 0.0 || p_choice <= 0.0 {
+            return None;
+        }
+        // From a disk facing the light, outside of the scene
+        let (tangent, bitangent) = light.direction.coordinate_system();
+        let [x, y] = sampling::concentric_disk(random2());
+        let origin = self.center + (light.direction + tangent * x + bitangent * y) * self.radius;
+        let pdf_position = 1.0 / (PI * self.radius * self.radius);
+        Some((
+            Ray::new(origin, -light.direction),
+            light.radiance * (1.0 / (p_choice * light.pdf * pdf_position)),
+        ))
+    }
+}
+
+/// Photons left by one path from the lights
+fn trace_photon(scene: &Scene, emitters: &Emitters, max_depth: usize) -> Vec<Photon> {
+    let mut photons = Vec::new();
+    let (mut ray, mut power) = match emitters.sample(scene) {
+        Some(start) => start,
+        None => return photons,
+    };
+
+    for bounce in 0..max_depth {
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => break,
+        };
+        if let Some(direction) = through_glass(scene, &ray, &hit) {
+            ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+            continue;
+        }
+        let (diffuse, specular) = surface_lobes(scene, &hit);
+        if bounce > 0 && diffuse.luminance() > 0.0 {
+            photons.push(Photon {
+                point: hit.point,
+                direction: -ray.direction,
+                power,
+            });
+        }
+
+        let diffuse_weight = diffuse.luminance();
+        let specular_weight = specular.luminance();
+        if diffuse_weight + specular_weight <= 0.0 {
+            break;
+        }
+        let p_specular = specular_weight / (diffuse_weight + specular_weight);
+        let direction = if sampling::random::<float>() < p_specular {
+            power = power * specular / p_specular;
+            ray.direction.reflect(hit.shading_normal)
+        } else {
+            power = power * diffuse / (1.0 - p_specular);
+            sampling::cosine_hemisphere(hit.shading_normal, random2()).0
+        };
+        ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+    }
+    photons
+}
+
+/// Direction a ray continues in when it hits glass, reflected or refracted by the Fresnel
+/// reflectance, which cancels out of the throughput
+fn through_glass(scene: &Scene, ray: &Ray, hit: &crate::raycast::RayHit) -> Option<Vector> {
+    let n = scene.material(hit)?.glass?.at(Ior::REFERENCE);
+    let eta = if hit.front_face { n } else { 1.0 / n };
+    let (direction, _) = scatter_glass(ray.direction, hit.shading_normal, eta, sampling::random());
+    Some(direction)
+}
+
+/// Diffuse and mirror reflectance at a hit, white diffuse for the default material
+fn surface_lobes(scene: &Scene, hit: &crate::raycast::RayHit) -> (Color, Color) {
+    let surface = surface_point(hit);
+    match scene.material(hit) {
+        Some(material) => (
+            material.diffuse.color(&surface),
+            material.specular.color(&surface),
+        ),
+        None => (Color::WHITE, Color::BLACK),
+    }
+}
+
+fn surface_point(hit: &crate::raycast::RayHit) -> SurfacePoint {
+    SurfacePoint {
+        point: hit.point,
+        normal: hit.shading_normal,
+        uv: hit.uv,
+        duvdx: [0.0; 2],
+        duvdy: [0.0; 2],
+        color: hit.color,
+    }
+}
+
+/// First diffuse surface seen along a camera ray, through mirror reflections and glass
+#[derive(Debug, Clone, Copy)]
+struct VisiblePoint {
+    point: Point,
+    /// Geometric normal, facing the camera side
+    normal: Vector,
+    /// Throughput from the camera, including the diffuse reflectance
+    beta: Color,
+}
+
+/// Follows a camera ray until it is reflected diffusely, returning the emitted and direct light
+/// found on the way and the surface where indirect light is to be estimated from photons
+fn visible_point(scene: &Scene, mut ray: Ray, max_depth: usize) -> (Color, Option<VisiblePoint>) {
+    let mut beta = Color::WHITE;
+    let mut radiance = Color::BLACK;
+
+    for _ in 0..max_depth {
+        let hit = match scene.raycast(&ray) {
+            Some(hit) => hit,
+            None => {
+                radiance = radiance + beta * scene.environment.radiance(ray.direction);
+                if let Some(sun) = scene.sun {
+                    radiance = radiance + beta * sun.radiance(ray.direction);
+                }
+                break;
+            }
+        };
+        if hit.front_face {
+            if let Some(material) = scene.material(&hit) {
+                radiance = radiance + beta * material.ambient.color(&surface_point(&hit));
+            }
+        }
+        if let Some(direction) = through_glass(scene, &ray, &hit) {
+            ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+            continue;
+        }
+
+        let (diffuse, specular) = surface_lobes(scene, &hit);
+        let diffuse_weight = diffuse.luminance();
+        let specular_weight = specular.luminance();
+        if diffuse_weight + specular_weight <= 0.0 {
+            break;
+        }
+        let p_specular = specular_weight / (diffuse_weight + specular_weight);
+        if sampling::random::<float>() < p_specular {
+            beta = beta * specular / p_specular;
+            let reflection = ray.direction.reflect(hit.shading_normal);
+            ray = Ray::spawn(hit.point, hit.error, hit.normal, reflection);
+            continue;
+        }
+
+        let beta = beta * diffuse / (1.0 - p_specular);
+        radiance = radiance + beta * direct_light(scene, &hit);
+        return (
+            radiance,
+            Some(VisiblePoint {
+                point: hit.point,
+                normal: hit.normal,
+                beta,
+            }),
+        );
+    }
+    (radiance, None)
+}
+
+/// Light from the emitters, the environment and the sun arriving directly at a diffuse hit,
+/// times the cosine over pi, by sampling each once
+fn direct_light(scene: &Scene, hit: &crate::raycast::RayHit) -> Color {
+    let mut radiance = Color::BLACK;
+    if let Some(light) = scene
+        .lights
+        .sample(hit.point, hit.shading_normal, random3())
+    {
+        let cos = light.direction.dot(hit.shading_normal);
+        let shadow = Ray::spawn_to(hit.point, hit.error, hit.normal, light.point);
+        if cos > 0.0 && scene.raycast(&shadow).is_none() {
+            radiance = radiance + light.radiance * (cos / PI / light.pdf);
+        }
+    }
+
+    let lights = [
+        scene.environment.sample(random2()),
+        scene.sun.map(|sun| sun.sample(random2())),
+    ];
+    for light in lights.iter().flatten() {
+        let cos = light.direction.dot(hit.shading_normal);
+        let shadow = Ray::spawn(hit.point, hit.error, hit.normal, light.direction);
+        if cos > 0.0 && scene.raycast(&shadow).is_none() {
+            radiance = radiance + light.radiance * (cos / PI / light.pdf);
+        }
+    }
+    radiance
+}
+
+/// Reflectance over pi times the power of the photons within `radius` arriving on the side of
+/// `normal`, and their number
+fn gather(photons: &PhotonMap, point: Point, normal: Vector, radius: float) -> (Color, usize) {
+    let mut power = Color::BLACK;
+    let mut count = 0;
+    photons.for_each_within(point, radius, |photon| {
+        if photon.direction.dot(normal) > 0.0 {
+            power = power + photon.power;
+            count += 1;
+        }
+    });
+    (power * (1.0 / PI), count)
+}
+
+/// Radiance arriving along a camera ray, with indirect light on diffuse surfaces estimated from
+/// the photons within `radius`, out of `paths` photon paths
+pub fn radiance(
+    scene: &Scene,
+    photons: &PhotonMap,
+    paths: usize,
+    radius: float,
+    origin: Point,
+    direction: Vector,
+    max_depth: usize,
+) -> Color {
+    let (radiance, visible) = visible_point(scene, Ray::new(origin, direction), max_depth);
+    match visible {
+        Some(visible) if paths > 0 => {
+            let (power, _) = gather(photons, visible.point, visible.normal, radius);
+            radiance + visible.beta * power * (1.0 / (paths as float * PI * radius * radius))
+        }
+        _ => radiance,
+    }
+}
+
+/// Statistics of a pixel for progressive photon mapping
+#[derive(Debug, Clone, Copy)]
+struct PixelStats {
+    radius: float,
+    /// Photons gathered so far, reduced as the radius shrinks
+    count: float,
+    /// Power of the gathered photons, for the current radius
+    flux: Color,
+    /// Sum of the emitted and direct light over the iterations
+    direct: Color,
+}
+
+/// Stochastic progressive photon mapping, where each iteration traces a new camera path for
+/// every pixel and new photons, and the gather radius of each pixel shrinks so that the
+/// estimate converges
+#[derive(Debug, Clone)]
+pub struct ProgressivePhotonMapping {
+    width: u32,
+    pixels: Vec<PixelStats>,
+    iterations: usize,
+    /// Photon paths traced over all iterations
+    paths: usize,
+}
+
+impl ProgressivePhotonMapping {
+    /// Fraction of the new photons kept at each iteration
+    const ALPHA: float = 2.0 / 3.0;
+
+    /// Image of `width` by `height` pixels, gathering photons within `radius` at first
+    pub fn new(width: u32, height: u32, radius: float) -> Self {
+        let pixel = PixelStats {
+            radius,
+            count: 0.0,
+            flux: Color::BLACK,
+            direct: Color::BLACK,
+        };
+        Self {
+            width,
+            pixels: vec![pixel; (width * height) as usize],
+            iterations: 0,
+            paths: 0,
+        }
+    }
+
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+
+    /// Refines the image with one camera path per pixel and `paths` photon paths
+    pub fn iterate(&mut self, scene: &Scene, camera: &Camera, paths: usize, max_depth: usize) {
+        let width = self.width;
+        let seed: u64 = sampling::random();
+        let visible: Vec<(Color, Option<VisiblePoint>)> = (0..self.pixels.len())
+            .into_par_iter()
+            .map(|i| {
+                sampling::seed(seed.wrapping_add(i as u64));
+                let x = (i as u32 % width) as float + sampling::random::<float>();
+                let y = (i as u32 / width) as float + sampling::random::<float>();
+                let (origin, direction, _) = camera.ray(x, y);
+                visible_point(scene, Ray::new(origin, direction), max_depth)
+            })
+            .collect();
+        let photons = PhotonMap::trace(scene, paths, max_depth);
+
+        self.pixels.par_iter_mut().zip(visible.par_iter()).for_each(
+            |(pixel, (direct, visible))| {
+                pixel.direct = pixel.direct + *direct;
+                let visible = match visible {
+                    Some(visible) => visible,
+                    None => return,
+                };
+                let (power, count) = gather(&photons, visible.point, visible.normal, pixel.radius);
+                if count == 0 {
+                    return;
+                }
+                let count = count as float;
+                let new_count = pixel.count + Self::ALPHA * count;
+                let new_radius = pixel.radius * (new_count / (pixel.count + count)).sqrt();
+                let shrink = (new_radius / pixel.radius).powi(2);
+                pixel.flux = (pixel.flux + visible.beta * power) * shrink;
+                pixel.count = new_count;
+                pixel.radius = new_radius;
+            },
+        );
+        self.iterations += 1;
+        self.paths += paths;
+    }
+
+    /// Current estimate of the radiance arriving at pixel `(x, y)`
+    pub fn radiance(&self, x: u32, y: u32) -> Color {
+        if self.iterations == 0 {
+            return Color::BLACK;
+        }
+        let pixel = &self.pixels[(y * self.width + x) as usize];
+        let indirect = if self.paths > 0 {
+            pixel.flux * (1.0 / (self.paths as float * PI * pixel.radius * pixel.radius))
+        } else {
+            Color::BLACK
+        };
+        pixel.direct / self.iterations as float + indirect
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::EnvironmentLight;
+    use crate::material::Material;
+    use crate::object::{Object, Shape};
+    use crate::scene::test_scenes::{assert_furnace, furnace};
+    use std::sync::Arc;
+
+    #[test]
+    fn kd_tree_finds_the_same_photons_as_a_linear_search() {
+        let photon = |i: usize| Photon {
+            point: Vector {
+                x: (i * 7 % 13) as float,
+                y: (i * 5 % 11) as float * 0.5,
+                z: (i % 3) as float,
+            },
+            direction: Vector::ZERO,
+            power: Color::WHITE * i as float,
+        };
+        let photons: Vec<Photon> = (0..500).map(photon).collect();
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.len(), 500);
+
+        for &(point, radius) in [
+            (Vector::ZERO, 2.0),
+            (
+                Vector {
+                    x: 6.0,
+                    y: 2.5,
+                    z: 1.0,
+                },
+                1.5,
+            ),
+            (
+                Vector {
+                    x: 20.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                3.0,
+            ),
+        ]
+        .iter()
+        {
+            let mut found = Vec::new();
+            map.for_each_within(point, radius, |p| found.push(p.power.r as usize));
+            found.sort_unstable();
+            let expected: Vec<usize> = photons
+                .iter()
+                .filter(|p| (p.point - point).len() <= radius)
+                .map(|p| p.power.r as usize)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn furnace_matches_geometric_series() {
+        sampling::seed(1);
+        let (scene, camera) = furnace(Color::WHITE, 0.5);
+        let paths = 50_000;
+        let photons = PhotonMap::trace(&scene, paths, 2);
+        // Only photons that bounced once before reaching a wall are kept
+        assert!(photons.len() > paths * 9 / 10 && photons.len() <= paths);
+
+        // Direct light is sampled once per camera ray, so average several rays per pixel
+        let rays = 16;
+        let mut total = Color::BLACK;
+        for y in 0..camera.height {
+            for x in 0..camera.width {
+                let (origin, direction, _) = camera.ray(x as float + 0.5, y as float + 0.5);
+                for _ in 0..rays {
+                    total = total + radiance(&scene, &photons, paths, 0.2, origin, direction, 2);
+                }
+            }
+        }
+        let mean = total / (camera.width * camera.height * rays) as float;
+        assert_furnace(mean, Color::WHITE, 0.5, 2, 0.03);
+    }
+
+    #[test]
+    fn progressive_radius_shrinks_and_estimate_converges() {
+        sampling::seed(1);
+        let (scene, camera) = furnace(Color::WHITE, 0.5);
+        let mut progressive = ProgressivePhotonMapping::new(camera.width, camera.height, 0.3);
+        for _ in 0..8 {
+            progressive.iterate(&scene, &camera, 5_000, 2);
+        }
+        assert_eq!(progressive.iterations(), 8);
+        assert!(progressive.pixels.iter().all(|p| p.radius < 0.3));
+
+        let mut total = Color::BLACK;
+        for y in 0..camera.height {
+            for x in 0..camera.width {
+                total = total + progressive.radiance(x, y);
+            }
+        }
+        let mean = total / (camera.width * camera.height) as float;
+        assert_furnace(mean, Color::WHITE, 0.5, 2, 0.03);
+    }
+
+    #[test]
+    fn mirror_focuses_photons_into_a_caustic() {
+        sampling::seed(1);
+        // A mirror above a floor, lit by a small light beside it
+        let mut materials = vec![
+            Material::diffuse("floor", Arc::new(Color::WHITE * 0.5)),
+            Material {
+                name: "mirror".to_owned(),
+                ambient: Arc::new(Color::BLACK),
+                diffuse: Arc::new(Color::BLACK),
+                specular: Arc::new(Color::WHITE),
+                glass: None,
+                medium: None,
+            },
+            Material::diffuse("light", Arc::new(Color::BLACK)),
+        ];
+        materials[2].ambient = Arc::new(Color::WHITE * 10.0);
+        let up = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let objects = vec![
+            Object {
+                shape: Shape::Disk {
+                    center: Vector::ZERO,
+                    normal: up,
+                    radius: 10.0,
+                },
+                material_id: Some(0),
+            },
+            Object {
+                shape: Shape::Disk {
+                    center: up * 4.0,
+                    normal: -up,
+                    radius: 1.0,
+                },
+                material_id: Some(1),
+            },
+            Object {
+                shape: Shape::Sphere {
+                    center: Vector {
+                        x: 3.0,
+                        y: 2.0,
+                        z: 0.0,
+                    },
+                    radius: 0.1,
+                },
+                material_id: Some(2),
+            },
+        ];
+        let scene = Scene::new(
+            objects,
+            materials,
+            EnvironmentLight::uniform(Color::BLACK),
+            None,
+        );
+        let photons = PhotonMap::trace(&scene, 20_000, 2);
+
+        // Seen from the floor, the light is mirrored to (3, 6, 0), and the mirror lights a disk
+        // of radius 3 around (-6, 0, 0)
+        let caustic = Vector {
+            x: -6.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let elsewhere = Vector {
+            x: 3.0,
+            y: 0.0,
+            z: 6.0,
+        };
+        let (focused, _) = gather(&photons, caustic, up, 1.0);
+        let (spread, _) = gather(&photons, elsewhere, up, 1.0);
+        assert!(focused.luminance() > 10.0 * spread.luminance().max(1e-6));
+    }
+
+    #[test]
+    fn glass_sphere_focuses_photons_into_a_caustic() {
+        sampling::seed(1);
+        // A glass ball lens above a floor, lit by a small light above it
+        let mut materials = vec![
+            Material::diffuse("floor", Arc::new(Color::WHITE * 0.5)),
+            Material::glass("glass", Ior::Constant(1.5)),
+            Material::diffuse("light", Arc::new(Color::BLACK)),
+        ];
+        materials[2].ambient = Arc::new(Color::WHITE * 10.0);
+        let up = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let objects = vec![
+            Object {
+                shape: Shape::Disk {
+                    center: Vector::ZERO,
+                    normal: up,
+                    radius: 10.0,
+                },
+                material_id: Some(0),
+            },
+            Object {
+                shape: Shape::Sphere {
+                    center: up * 2.4,
+                    radius: 1.0,
+                },
+                material_id: Some(1),
+            },
+            Object {
+                shape: Shape::Sphere {
+                    center: up * 6.4,
+                    radius: 0.1,
+                },
+                material_id: Some(2),
+            },
+        ];
+        let scene = Scene::new(
+            objects,
+            materials,
+            EnvironmentLight::uniform(Color::BLACK),
+            None,
+        );
+        let photons = PhotonMap::trace(&scene, 50_000, 3);
+
+        // The ball has a focal length of 1.5 from its center, which images the light 2.4 below
+        // the center, on the floor
+        let beside = Vector {
+            x: 1.5,
+            y: 0.0,
+            z: 0.0,
+        };
+        let (focused, _) = gather(&photons, Vector::ZERO, up, 0.3);
+        let (spread, _) = gather(&photons, beside, up, 0.3);
+        assert!(focused.luminance() > 10.0 * spread.luminance().max(1e-6));
+    }
+}
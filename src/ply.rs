@@ -0,0 +1,486 @@
+//! Reader for PLY polygon files, as written by 3D scanners
+//! http://paulbourke.net/dataformats/ply/
+
+use crate::color::Color;
+use crate::mesh::{triangulate, Mesh, MeshTriangle};
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+/// Scalar types, with both their old and their sized names
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl Type {
+    fn parse(name: &str) -> io::Result<Self> {
+        Ok(match name {
+            "char" | "int8" => Self::I8,
+            "uchar" | "uint8" => Self::U8,
+            "short" | "int16" => Self::I16,
+            "ushort" | "uint16" => Self::U16,
+            "int" | "int32" => Self::I32,
+            "uint" | "uint32" => Self::U32,
+            "float" | "float32" => Self::F32,
+            "double" | "float64" => Self::F64,
+            _ => return Err(invalid("Unknown PLY property type")),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Self::I8 | Self::U8 => 1,
+            Self::I16 | Self::U16 => 2,
+            Self::I32 | Self::U32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+
+    /// Value that integer colors of this type reach at full intensity
+    fn color_scale(self) -> f64 {
+        match self {
+            Self::U8 | Self::I8 => 255.0,
+            Self::U16 | Self::I16 => 65535.0,
+            Self::U32 | Self::I32 => u32::MAX as f64,
+            Self::F32 | Self::F64 => 1.0,
+        }
+    }
+
+    fn decode(self, bytes: &[u8], format: Format) -> f64 {
+        macro_rules! decode {
+            ($t:ty) => {{
+                let mut array = [0; std::mem::size_of::<$t>()];
+                array.copy_from_slice(bytes);
+                if format == Format::BigEndian {
+                    <$t>::from_be_bytes(array) as f64
+                } else {
+                    <$t>::from_le_bytes(array) as f64
+                }
+            }};
+        }
+        match self {
+            Self::I8 => decode!(i8),
+            Self::U8 => decode!(u8),
+            Self::I16 => decode!(i16),
+            Self::U16 => decode!(u16),
+            Self::I32 => decode!(i32),
+            Self::U32 => decode!(u32),
+            Self::F32 => decode!(f32),
+            Self::F64 => decode!(f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Property {
+    name: String,
+    /// Type of the length of a list property
+    count: Option<Type>,
+    value: Type,
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+/// Loads the vertices and faces of a PLY file, scaled by `scale`.
+/// Polygons are triangulated, other elements such as edges are skipped.
+pub fn load<P: AsRef<Path>>(path: P, scale: float) -> io::Result<Mesh> {
+    parse(&fs::read(path)?, scale)
+}
+
+pub fn parse(bytes: &[u8], scale: float) -> io::Result<Mesh> {
+    const END: &[u8] = b"end_header";
+    let end = bytes
+        .windows(END.len())
+        .position(|w| w == END)
+        .ok_or_else(|| invalid("Missing end of PLY header"))?;
+    // The body starts after the line break ending the header, which may be CR LF
+    let body_start = bytes[end..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(bytes.len(), |i| end + i + 1);
+    let header =
+        std::str::from_utf8(&bytes[..end]).map_err(|_| invalid("PLY header is not text"))?;
+    let (format, elements) = parse_header(header)?;
+    let body = &bytes[body_start..];
+
+    match format {
+        Format::Ascii => {
+            let text = std::str::from_utf8(body).map_err(|_| invalid("PLY body is not text"))?;
+            let mut words = text.split_ascii_whitespace();
+            read_body(&elements, scale, |_| {
+                words
+                    .next()
+                    .ok_or_else(|| invalid("Unexpected end of PLY file"))?
+                    .parse()
+                    .map_err(|_| invalid("Invalid number in PLY file"))
+            })
+        }
+        _ => {
+            let mut at = 0;
+            read_body(&elements, scale, |value: Type| {
+                let bytes = body
+                    .get(at..at + value.size())
+                    .ok_or_else(|| invalid("Unexpected end of PLY file"))?;
+                at += value.size();
+                Ok(value.decode(bytes, format))
+            })
+        }
+    }
+}
+
+fn parse_header(header: &str) -> io::Result<(Format, Vec<Element>)> {
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return Err(invalid("Missing PLY magic number"));
+    }
+
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    for line in lines {
+        let words: Vec<&str> = line.split_ascii_whitespace().collect();
+        match words.as_slice() {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::LittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(Format::BigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .map_err(|_| invalid("Invalid PLY element count"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count, value, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of an element"))?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    count: Some(Type::parse(count)?),
+                    value: Type::parse(value)?,
+                }),
+            ["property", value, name] => elements
+                .last_mut()
+                .ok_or_else(|| invalid("PLY property outside of an element"))?
+                .properties
+                .push(Property {
+                    name: name.to_string(),
+                    count: None,
+                    value: Type::parse(value)?,
+                }),
+            ["format", ..] => return Err(invalid("Unknown PLY format")),
+            // Comments, object information and empty lines
+            _ => {}
+        }
+    }
+
+    Ok((
+        format.ok_or_else(|| invalid("Missing PLY format"))?,
+        elements,
+    ))
+}
+
+/// Reads every element, `next` returns the next value of the given type
+fn read_body<F>(elements: &[Element], scale: float, mut next: F) -> io::Result<Mesh>
+where
+    F: FnMut(Type) -> io::Result<f64>,
+{
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut uvs = Vec::new();
+    let mut colors = Vec::new();
+    let mut triangles = Vec::new();
+
+    // Values of one element, with lists as their length followed by their items
+    let mut values = Vec::new();
+    // Start of each property in `values`
+    let mut starts = Vec::new();
+    for element in elements {
+        let find = |names: &[&str]| {
+            names.iter().find_map(|name| {
+                element
+                    .properties
+                    .iter()
+                    .position(|p| p.name == *name && p.count.is_none())
+            })
+        };
+        let position = [find(&["x"]), find(&["y"]), find(&["z"])];
+        let normal = [find(&["nx"]), find(&["ny"]), find(&["nz"])];
+        let color = [
+            find(&["red", "diffuse_red", "r"]),
+            find(&["green", "diffuse_green", "g"]),
+            find(&["blue", "diffuse_blue", "b"]),
+        ];
+        let uv = [
+            find(&["u", "s", "texture_u", "texture_s"]),
+            find(&["v", "t", "texture_v", "texture_t"]),
+        ];
+        let corners = element.properties.iter().position(|p| {
+            p.count.is_some() && (p.name == "vertex_indices" || p.name == "vertex_index")
+        });
+
+        for _ in 0..element.count {
+            values.clear();
+            starts.clear();
+            for property in element.properties.iter() {
+                starts.push(values.len());
+                match property.count {
+                    Some(count) => {
+                        let n = next(count)?;
+                        values.push(n);
+                        for _ in 0..n as usize {
+                            values.push(next(property.value)?);
+                        }
+                    }
+                    None => values.push(next(property.value)?),
+                }
+            }
+            let get = |property: Option<usize>| property.map(|p| values[starts[p]]);
+
+            match element.name.as_str() {
+                "vertex" => {
+                    let vector = |[x, y, z]: [Option<usize>; 3], scale: float| {
+                        Some(Vector {
+                            x: get(x)? as float * scale,
+                            y: get(y)? as float * scale,
+                            z: get(z)? as float * scale,
+                        })
+                    };
+                    positions.push(
+                        vector(position, scale)
+                            .ok_or_else(|| invalid("PLY vertex without a position"))?,
+                    );
+                    if let Some(normal) = vector(normal, 1.0) {
+                        normals.push(normal);
+                    }
+                    if let (Some(r), Some(g), Some(b)) =
+                        (get(color[0]), get(color[1]), get(color[2]))
+                    {
+                        let channel = |value: f64, property: Option<usize>| {
+                            let scale = element.properties[property.unwrap()].value.color_scale();
+                            (value / scale) as float
+                        };
+                        colors.push(Color {
+                            r: channel(r, color[0]),
+                            g: channel(g, color[1]),
+                            b: channel(b, color[2]),
+                        });
+                    }
+                    if let (Some(u), Some(v)) = (get(uv[0]), get(uv[1])) {
+                        uvs.push([u as float, v as float]);
+                    }
+                }
+                "face" => {
+                    let corners = match corners {
+                        Some(corners) => corners,
+                        None => continue,
+                    };
+                    let start = starts[corners];
+                    let face = &values[start + 1..start + 1 + values[start] as usize];
+                    if face
+                        .iter()
+                        .any(|&i| i < 0.0 || i as usize >= positions.len())
+                    {
+                        return Err(invalid("PLY face refers to a missing vertex"));
+                    }
+                    let points: Vec<Point> = face.iter().map(|&i| positions[i as usize]).collect();
+                    for [a, b, c] in triangulate(&points).0 {
+                        triangles.push(MeshTriangle {
+                            vertices: [face[a] as u32, face[b] as u32, face[c] as u32],
+                            material_id: None,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Attributes are only kept if every vertex has them
+    let vertices = positions.len();
+    let complete = |length: usize| length == vertices;
+    if !complete(normals.len()) {
+        normals.clear();
+    }
+    if !complete(uvs.len()) {
+        uvs.clear();
+    }
+    let mesh = Mesh::new(positions, normals, uvs, triangles);
+    Ok(if complete(colors.len()) && vertices > 0 {
+        mesh.with_colors(colors)
+    } else {
+        mesh
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Square of two triangles given as a quad, with colored corners and an edge element
+    const ASCII: &str = "ply
+format ascii 1.0
+comment made by hand
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+element edge 1
+property int vertex1
+property int vertex2
+end_header
+0 0 0 255 0 0
+1 0 0 0 255 0
+1 1 0 0 0 255
+0 1 0 255 255 255
+4 0 1 2 3
+0 2
+";
+
+    /// The same square in binary
+    fn binary(big_endian: bool) -> Vec<u8> {
+        let header = ASCII.split("end_header\n").next().unwrap().replace(
+            "format ascii",
+            if big_endian {
+                "format binary_big_endian"
+            } else {
+                "format binary_little_endian"
+            },
+        );
+        let mut bytes = format!("{}end_header\r\n", header).into_bytes();
+        let f32_bytes = |x: f32| {
+            if big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            }
+        };
+        let i32_bytes = |x: i32| {
+            if big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            }
+        };
+        let corners = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        for (&(x, y), color) in corners.iter().zip(colors.iter()) {
+            for &c in [x, y, 0.0].iter() {
+                bytes.extend(f32_bytes(c));
+            }
+            bytes.extend(color);
+        }
+        bytes.push(4);
+        for i in 0..4 {
+            bytes.extend(i32_bytes(i));
+        }
+        bytes.extend(i32_bytes(0));
+        bytes.extend(i32_bytes(2));
+        bytes
+    }
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let ascii = parse(ASCII.as_bytes(), 2.0).unwrap();
+        assert_eq!(ascii.len(), 2);
+        assert_eq!(
+            ascii.positions[2],
+            Vector {
+                x: 2.0,
+                y: 2.0,
+                z: 0.0
+            }
+        );
+        assert_eq!(ascii.colors[1], Color::GREEN);
+        assert!(ascii.normals.is_empty() && ascii.uvs.is_empty());
+
+        for &big_endian in [false, true].iter() {
+            let binary = parse(&binary(big_endian), 2.0).unwrap();
+            assert_eq!(binary, ascii);
+        }
+    }
+
+    #[test]
+    fn same_triangles_as_obj() {
+        let obj = tobj::Mesh {
+            positions: vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0],
+            indices: vec![0, 1, 2, 3],
+            num_face_indices: vec![4],
+            ..tobj::Mesh::empty()
+        };
+        let obj = Mesh::from_obj(&obj, 2.0, false).mesh;
+        let ply = parse(ASCII.as_bytes(), 2.0).unwrap();
+        assert_eq!(ply.positions, obj.positions);
+        assert_eq!(ply.triangles, obj.triangles);
+    }
+
+    #[test]
+    fn normals_and_texture_coordinates() {
+        let text = "ply
+format ascii 1.0
+element vertex 3
+property double x
+property double y
+property double z
+property float nx
+property float ny
+property float nz
+property float s
+property float t
+element face 1
+property list uchar uint vertex_index
+end_header
+0 0 0 0 0 1 0 0
+1 0 0 0 0 1 1 0
+0 1 0 0 0 1 0 1
+3 0 1 2
+";
+        let mesh = parse(text.as_bytes(), 1.0).unwrap();
+        assert_eq!(mesh.len(), 1);
+        assert_eq!(mesh.normals.len(), 3);
+        assert_eq!(mesh.uvs[2], [0.0, 1.0]);
+        assert!(mesh.colors.is_empty());
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        let missing_vertex = ASCII.replace("4 0 1 2 3", "4 0 1 2 4");
+        assert!(parse(missing_vertex.as_bytes(), 1.0).is_err());
+        let truncated = ASCII.replace("0 2\n", "0");
+        assert!(parse(truncated.as_bytes(), 1.0).is_err());
+        assert!(parse(b"ply\nformat ascii 1.0\n", 1.0).is_err());
+        let bytes = binary(false);
+        assert!(parse(&bytes[..bytes.len() - 1], 1.0).is_err());
+    }
+}
@@ -2,5 +2,5 @@
 pub type float = f32;
 
 pub fn approx_eq(a: float, b: float) -> bool {
-    (a - b) < 0.000_01
+    (a - b).abs() < 0.000_01
 }
@@ -0,0 +1,143 @@
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+/// Shadow rays end this fraction short of their target, so that they do not hit the surface they aim at
+const SHADOW_EPSILON: float = 0.0001;
+
+/// Half line from `origin`, of which only the part between `t_min` and `t_max` is intersected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Point,
+    /// Normalized
+    pub direction: Vector,
+    pub t_min: float,
+    pub t_max: float,
+}
+
+impl Ray {
+    /// Unbounded ray, `direction` does not need to be normalized
+    pub fn new(origin: Point, direction: Vector) -> Self {
+        Self {
+            origin,
+            direction: direction.normalized(),
+            t_min: 0.0,
+            t_max: float::INFINITY,
+        }
+    }
+
+    /// Ray leaving a surface point with the given error bound and geometric normal
+    pub fn spawn(point: Point, error: Vector, normal: Vector, direction: Vector) -> Self {
+        Self::new(offset_origin(point, error, normal, direction), direction)
+    }
+
+    /// Ray from a surface point ending just before `target`, for testing visibility
+    pub fn spawn_to(point: Point, error: Vector, normal: Vector, target: Point) -> Self {
+        let origin = offset_origin(point, error, normal, target - point);
+        let to_target = target - origin;
+        Self {
+            t_max: to_target.len() * (1.0 - SHADOW_EPSILON),
+            ..Self::new(origin, to_target)
+        }
+    }
+
+    pub fn at(&self, t: float) -> Point {
+        self.origin + self.direction * t
+    }
+}
+
+/// Bound on the relative rounding error of `n` consecutive floating point operations
+/// https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error#ErrorPropagation
+pub fn gamma(n: u32) -> float {
+    let e = n as float * float::EPSILON * 0.5;
+    e / (1.0 - e)
+}
+
+/// Moves a surface point off the surface by its error bound, to the side that `direction` leaves to,
+/// so that a ray from it can not hit the same surface again
+/// https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error#RobustSpawnedRayOrigins
+pub fn offset_origin(point: Point, error: Vector, normal: Vector, direction: Vector) -> Point {
+    let distance = normal.abs().dot(error);
+    let offset = if direction.dot(normal) < 0.0 {
+        normal * -distance
+    } else {
+        normal * distance
+    };
+    let moved = point + offset;
+
+    // Round away from the surface so that the offset itself is not lost to rounding
+    let away = |value: float, offset: float| {
+        if offset > 0.0 {
+            next_up(value)
+        } else if offset < 0.0 {
+            next_down(value)
+        } else {
+            value
+        }
+    };
+    Vector {
+        x: away(moved.x, offset.x),
+        y: away(moved.y, offset.y),
+        z: away(moved.z, offset.z),
+    }
+}
+
+/// Smallest float greater than `x`
+fn next_up(x: float) -> float {
+    if x.is_infinite() && x > 0.0 {
+        return x;
+    }
+    if x == 0.0 {
+        return float::from_bits(1);
+    }
+    let bits = x.to_bits();
+    float::from_bits(if x > 0.0 { bits + 1 } else { bits - 1 })
+}
+
+/// Largest float less than `x`
+fn next_down(x: float) -> float {
+    -next_up(-x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_leaves_error_box() {
+        let point = Vector {
+            x: 1e6,
+            y: -3.0,
+            z: 0.5,
+        };
+        let error = Vector {
+            x: 0.1,
+            y: 0.1,
+            z: 0.1,
+        };
+        let normal = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let up = offset_origin(point, error, normal, normal);
+        assert!(up.y > point.y + 0.1);
+        let down = offset_origin(point, error, normal, -normal);
+        assert!(down.y < point.y - 0.1);
+        assert_eq!(up.x, point.x);
+
+        assert!(next_up(1.0) > 1.0 && next_down(1.0) < 1.0);
+        assert!(next_up(-0.0) > 0.0 && next_down(0.0) < 0.0);
+    }
+
+    #[test]
+    fn shadow_ray_stops_before_target() {
+        let target = Vector {
+            x: 0.0,
+            y: 10.0,
+            z: 0.0,
+        };
+        let ray = Ray::spawn_to(Vector::ZERO, Vector::ZERO, target, target);
+        assert!(ray.t_max < 10.0 && ray.t_max > 9.99);
+        assert!((ray.at(ray.t_max) - target).len() < 0.01);
+    }
+}
@@ -1,145 +1,2130 @@
-use crate::object::{Object, Shape};
+use crate::bounds::Bounds;
+use crate::color::Color;
+use crate::matrix::Transform;
+use crate::object::{axis_vector, CsgOperation, Frame, Geometry, Object, Shape};
 use crate::prelude::*;
+use crate::ray::{gamma, offset_origin, Ray};
+use crate::sdf::{clip_to_bounds, Sdf, SphereTracing};
 use crate::vector::{Point, Vector};
 
+use std::f32::consts::PI;
+
 #[derive(Debug, Clone, Copy)]
 pub struct RayHit {
     /// Index
     pub object: usize,
+    /// Triangle of a mesh, zero for other shapes.
+    /// Instances number the parts of their objects one after another.
+    pub primitive: usize,
+    /// Weights of the triangle corners at the hit point, zero for other shapes
+    pub barycentric: [float; 3],
     pub distance: float,
+    /// Hit point, computed so that `error` bounds how far it can be from the true surface
+    pub point: Point,
+    /// Bound on the rounding error of `point` along each axis
+    pub error: Vector,
+    /// Geometric normal, facing against the ray
     pub normal: Vector,
+    /// Normal for shading, interpolated from the vertex normals of meshes.
+    /// On the same side of the surface as `normal`.
+    pub shading_normal: Vector,
+    /// Whether the ray arrived from the outward side, which emits light
+    pub front_face: bool,
+    /// Material of the surface, set from the object that was hit
+    pub material_id: Option<usize>,
+    /// Interpolated vertex color, white for surfaces without
+    pub color: Color,
+    /// Surface parameterization at the hit point
+    pub uv: [float; 2],
+    /// Partial derivatives of the position with respect to `uv`
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    /// Partial derivatives of the normal with respect to `uv`
+    pub dndu: Vector,
+    pub dndv: Vector,
 }
 
-pub fn raycast(from: Point, direction: Vector, objects: &[Object]) -> Option<RayHit> {
-    let direction = direction.normalized();
+/// Surface area covered by a pixel around a hit point
+#[derive(Debug, Clone, Copy)]
+pub struct Footprint {
+    pub dpdx: Vector,
+    pub dpdy: Vector,
+    pub duvdx: [float; 2],
+    pub duvdy: [float; 2],
+}
 
-    let mut closest: Option<RayHit> = None;
+impl Footprint {
+    pub const ZERO: Self = Self {
+        dpdx: Vector::ZERO,
+        dpdy: Vector::ZERO,
+        duvdx: [0.0; 2],
+        duvdy: [0.0; 2],
+    };
+}
 
-    for (i, object) in objects.iter().enumerate() {
-        let opt_hit = match object.shape {
-            Shape::Sphere { center, radius } => ray_sphere(from, direction, center, radius),
-            Shape::Triangle { corners } => ray_triange(from, direction, corners),
-        };
+/// Auxiliary rays offset by one pixel in x and y from the main ray,
+/// used to estimate texture filter footprints
+/// https://www.pbr-book.org/3ed-2018/Texture/Sampling_and_Antialiasing#FindingtheTextureSamplingRate
+#[derive(Debug, Clone, Copy)]
+pub struct RayDifferential {
+    pub rx_origin: Point,
+    pub rx_direction: Vector,
+    pub ry_origin: Point,
+    pub ry_direction: Vector,
+}
 
-        if let Some(mut hit) = opt_hit {
-            hit.object = i; // Fill in the object
-            if let Some(old) = closest {
-                if old.distance > hit.distance {
-                    closest = Some(hit);
-                }
+impl RayDifferential {
+    /// Footprint of the offset rays on the tangent plane of a hit
+    pub fn footprint(&self, hit: &RayHit, point: Point) -> Footprint {
+        let n = hit.normal;
+        let d = n.dot(point);
+
+        let tx_den = n.dot(self.rx_direction);
+        let ty_den = n.dot(self.ry_direction);
+        if tx_den.abs() < 1e-8 || ty_den.abs() < 1e-8 {
+            return Footprint::ZERO;
+        }
+
+        let tx = (d - n.dot(self.rx_origin)) / tx_den;
+        let ty = (d - n.dot(self.ry_origin)) / ty_den;
+        let dpdx = self.rx_origin + self.rx_direction * tx - point;
+        let dpdy = self.ry_origin + self.ry_direction * ty - point;
+
+        // Project onto the two axes where the surface is least foreshortened
+        // and solve dp = dpdu * du + dpdv * dv by least squares
+        let (a0, a1) = if n.x.abs() > n.y.abs() && n.x.abs() > n.z.abs() {
+            (1, 2)
+        } else if n.y.abs() > n.z.abs() {
+            (0, 2)
+        } else {
+            (0, 1)
+        };
+        let solve = |dp: Vector| -> [float; 2] {
+            let det = hit.dpdu[a0] * hit.dpdv[a1] - hit.dpdv[a0] * hit.dpdu[a1];
+            if det.abs() < 1e-10 {
+                return [0.0; 2];
+            }
+            let du = (hit.dpdv[a1] * dp[a0] - hit.dpdv[a0] * dp[a1]) / det;
+            let dv = (hit.dpdu[a0] * dp[a1] - hit.dpdu[a1] * dp[a0]) / det;
+            if du.is_finite() && dv.is_finite() {
+                [du, dv]
             } else {
-                closest = Some(hit);
+                [0.0; 2]
             }
+        };
+
+        Footprint {
+            dpdx,
+            dpdy,
+            duvdx: solve(dpdx),
+            duvdy: solve(dpdy),
+        }
+    }
+
+    /// Differentials of a ray mirrored at a hit, where `direction` is the incoming ray
+    pub fn reflect(
+        &self,
+        direction: Vector,
+        hit: &RayHit,
+        point: Point,
+        footprint: &Footprint,
+    ) -> Self {
+        let n = hit.normal;
+        let wo = -direction;
+        let wi = direction.reflect(n);
+
+        let dndx = hit.dndu * footprint.duvdx[0] + hit.dndv * footprint.duvdx[1];
+        let dndy = hit.dndu * footprint.duvdy[0] + hit.dndv * footprint.duvdy[1];
+        let dwodx = -self.rx_direction - wo;
+        let dwody = -self.ry_direction - wo;
+        let ddndx = dwodx.dot(n) + wo.dot(dndx);
+        let ddndy = dwody.dot(n) + wo.dot(dndy);
+
+        Self {
+            rx_origin: point + footprint.dpdx,
+            rx_direction: wi - dwodx + (dndx * wo.dot(n) + n * ddndx) * 2.0,
+            ry_origin: point + footprint.dpdy,
+            ry_direction: wi - dwody + (dndy * wo.dot(n) + n * ddndy) * 2.0,
+        }
+    }
+}
+
+pub fn raycast(ray: &Ray, objects: &[Object]) -> Option<RayHit> {
+    let mut ray = *ray;
+    let mut closest: Option<RayHit> = None;
+
+    for (i, object) in objects.iter().enumerate() {
+        if let Some(mut hit) = intersect_object(object, &ray) {
+            // Fill in the object
+            hit.object = i;
+            // Only nearer hits are found from now on
+            ray.t_max = hit.distance;
+            closest = Some(hit);
         }
     }
 
     closest
 }
 
+/// Closest hit of a ray with an object, falling back to its material when the surface has none
+pub fn intersect_object(object: &Object, ray: &Ray) -> Option<RayHit> {
+    let mut hit = intersect(&object.shape, ray)?;
+    hit.material_id = hit.material_id.or(object.material_id);
+    Some(hit)
+}
+
+/// Closest hit of a ray with a single shape within the range of the ray.
+/// Object is filled back later.
+pub fn intersect(shape: &Shape, ray: &Ray) -> Option<RayHit> {
+    // Shapes find their nearest hit in front of the origin, so it is moved to the start of the range
+    let start = ray.t_min.max(0.0);
+    let shifted = Ray {
+        origin: ray.at(start),
+        t_min: 0.0,
+        t_max: ray.t_max - start,
+        ..*ray
+    };
+    let mut hit = intersect_shape(shape, &shifted)?;
+    hit.distance += start;
+    if hit.distance < ray.t_max {
+        Some(hit)
+    } else {
+        None
+    }
+}
+
+/// Nearest hit in front of the origin of the ray
+fn intersect_shape(shape: &Shape, ray: &Ray) -> Option<RayHit> {
+    let (from, direction) = (ray.origin, ray.direction);
+    match *shape {
+        Shape::Sphere { center, radius } => ray_sphere(from, direction, center, radius),
+        Shape::Triangle { corners, uvs } => ray_triangle(from, direction, corners, uvs),
+        Shape::Plane { point, normal } => ray_plane(from, direction, point, normal),
+        Shape::Rectangle { corner, edges } => ray_rectangle(from, direction, corner, edges),
+        Shape::BilinearPatch { corners, uvs } => ray_bilinear_patch(from, direction, corners, uvs),
+        Shape::Disk {
+            center,
+            normal,
+            radius,
+        } => ray_disk(from, direction, Frame::new(center, normal), radius),
+        Shape::AxisBox { min, max } => ray_box(from, direction, min, max),
+        Shape::Cylinder { base, axis, radius } => {
+            ray_cylinder(from, direction, Frame::new(base, axis), axis.len(), radius)
+        }
+        Shape::Cone { base, axis, radius } => {
+            ray_cone(from, direction, Frame::new(base, axis), axis.len(), radius)
+        }
+        Shape::Torus {
+            center,
+            axis,
+            major_radius,
+            minor_radius,
+        } => ray_torus(
+            from,
+            direction,
+            Frame::new(center, axis),
+            major_radius,
+            minor_radius,
+        ),
+        Shape::Mesh { ref mesh } => mesh.raycast(ray),
+        Shape::Instance {
+            ref geometry,
+            ref transform,
+        } => ray_instance(ray, geometry, transform),
+        Shape::Csg {
+            operation,
+            ref operands,
+        } => ray_csg(ray, operation, operands),
+        Shape::Sdf {
+            ref sdf,
+            bounds,
+            tracing,
+        } => ray_sdf(from, direction, sdf, &bounds, tracing),
+    }
+}
+
+/// Relative error allowed for distances found by solving polynomials or by iteration
+const SOLVED_ERROR: float = 64.0 * float::EPSILON;
+
+/// Point at a distance along a ray, with a bound on its error when the distance itself is off by
+/// up to `distance_error` relative to it
+fn ray_point(
+    from: Point,
+    direction: Vector,
+    distance: float,
+    distance_error: float,
+) -> (Point, Vector) {
+    let travel = direction * distance;
+    let point = from + travel;
+    let error = from.abs() * gamma(1) + travel.abs() * (gamma(3) + distance_error);
+    (point, error)
+}
+
+/// Point at a distance along a ray, projected back onto the plane it hit
+fn plane_point(
+    from: Point,
+    direction: Vector,
+    distance: float,
+    reference: Point,
+    normal: Vector,
+) -> (Point, Vector) {
+    let point = from + direction * distance;
+    let point = point - normal * normal.dot(point - reference);
+    (point, (point.abs() + reference.abs()) * gamma(8))
+}
+
+/// World point of a point in a local frame, where `local_error` bounds its error in every direction
+fn frame_point(frame: Frame, p: Vector, local_error: float) -> (Point, Vector) {
+    let point = frame.to_world(p);
+    let e = local_error + gamma(6) * p.len();
+    let error = Vector { x: e, y: e, z: e } + (point.abs() + frame.origin.abs()) * gamma(2);
+    (point, error)
+}
+
+/// Sphere tracing, stepping along the ray by the distance to the surface
+/// https://graphics.stanford.edu/courses/cs348b-20-spring-content/uploads/hart.pdf
+fn ray_sdf(
+    from: Point,
+    direction: Vector,
+    sdf: &Sdf,
+    bounds: &Bounds,
+    tracing: SphereTracing,
+) -> Option<RayHit> {
+    let (near, far) = clip_to_bounds(bounds, from, direction)?;
+    let epsilon = tracing.epsilon;
+
+    // Which side the ray travels on
+    let mut t = near;
+    let side = sdf.distance(from + direction * t).signum();
+
+    let mut hit = None;
+    for _ in 0..tracing.max_steps {
+        if t > far {
+            return None;
+        }
+        let distance = side * sdf.distance(from + direction * t);
+        if distance < epsilon {
+            hit = Some(t);
+            break;
+        }
+        t += distance * tracing.step_scale;
+    }
+    let distance = hit?;
+
+    let (point, error) = ray_point(from, direction, distance, 0.0);
+    let normal = sdf.normal(point, epsilon);
+    let (dpdu, dpdv) = normal.coordinate_system();
+    // Tracing stops on either side within epsilon of the surface, rays leaving it must start further out
+    let e = 3.0 * epsilon;
+    let hit = RayHit {
+        object: 0,
+        primitive: 0,
+        barycentric: [0.0; 3],
+        distance,
+        point,
+        error: error + Vector { x: e, y: e, z: e },
+        normal,
+        shading_normal: normal,
+        front_face: true,
+        material_id: None,
+        color: Color::WHITE,
+        uv: [0.0, 0.0],
+        dpdu,
+        dpdv,
+        dndu: Vector::ZERO,
+        dndv: Vector::ZERO,
+    };
+    Some(face_ray(hit, direction))
+}
+
+/// Most surfaces of a single CSG operand that are followed along a ray
+const MAX_CROSSINGS: usize = 64;
+
+/// Surfaces of a closed shape along a ray, in order, and whether the ray starts inside
+fn crossings(object: &Object, ray: &Ray) -> (bool, Vec<RayHit>) {
+    let mut hits: Vec<RayHit> = Vec::new();
+    let mut next = *ray;
+    while hits.len() < MAX_CROSSINGS {
+        let mut hit = match intersect_object(object, &next) {
+            Some(hit) => hit,
+            None => break,
+        };
+        // Distances are measured from the original origin
+        let start = (next.origin - ray.origin).dot(ray.direction);
+        hit.distance += start;
+        // Continue from just past the surface
+        let origin = offset_origin(hit.point, hit.error, hit.normal, ray.direction);
+        next = Ray {
+            origin,
+            t_max: ray.t_max - (origin - ray.origin).dot(ray.direction),
+            ..*ray
+        };
+        hits.push(hit);
+    }
+    // Leaving through the first surface means the ray started inside
+    let starts_inside = matches!(hits.first(), Some(hit) if !hit.front_face);
+    (starts_inside, hits)
+}
+
+/// Combines the entry and exit points of both operands and returns the first boundary of the result.
+/// Surfaces of a subtracted operand bound the result from its inside, so their front side is flipped.
+/// https://www.cs.cornell.edu/courses/cs4620/2011fa/lectures/27csg.pdf
+fn ray_csg(ray: &Ray, operation: CsgOperation, operands: &[Object; 2]) -> Option<RayHit> {
+    let (inside_a, hits_a) = crossings(&operands[0], ray);
+    let (inside_b, hits_b) = crossings(&operands[1], ray);
+    let mut inside = [inside_a, inside_b];
+
+    let mut events: Vec<(usize, RayHit)> = hits_a
+        .into_iter()
+        .map(|hit| (0, hit))
+        .chain(hits_b.into_iter().map(|hit| (1, hit)))
+        .collect();
+    events.sort_by(|a, b| {
+        a.1.distance
+            .partial_cmp(&b.1.distance)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut was_inside = operation.contains(inside);
+    for (operand, mut hit) in events {
+        inside[operand] = hit.front_face;
+        let is_inside = operation.contains(inside);
+        if is_inside != was_inside {
+            // The normal already faces the ray, only which side is outward changes
+            hit.front_face = is_inside;
+            return Some(hit);
+        }
+        was_inside = is_inside;
+    }
+    None
+}
+
+/// Intersects shared geometry in its own space and brings the hit back to the world
+fn ray_instance(ray: &Ray, geometry: &Geometry, transform: &Transform) -> Option<RayHit> {
+    let local_direction = transform.to_object.mul_rotate(ray.direction);
+    // Distances along the unnormalized direction are world distances
+    let scale = local_direction.len();
+    let local_ray = Ray {
+        origin: transform.to_object.mul_translate(ray.origin),
+        direction: local_direction * (1.0 / scale),
+        t_min: 0.0,
+        t_max: ray.t_max * scale,
+    };
+    let mut hit = geometry.raycast(&local_ray)?;
+
+    hit.primitive += geometry.first_primitive(hit.object);
+    hit.distance /= scale;
+    let (point, error) = transform.point_with_error(hit.point, hit.error);
+    hit.point = point;
+    hit.error = error;
+    let normal = transform.normal(hit.normal);
+    let normal_scale = 1.0 / normal.len();
+    hit.normal = normal * normal_scale;
+    hit.shading_normal = transform.normal(hit.shading_normal).normalized();
+    hit.dpdu = transform.vector(hit.dpdu);
+    hit.dpdv = transform.vector(hit.dpdv);
+    // Derivatives of the normalization are left out, exact for uniform scaling
+    hit.dndu = transform.normal(hit.dndu) * normal_scale;
+    hit.dndv = transform.normal(hit.dndv) * normal_scale;
+    Some(hit)
+}
+
 /// Object is filled back later
 fn ray_sphere(from: Point, direction: Vector, center: Point, radius: float) -> Option<RayHit> {
     // Center of the sphere, shifted as if the ray was short from the origo
     let relative = center - from;
 
-    let c = relative.len2() - radius.powi(2);
-    let d = direction.dot(relative).powi(2) - c;
+    // Discriminant from the distance between the ray and the center, which loses less precision
+    // https://link.springer.com/content/pdf/10.1007/978-1-4842-4427-2_7.pdf
+    let b = direction.dot(relative);
+    let d = radius * radius - (relative - direction * b).len2();
 
     if d <= 0.0 {
         return None;
     }
 
-    let t_a = 0.5 * d.sqrt() + relative.dot(direction);
-    let t_b = -0.5 * d.sqrt() + relative.dot(direction);
-    let distance = t_a.min(t_b);
+    let c = relative.len2() - radius * radius;
+    let q = b + b.signum() * d.sqrt();
+    let (near, far) = if q == 0.0 {
+        (0.0, 0.0)
+    } else {
+        ((c / q).min(q), (c / q).max(q))
+    };
+    // Rays from inside leave through the far side, roots this close to the origin are rounding
+    // errors of rays leaving the surface
+    let epsilon = SOLVED_ERROR * relative.len();
+    let distance = if near > epsilon { near } else { far };
 
-    if distance <= 0.0 {
+    if distance <= epsilon {
         return None;
     }
 
-    let hit_point: Point = from + direction * distance;
-    let normal = (hit_point - center).normalized();
+    // Projected back onto the sphere
+    let (hit_point, _) = ray_point(from, direction, distance, 0.0);
+    let offset = hit_point - center;
+    let offset = offset * (radius / offset.len());
+    let hit_point = center + offset;
+    let error = offset.abs() * gamma(5) + hit_point.abs() * gamma(1);
+    let normal = offset * (1.0 / radius);
+
+    // Spherical coordinates, u around the y axis and v from the bottom pole
+    let u = 0.5 + normal.z.atan2(normal.x) / (2.0 * PI);
+    let v = (-normal.y).clamp(-1.0, 1.0).acos() / PI;
 
-    Some(RayHit {
+    // Distance from the y axis, kept away from zero at the poles
+    let ring = (normal.x * normal.x + normal.z * normal.z).sqrt().max(1e-6);
+    let dpdu = Vector {
+        x: -normal.z,
+        y: 0.0,
+        z: normal.x,
+    } * (2.0 * PI * radius);
+    let dpdv = Vector {
+        x: -normal.y * normal.x / ring,
+        y: ring,
+        z: -normal.y * normal.z / ring,
+    } * (PI * radius);
+
+    let hit = RayHit {
         object: 0,
+        primitive: 0,
+        barycentric: [0.0; 3],
         distance,
+        point: hit_point,
+        error,
         normal,
-    })
+        shading_normal: normal,
+        front_face: true,
+        material_id: None,
+        color: Color::WHITE,
+        uv: [u, v],
+        dpdu,
+        dpdv,
+        dndu: dpdu * (1.0 / radius),
+        dndv: dpdv * (1.0 / radius),
+    };
+    Some(face_ray(hit, direction))
 }
 
-/// Object is filled back later
-fn ray_triange(from: Point, direction: Vector, corners: [Point; 3]) -> Option<RayHit> {
-    let edge1 = corners[1] - corners[0];
-    let edge2 = corners[2] - corners[0];
+/// Distance to a triangle and the barycentric weights of its corners at the hit.
+/// Rays through an edge shared by two triangles hit at least one of them, possibly both, so
+/// meshes have no cracks, and degenerate triangles are never hit.
+/// https://www.pbr-book.org/3ed-2018/Shapes/Triangle_Meshes#TriangleIntersection
+pub fn ray_triangle_barycentric(
+    from: Point,
+    direction: Vector,
+    corners: [Point; 3],
+) -> Option<(float, [float; 3])> {
+    // Make the largest direction component z, so the shear below is well defined
+    let d = direction.abs();
+    let kz = if d.x > d.y && d.x > d.z {
+        0
+    } else if d.y > d.z {
+        1
+    } else {
+        2
+    };
+    let (kx, ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if direction[kz] == 0.0 {
+        return None;
+    }
 
-    if edge1.len2() < 0.00001 || edge2.len2() < 0.00001 {
+    // Corners relative to the ray origin, sheared so that the ray points along +z
+    let shear_x = -direction[kx] / direction[kz];
+    let shear_y = -direction[ky] / direction[kz];
+    let shear_z = 1.0 / direction[kz];
+    let mut p = [[0.0 as float; 3]; 3];
+    for (p, corner) in p.iter_mut().zip(corners.iter()) {
+        let relative = *corner - from;
+        *p = [
+            relative[kx] + shear_x * relative[kz],
+            relative[ky] + shear_y * relative[kz],
+            relative[kz],
+        ];
+    }
+
+    // Edge functions, twice the signed areas of the triangles the ray forms with each edge
+    let mut e = [0.0 as float; 3];
+    for i in 0..3 {
+        let (a, b) = (p[(i + 1) % 3], p[(i + 2) % 3]);
+        e[i] = a[0] * b[1] - a[1] * b[0];
+    }
+    if e.contains(&0.0) {
+        // Exactly on an edge in single precision, decide with double precision instead
+        for i in 0..3 {
+            let (a, b) = (p[(i + 1) % 3], p[(i + 2) % 3]);
+            e[i] = (a[0] as f64 * b[1] as f64 - a[1] as f64 * b[0] as f64) as float;
+        }
+    }
+    let negative = e.iter().any(|&e| e < 0.0);
+    let positive = e.iter().any(|&e| e > 0.0);
+    if negative && positive {
+        return None;
+    }
+    let det = e[0] + e[1] + e[2];
+    if det == 0.0 {
         return None;
     }
 
-    let normal = edge1.normalized().cross(edge2.normalized());
-    if normal.len2() < 0.0001 {
-        println!("{:?}", edge1);
-        println!("{:?}", edge2);
-        println!("{:?}", normal);
-        panic!("!!");
-        // return None;
+    // Distance scaled by the determinant, which must be in front of the origin
+    for p in p.iter_mut() {
+        p[2] *= shear_z;
+    }
+    let t_scaled = e[0] * p[0][2] + e[1] * p[1][2] + e[2] * p[2][2];
+    if (det < 0.0 && t_scaled >= 0.0) || (det > 0.0 && t_scaled <= 0.0) {
+        return None;
     }
-    
-    let normal = normal.normalized();
+    let inverse_det = 1.0 / det;
+    let distance = t_scaled * inverse_det;
 
-    // Is parallel?
-    if normal.dot(direction).abs() < 0.000001 {
+    // Reject distances that could be zero or negative after rounding
+    let max_of = |axis: usize| {
+        p.iter()
+            .map(|p| p[axis].abs())
+            .fold(0.0 as float, float::max)
+    };
+    let (max_x, max_y, max_z) = (max_of(0), max_of(1), max_of(2));
+    let delta_z = gamma(3) * max_z;
+    let delta_x = gamma(5) * (max_x + max_z);
+    let delta_y = gamma(5) * (max_y + max_z);
+    let delta_e = 2.0 * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let max_e = e.iter().fold(0.0 as float, |m, e| m.max(e.abs()));
+    let delta_t =
+        3.0 * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inverse_det.abs();
+    if distance <= delta_t {
         return None;
     }
 
-    // Plane intersection point
-    let distance = (normal.dot(corners[0] - from)) / normal.dot(direction);
+    Some((
+        distance,
+        [e[0] * inverse_det, e[1] * inverse_det, e[2] * inverse_det],
+    ))
+}
+
+/// Object is filled back later
+fn ray_triangle(
+    from: Point,
+    direction: Vector,
+    corners: [Point; 3],
+    uvs: [[float; 2]; 3],
+) -> Option<RayHit> {
+    let (distance, barycentric) = ray_triangle_barycentric(from, direction, corners)?;
+    let normal = triangle_normal(corners)?;
+    Some(triangle_hit(
+        direction,
+        distance,
+        barycentric,
+        corners,
+        uvs,
+        normal,
+    ))
+}
+
+/// Unit normal on the side the corners go around counterclockwise,
+/// `None` if the triangle is too thin or too large for one
+pub(crate) fn triangle_normal(corners: [Point; 3]) -> Option<Vector> {
+    let area = (corners[1] - corners[0]).cross(corners[2] - corners[0]);
+    if area.len2() == 0.0 || !area.len2().is_finite() {
+        None
+    } else {
+        Some(area.normalized())
+    }
+}
+
+/// Hit on a triangle from the barycentric weights of its corners, `normal` is the unit normal
+/// on the side the corners go around counterclockwise.
+/// Object is filled back later.
+pub(crate) fn triangle_hit(
+    direction: Vector,
+    distance: float,
+    [b0, b1, b2]: [float; 3],
+    corners: [Point; 3],
+    uvs: [[float; 2]; 3],
+    normal: Vector,
+) -> RayHit {
+    let edge1 = corners[1] - corners[0];
+
+    // Interpolating the corners gives a point with a known error bound, unlike following the ray
+    // https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error#Triangles
+    let weighted = [corners[0] * b0, corners[1] * b1, corners[2] * b2];
+    let point = weighted[0] + weighted[1] + weighted[2];
+    let error = (weighted[0].abs() + weighted[1].abs() + weighted[2].abs()) * gamma(7);
+    let uv = [
+        b0 * uvs[0][0] + b1 * uvs[1][0] + b2 * uvs[2][0],
+        b0 * uvs[0][1] + b1 * uvs[1][1] + b2 * uvs[2][1],
+    ];
 
-    // Behind the ray?
-    if distance <= 0.0001 {
+    // Position derivatives from the texture coordinate differences
+    let duv02 = [uvs[0][0] - uvs[2][0], uvs[0][1] - uvs[2][1]];
+    let duv12 = [uvs[1][0] - uvs[2][0], uvs[1][1] - uvs[2][1]];
+    let dp02 = corners[0] - corners[2];
+    let dp12 = corners[1] - corners[2];
+    let det = duv02[0] * duv12[1] - duv02[1] * duv12[0];
+    let (dpdu, dpdv) = if det.abs() < 1e-10 {
+        // Degenerate UVs, any tangent frame will do
+        (edge1, normal.cross(edge1))
+    } else {
+        (
+            (dp02 * duv12[1] - dp12 * duv02[1]) * (1.0 / det),
+            (dp12 * duv02[0] - dp02 * duv12[0]) * (1.0 / det),
+        )
+    };
+
+    face_ray(
+        RayHit {
+            object: 0,
+            primitive: 0,
+            barycentric: [b0, b1, b2],
+            distance,
+            point,
+            error,
+            normal,
+            shading_normal: normal,
+            front_face: true,
+            material_id: None,
+            color: Color::WHITE,
+            uv,
+            dpdu,
+            dpdv,
+            dndu: Vector::ZERO,
+            dndv: Vector::ZERO,
+        },
+        direction,
+    )
+}
+
+/// Turns the outward normal of a hit against the ray
+fn face_ray(mut hit: RayHit, direction: Vector) -> RayHit {
+    hit.front_face = hit.normal.dot(direction) < 0.0;
+    if !hit.front_face {
+        hit.normal = -hit.normal;
+        hit.shading_normal = -hit.shading_normal;
+        hit.dndu = -hit.dndu;
+        hit.dndv = -hit.dndv;
+    }
+    hit
+}
+
+/// Roots of a x² + b x + c in increasing order
+fn quadratic(a: float, b: float, c: float) -> Option<(float, float)> {
+    if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
         return None;
     }
+    // Avoids cancellation between b and the square root
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    Some((t0.min(t1), t0.max(t1)))
+}
 
-    let hit_point = from + direction * distance;
+/// Nearest candidate distance in front of the origin
+fn nearest<T>(candidates: impl IntoIterator<Item = (float, T)>) -> Option<(float, T)> {
+    candidates
+        .into_iter()
+        .filter(|(t, _)| *t > 0.0 && t.is_finite())
+        .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal))
+}
 
-    // Inside-outside test for each edge
+/// Angle around the local y axis as a fraction of a full turn
+fn turn(p: Vector) -> float {
+    (p.z.atan2(p.x) / (2.0 * PI)).rem_euclid(1.0)
+}
+
+/// Derivative of a point on a circle around the local y axis with respect to its `turn`
+fn dpdturn(p: Vector) -> Vector {
+    Vector {
+        x: -p.z,
+        y: 0.0,
+        z: p.x,
+    } * (2.0 * PI)
+}
 
-    let edge = corners[1] - corners[0];
-    // assert!(edge.len2() > 0.0001);
-    let vp = hit_point - corners[0];
-    if vp.len2() < 0.0001 {
+/// Object is filled back later
+fn ray_plane(from: Point, direction: Vector, point: Point, normal: Vector) -> Option<RayHit> {
+    let frame = Frame::new(point, normal);
+    let o = frame.to_local(from);
+    let d = frame.dir_to_local(direction);
+    if d.y.abs() < 1e-8 {
         return None;
     }
-    if normal.dot(edge.cross(vp)) < 0.0 {
+
+    let distance = -o.y / d.y;
+    if distance <= 0.0 {
         return None;
     }
 
-    let edge = corners[2] - corners[1];
-    // assert!(edge.len2() > 0.0001);
-    let vp = hit_point - corners[1];
-    if vp.len2() < 0.0001 {
+    // World units along the plane
+    let p = o + d * distance;
+    let (hit_point, error) = plane_point(from, direction, distance, point, frame.y);
+    Some(face_ray(
+        RayHit {
+            object: 0,
+            primitive: 0,
+            barycentric: [0.0; 3],
+            distance,
+            point: hit_point,
+            error,
+            normal: frame.y,
+            shading_normal: frame.y,
+            front_face: true,
+            material_id: None,
+            color: Color::WHITE,
+            uv: [p.x, p.z],
+            dpdu: frame.x,
+            dpdv: frame.z,
+            dndu: Vector::ZERO,
+            dndv: Vector::ZERO,
+        },
+        direction,
+    ))
+}
+
+/// Object is filled back later
+fn ray_rectangle(
+    from: Point,
+    direction: Vector,
+    corner: Point,
+    edges: [Vector; 2],
+) -> Option<RayHit> {
+    let n = edges[0].cross(edges[1]);
+    let denominator = n.dot(direction);
+    if denominator.abs() < 1e-8 * n.len() {
         return None;
     }
-    if normal.dot(edge.cross(vp)) < 0.0 {
+
+    let distance = n.dot(corner - from) / denominator;
+    if distance <= 0.0 {
         return None;
     }
 
-    let edge = corners[0] - corners[2];
-    // assert!(edge.len2() > 0.0001);
-    let vp = hit_point - corners[2];
-    if vp.len2() < 0.0001 {
+    // Coordinates along both edges
+    let p = from + direction * distance - corner;
+    let u = p.cross(edges[1]).dot(n) / n.len2();
+    let v = edges[0].cross(p).dot(n) / n.len2();
+    if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
         return None;
     }
-    if normal.dot(edge.cross(vp)) < 0.0 {
+
+    let normal = n.normalized();
+    let (point, error) = plane_point(from, direction, distance, corner, normal);
+    Some(face_ray(
+        RayHit {
+            object: 0,
+            primitive: 0,
+            barycentric: [0.0; 3],
+            distance,
+            point,
+            error,
+            normal,
+            shading_normal: normal,
+            front_face: true,
+            material_id: None,
+            color: Color::WHITE,
+            uv: [u, v],
+            dpdu: edges[0],
+            dpdv: edges[1],
+            dndu: Vector::ZERO,
+            dndv: Vector::ZERO,
+        },
+        direction,
+    ))
+}
+
+/// Bilinear patch with `corners` at uv (0, 0), (1, 0), (0, 1) and (1, 1), facing dpdu × dpdv.
+/// Object is filled back later.
+/// https://link.springer.com/content/pdf/10.1007/978-1-4842-4427-2_8.pdf
+fn ray_bilinear_patch(
+    from: Point,
+    direction: Vector,
+    corners: [Point; 4],
+    uvs: [[float; 2]; 4],
+) -> Option<RayHit> {
+    let [p00, p10, p01, p11] = corners;
+
+    // Each u picks a line across the patch, which the ray meets when their distance is zero
+    let a = (p10 - p00).cross(p01 - p11).dot(direction);
+    let c = (p00 - from).cross(direction).dot(p01 - p00);
+    let b = (p10 - from).cross(direction).dot(p11 - p10) - a - c;
+    let (u1, u2) = quadratic(a, b, c)?;
+
+    let max_abs = |v: Vector| v.x.abs().max(v.y.abs()).max(v.z.abs());
+    let epsilon = gamma(10)
+        * (max_abs(from)
+            + max_abs(direction)
+            + max_abs(p00)
+            + max_abs(p10)
+            + max_abs(p01)
+            + max_abs(p11));
+
+    // Distance and v along the line at each u
+    let on_line = |u: float| -> Option<(float, float, float)> {
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let line_from = p00 + (p10 - p00) * u;
+        let line = p01 + (p11 - p01) * u - line_from;
+        let delta = line_from - from;
+        let perpendicular = direction.cross(line);
+        let p2 = perpendicular.len2();
+        let v = delta.dot(direction.cross(perpendicular));
+        let t = delta.dot(line.cross(perpendicular));
+        if t > p2 * epsilon && (0.0..=p2).contains(&v) {
+            Some((t / p2, u, v / p2))
+        } else {
+            None
+        }
+    };
+    let (distance, u, v) = match (on_line(u1), on_line(u2)) {
+        (Some(a), Some(b)) => {
+            if a.0 <= b.0 {
+                a
+            } else {
+                b
+            }
+        }
+        (a, b) => a.or(b)?,
+    };
+
+    let lerp = |a: Point, b: Point, t: float| a + (b - a) * t;
+    let point = lerp(lerp(p00, p10, u), lerp(p01, p11, u), v);
+    let error = (p00.abs() + p10.abs() + p01.abs() + p11.abs()) * gamma(6);
+    let mut dpdu = lerp(p10, p11, v) - lerp(p00, p01, v);
+    let mut dpdv = lerp(p01, p11, u) - lerp(p00, p10, u);
+    let mut area = dpdu.cross(dpdv);
+    if area.len2() == 0.0 {
+        // Collapsed edge, such as the apex of a triangle shaped patch
+        area = (p10 - p00).cross(p01 - p00) + (p11 - p01).cross(p11 - p10);
+        if area.len2() == 0.0 {
+            return None;
+        }
+    }
+    let normal = area.normalized();
+
+    // Normal derivatives from the Weingarten equations, the only second derivative is the twist
+    let twist = normal.dot(p00 - p10 - p01 + p11);
+    let (e, f, g) = (dpdu.dot(dpdu), dpdu.dot(dpdv), dpdv.dot(dpdv));
+    let inverse = 1.0 / (e * g - f * f);
+    let (mut dndu, mut dndv) = if inverse.is_finite() {
+        (
+            dpdu * (twist * f * inverse) - dpdv * (twist * e * inverse),
+            dpdv * (twist * f * inverse) - dpdu * (twist * g * inverse),
+        )
+    } else {
+        (Vector::ZERO, Vector::ZERO)
+    };
+
+    // Derivatives with respect to the texture coordinates instead of the patch parameters
+    let uv_at = |axis: usize| {
+        let lerp = |a: float, b: float, t: float| a + (b - a) * t;
+        let [a, b, c, d] = [uvs[0][axis], uvs[1][axis], uvs[2][axis], uvs[3][axis]];
+        (
+            lerp(lerp(a, b, u), lerp(c, d, u), v),
+            lerp(b, d, v) - lerp(a, c, v),
+            lerp(c, d, u) - lerp(a, b, u),
+        )
+    };
+    let ((s, dsdu, dsdv), (t, dtdu, dtdv)) = (uv_at(0), uv_at(1));
+    let det = dsdu * dtdv - dsdv * dtdu;
+    if det.abs() > 1e-10 && uvs != Shape::PATCH_UVS {
+        let to_st = |du: Vector, dv: Vector| {
+            (
+                (du * dtdv - dv * dtdu) * (1.0 / det),
+                (dv * dsdu - du * dsdv) * (1.0 / det),
+            )
+        };
+        let (dpds, dpdt) = to_st(dpdu, dpdv);
+        let (dnds, dndt) = to_st(dndu, dndv);
+        dpdu = dpds;
+        dpdv = dpdt;
+        dndu = dnds;
+        dndv = dndt;
+    }
+
+    Some(face_ray(
+        RayHit {
+            object: 0,
+            primitive: 0,
+            barycentric: [0.0; 3],
+            distance,
+            point,
+            error,
+            normal,
+            shading_normal: normal,
+            front_face: true,
+            material_id: None,
+            color: Color::WHITE,
+            uv: [s, t],
+            dpdu,
+            dpdv,
+            dndu,
+            dndv,
+        },
+        direction,
+    ))
+}
+
+/// Hit on a disk at height `y` of the local frame, facing `facing` along y
+fn disk_hit(frame: Frame, p: Vector, radius: float, facing: float, distance: float) -> RayHit {
+    let rho = (p.x * p.x + p.z * p.z).sqrt();
+    let radial = if rho > 0.0 {
+        Vector {
+            x: p.x / rho,
+            y: 0.0,
+            z: p.z / rho,
+        }
+    } else {
+        axis_vector(0)
+    };
+    let (point, error) = frame_point(frame, p, 0.0);
+    let normal = frame.y * facing;
+    RayHit {
+        object: 0,
+        primitive: 0,
+        barycentric: [0.0; 3],
+        distance,
+        point,
+        error,
+        normal,
+        shading_normal: normal,
+        front_face: true,
+        material_id: None,
+        color: Color::WHITE,
+        uv: [turn(p), rho / radius],
+        dpdu: frame.dir_to_world(dpdturn(p)),
+        dpdv: frame.dir_to_world(radial * radius),
+        dndu: Vector::ZERO,
+        dndv: Vector::ZERO,
+    }
+}
+
+/// Local point moved exactly onto the plane at height `y`
+fn on_height(p: Vector, y: float) -> Vector {
+    Vector { y, ..p }
+}
+
+/// Distance to the disk of `radius` at height `y` of the local frame
+fn ray_local_disk(o: Vector, d: Vector, y: float, radius: float) -> Option<float> {
+    if d.y.abs() < 1e-8 {
         return None;
     }
+    let t = (y - o.y) / d.y;
+    let p = o + d * t;
+    if p.x * p.x + p.z * p.z <= radius * radius && t > 0.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
 
-    Some(RayHit {
+/// Object is filled back later
+fn ray_disk(from: Point, direction: Vector, frame: Frame, radius: float) -> Option<RayHit> {
+    let o = frame.to_local(from);
+    let d = frame.dir_to_local(direction);
+    let (distance, _) = nearest(ray_local_disk(o, d, 0.0, radius).map(|t| (t, ())))?;
+    let hit = disk_hit(
+        frame,
+        on_height(o + d * distance, 0.0),
+        radius,
+        1.0,
+        distance,
+    );
+    Some(face_ray(hit, direction))
+}
+
+/// Object is filled back later
+fn ray_box(from: Point, direction: Vector, min: Point, max: Point) -> Option<RayHit> {
+    // Slab test, remembering which axis each side of the interval came from
+    let (mut near, mut far) = ((float::NEG_INFINITY, 0), (float::INFINITY, 0));
+    for axis in 0..3 {
+        let inverse = 1.0 / direction[axis];
+        let mut t0 = (min[axis] - from[axis]) * inverse;
+        let mut t1 = (max[axis] - from[axis]) * inverse;
+        if t0 > t1 {
+            std::mem::swap(&mut t0, &mut t1);
+        }
+        if t0 > near.0 {
+            near = (t0, axis);
+        }
+        if t1 < far.0 {
+            far = (t1, axis);
+        }
+    }
+    if near.0 > far.0 {
+        return None;
+    }
+
+    let (distance, axis) = nearest(vec![near, far])?;
+    let (mut p, mut error) = ray_point(from, direction, distance, gamma(3));
+    let size = max - min;
+    let outward = if (p[axis] - min[axis]).abs() < (p[axis] - max[axis]).abs() {
+        -1.0
+    } else {
+        1.0
+    };
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+
+    // Exactly on the face
+    let face = if outward < 0.0 { min[axis] } else { max[axis] };
+    let with_axis = |v: Vector, value: float| match axis {
+        0 => Vector { x: value, ..v },
+        1 => Vector { y: value, ..v },
+        _ => Vector { z: value, ..v },
+    };
+    p = with_axis(p, face);
+    error = with_axis(error, 0.0);
+    let normal = axis_vector(axis) * outward;
+
+    Some(face_ray(
+        RayHit {
+            object: 0,
+            primitive: 0,
+            barycentric: [0.0; 3],
+            distance,
+            point: p,
+            error,
+            normal,
+            shading_normal: normal,
+            front_face: true,
+            material_id: None,
+            color: Color::WHITE,
+            uv: [(p[a] - min[a]) / size[a], (p[b] - min[b]) / size[b]],
+            dpdu: axis_vector(a) * size[a],
+            dpdv: axis_vector(b) * size[b],
+            dndu: Vector::ZERO,
+            dndv: Vector::ZERO,
+        },
+        direction,
+    ))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RoundPart {
+    Side,
+    Base,
+    Top,
+}
+
+/// Object is filled back later
+fn ray_cylinder(
+    from: Point,
+    direction: Vector,
+    frame: Frame,
+    height: float,
+    radius: float,
+) -> Option<RayHit> {
+    let o = frame.to_local(from);
+    let d = frame.dir_to_local(direction);
+
+    let mut candidates = Vec::with_capacity(4);
+    let a = d.x * d.x + d.z * d.z;
+    let b = 2.0 * (o.x * d.x + o.z * d.z);
+    let c = o.x * o.x + o.z * o.z - radius * radius;
+    // Roots this close to the origin are rounding errors of rays leaving the surface
+    let epsilon = SOLVED_ERROR * (o.len() + radius);
+    if let Some((t0, t1)) = quadratic(a, b, c) {
+        for &t in [t0, t1].iter() {
+            if t > epsilon && (0.0..=height).contains(&(o.y + d.y * t)) {
+                candidates.push((t, RoundPart::Side));
+            }
+        }
+    }
+    candidates.extend(ray_local_disk(o, d, 0.0, radius).map(|t| (t, RoundPart::Base)));
+    candidates.extend(ray_local_disk(o, d, height, radius).map(|t| (t, RoundPart::Top)));
+
+    let (distance, part) = nearest(candidates)?;
+    let p = o + d * distance;
+    let hit = match part {
+        RoundPart::Side => {
+            // Projected back onto the side
+            let rho = (p.x * p.x + p.z * p.z).sqrt();
+            let outward = Vector {
+                x: p.x / rho,
+                y: 0.0,
+                z: p.z / rho,
+            };
+            let p = on_height(outward * radius, p.y);
+            let (point, error) = frame_point(frame, p, 0.0);
+            let normal = frame.dir_to_world(outward);
+            RayHit {
+                object: 0,
+                primitive: 0,
+                barycentric: [0.0; 3],
+                distance,
+                point,
+                error,
+                normal,
+                shading_normal: normal,
+                front_face: true,
+                material_id: None,
+                color: Color::WHITE,
+                uv: [turn(p), p.y / height],
+                dpdu: frame.dir_to_world(dpdturn(p)),
+                dpdv: frame.y * height,
+                dndu: frame.dir_to_world(dpdturn(p) * (1.0 / radius)),
+                dndv: Vector::ZERO,
+            }
+        }
+        RoundPart::Base => disk_hit(frame, on_height(p, 0.0), radius, -1.0, distance),
+        RoundPart::Top => disk_hit(frame, on_height(p, height), radius, 1.0, distance),
+    };
+    Some(face_ray(hit, direction))
+}
+
+/// Object is filled back later
+fn ray_cone(
+    from: Point,
+    direction: Vector,
+    frame: Frame,
+    height: float,
+    radius: float,
+) -> Option<RayHit> {
+    let o = frame.to_local(from);
+    let d = frame.dir_to_local(direction);
+
+    // x² + z² = (k (h - y))²
+    let k = radius / height;
+    let w = height - o.y;
+    let mut candidates = Vec::with_capacity(3);
+    let a = d.x * d.x + d.z * d.z - k * k * d.y * d.y;
+    let b = 2.0 * (o.x * d.x + o.z * d.z) + 2.0 * k * k * w * d.y;
+    let c = o.x * o.x + o.z * o.z - k * k * w * w;
+    // Roots this close to the origin are rounding errors of rays leaving the surface
+    let epsilon = SOLVED_ERROR * (o.len() + radius);
+    if let Some((t0, t1)) = quadratic(a, b, c) {
+        for &t in [t0, t1].iter() {
+            if t > epsilon && (0.0..=height).contains(&(o.y + d.y * t)) {
+                candidates.push((t, RoundPart::Side));
+            }
+        }
+    }
+    candidates.extend(ray_local_disk(o, d, 0.0, radius).map(|t| (t, RoundPart::Base)));
+
+    let (distance, part) = nearest(candidates)?;
+    let p = o + d * distance;
+    let hit = match part {
+        RoundPart::Side => {
+            let phi = 2.0 * PI * turn(p);
+            let slant = (height * height + radius * radius).sqrt();
+            let (sin, cos) = phi.sin_cos();
+            let outward = Vector {
+                x: cos * height,
+                y: radius,
+                z: sin * height,
+            } * (1.0 / slant);
+            let (point, error) = frame_point(frame, p, (o.len() + distance) * SOLVED_ERROR);
+            let normal = frame.dir_to_world(outward);
+            RayHit {
+                object: 0,
+                primitive: 0,
+                barycentric: [0.0; 3],
+                distance,
+                point,
+                error,
+                normal,
+                shading_normal: normal,
+                front_face: true,
+                material_id: None,
+                color: Color::WHITE,
+                uv: [turn(p), p.y / height],
+                dpdu: frame.dir_to_world(dpdturn(p)),
+                dpdv: frame.dir_to_world(Vector {
+                    x: -radius * cos,
+                    y: height,
+                    z: -radius * sin,
+                }),
+                dndu: frame.dir_to_world(
+                    Vector {
+                        x: -sin,
+                        y: 0.0,
+                        z: cos,
+                    } * (2.0 * PI * height / slant),
+                ),
+                dndv: Vector::ZERO,
+            }
+        }
+        _ => disk_hit(frame, on_height(p, 0.0), radius, -1.0, distance),
+    };
+    Some(face_ray(hit, direction))
+}
+
+/// Object is filled back later
+fn ray_torus(
+    from: Point,
+    direction: Vector,
+    frame: Frame,
+    major_radius: float,
+    minor_radius: float,
+) -> Option<RayHit> {
+    let o = frame.to_local(from);
+    let d = frame.dir_to_local(direction);
+
+    // Solve from the point closest to the center, in units of the major radius,
+    // to keep the quartic well conditioned
+    let shift = -o.dot(d);
+    let scale = major_radius as f64;
+    let (ox, oy, oz) = (
+        (o.x + d.x * shift) as f64 / scale,
+        (o.y + d.y * shift) as f64 / scale,
+        (o.z + d.z * shift) as f64 / scale,
+    );
+    let (dx, dy, dz) = (d.x as f64, d.y as f64, d.z as f64);
+    let r = minor_radius as f64 / scale;
+
+    // (|p|² + R² - r²)² = 4 R² (x² + z²) with R = 1
+    let f = ox * dx + oy * dy + oz * dz;
+    let g = ox * ox + oy * oy + oz * oz + 1.0 - r * r;
+    let coefficients = [
+        1.0,
+        4.0 * f,
+        4.0 * f * f + 2.0 * g - 4.0 * (dx * dx + dz * dz),
+        4.0 * f * g - 8.0 * (ox * dx + oz * dz),
+        g * g - 4.0 * (ox * ox + oz * oz),
+    ];
+    let roots = solve_quartic(coefficients);
+    // Roots this close to the origin are rounding errors of rays leaving the surface
+    let epsilon = SOLVED_ERROR * (o.len() + major_radius + minor_radius);
+    let (distance, _) = nearest(
+        roots
+            .into_iter()
+            .map(|t| ((t * scale) as float + shift, ()))
+            .filter(|&(t, _)| t > epsilon),
+    )?;
+
+    let p = o + d * distance;
+    let rho = (p.x * p.x + p.z * p.z).sqrt().max(1e-12);
+    let theta = p.y.atan2(rho - major_radius);
+    let (sin_phi, cos_phi) = (p.z / rho, p.x / rho);
+    let (sin_theta, cos_theta) = theta.sin_cos();
+    let outward = Vector {
+        x: cos_theta * cos_phi,
+        y: sin_theta,
+        z: cos_theta * sin_phi,
+    };
+    let dndv = Vector {
+        x: -sin_theta * cos_phi,
+        y: cos_theta,
+        z: -sin_theta * sin_phi,
+    } * (2.0 * PI);
+
+    let (point, error) = frame_point(frame, p, (o.len() + distance) * SOLVED_ERROR);
+    let normal = frame.dir_to_world(outward);
+    let hit = RayHit {
         object: 0,
+        primitive: 0,
+        barycentric: [0.0; 3],
         distance,
-        normal: if normal.dot(direction) >= 0.0 {
-            -normal
+        point,
+        error,
+        normal,
+        shading_normal: normal,
+        front_face: true,
+        material_id: None,
+        color: Color::WHITE,
+        uv: [turn(p), (theta / (2.0 * PI)).rem_euclid(1.0)],
+        dpdu: frame.dir_to_world(dpdturn(p)),
+        dpdv: frame.dir_to_world(dndv * minor_radius),
+        dndu: frame.dir_to_world(
+            Vector {
+                x: -sin_phi,
+                y: 0.0,
+                z: cos_phi,
+            } * (2.0 * PI * cos_theta),
+        ),
+        dndv: frame.dir_to_world(dndv),
+    };
+    Some(face_ray(hit, direction))
+}
+
+/// Real roots of x² + p x + q
+fn solve_quadratic(p: f64, q: f64) -> Vec<f64> {
+    let discriminant = p * p / 4.0 - q;
+    if discriminant.abs() < 1e-14 {
+        vec![-p / 2.0]
+    } else if discriminant < 0.0 {
+        Vec::new()
+    } else {
+        let s = discriminant.sqrt();
+        vec![-p / 2.0 + s, -p / 2.0 - s]
+    }
+}
+
+/// Real roots of x³ + a x² + b x + c
+fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // Substitute x = y - a/3 to eliminate the quadratic term
+    let p = (b - a * a / 3.0) / 3.0;
+    let q = (2.0 / 27.0 * a * a * a - a * b / 3.0 + c) / 2.0;
+    let cube_p = p * p * p;
+    let discriminant = q * q + cube_p;
+
+    let roots = if discriminant.abs() < 1e-14 {
+        if q.abs() < 1e-14 {
+            vec![0.0]
         } else {
-            normal
-        },
-    })
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if discriminant < 0.0 {
+        // Three real roots
+        let phi = (-q / (-cube_p).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        let third = std::f64::consts::PI / 3.0;
+        vec![
+            t * phi.cos(),
+            -t * (phi + third).cos(),
+            -t * (phi - third).cos(),
+        ]
+    } else {
+        let s = discriminant.sqrt();
+        vec![(s - q).cbrt() - (s + q).cbrt()]
+    };
+
+    roots.into_iter().map(|y| y - a / 3.0).collect()
+}
+
+/// Real roots of a polynomial of degree four, coefficients from the highest power.
+/// Ferrari's method, polished with Newton's method.
+/// https://github.com/erich666/GraphicsGems/blob/master/gems/Roots3And4.c
+fn solve_quartic(coefficients: [f64; 5]) -> Vec<f64> {
+    let [a, b, c, d, e] = coefficients;
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+
+    // Substitute x = y - b/4 to get y⁴ + p y² + q y + r
+    let b2 = b * b;
+    let p = -3.0 / 8.0 * b2 + c;
+    let q = b2 * b / 8.0 - b * c / 2.0 + d;
+    let r = -3.0 / 256.0 * b2 * b2 + b2 * c / 16.0 - b * d / 4.0 + e;
+
+    let mut roots = Vec::with_capacity(4);
+    if r.abs() < 1e-14 {
+        roots.push(0.0);
+        roots.extend(solve_cubic(0.0, p, q));
+    } else {
+        // Any real root of the resolvent cubic splits the quartic into two quadratics
+        let z = solve_cubic(-p / 2.0, -r, r * p / 2.0 - q * q / 8.0)[0];
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if u.abs() < 1e-14 {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return roots;
+        };
+        let v = if v.abs() < 1e-14 {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return roots;
+        };
+        let v = if q < 0.0 { -v } else { v };
+        roots.extend(solve_quadratic(v, z - u));
+        roots.extend(solve_quadratic(-v, z + u));
+    }
+
+    let eval = |x: f64| (((x + b) * x + c) * x + d) * x + e;
+    let derivative = |x: f64| ((4.0 * x + 3.0 * b) * x + 2.0 * c) * x + d;
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut x = y - b / 4.0;
+            for _ in 0..2 {
+                let slope = derivative(x);
+                if slope != 0.0 {
+                    x -= eval(x) / slope;
+                }
+            }
+            x
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    fn hit(shape: Shape, from: Point, direction: Vector) -> Option<RayHit> {
+        intersect(&shape, &Ray::new(from, direction))
+    }
+
+    fn assert_close(a: Vector, b: Vector) {
+        assert!((a - b).len() < 0.001, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn plane_both_sides() {
+        let plane = Shape::Plane {
+            point: v(0.0, 1.0, 0.0),
+            normal: v(0.0, 2.0, 0.0),
+        };
+        let h = hit(plane.clone(), v(3.0, 6.0, 4.0), v(0.0, -1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 5.0));
+        assert!(h.front_face);
+        assert_close(h.normal, v(0.0, 1.0, 0.0));
+
+        let h = hit(plane.clone(), v(3.0, -1.0, 4.0), v(0.0, 1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 2.0));
+        assert!(!h.front_face);
+        assert_close(h.normal, v(0.0, -1.0, 0.0));
+
+        assert!(hit(plane, v(3.0, 6.0, 4.0), v(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn rectangle_uv_and_bounds() {
+        let rectangle = Shape::Rectangle {
+            corner: v(0.0, 0.0, 0.0),
+            edges: [v(2.0, 0.0, 0.0), v(0.0, 0.0, 4.0)],
+        };
+        let h = hit(rectangle.clone(), v(1.0, 5.0, 3.0), v(0.0, -1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 5.0));
+        assert!(approx_eq(h.uv[0], 0.5) && approx_eq(h.uv[1], 0.75));
+        // Edges in this order face down
+        assert!(!h.front_face);
+
+        assert!(hit(rectangle, v(3.0, 5.0, 1.0), v(0.0, -1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn bilinear_patch_planar_and_curved() {
+        let corner = v(1.0, 2.0, 3.0);
+        let edges = [v(2.0, 0.0, 1.0), v(0.0, 3.0, 0.0)];
+        let rectangle = Shape::Rectangle { corner, edges };
+        let patch = Shape::BilinearPatch {
+            corners: [
+                corner,
+                corner + edges[0],
+                corner + edges[1],
+                corner + edges[0] + edges[1],
+            ],
+            uvs: Shape::PATCH_UVS,
+        };
+        assert!(approx_eq(patch.area(), rectangle.area()));
+        for i in 0..25 {
+            let from = v(
+                (i % 5) as float * 0.6 + 0.05,
+                2.5 + (i / 5) as float * 0.6,
+                -2.0,
+            );
+            let direction = v(0.2, 0.1, 1.0);
+            let a = hit(rectangle.clone(), from, direction);
+            let b = hit(patch.clone(), from, direction);
+            assert_eq!(a.is_some(), b.is_some(), "{}", i);
+            if let (Some(a), Some(b)) = (a, b) {
+                assert!((a.distance - b.distance).abs() < 0.001);
+                assert_close(a.normal, b.normal);
+                assert!((a.uv[0] - b.uv[0]).abs() < 0.001 && (a.uv[1] - b.uv[1]).abs() < 0.001);
+                assert_close(b.dndu, Vector::ZERO);
+            }
+        }
+
+        // Saddle z = x y
+        let saddle = Shape::BilinearPatch {
+            corners: [
+                v(0.0, 0.0, 0.0),
+                v(1.0, 0.0, 0.0),
+                v(0.0, 1.0, 0.0),
+                v(1.0, 1.0, 1.0),
+            ],
+            uvs: Shape::PATCH_UVS,
+        };
+        for i in 0..25 {
+            let (x, y) = (
+                (i % 5) as float * 0.24 + 0.02,
+                (i / 5) as float * 0.24 + 0.02,
+            );
+            let h = hit(saddle.clone(), v(x, y, 5.0), v(0.0, 0.0, -1.0)).unwrap();
+            assert_close(h.point, v(x, y, x * y));
+            assert!((h.uv[0] - x).abs() < 0.001 && (h.uv[1] - y).abs() < 0.001);
+            assert_close(h.normal, v(-y, -x, 1.0).normalized());
+        }
+        assert!(hit(saddle, v(1.5, 0.5, 5.0), v(0.0, 0.0, -1.0)).is_none());
+    }
+
+    #[test]
+    fn disk_radius() {
+        let disk = Shape::Disk {
+            center: v(0.0, 0.0, 0.0),
+            normal: v(0.0, 1.0, 0.0),
+            radius: 2.0,
+        };
+        let h = hit(disk.clone(), v(1.0, 5.0, 0.0), v(0.0, -1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 5.0));
+        assert!(approx_eq(h.uv[1], 0.5));
+        assert!(h.front_face);
+        assert!(hit(disk, v(2.1, 5.0, 0.0), v(0.0, -1.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn box_outside_and_inside() {
+        let cube = Shape::AxisBox {
+            min: v(-1.0, -1.0, -1.0),
+            max: v(1.0, 1.0, 1.0),
+        };
+        let h = hit(cube.clone(), v(-5.0, 0.5, 0.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 4.0));
+        assert!(h.front_face);
+        assert_close(h.normal, v(-1.0, 0.0, 0.0));
+        assert!(approx_eq(h.uv[0], 0.75) && approx_eq(h.uv[1], 0.5));
+
+        let h = hit(cube.clone(), v(0.0, 0.0, 0.0), v(0.0, 1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 1.0));
+        assert!(!h.front_face);
+        assert_close(h.normal, v(0.0, -1.0, 0.0));
+
+        assert!(hit(cube, v(-5.0, 1.5, 0.0), v(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn cylinder_side_and_caps() {
+        let cylinder = Shape::Cylinder {
+            base: v(0.0, 0.0, 0.0),
+            axis: v(0.0, 2.0, 0.0),
+            radius: 1.0,
+        };
+        let h = hit(cylinder.clone(), v(-5.0, 1.0, 0.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 4.0));
+        assert_close(h.normal, v(-1.0, 0.0, 0.0));
+        assert!(approx_eq(h.uv[1], 0.5));
+
+        let h = hit(cylinder.clone(), v(0.0, 5.0, 0.5), v(0.0, -1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 3.0));
+        assert_close(h.normal, v(0.0, 1.0, 0.0));
+
+        let h = hit(cylinder.clone(), v(0.0, 1.0, 0.0), v(0.0, -1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 1.0));
+        assert!(!h.front_face);
+
+        assert!(hit(cylinder, v(-5.0, 2.5, 0.0), v(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn cone_side_and_base() {
+        let cone = Shape::Cone {
+            base: v(0.0, 0.0, 0.0),
+            axis: v(0.0, 2.0, 0.0),
+            radius: 1.0,
+        };
+        let h = hit(cone.clone(), v(-5.0, 1.0, 0.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 4.5));
+        assert_close(h.normal, v(-2.0, 1.0, 0.0).normalized());
+
+        let h = hit(cone.clone(), v(0.5, -5.0, 0.0), v(0.0, 1.0, 0.0)).unwrap();
+        assert!(approx_eq(h.distance, 5.0));
+        assert_close(h.normal, v(0.0, -1.0, 0.0));
+
+        // Passes above the apex
+        assert!(hit(cone, v(-5.0, 2.1, 0.0), v(1.0, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn torus_tube_and_hole() {
+        let torus = Shape::Torus {
+            center: v(0.0, 0.0, 0.0),
+            axis: v(0.0, 1.0, 0.0),
+            major_radius: 2.0,
+            minor_radius: 0.5,
+        };
+        let h = hit(torus.clone(), v(-5.0, 0.0, 0.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!((h.distance - 2.5).abs() < 0.001, "{}", h.distance);
+        assert_close(h.normal, v(-1.0, 0.0, 0.0));
+
+        let h = hit(torus.clone(), v(2.0, 5.0, 0.0), v(0.0, -1.0, 0.0)).unwrap();
+        assert!((h.distance - 4.5).abs() < 0.001, "{}", h.distance);
+        assert_close(h.normal, v(0.0, 1.0, 0.0));
+
+        // Straight down through the hole
+        assert!(hit(torus.clone(), v(0.0, 5.0, 0.0), v(0.0, -1.0, 0.0)).is_none());
+
+        // From inside the tube
+        let h = hit(torus, v(2.0, 0.0, 0.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!((h.distance - 0.5).abs() < 0.001);
+        assert!(!h.front_face);
+    }
+
+    #[test]
+    fn instance_matches_transformed_shape() {
+        use crate::matrix::Matrix;
+        use crate::object::Geometry;
+        use crate::Angle;
+        use std::sync::Arc;
+
+        let geometry = Arc::new(Geometry::new(vec![Object {
+            shape: Shape::Sphere {
+                center: v(0.0, 0.0, 0.0),
+                radius: 1.0,
+            },
+            material_id: Some(3),
+        }]));
+        let to_world = Matrix::translation(v(5.0, 1.0, 0.0))
+            * Matrix::rotation(v(0.0, 1.0, 0.0), Angle { radians: 0.4 })
+            * Matrix::scale(v(2.0, 2.0, 2.0));
+        let instance = Shape::Instance {
+            geometry,
+            transform: Transform::new(to_world).unwrap(),
+        };
+        let sphere = Shape::Sphere {
+            center: v(5.0, 1.0, 0.0),
+            radius: 2.0,
+        };
+
+        for i in 0..10 {
+            let from = v(-3.0, i as float * 0.3, -1.0);
+            let direction = v(8.0, 0.0, 1.0);
+            let a = hit(instance.clone(), from, direction).unwrap();
+            let b = hit(sphere.clone(), from, direction).unwrap();
+            assert!((a.distance - b.distance).abs() < 0.001);
+            assert_close(a.normal, b.normal);
+            assert_eq!(a.material_id, Some(3));
+        }
+        assert!(hit(instance.clone(), v(-3.0, 5.0, 0.0), v(1.0, 0.0, 0.0)).is_none());
+
+        assert!((instance.area() - sphere.area()).abs() < 0.01);
+        let bounds = instance.bounds();
+        assert!(bounds.min.x <= 3.0 && bounds.max.x >= 7.0);
+    }
+
+    #[test]
+    fn csg_combines_solids() {
+        let object = |shape: Shape, material_id: usize| Object {
+            shape,
+            material_id: Some(material_id),
+        };
+        let csg = |operation: CsgOperation| Shape::Csg {
+            operation,
+            operands: Box::new([
+                object(
+                    Shape::AxisBox {
+                        min: v(-1.0, -1.0, -1.0),
+                        max: v(1.0, 1.0, 1.0),
+                    },
+                    0,
+                ),
+                object(
+                    Shape::Sphere {
+                        center: v(1.0, 0.0, 0.0),
+                        radius: 0.5,
+                    },
+                    1,
+                ),
+            ]),
+        };
+        let from = v(5.0, 0.0, 0.0);
+        let left = v(-1.0, 0.0, 0.0);
+
+        let h = hit(csg(CsgOperation::Union), from, left).unwrap();
+        assert!(approx_eq(h.distance, 3.5));
+        assert_eq!(h.material_id, Some(1));
+        assert!(h.front_face);
+
+        let h = hit(csg(CsgOperation::Intersection), from, left).unwrap();
+        assert!(approx_eq(h.distance, 4.0));
+        assert_eq!(h.material_id, Some(0));
+
+        // Enters the box through the bottom of the hollow left by the sphere
+        let h = hit(csg(CsgOperation::Difference), from, left).unwrap();
+        assert!(approx_eq(h.distance, 4.5));
+        assert_eq!(h.material_id, Some(1));
+        assert!(h.front_face);
+        assert_close(h.normal, v(1.0, 0.0, 0.0));
+
+        // Leaving the box from inside, where the ray starts
+        let h = hit(csg(CsgOperation::Difference), v(-0.5, 0.0, 0.0), -left).unwrap();
+        assert!(approx_eq(h.distance, 1.0));
+        assert!(!h.front_face);
+
+        // Nested nodes and misses
+        let nested = Shape::Csg {
+            operation: CsgOperation::Difference,
+            operands: Box::new([
+                object(csg(CsgOperation::Union), 2),
+                object(
+                    Shape::AxisBox {
+                        min: v(-2.0, -2.0, -2.0),
+                        max: v(2.0, 2.0, 0.0),
+                    },
+                    3,
+                ),
+            ]),
+        };
+        assert!(hit(nested.clone(), from, left).is_none());
+        let h = hit(nested.clone(), v(0.0, 0.0, 5.0), v(0.0, 0.0, -1.0)).unwrap();
+        assert!(approx_eq(h.distance, 4.0));
+        assert_eq!(h.material_id, Some(0));
+        let h = hit(nested, v(0.0, 0.0, -5.0), v(0.0, 0.0, 1.0)).unwrap();
+        assert!(approx_eq(h.distance, 5.0));
+        assert_eq!(h.material_id, Some(3));
+        assert!(h.front_face);
+    }
+
+    #[test]
+    fn sphere_traced_field() {
+        let field = Sdf::Translate {
+            offset: v(0.0, 0.0, 5.0),
+            sdf: Box::new(Sdf::RoundBox {
+                half_size: v(1.0, 1.0, 1.0),
+                radius: 0.2,
+            }),
+        };
+        let sdf = Shape::Sdf {
+            sdf: std::sync::Arc::new(field),
+            bounds: Bounds {
+                min: v(-2.0, -2.0, 3.0),
+                max: v(2.0, 2.0, 7.0),
+            },
+            tracing: SphereTracing::default(),
+        };
+
+        let h = hit(sdf.clone(), Vector::ZERO, v(0.0, 0.0, 1.0)).unwrap();
+        assert!((h.distance - 4.0).abs() < 0.001);
+        assert_close(h.normal, v(0.0, 0.0, -1.0));
+        assert!(h.front_face);
+
+        // Leaving from inside
+        let h = hit(sdf.clone(), v(0.0, 0.0, 5.0), v(1.0, 0.0, 0.0)).unwrap();
+        assert!((h.distance - 1.0).abs() < 0.001);
+        assert!(!h.front_face);
+
+        assert!(hit(sdf.clone(), Vector::ZERO, v(0.0, 1.0, 1.0)).is_none());
+
+        // Sorted together with other shapes
+        let objects = [
+            Object {
+                shape: Shape::Sphere {
+                    center: v(0.0, 0.0, 8.0),
+                    radius: 1.0,
+                },
+                material_id: None,
+            },
+            Object {
+                shape: sdf,
+                material_id: None,
+            },
+            Object {
+                shape: Shape::Triangle {
+                    corners: [v(-1.0, -1.0, 2.0), v(1.0, -1.0, 2.0), v(0.0, -0.1, 2.0)],
+                    uvs: Shape::BARYCENTRIC_UVS,
+                },
+                material_id: None,
+            },
+        ];
+        assert_eq!(
+            raycast(&Ray::new(Vector::ZERO, v(0.0, 0.0, 1.0)), &objects)
+                .unwrap()
+                .object,
+            1
+        );
+        assert_eq!(
+            raycast(&Ray::new(v(0.0, -0.5, 0.0), v(0.0, 0.0, 1.0)), &objects)
+                .unwrap()
+                .object,
+            2
+        );
+        assert_eq!(
+            raycast(&Ray::new(v(0.0, 0.0, 6.5), v(0.0, 0.0, 1.0)), &objects)
+                .unwrap()
+                .object,
+            0
+        );
+    }
+
+    /// Closed triangle meshes around the origin: a cube, an octahedron and a sphere, all within
+    /// the unit cube and containing the cube of half size 0.5
+    fn closed_meshes() -> Vec<Vec<[Point; 3]>> {
+        let mut cube = vec![];
+        for axis in 0..3 {
+            for &sign in [-1.0, 1.0].iter() {
+                let corner = |a: float, b: float| {
+                    let mut c = [0.0; 3];
+                    c[axis] = sign;
+                    c[(axis + 1) % 3] = a;
+                    c[(axis + 2) % 3] = b;
+                    v(c[0], c[1], c[2])
+                };
+                cube.push([corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0)]);
+                cube.push([corner(-1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)]);
+            }
+        }
+
+        let mut octahedron = vec![];
+        for &x in [-1.0, 1.0].iter() {
+            for &y in [-1.0, 1.0].iter() {
+                for &z in [-1.0, 1.0].iter() {
+                    octahedron.push([v(x, 0.0, 0.0), v(0.0, y, 0.0), v(0.0, 0.0, z)]);
+                }
+            }
+        }
+
+        let mut sphere = vec![];
+        let (stacks, slices) = (9, 13);
+        let at = |i: usize, j: usize| {
+            let theta = PI * i as float / stacks as float;
+            let phi = 2.0 * PI * (j % slices) as float / slices as float;
+            v(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            )
+        };
+        for i in 0..stacks {
+            for j in 0..slices {
+                sphere.push([at(i, j), at(i + 1, j), at(i + 1, j + 1)]);
+                sphere.push([at(i, j), at(i + 1, j + 1), at(i, j + 1)]);
+            }
+        }
+
+        vec![cube, octahedron, sphere]
+    }
+
+    #[test]
+    fn triangle_meshes_do_not_leak() {
+        let offset = v(123.0, -45.0, 67.0);
+        for mesh in closed_meshes() {
+            let objects: Vec<Object> = mesh
+                .iter()
+                .map(|&[a, b, c]| Object {
+                    shape: Shape::Triangle {
+                        corners: [a + offset, b + offset, c + offset],
+                        uvs: Shape::BARYCENTRIC_UVS,
+                    },
+                    material_id: None,
+                })
+                .collect();
+
+            // From inside, towards every vertex and edge of a grid on the cube around it
+            let n = 16;
+            for &from in [v(0.0, 0.0, 0.0), v(0.25, -0.125, 0.375)].iter() {
+                for axis in 0..3 {
+                    for &sign in [-1.0, 1.0].iter() {
+                        for i in 0..=n {
+                            for j in 0..=n {
+                                let mut d = [0.0; 3];
+                                d[axis] = sign;
+                                d[(axis + 1) % 3] = 2.0 * i as float / n as float - 1.0;
+                                d[(axis + 2) % 3] = 2.0 * j as float / n as float - 1.0;
+                                let ray = Ray::new(from + offset, v(d[0], d[1], d[2]));
+                                assert!(raycast(&ray, &objects).is_some(), "{:?}", ray);
+                            }
+                        }
+                    }
+                }
+            }
+
+            // From outside, parallel rays through the inner cube, along edges and diagonals
+            for axis in 0..3 {
+                for i in 0..=n {
+                    for j in 0..=n {
+                        let mut from = [0.0; 3];
+                        from[axis] = -5.0;
+                        from[(axis + 1) % 3] = i as float / n as float - 0.5;
+                        from[(axis + 2) % 3] = j as float / n as float - 0.5;
+                        let mut d = [0.0; 3];
+                        d[axis] = 1.0;
+                        let from = v(from[0], from[1], from[2]) + offset;
+                        let hit = raycast(&Ray::new(from, v(d[0], d[1], d[2])), &objects)
+                            .expect("Ray leaked into the mesh");
+                        let direction = v(d[0], d[1], d[2]);
+                        let ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+                        assert!(raycast(&ray, &objects).is_some(), "{:?}", ray);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_triangles_are_missed() {
+        let direction = v(0.0, 0.0, 1.0);
+        // Collinear and repeated corners
+        for corners in [
+            [v(-1.0, 0.0, 2.0), v(0.0, 0.0, 2.0), v(1.0, 0.0, 2.0)],
+            [v(0.0, 0.0, 2.0), v(0.0, 0.0, 2.0), v(1.0, 1.0, 2.0)],
+            [v(0.0, 0.0, 2.0); 3],
+            // Seen edge on
+            [v(0.0, -1.0, 1.0), v(0.0, 1.0, 1.0), v(0.0, 0.0, 3.0)],
+        ]
+        .iter()
+        {
+            let shape = Shape::Triangle {
+                corners: *corners,
+                uvs: Shape::BARYCENTRIC_UVS,
+            };
+            assert!(hit(shape, Vector::ZERO, direction).is_none());
+        }
+
+        let (distance, barycentric) = ray_triangle_barycentric(
+            v(0.2, 0.3, 0.0),
+            direction,
+            [v(0.0, 0.0, 2.0), v(1.0, 0.0, 2.0), v(0.0, 1.0, 2.0)],
+        )
+        .unwrap();
+        assert!(approx_eq(distance, 2.0));
+        assert!(approx_eq(barycentric[0], 0.5));
+        assert!(approx_eq(barycentric[1], 0.2));
+        assert!(approx_eq(barycentric[2], 0.3));
+    }
+
+    #[test]
+    fn spawned_rays_do_not_hit_their_surface() {
+        // Scenes a millimeter to kilometers across, away from the origin
+        for &scale in [1e-3, 1.0, 1e3].iter() {
+            let center = v(30.0, -20.0, 50.0) * scale;
+            let s = |x: float, y: float, z: float| v(x, y, z) * scale;
+            // Convex shapes, and whether they are closed
+            let shapes = [
+                (
+                    Shape::Sphere {
+                        center,
+                        radius: scale,
+                    },
+                    true,
+                ),
+                (
+                    Shape::AxisBox {
+                        min: center - s(1.0, 0.5, 1.0),
+                        max: center + s(1.0, 0.5, 1.0),
+                    },
+                    true,
+                ),
+                (
+                    Shape::Cylinder {
+                        base: center - s(0.0, 1.0, 0.0),
+                        axis: s(0.3, 2.0, 0.0),
+                        radius: 0.8 * scale,
+                    },
+                    true,
+                ),
+                (
+                    Shape::Triangle {
+                        corners: [
+                            center + s(-1.0, -1.0, 0.3),
+                            center + s(1.0, -1.0, -0.3),
+                            center + s(0.0, 1.0, 0.1),
+                        ],
+                        uvs: Shape::BARYCENTRIC_UVS,
+                    },
+                    false,
+                ),
+            ];
+
+            for (shape, closed) in shapes.iter() {
+                let mut misses = 0;
+                for i in 0..200 {
+                    let z = 1.0 - 2.0 * (i as float + 0.5) / 200.0;
+                    let phi = i as float * 2.4;
+                    let ring = (1.0 - z * z).sqrt();
+                    let direction = v(ring * phi.cos(), ring * phi.sin(), z);
+                    let (a, b) = direction.coordinate_system();
+                    let aim = center
+                        + a * (((i * 7) % 23) as float / 10.0 - 1.1) * scale
+                        + b * (((i * 11) % 19) as float / 9.0 - 1.0) * scale;
+                    let from = aim - direction * (5.0 * scale);
+                    let hit = match intersect(shape, &Ray::new(from, direction)) {
+                        Some(hit) => hit,
+                        None => continue,
+                    };
+
+                    let reflected = direction.reflect(hit.normal);
+                    let ray = Ray::spawn(hit.point, hit.error, hit.normal, reflected);
+                    assert!(intersect(shape, &ray).is_none(), "{:?} {}", shape, i);
+
+                    if *closed {
+                        // Entering, so the next surface is the way out
+                        let ray = Ray::spawn(hit.point, hit.error, hit.normal, direction);
+                        match intersect(shape, &ray) {
+                            Some(exit) => assert!(!exit.front_face, "{:?} {}", shape, i),
+                            None => misses += 1,
+                        }
+                    }
+                }
+                // Only grazing hits may slip past the far side
+                assert!(misses < 4, "{:?} {}", shape, misses);
+            }
+        }
+    }
+
+    #[test]
+    fn samples_lie_on_surface() {
+        let shapes = [
+            Shape::Rectangle {
+                corner: v(1.0, 2.0, 3.0),
+                edges: [v(2.0, 0.0, 1.0), v(0.0, 3.0, 0.0)],
+            },
+            Shape::BilinearPatch {
+                corners: [
+                    v(0.0, 0.0, 0.0),
+                    v(2.0, 0.0, 0.5),
+                    v(0.0, 2.0, 0.0),
+                    v(2.5, 2.0, -0.5),
+                ],
+                uvs: [[0.0, 0.0], [2.0, 0.0], [0.0, 1.0], [2.0, 1.0]],
+            },
+            Shape::Disk {
+                center: v(1.0, 0.0, 0.0),
+                normal: v(1.0, 1.0, 0.0),
+                radius: 2.0,
+            },
+            Shape::AxisBox {
+                min: v(-1.0, 0.0, 2.0),
+                max: v(1.0, 3.0, 2.5),
+            },
+            Shape::Cylinder {
+                base: v(0.0, 1.0, 0.0),
+                axis: v(1.0, 2.0, 0.5),
+                radius: 0.7,
+            },
+            Shape::Cone {
+                base: v(0.0, 1.0, 0.0),
+                axis: v(0.0, 0.0, 3.0),
+                radius: 1.2,
+            },
+            Shape::Torus {
+                center: v(0.0, 1.0, 0.0),
+                axis: v(0.3, 1.0, 0.0),
+                major_radius: 3.0,
+                minor_radius: 1.0,
+            },
+        ];
+        for shape in shapes.iter() {
+            let bounds = shape.bounds();
+            for i in 0..50 {
+                let u = [(i % 7) as float / 7.0 + 0.05, (i / 7) as float / 8.0 + 0.05];
+                let sample = shape.sample(u);
+                let p = sample.point;
+                assert!(
+                    p.x >= bounds.min.x - 0.001 && p.x <= bounds.max.x + 0.001,
+                    "{:?} {:?}",
+                    shape,
+                    p
+                );
+
+                // Looking back at the sampled point hits it from the front
+                let from = p + sample.normal * 0.01;
+                let h = intersect(shape, &Ray::new(from, -sample.normal)).unwrap();
+                assert!((h.distance - 0.01).abs() < 0.001, "{:?} {:?}", shape, h);
+                assert!(h.front_face);
+                assert_close(h.normal, sample.normal);
+                assert!(
+                    (h.uv[0] - sample.uv[0]).abs() < 0.01 || (h.uv[0] - sample.uv[0]).abs() > 0.99
+                );
+                assert!(
+                    (h.uv[1] - sample.uv[1]).abs() < 0.01,
+                    "{:?} {:?} {:?}",
+                    shape,
+                    h,
+                    sample
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,411 @@
+use crate::prelude::*;
+use crate::vector::Vector;
+
+use rand::distributions::Standard;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::f32::consts::PI;
+
+thread_local! {
+    /// Generator of the random numbers the integrators sample on this thread
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+/// Random value from the generator of the current thread, in 0..1 for floats
+pub fn random<T>() -> T
+where
+    Standard: rand::distributions::Distribution<T>,
+{
+    RNG.with(|rng| rng.borrow_mut().gen())
+}
+
+/// Restarts the generator of the current thread from `seed`, so that what is sampled on it
+/// afterwards can be reproduced. Work split between threads seeds each of its items from a
+/// seed drawn beforehand and the index of the item, so that the result does not depend on
+/// which thread does what.
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+/// Cosine weighted direction on the hemisphere around `normal`, with its pdf
+/// https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#Cosine-WeightedHemisphereSampling
+pub fn cosine_hemisphere(normal: Vector, u: [float; 2]) -> (Vector, float) {
+    let [x, y] = concentric_disk(u);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+
+    let (tangent, bitangent) = normal.coordinate_system();
+    let direction = (tangent * x + bitangent * y + normal * z).normalized();
+    (direction, z / PI)
+}
+
+/// Uniformly distributed point on the unit disk, mapping concentric squares to circles
+/// https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#SamplingaUnitDisk
+pub fn concentric_disk(u: [float; 2]) -> [float; 2] {
+    let ox = 2.0 * u[0] - 1.0;
+    let oy = 2.0 * u[1] - 1.0;
+    if ox == 0.0 && oy == 0.0 {
+        [0.0, 0.0]
+    } else if ox.abs() > oy.abs() {
+        let theta = PI / 4.0 * (oy / ox);
+        [ox * theta.cos(), ox * theta.sin()]
+    } else {
+        let theta = PI / 2.0 - PI / 4.0 * (ox / oy);
+        [oy * theta.cos(), oy * theta.sin()]
+    }
+}
+
+/// Multiple importance sampling weight of a strategy taking `n_f` samples with pdf `f_pdf`,
+/// combined with one taking `n_g` samples with `g_pdf`
+/// https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/Importance_Sampling#MultipleImportanceSampling
+pub fn power_heuristic(n_f: usize, f_pdf: float, n_g: usize, g_pdf: float) -> float {
+    let f = n_f as float * f_pdf;
+    let g = n_g as float * g_pdf;
+    if f == 0.0 && g == 0.0 {
+        return 0.0;
+    }
+    if f.is_infinite() {
+        return 1.0;
+    }
+    (f * f) / (f * f + g * g)
+}
+
+/// Piecewise-constant 1D distribution over 0..1
+/// https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/Sampling_Random_Variables#Example:Piecewise-Constant1DFunctions
+#[derive(Debug, Clone)]
+pub struct Distribution1D {
+    func: Vec<float>,
+    /// Normalized cumulative distribution, one longer than `func`
+    cdf: Vec<float>,
+    /// Integral of `func` over 0..1
+    integral: float,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<float>) -> Self {
+        assert!(!func.is_empty());
+        let n = func.len();
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        for i in 0..n {
+            cdf.push(cdf[i] + func[i].abs() / n as float);
+        }
+
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // Nothing to prefer, sample uniformly
+            for (i, c) in cdf.iter_mut().enumerate().skip(1) {
+                *c = i as float / n as float;
+            }
+        } else {
+            for c in cdf.iter_mut().skip(1) {
+                *c /= integral;
+            }
+        }
+
+        Self {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> float {
+        self.integral
+    }
+
+    /// Continuous sample in 0..1, its pdf and the index of the segment it falls in
+    pub fn sample_continuous(&self, u: float) -> (float, float, usize) {
+        // Last cdf entry that is <= u
+        let offset = self
+            .cdf
+            .partition_point(|&c| c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let x = (offset as float + du) / self.count() as float;
+        (x.min(1.0 - float::EPSILON), self.pdf_of(offset), offset)
+    }
+
+    /// Discrete sample, its probability and the remapped uniform sample within the segment
+    pub fn sample_discrete(&self, u: float) -> (usize, float, float) {
+        let (_, _, offset) = self.sample_continuous(u);
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let remapped = if width > 0.0 {
+            ((u - self.cdf[offset]) / width).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        (offset, width, remapped)
+    }
+
+    /// Probability of choosing segment `index` with `sample_discrete`
+    pub fn discrete_pdf(&self, index: usize) -> float {
+        self.cdf[index + 1] - self.cdf[index]
+    }
+
+    /// Density of `sample_continuous` at `x` in 0..1
+    pub fn pdf(&self, x: float) -> float {
+        let offset = ((x * self.count() as float) as usize).min(self.count() - 1);
+        self.pdf_of(offset)
+    }
+
+    fn pdf_of(&self, offset: usize) -> float {
+        if self.integral > 0.0 {
+            self.func[offset].abs() / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Piecewise-constant 2D distribution over the unit square, for row-major `func`
+/// https://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations#Piecewise-Constant2DDistributions
+#[derive(Debug, Clone)]
+pub struct Distribution2D {
+    /// Distribution along u for each row
+    conditional: Vec<Distribution1D>,
+    /// Distribution of rows along v
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[float], width: usize, height: usize) -> Self {
+        assert_eq!(func.len(), width * height);
+        let conditional: Vec<_> = func
+            .chunks_exact(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(conditional.iter().map(|c| c.integral()).collect());
+
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Point in the unit square and its pdf
+    pub fn sample_continuous(&self, u: [float; 2]) -> ([float; 2], float) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u[1]);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u[0]);
+        ([u, v], pdf_u * pdf_v)
+    }
+
+    /// Density of `sample_continuous` at `uv`
+    pub fn pdf(&self, uv: [float; 2]) -> float {
+        let count = self.marginal.count();
+        let row = ((uv[1] * count as float) as usize).min(count - 1);
+        let conditional = &self.conditional[row];
+        let width = conditional.count();
+        let column = ((uv[0] * width as float) as usize).min(width - 1);
+        if self.marginal.integral() > 0.0 {
+            conditional.func[column].abs() / self.marginal.integral()
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Constant time discrete sampling proportional to weights, Vose's method
+/// https://www.keithschwarz.com/darts-dice-coins/
+#[derive(Debug, Clone)]
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AliasBin {
+    /// Probability of keeping this bin instead of taking the alias
+    threshold: float,
+    alias: usize,
+    pmf: float,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[float]) -> Self {
+        assert!(!weights.is_empty());
+        let n = weights.len();
+        let total: float = weights.iter().map(|w| w.abs()).sum();
+        let pmf: Vec<float> = if total > 0.0 {
+            weights.iter().map(|w| w.abs() / total).collect()
+        } else {
+            vec![1.0 / n as float; n]
+        };
+
+        // Split into bins holding less and more than the average probability
+        let mut scaled: Vec<float> = pmf.iter().map(|p| p * n as float).collect();
+        let (mut under, mut over): (Vec<usize>, Vec<usize>) =
+            (0..n).partition(|&i| scaled[i] < 1.0);
+        let mut bins: Vec<AliasBin> = pmf
+            .iter()
+            .enumerate()
+            .map(|(i, &pmf)| AliasBin {
+                threshold: 1.0,
+                alias: i,
+                pmf,
+            })
+            .collect();
+
+        while let (Some(&small), Some(&large)) = (under.last(), over.last()) {
+            under.pop();
+            bins[small].threshold = scaled[small];
+            bins[small].alias = large;
+
+            // Large bin gives away what fills the small one
+            scaled[large] -= 1.0 - scaled[small];
+            if scaled[large] < 1.0 {
+                over.pop();
+                under.push(large);
+            }
+        }
+
+        // Leftovers are full up to rounding errors
+        for i in under.into_iter().chain(over) {
+            bins[i].threshold = 1.0;
+        }
+
+        Self { bins }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bins.len()
+    }
+
+    /// Index, its probability and the remapped uniform sample
+    pub fn sample(&self, u: float) -> (usize, float, float) {
+        let n = self.bins.len();
+        let scaled = u * n as float;
+        let bin = (scaled as usize).min(n - 1);
+        let up = (scaled - bin as float).min(ONE_MINUS_EPSILON);
+
+        let entry = self.bins[bin];
+        if up < entry.threshold {
+            (
+                bin,
+                entry.pmf,
+                (up / entry.threshold).min(ONE_MINUS_EPSILON),
+            )
+        } else {
+            let alias = entry.alias;
+            let remapped = (up - entry.threshold) / (1.0 - entry.threshold);
+            (alias, self.bins[alias].pmf, remapped.min(ONE_MINUS_EPSILON))
+        }
+    }
+
+    /// Probability of `sample` returning `index`
+    pub fn pmf(&self, index: usize) -> float {
+        self.bins[index].pmf
+    }
+}
+
+/// Largest float below one, for keeping remapped samples in 0..1
+pub(crate) const ONE_MINUS_EPSILON: float = 1.0 - float::EPSILON / 2.0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_generators_repeat_their_numbers() {
+        seed(7);
+        let first: Vec<float> = (0..8).map(|_| random()).collect();
+        assert!(first.iter().all(|&u| (0.0..1.0).contains(&u)));
+        seed(7);
+        let again: Vec<float> = (0..8).map(|_| random()).collect();
+        assert_eq!(first, again);
+        seed(8);
+        assert_ne!(random::<float>(), first[0]);
+    }
+
+    #[test]
+    fn distribution_1d_follows_function() {
+        let d = Distribution1D::new(vec![0.0, 1.0, 3.0, 0.0]);
+        assert!(approx_eq(d.integral(), 1.0));
+
+        let (x, pdf, offset) = d.sample_continuous(0.1);
+        assert_eq!(offset, 1);
+        assert!((0.25..0.5).contains(&x));
+        assert!(approx_eq(pdf, 1.0));
+
+        let (x, pdf, offset) = d.sample_continuous(0.9);
+        assert_eq!(offset, 2);
+        assert!((0.5..0.75).contains(&x));
+        assert!(approx_eq(pdf, 3.0));
+        assert!(approx_eq(d.pdf(x), 3.0));
+        assert!(approx_eq(d.discrete_pdf(2), 0.75));
+    }
+
+    #[test]
+    fn distribution_2d_pdf_matches_samples() {
+        let func = [1.0, 2.0, 0.0, 4.0, 1.0, 0.0];
+        let d = Distribution2D::new(&func, 3, 2);
+
+        for i in 0..50 {
+            for j in 0..50 {
+                let u = [(i as float + 0.5) / 50.0, (j as float + 0.5) / 50.0];
+                let (uv, pdf) = d.sample_continuous(u);
+                assert!(pdf > 0.0);
+                assert!(approx_eq(d.pdf(uv), pdf));
+            }
+        }
+
+        // Integral of the pdf over the domain is one
+        let total: float = (0..6)
+            .map(|i| {
+                let uv = [
+                    ((i % 3) as float + 0.5) / 3.0,
+                    ((i / 3) as float + 0.5) / 2.0,
+                ];
+                d.pdf(uv) / 6.0
+            })
+            .sum();
+        assert!(approx_eq(total, 1.0));
+    }
+
+    #[test]
+    fn cosine_hemisphere_above_surface() {
+        let normal = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        for i in 0..100 {
+            let u = [(i % 10) as float / 10.0, (i / 10) as float / 10.0];
+            let (d, pdf) = cosine_hemisphere(normal, u);
+            assert!(d.is_normalized());
+            assert!(d.dot(normal) >= 0.0);
+            assert!(approx_eq(pdf, d.dot(normal) / PI));
+        }
+    }
+
+    #[test]
+    fn alias_table_follows_weights() {
+        let weights = [1.0, 0.0, 5.0, 2.0];
+        let table = AliasTable::new(&weights);
+        assert!(approx_eq(table.pmf(2), 0.625));
+
+        let mut counts = [0; 4];
+        let n = 8000;
+        for i in 0..n {
+            let (index, pmf, remapped) = table.sample((i as float + 0.5) / n as float);
+            assert!(approx_eq(pmf, table.pmf(index)));
+            assert!((0.0..1.0).contains(&remapped));
+            counts[index] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        for (count, weight) in counts.iter().zip(weights.iter()) {
+            let expected = weight / 8.0;
+            assert!((*count as float / n as float - expected).abs() < 0.01);
+        }
+    }
+}
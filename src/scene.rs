@@ -0,0 +1,167 @@
+use crate::bounds::Bounds;
+use crate::bvh::Bvh;
+use crate::environment::EnvironmentLight;
+use crate::light::Lights;
+use crate::material::Material;
+use crate::medium::Medium;
+use crate::object::Object;
+use crate::ray::Ray;
+use crate::raycast::{intersect_object, RayHit};
+use crate::sky::SunLight;
+
+/// Everything needed to render an image, apart from the camera
+#[derive(Debug, Clone)]
+pub struct Scene {
+    pub objects: Vec<Object>,
+    pub materials: Vec<Material>,
+    /// Light arriving from outside of the scene, also seen by camera rays that miss
+    pub environment: EnvironmentLight,
+    /// Sun disk, sampled separately as it is too small to find in the environment map
+    pub sun: Option<SunLight>,
+    /// Emissive objects
+    pub lights: Lights,
+    /// Medium outside of the closed surfaces with media, where camera rays start
+    pub atmosphere: Option<Medium>,
+    /// Top level of the acceleration structure, instances hold their own
+    bvh: Bvh,
+}
+
+impl Scene {
+    pub fn new(
+        objects: Vec<Object>,
+        materials: Vec<Material>,
+        environment: EnvironmentLight,
+        sun: Option<SunLight>,
+    ) -> Self {
+        let lights = Lights::new(&objects, &materials);
+        let bounds: Vec<Bounds> = objects.iter().map(|o| o.shape.bounds()).collect();
+        Self {
+            bvh: Bvh::new(&bounds),
+            objects,
+            materials,
+            environment,
+            sun,
+            lights,
+            atmosphere: None,
+        }
+    }
+
+    /// Bounds of the objects with finite extent, empty if there are none
+    pub fn finite_bounds(&self) -> Bounds {
+        self.objects
+            .iter()
+            .map(|object| object.shape.bounds())
+            .filter(|bounds| bounds.diagonal().len().is_finite())
+            .fold(Bounds::EMPTY, Bounds::union)
+    }
+
+    pub fn raycast(&self, ray: &Ray) -> Option<RayHit> {
+        self.bvh
+            .raycast(ray, |i, ray| intersect_object(&self.objects[i], ray))
+    }
+
+    /// Material of the surface hit, `None` if it uses the default material
+    pub fn material(&self, hit: &RayHit) -> Option<&Material> {
+        hit.material_id
+            .map(|material_id| &self.materials[material_id])
+    }
+}
+
+/// Scenes shared by the tests of the integrators
+#[cfg(test)]
+pub(crate) mod test_scenes {
+    use crate::camera::Camera;
+    use crate::color::Color;
+    use crate::environment::EnvironmentLight;
+    use crate::material::Material;
+    use crate::matrix::Matrix;
+    use crate::object::{Object, Shape};
+    use crate::prelude::*;
+    use crate::scene::Scene;
+    use crate::vector::Vector;
+
+    use std::sync::Arc;
+
+    /// Closed box of emissive diffuse triangles facing inwards, and a small camera in its
+    /// middle
+    pub(crate) fn furnace(emission: Color, albedo: float) -> (Scene, Camera) {
+        let mut objects = Vec::new();
+        for axis in 0..3 {
+            for &side in [-1.0, 1.0].iter() {
+                let corner = |u: float, v: float| {
+                    let mut p = [0.0; 3];
+                    p[axis] = side;
+                    p[(axis + 1) % 3] = u;
+                    p[(axis + 2) % 3] = v;
+                    Vector {
+                        x: p[0],
+                        y: p[1],
+                        z: p[2],
+                    }
+                };
+                let inward = corner(0.0, 0.0) * -1.0;
+                for &[a, b, c] in [
+                    [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0)],
+                    [(-1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)],
+                ]
+                .iter()
+                {
+                    let (a, mut b, mut c) = (corner(a.0, a.1), corner(b.0, b.1), corner(c.0, c.1));
+                    if (b - a).cross(c - a).dot(inward) < 0.0 {
+                        std::mem::swap(&mut b, &mut c);
+                    }
+                    objects.push(Object {
+                        shape: Shape::Triangle {
+                            corners: [a, b, c],
+                            uvs: Shape::BARYCENTRIC_UVS,
+                        },
+                        material_id: Some(0),
+                    });
+                }
+            }
+        }
+        let mut material = Material::diffuse("wall", Arc::new(Color::WHITE * albedo));
+        material.ambient = Arc::new(emission);
+        let scene = Scene::new(
+            objects,
+            vec![material],
+            EnvironmentLight::uniform(Color::BLACK),
+            None,
+        );
+        let camera = Camera {
+            transform: Matrix::IDENTITY,
+            width: 8,
+            height: 6,
+            vertical_fov: Camera::DEFAULT_FOV,
+        };
+        (scene, camera)
+    }
+
+    /// Checks the mean radiance seen in a furnace against the emission seen directly and after
+    /// each bounce up to `max_depth`, a geometric series in the albedo, within a relative
+    /// `tolerance` in each channel
+    pub(crate) fn assert_furnace(
+        mean: Color,
+        emission: Color,
+        albedo: float,
+        max_depth: usize,
+        tolerance: float,
+    ) {
+        let series: float = (0..=max_depth).map(|depth| albedo.powi(depth as i32)).sum();
+        let expected = emission * series;
+        for &(mean, expected) in [
+            (mean.r, expected.r),
+            (mean.g, expected.g),
+            (mean.b, expected.b),
+        ]
+        .iter()
+        {
+            assert!(
+                (mean - expected).abs() <= tolerance * expected,
+                "{:?}, expected {:?}",
+                mean,
+                expected
+            );
+        }
+    }
+}
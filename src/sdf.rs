@@ -0,0 +1,295 @@
+use crate::bounds::Bounds;
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+/// Signed distance field expression, negative inside the surface.
+/// Operations that bend space make the result only approximate the distance,
+/// which sphere tracing makes up for with a smaller `step_scale`.
+/// https://iquilezles.org/articles/distfunctions/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sdf {
+    Sphere {
+        radius: float,
+    },
+    /// Box with its edges rounded off by `radius`, `half_size` includes the rounding
+    RoundBox {
+        half_size: Vector,
+        radius: float,
+    },
+    /// Ring around the y axis
+    Torus {
+        major_radius: float,
+        minor_radius: float,
+    },
+    /// Power 8 Mandelbulb fractal, about 1.2 in radius
+    Mandelbulb {
+        iterations: u32,
+    },
+    Translate {
+        offset: Vector,
+        sdf: Box<Sdf>,
+    },
+    Scale {
+        scale: float,
+        sdf: Box<Sdf>,
+    },
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    /// First field with the second one cut away
+    Difference(Box<Sdf>, Box<Sdf>),
+    /// Union blending the surfaces together within distance `k`, as for metaballs
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        k: float,
+    },
+    /// Infinite copies of the field, `period` apart along each axis, no repetition on zero axes
+    Repeat {
+        period: Vector,
+        sdf: Box<Sdf>,
+    },
+    /// Rotates each slice around the y axis by `rate` radians per unit of height
+    Twist {
+        rate: float,
+        sdf: Box<Sdf>,
+    },
+    /// Sine ripples of the surface
+    Displace {
+        amplitude: float,
+        frequency: float,
+        sdf: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    /// Blends several spheres together
+    pub fn metaballs(centers: &[Point], radius: float, k: float) -> Self {
+        let ball = |center: Point| Sdf::Translate {
+            offset: center,
+            sdf: Box::new(Sdf::Sphere { radius }),
+        };
+        let mut balls = centers.iter().map(|&c| ball(c));
+        let first = balls.next().expect("Metaballs need at least one center");
+        balls.fold(first, |a, b| Sdf::SmoothUnion {
+            a: Box::new(a),
+            b: Box::new(b),
+            k,
+        })
+    }
+
+    pub fn distance(&self, p: Point) -> float {
+        match self {
+            Sdf::Sphere { radius } => p.len() - radius,
+            Sdf::RoundBox { half_size, radius } => {
+                let q = Vector {
+                    x: p.x.abs() - half_size.x + radius,
+                    y: p.y.abs() - half_size.y + radius,
+                    z: p.z.abs() - half_size.z + radius,
+                };
+                q.max(Vector::ZERO).len() + q.x.max(q.y).max(q.z).min(0.0) - radius
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let ring = (p.x * p.x + p.z * p.z).sqrt() - major_radius;
+                (ring * ring + p.y * p.y).sqrt() - minor_radius
+            }
+            Sdf::Mandelbulb { iterations } => mandelbulb(p, *iterations),
+            Sdf::Translate { offset, sdf } => sdf.distance(p - *offset),
+            Sdf::Scale { scale, sdf } => sdf.distance(p * (1.0 / scale)) * scale,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Difference(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion { a, b, k } => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (b - a) / k).clamp(0.0, 1.0);
+                b + (a - b) * h - k * h * (1.0 - h)
+            }
+            Sdf::Repeat { period, sdf } => {
+                let wrap = |x: float, period: float| {
+                    if period > 0.0 {
+                        x - period * (x / period).round()
+                    } else {
+                        x
+                    }
+                };
+                sdf.distance(Vector {
+                    x: wrap(p.x, period.x),
+                    y: wrap(p.y, period.y),
+                    z: wrap(p.z, period.z),
+                })
+            }
+            Sdf::Twist { rate, sdf } => {
+                let (s, c) = (rate * p.y).sin_cos();
+                sdf.distance(Vector {
+                    x: c * p.x - s * p.z,
+                    y: p.y,
+                    z: s * p.x + c * p.z,
+                })
+            }
+            Sdf::Displace {
+                amplitude,
+                frequency,
+                sdf,
+            } => {
+                let ripple =
+                    (frequency * p.x).sin() * (frequency * p.y).sin() * (frequency * p.z).sin();
+                sdf.distance(p) + amplitude * ripple
+            }
+        }
+    }
+
+    /// Outward surface normal from central differences of the distance
+    pub fn normal(&self, p: Point, h: float) -> Vector {
+        let axis = |v: Vector| self.distance(p + v * h) - self.distance(p - v * h);
+        Vector {
+            x: axis(Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            }),
+            y: axis(Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            }),
+            z: axis(Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            }),
+        }
+        .normalized()
+    }
+}
+
+/// Distance estimate of the power 8 Mandelbulb
+/// https://iquilezles.org/articles/mandelbulb/
+fn mandelbulb(p: Point, iterations: u32) -> float {
+    const POWER: float = 8.0;
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = z.len();
+    for _ in 0..iterations {
+        if r > 2.0 {
+            break;
+        }
+        let theta = (z.z / r).clamp(-1.0, 1.0).acos() * POWER;
+        let phi = z.y.atan2(z.x) * POWER;
+        dr = r.powf(POWER - 1.0) * POWER * dr + 1.0;
+        let zr = r.powf(POWER);
+        z = Vector {
+            x: theta.sin() * phi.cos(),
+            y: theta.sin() * phi.sin(),
+            z: theta.cos(),
+        } * zr
+            + p;
+        r = z.len();
+    }
+    0.5 * r.ln() * r / dr
+}
+
+/// Limits of sphere tracing a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SphereTracing {
+    /// Steps taken before giving up on a ray
+    pub max_steps: u32,
+    /// Distance from the surface that counts as a hit
+    pub epsilon: float,
+    /// Fraction of the distance to step at a time, below one for fields that overestimate it
+    pub step_scale: float,
+}
+
+impl Default for SphereTracing {
+    fn default() -> Self {
+        Self {
+            max_steps: 256,
+            epsilon: 0.0005,
+            step_scale: 1.0,
+        }
+    }
+}
+
+/// Distance along a ray to where it enters and leaves a box, if it does
+pub(crate) fn clip_to_bounds(
+    bounds: &Bounds,
+    from: Point,
+    direction: Vector,
+) -> Option<(float, float)> {
+    let mut near: float = 0.0;
+    let mut far = float::INFINITY;
+    for axis in 0..3 {
+        let inverse = 1.0 / direction[axis];
+        let t0 = (bounds.min[axis] - from[axis]) * inverse;
+        let t1 = (bounds.max[axis] - from[axis]) * inverse;
+        near = near.max(t0.min(t1));
+        far = far.min(t0.max(t1));
+    }
+    if near <= far {
+        Some((near, far))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    #[test]
+    fn distances_of_primitives() {
+        let sphere = Sdf::Sphere { radius: 2.0 };
+        assert!(approx_eq(sphere.distance(v(3.0, 0.0, 0.0)), 1.0));
+        assert!(approx_eq(sphere.distance(Vector::ZERO), -2.0));
+
+        let rounded = Sdf::RoundBox {
+            half_size: v(1.0, 2.0, 3.0),
+            radius: 0.5,
+        };
+        assert!(approx_eq(rounded.distance(v(0.0, 3.0, 0.0)), 1.0));
+        // Corner is rounded off
+        let corner = rounded.distance(v(1.0, 2.0, 3.0));
+        assert!(corner > 0.1 && corner < 0.5);
+
+        let moved = Sdf::Translate {
+            offset: v(0.0, 5.0, 0.0),
+            sdf: Box::new(Sdf::Scale {
+                scale: 2.0,
+                sdf: Box::new(Sdf::Torus {
+                    major_radius: 1.0,
+                    minor_radius: 0.25,
+                }),
+            }),
+        };
+        assert!(approx_eq(moved.distance(v(2.0, 5.0, 0.0)), -0.5));
+
+        let repeated = Sdf::Repeat {
+            period: v(4.0, 0.0, 0.0),
+            sdf: Box::new(Sdf::Sphere { radius: 1.0 }),
+        };
+        assert!(approx_eq(repeated.distance(v(8.0, 0.0, 0.0)), -1.0));
+        assert!(approx_eq(repeated.distance(v(10.0, 0.0, 0.0)), 1.0));
+
+        // Blending only adds material
+        let blob = Sdf::metaballs(&[v(-1.0, 0.0, 0.0), v(1.0, 0.0, 0.0)], 1.2, 0.5);
+        assert!(blob.distance(Vector::ZERO) < -0.2);
+        assert!(blob.distance(v(0.0, 1.0, 0.0)) < sphere.distance(v(0.0, 3.0, 0.0)));
+
+        assert!(Sdf::Mandelbulb { iterations: 8 }.distance(v(3.0, 0.0, 0.0)) > 1.0);
+    }
+
+    #[test]
+    fn normal_points_outward() {
+        let sdf = Sdf::Twist {
+            rate: 0.3,
+            sdf: Box::new(Sdf::Sphere { radius: 1.0 }),
+        };
+        let n = sdf.normal(v(0.0, 0.0, 1.0), 0.001);
+        assert!((n - v(0.0, 0.0, 1.0)).len() < 0.01);
+    }
+}
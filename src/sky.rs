@@ -0,0 +1,421 @@
+use crate::angle::Angle;
+use crate::color::Color;
+use crate::environment::{self, EnvironmentLight, EnvironmentSample};
+use crate::mipmap::Image;
+use crate::prelude::*;
+use crate::vector::Vector;
+
+use std::f32::consts::PI;
+
+/// Radiance units per kcd/m², so that a clear zenith is around one
+const LUMINANCE_SCALE: float = 0.1;
+
+/// Luminance of the sun disk outside of the atmosphere, kcd/m²
+const SUN_LUMINANCE: float = 1.6e6;
+
+/// Apparent angular radius of the sun
+pub const SUN_RADIUS: Angle = Angle { radians: 0.004_65 };
+
+/// Fraction of the horizon radiance reflected by the ground below it
+const GROUND_ALBEDO: float = 0.2;
+
+/// Direction towards a point in the sky. World space has +y up, +x east and -z north.
+pub fn sky_direction(elevation: Angle, azimuth: Angle) -> Vector {
+    let (e, a) = (elevation.radians, azimuth.radians);
+    Vector {
+        x: e.cos() * a.sin(),
+        y: e.sin(),
+        z: -e.cos() * a.cos(),
+    }
+}
+
+/// Perez sky luminance distribution function coefficients
+#[derive(Debug, Clone, Copy)]
+struct Perez([float; 5]);
+
+impl Perez {
+    /// Relative brightness at zenith angle `theta` and angle `gamma` from the sun
+    fn eval(self, theta: float, gamma: float) -> float {
+        let [a, b, c, d, e] = self.0;
+        let cos_theta = theta.cos().max(0.01);
+        (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+    }
+}
+
+/// Analytic daylight model
+/// https://www2.cs.duke.edu/courses/cps124/spring08/assign/07_papers/p91-preetham.pdf
+#[derive(Debug, Clone)]
+pub struct PhysicalSky {
+    /// Unit vector towards the sun
+    sun_direction: Vector,
+    turbidity: float,
+    intensity: float,
+    /// Zenith luminance and chromaticity
+    zenith: [float; 3],
+    /// Distribution coefficients for luminance and both chromaticities
+    perez: [Perez; 3],
+}
+
+impl PhysicalSky {
+    /// `turbidity` from 2 (very clear) to around 10 (hazy), `intensity` scales all radiance
+    pub fn new(elevation: Angle, azimuth: Angle, turbidity: float, intensity: float) -> Self {
+        let t = turbidity;
+        let sun_direction = sky_direction(elevation, azimuth);
+        let theta_s = (PI / 2.0 - elevation.radians).clamp(0.0, PI / 2.0);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192).max(0.0);
+
+        let chromaticity = |m: [[float; 4]; 3]| -> float {
+            let ts = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let tt = [t * t, t, 1.0];
+            (0..3)
+                .map(|i| tt[i] * (0..4).map(|j| m[i][j] * ts[j]).sum::<float>())
+                .sum()
+        };
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            Perez([
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ]),
+            Perez([
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ]),
+            Perez([
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ]),
+        ];
+
+        Self {
+            sun_direction,
+            turbidity,
+            intensity,
+            zenith: [luminance, x, y],
+            perez,
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vector {
+        self.sun_direction
+    }
+
+    /// Sky radiance seen when looking towards `direction`, excluding the sun disk.
+    /// Below the horizon the ground reflects some of the horizon light.
+    pub fn radiance(&self, direction: Vector) -> Color {
+        let direction = direction.normalized();
+        let below = direction.y < 0.0;
+        let up = if below {
+            Vector {
+                y: 0.0,
+                ..direction
+            }
+        } else {
+            direction
+        };
+        if up.len2() == 0.0 {
+            return Color::BLACK;
+        }
+        let up = up.normalized();
+
+        let theta = up.y.clamp(-1.0, 1.0).acos();
+        let gamma = up.dot(self.sun_direction).clamp(-1.0, 1.0).acos();
+        let theta_s = self.sun_direction.y.clamp(-1.0, 1.0).acos().min(PI / 2.0);
+
+        let mut xyy = self.zenith;
+        for (value, perez) in xyy.iter_mut().zip(self.perez.iter()) {
+            *value *= perez.eval(theta, gamma) / perez.eval(0.0, theta_s);
+        }
+
+        let color = xyy_to_color(xyy) * (LUMINANCE_SCALE * self.intensity);
+        if below {
+            color * GROUND_ALBEDO
+        } else {
+            color
+        }
+    }
+
+    /// Sun disk with its radiance attenuated by the atmosphere
+    pub fn sun(&self) -> SunLight {
+        // Relative optical air mass, Kasten and Young
+        let zenith_degrees = self.sun_direction.y.clamp(-1.0, 1.0).acos().to_degrees();
+        let mass = if zenith_degrees < 93.885 {
+            1.0 / ((90.0 - zenith_degrees).to_radians().sin()
+                + 0.15 * (93.885 - zenith_degrees).powf(-1.253))
+        } else {
+            float::INFINITY
+        };
+
+        // Rayleigh scattering and Ångström aerosol extinction at representative wavelengths
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = |micrometers: float| -> float {
+            let rayleigh = (-0.008735 * micrometers.powf(-4.08) * mass).exp();
+            let aerosol = (-beta * micrometers.powf(-1.3) * mass).exp();
+            rayleigh * aerosol
+        };
+
+        let luminance = SUN_LUMINANCE * LUMINANCE_SCALE * self.intensity;
+        SunLight {
+            direction: self.sun_direction,
+            radius: SUN_RADIUS,
+            radiance: Color {
+                r: transmittance(0.65),
+                g: transmittance(0.55),
+                b: transmittance(0.45),
+            } * luminance,
+        }
+    }
+
+    /// Sky dome baked into an importance sampled equirectangular map
+    pub fn environment(&self, width: usize, height: usize) -> EnvironmentLight {
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let uv = [
+                    (x as float + 0.5) / width as float,
+                    (y as float + 0.5) / height as float,
+                ];
+                pixels.push(self.radiance(environment::uv_to_direction(uv)));
+            }
+        }
+
+        let image = Image {
+            width,
+            height,
+            pixels,
+        };
+        EnvironmentLight::new(image, Angle { radians: 0.0 }, 1.0)
+    }
+}
+
+fn xyy_to_color(xyy: [float; 3]) -> Color {
+    let [luminance, x, y] = xyy;
+    if y <= 0.0 {
+        return Color::BLACK;
+    }
+    let color = Color::from_xyz(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    Color {
+        r: color.r.max(0.0),
+        g: color.g.max(0.0),
+        b: color.b.max(0.0),
+    }
+}
+
+/// Distant light subtending a small cone, such as the sun
+#[derive(Debug, Clone, Copy)]
+pub struct SunLight {
+    /// Unit vector towards the center of the disk
+    pub direction: Vector,
+    /// Angular radius of the disk
+    pub radius: Angle,
+    pub radiance: Color,
+}
+
+impl SunLight {
+    /// `1 - cos(radius)`, without cancellation for tiny disks
+    fn one_minus_cos(&self) -> float {
+        2.0 * (0.5 * self.radius.radians).sin().powi(2)
+    }
+
+    /// Radiance seen when looking towards `direction`
+    pub fn radiance(&self, direction: Vector) -> Color {
+        if self.contains(direction) {
+            self.radiance
+        } else {
+            Color::BLACK
+        }
+    }
+
+    fn contains(&self, direction: Vector) -> bool {
+        let cos = direction.normalized().dot(self.direction);
+        1.0 - cos <= self.one_minus_cos()
+    }
+
+    /// Uniformly distributed direction within the disk, `u` uniform in 0..1
+    pub fn sample(&self, u: [float; 2]) -> EnvironmentSample {
+        let one_minus_cos = u[0] * self.one_minus_cos();
+        let cos_theta = 1.0 - one_minus_cos;
+        let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+        let phi = 2.0 * PI * u[1];
+
+        let (tangent, bitangent) = self.direction.coordinate_system();
+        let direction = (tangent * (sin_theta * phi.cos())
+            + bitangent * (sin_theta * phi.sin())
+            + self.direction * cos_theta)
+            .normalized();
+
+        EnvironmentSample {
+            direction,
+            radiance: self.radiance,
+            pdf: self.uniform_pdf(),
+        }
+    }
+
+    /// Solid angle density of `sample` choosing `direction`
+    pub fn pdf(&self, direction: Vector) -> float {
+        if self.contains(direction) {
+            self.uniform_pdf()
+        } else {
+            0.0
+        }
+    }
+
+    fn uniform_pdf(&self) -> float {
+        1.0 / (2.0 * PI * self.one_minus_cos())
+    }
+}
+
+/// Position of the sun seen from the given location at a UTC date and time,
+/// as elevation above the horizon and azimuth clockwise from north.
+/// Accurate to about a hundredth of a degree between 1950 and 2050.
+/// https://en.wikipedia.org/wiki/Position_of_the_Sun
+pub fn sun_position(
+    latitude: Angle,
+    longitude: Angle,
+    year: i32,
+    month: u32,
+    day: u32,
+    utc_hours: float,
+) -> (Angle, Angle) {
+    // Days since J2000.0, computed in double precision as they are large
+    let n = julian_day(year, month, day) + utc_hours as f64 / 24.0 - 2_451_545.0;
+
+    let mean_longitude = (280.460 + 0.985_647_4 * n).rem_euclid(360.0).to_radians();
+    let mean_anomaly = (357.528 + 0.985_600_3 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_longitude = mean_longitude
+        + (1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin()).to_radians();
+    let obliquity = (23.439 - 0.000_000_4 * n).to_radians();
+
+    let right_ascension =
+        (obliquity.cos() * ecliptic_longitude.sin()).atan2(ecliptic_longitude.cos());
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+
+    let sidereal_hours = 18.697_374_558 + 24.065_709_824_419_08 * n;
+    let local_sidereal = (sidereal_hours * 15.0).to_radians() + longitude.radians as f64;
+    let hour_angle = local_sidereal - right_ascension;
+
+    let lat = latitude.radians as f64;
+    let elevation =
+        (lat.sin() * declination.sin() + lat.cos() * declination.cos() * hour_angle.cos()).asin();
+    let azimuth = (-hour_angle.sin())
+        .atan2(declination.tan() * lat.cos() - lat.sin() * hour_angle.cos())
+        .rem_euclid(2.0 * std::f64::consts::PI);
+
+    (
+        Angle {
+            radians: elevation as float,
+        },
+        Angle {
+            radians: azimuth as float,
+        },
+    )
+}
+
+/// Julian day number at midnight UTC starting the given Gregorian date
+fn julian_day(year: i32, month: u32, day: u32) -> f64 {
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 12)
+    } else {
+        (year, month)
+    };
+    let a = (y as f64 / 100.0).floor();
+    let b = 2.0 - a + (a / 4.0).floor();
+    (365.25 * (y as f64 + 4716.0)).floor() + (30.6001 * (m as f64 + 1.0)).floor() + day as f64 + b
+        - 1524.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn degrees(d: float) -> Angle {
+        Angle {
+            radians: d.to_radians(),
+        }
+    }
+
+    #[test]
+    fn julian_day_epoch() {
+        assert_eq!(julian_day(2000, 1, 1), 2_451_544.5);
+        assert_eq!(julian_day(1987, 6, 19), 2_446_965.5);
+    }
+
+    #[test]
+    fn sun_position_known_locations() {
+        // Equinox noon on the equator at Greenwich, sun nearly overhead
+        let (elevation, _) = sun_position(degrees(0.0), degrees(0.0), 2021, 3, 20, 12.1);
+        assert!(elevation.radians.to_degrees() > 85.0, "{:?}", elevation);
+
+        // Helsinki at solar noon of the summer solstice, sun due south
+        let (elevation, azimuth) = sun_position(degrees(60.17), degrees(24.94), 2021, 6, 21, 10.33);
+        assert!(
+            (elevation.radians.to_degrees() - 53.3).abs() < 0.3,
+            "{:?}",
+            elevation
+        );
+        assert!(
+            (azimuth.radians.to_degrees() - 180.0).abs() < 2.0,
+            "{:?}",
+            azimuth
+        );
+
+        // Morning sun rises in the east
+        let (elevation, azimuth) = sun_position(degrees(60.17), degrees(24.94), 2021, 6, 21, 4.0);
+        assert!(elevation.radians > 0.0);
+        assert!(azimuth.radians.to_degrees() > 45.0 && azimuth.radians.to_degrees() < 135.0);
+    }
+
+    #[test]
+    fn sky_brightest_near_sun() {
+        let sky = PhysicalSky::new(degrees(30.0), degrees(90.0), 3.0, 1.0);
+        let towards =
+            sky.radiance(sky.sun_direction() + sky_direction(degrees(5.0), degrees(90.0)));
+        let away = sky.radiance(sky_direction(degrees(30.0), degrees(270.0)));
+        assert!(towards.luminance() > away.luminance());
+
+        // Clear sky is blue away from the sun
+        let zenith = sky.radiance(sky_direction(degrees(90.0), degrees(0.0)));
+        assert!(zenith.b > zenith.r);
+    }
+
+    #[test]
+    fn sun_reddens_at_sunset() {
+        let noon = PhysicalSky::new(degrees(60.0), degrees(180.0), 3.0, 1.0).sun();
+        let sunset = PhysicalSky::new(degrees(2.0), degrees(270.0), 3.0, 1.0).sun();
+        assert!(noon.radiance.luminance() > sunset.radiance.luminance());
+        assert!(sunset.radiance.r / sunset.radiance.b > noon.radiance.r / noon.radiance.b);
+    }
+
+    #[test]
+    fn sun_sample_inside_disk() {
+        let sun = PhysicalSky::new(degrees(45.0), degrees(10.0), 2.5, 1.0).sun();
+        for i in 0..100 {
+            let u = [(i % 10) as float / 10.0, (i / 10) as float / 10.0];
+            let sample = sun.sample(u);
+            assert!(sun.pdf(sample.direction) > 0.0);
+            assert_eq!(sun.radiance(sample.direction), sun.radiance);
+        }
+        assert_eq!(sun.pdf(-sun.direction), 0.0);
+    }
+}
@@ -0,0 +1,589 @@
+//! Spectral rendering, where paths carry radiance at a few wavelengths instead of RGB, so that
+//! light interacts with surfaces by spectra and glass can split light by wavelength.
+//! RGB colors are upsampled to smooth spectra, which are sigmoids of quadratic polynomials.
+//! https://rgl.epfl.ch/publications/Jakob2019Spectral
+//! https://www.pbr-book.org/4ed/Radiometry,_Spectra,_and_Color/Color#RGBtoSpectrumConversion
+
+use crate::color::Color;
+use crate::prelude::*;
+
+use rayon::prelude::*;
+use std::ops::{Add, Div, Mul};
+use std::sync::OnceLock;
+
+/// Wavelengths each path carries
+pub const WAVELENGTHS: usize = 4;
+
+/// Range of visible wavelengths in nanometers
+pub const LAMBDA_MIN: float = 360.0;
+pub const LAMBDA_MAX: float = 830.0;
+
+/// Values of a spectrum at the sampled wavelengths
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledSpectrum(pub [float; WAVELENGTHS]);
+
+impl SampledSpectrum {
+    pub const ZERO: Self = Self([0.0; WAVELENGTHS]);
+    pub const ONE: Self = Self([1.0; WAVELENGTHS]);
+
+    pub fn average(self) -> float {
+        self.0.iter().sum::<float>() / WAVELENGTHS as float
+    }
+
+    pub fn max(self) -> float {
+        self.0.iter().copied().fold(float::NEG_INFINITY, float::max)
+    }
+
+    fn map(self, f: impl Fn(float) -> float) -> Self {
+        Self(self.0.map(f))
+    }
+
+    fn zip(self, other: Self, f: impl Fn(float, float) -> float) -> Self {
+        let mut values = self.0;
+        for (value, other) in values.iter_mut().zip(other.0) {
+            *value = f(*value, other);
+        }
+        Self(values)
+    }
+}
+
+impl Add for SampledSpectrum {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        self.zip(rhs, |a, b| a + b)
+    }
+}
+
+impl Mul for SampledSpectrum {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        self.zip(rhs, |a, b| a * b)
+    }
+}
+
+impl Mul<float> for SampledSpectrum {
+    type Output = Self;
+
+    fn mul(self, rhs: float) -> Self {
+        self.map(|a| a * rhs)
+    }
+}
+
+impl Div<float> for SampledSpectrum {
+    type Output = Self;
+
+    fn div(self, rhs: float) -> Self {
+        self.map(|a| a / rhs)
+    }
+}
+
+/// Wavelengths a path carries, in nanometers, and their densities
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [float; WAVELENGTHS],
+    /// Zero for wavelengths that no longer contribute
+    pub pdf: [float; WAVELENGTHS],
+}
+
+impl SampledWavelengths {
+    /// Hero wavelength chosen by `u` and the others at even offsets from it, each distributed
+    /// like the sensitivity of the eye
+    /// https://www.pbr-book.org/4ed/Radiometry,_Spectra,_and_Color/Sampling_Light_Wavelengths
+    pub fn sample_visible(u: float) -> Self {
+        let mut lambda = [0.0; WAVELENGTHS];
+        let mut pdf = [0.0; WAVELENGTHS];
+        for i in 0..WAVELENGTHS {
+            let u = u + i as float / WAVELENGTHS as float;
+            let u = u - u.floor();
+            lambda[i] = (538.0 - 138.888_89 * (0.856_910_6 - 1.827_502 * u).atanh())
+                .clamp(LAMBDA_MIN, LAMBDA_MAX);
+            pdf[i] = visible_pdf(lambda[i]);
+        }
+        Self { lambda, pdf }
+    }
+
+    /// Keeps only the hero wavelength, for paths that depend on the wavelength such as
+    /// refraction through dispersive glass
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.0;
+        }
+        self.pdf[0] /= WAVELENGTHS as float;
+    }
+
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|&pdf| pdf == 0.0)
+    }
+
+    /// Linear sRGB color of radiance at these wavelengths, through the CIE XYZ response of
+    /// the eye. White balanced so that the light RGB white stands for is white.
+    pub fn to_color(&self, radiance: SampledSpectrum) -> Color {
+        let mut xyz = [0.0; 3];
+        for i in 0..WAVELENGTHS {
+            if self.pdf[i] > 0.0 {
+                let cmf = cie_xyz(self.lambda[i]);
+                for (sum, cmf) in xyz.iter_mut().zip(cmf) {
+                    *sum += radiance.0[i] * cmf / self.pdf[i];
+                }
+            }
+        }
+        let color = Color::from_xyz(xyz[0], xyz[1], xyz[2]) / WAVELENGTHS as float;
+        let white = tables().white;
+        Color {
+            r: color.r / white.r,
+            g: color.g / white.g,
+            b: color.b / white.b,
+        }
+    }
+
+    /// Reflectance spectrum of a surface color, clamped to 0..1
+    pub fn reflectance(&self, rgb: Color) -> SampledSpectrum {
+        let sigmoid = RgbSigmoid::new(rgb);
+        SampledSpectrum(self.lambda.map(|lambda| sigmoid.eval(lambda)))
+    }
+
+    /// Emission spectrum of a light color, the white illuminant tinted by a reflectance
+    pub fn emission(&self, rgb: Color) -> SampledSpectrum {
+        let illuminant = SampledSpectrum(self.lambda.map(white_illuminant));
+        self.unbounded(rgb) * illuminant
+    }
+
+    /// Smooth spectrum of any nonnegative color, a reflectance scaled up as needed
+    pub fn unbounded(&self, rgb: Color) -> SampledSpectrum {
+        let max = rgb.r.max(rgb.g).max(rgb.b);
+        if max.is_nan() || max <= 0.0 {
+            return SampledSpectrum::ZERO;
+        }
+        // Tints are at most half bright so that saturated colors can be reached
+        let scale = 2.0 * max;
+        let sigmoid = RgbSigmoid::new(rgb / scale);
+        SampledSpectrum(self.lambda.map(|lambda| scale * sigmoid.eval(lambda)))
+    }
+}
+
+/// Density of `sample_visible` choosing a wavelength
+fn visible_pdf(lambda: float) -> float {
+    if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+        return 0.0;
+    }
+    0.003_939_804 / (0.0072 * (lambda - 538.0)).cosh().powi(2)
+}
+
+/// CIE 1931 color matching functions at a wavelength in nanometers, from their multi-lobe
+/// Gaussian fit
+/// https://jcgt.org/published/0002/02/01/
+pub fn cie_xyz(lambda: float) -> [float; 3] {
+    let g = |mean: float, below: float, above: float| {
+        let t = (lambda - mean) / if lambda < mean { below } else { above };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ]
+}
+
+/// Relative power of the light that RGB white stands for, the D65 daylight of sRGB
+/// approximated by a blackbody at its color temperature
+fn white_illuminant(lambda: float) -> float {
+    const TEMPERATURE: float = 6504.0;
+    // Second radiation constant in nanometer kelvins
+    const C2: float = 1.438_777e7;
+    const REFERENCE: float = 560.0;
+    let exponential = |lambda: float| (C2 / (lambda * TEMPERATURE)).exp() - 1.0;
+    (REFERENCE / lambda).powi(5) * exponential(REFERENCE) / exponential(lambda)
+}
+
+/// Smooth spectrum `sigmoid(c0 t² + c1 t + c2)`, where `t` is the wavelength mapped from the
+/// visible range to 0..1
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct RgbSigmoid([float; 3]);
+
+impl RgbSigmoid {
+    /// Spectrum that reflects white light as `rgb`, clamped to 0..1
+    fn new(rgb: Color) -> Self {
+        let rgb = [rgb.r, rgb.g, rgb.b].map(|c| if c > 0.0 { c.min(1.0) } else { 0.0 });
+        if rgb[0] == rgb[1] && rgb[1] == rgb[2] {
+            // Constant, found by inverting the sigmoid
+            let v = rgb[0];
+            return Self([0.0, 0.0, (v - 0.5) / (v * (1.0 - v)).sqrt()]);
+        }
+        tables().coefficients(rgb)
+    }
+
+    fn eval(self, lambda: float) -> float {
+        let t = (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN);
+        sigmoid((self.0[0] * t + self.0[1]) * t + self.0[2]) as float
+    }
+}
+
+fn sigmoid(x: float) -> float {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+/// Resolution of the coefficient table along each axis
+const TABLE_RESOLUTION: usize = 16;
+
+/// Wavelengths the spectra are integrated over when fitting them, 5 nm apart
+const FIT_SAMPLES: usize = 95;
+
+/// Sigmoid coefficients fitted to RGB colors, computed the first time they are needed.
+/// Colors are indexed by their largest component, and the other two relative to it.
+struct Tables {
+    /// Largest component at each table position, denser near black and full brightness
+    scale: [float; TABLE_RESOLUTION],
+    /// Coefficients for each largest component, its value, and the two ratios
+    coefficients: Vec<[float; 3]>,
+    /// Linear sRGB of the white illuminant, before white balancing
+    white: Color,
+}
+
+fn tables() -> &'static Tables {
+    static TABLES: OnceLock<Tables> = OnceLock::new();
+    TABLES.get_or_init(Tables::new)
+}
+
+impl Tables {
+    fn new() -> Self {
+        // Weights turning fit samples of a reflectance into the white balanced sRGB color it
+        // reflects the white illuminant as
+        let mut t = [0.0; FIT_SAMPLES];
+        let mut weights = [[0.0f64; 3]; FIT_SAMPLES];
+        let mut white = [0.0f64; 3];
+        for (i, (t, weight)) in t.iter_mut().zip(weights.iter_mut()).enumerate() {
+            *t = i as f64 / (FIT_SAMPLES - 1) as f64;
+            let lambda = LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * *t as float;
+            let [x, y, z] = cie_xyz(lambda).map(|c| c * white_illuminant(lambda));
+            let rgb = Color::from_xyz(x, y, z);
+            *weight = [rgb.r as f64, rgb.g as f64, rgb.b as f64];
+            for c in 0..3 {
+                white[c] += weight[c];
+            }
+        }
+        for weight in weights.iter_mut() {
+            for c in 0..3 {
+                weight[c] /= white[c];
+            }
+        }
+        // The white illuminant's color, integrated over the 5 nm steps
+        let white = white.map(|w| w * (LAMBDA_MAX - LAMBDA_MIN) as f64 / (FIT_SAMPLES - 1) as f64);
+        let fit = Fit { t, weights };
+
+        let n = TABLE_RESOLUTION;
+        let mut scale = [0.0; TABLE_RESOLUTION];
+        for (k, scale) in scale.iter_mut().enumerate() {
+            let smoothstep = |x: f64| x * x * (3.0 - 2.0 * x);
+            *scale = smoothstep(smoothstep(k as f64 / (n - 1) as f64)) as float;
+        }
+
+        // Each column along the largest component starts from the solution next to it
+        let columns: Vec<Vec<[float; 3]>> = (0..3 * n * n)
+            .into_par_iter()
+            .map(|column| {
+                let (largest, j, i) = (column / (n * n), column / n % n, column % n);
+                let (x, y) = (i as f64 / (n - 1) as f64, j as f64 / (n - 1) as f64);
+                let mut fitted = vec![[0.0; 3]; n];
+                let start = n / 5;
+                let mut solve = |k: usize, c: &mut [f64; 3]| {
+                    let z = scale[k] as f64;
+                    let mut rgb = [0.0; 3];
+                    rgb[largest] = z;
+                    rgb[(largest + 1) % 3] = x * z;
+                    rgb[(largest + 2) % 3] = y * z;
+                    fit.gauss_newton(rgb, c);
+                    fitted[k] = c.map(|c| c as float);
+                };
+                let mut c = [0.0; 3];
+                for k in start..n {
+                    solve(k, &mut c);
+                }
+                let mut c = [0.0; 3];
+                for k in (0..start).rev() {
+                    solve(k, &mut c);
+                }
+                fitted
+            })
+            .collect();
+
+        let mut coefficients = vec![[0.0; 3]; 3 * n * n * n];
+        for (column, fitted) in columns.into_iter().enumerate() {
+            let (largest, j, i) = (column / (n * n), column / n % n, column % n);
+            for (k, c) in fitted.into_iter().enumerate() {
+                coefficients[((largest * n + k) * n + j) * n + i] = c;
+            }
+        }
+
+        Self {
+            scale,
+            coefficients,
+            white: Color {
+                r: white[0] as float,
+                g: white[1] as float,
+                b: white[2] as float,
+            },
+        }
+    }
+
+    /// Trilinearly interpolated coefficients of a color with components in 0..1
+    fn coefficients(&self, rgb: [float; 3]) -> RgbSigmoid {
+        let n = TABLE_RESOLUTION;
+        let largest = if rgb[0] > rgb[1] && rgb[0] > rgb[2] {
+            0
+        } else if rgb[1] > rgb[2] {
+            1
+        } else {
+            2
+        };
+        let z = rgb[largest];
+        let x = rgb[(largest + 1) % 3] / z * (n - 1) as float;
+        let y = rgb[(largest + 2) % 3] / z * (n - 1) as float;
+        let xi = (x as usize).min(n - 2);
+        let yi = (y as usize).min(n - 2);
+        let zi = self.scale[1..n - 1].partition_point(|&s| s <= z);
+        let (dx, dy) = (x - xi as float, y - yi as float);
+        let dz = (z - self.scale[zi]) / (self.scale[zi + 1] - self.scale[zi]);
+
+        let at =
+            |k: usize, j: usize, i: usize| self.coefficients[((largest * n + k) * n + j) * n + i];
+        let lerp =
+            |a: [float; 3], b: [float; 3], t: float| [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t);
+        let plane = |k: usize| {
+            lerp(
+                lerp(at(k, yi, xi), at(k, yi, xi + 1), dx),
+                lerp(at(k, yi + 1, xi), at(k, yi + 1, xi + 1), dx),
+                dy,
+            )
+        };
+        RgbSigmoid(lerp(plane(zi), plane(zi + 1), dz))
+    }
+}
+
+/// Quadrature for the color a sigmoid spectrum reflects
+struct Fit {
+    t: [f64; FIT_SAMPLES],
+    weights: [[f64; 3]; FIT_SAMPLES],
+}
+
+impl Fit {
+    fn rgb(&self, c: [f64; 3]) -> [f64; 3] {
+        let mut rgb = [0.0; 3];
+        for (&t, weight) in self.t.iter().zip(self.weights.iter()) {
+            let x = (c[0] * t + c[1]) * t + c[2];
+            let s = 0.5 + x / (2.0 * (1.0 + x * x).sqrt());
+            for (sum, weight) in rgb.iter_mut().zip(weight) {
+                *sum += s * weight;
+            }
+        }
+        rgb
+    }
+
+    /// CIELAB color a sigmoid spectrum reflects, where differences are closer to how
+    /// different colors look than in RGB
+    fn lab(&self, c: [f64; 3]) -> [f64; 3] {
+        lab(self.rgb(c))
+    }
+
+    /// Refines coefficients `c` until their spectrum reflects `target`, or as close as
+    /// reflectances get for colors at the edge of the gamut
+    fn gauss_newton(&self, target: [f64; 3], c: &mut [f64; 3]) {
+        const STEP: f64 = 1e-5;
+        let target = lab(target);
+        let error = |c: [f64; 3]| {
+            let lab = self.lab(c);
+            let residual = [0, 1, 2].map(|i| lab[i] - target[i]);
+            (residual, residual.iter().map(|r| r * r).sum::<f64>())
+        };
+        let (mut residual, mut squared) = error(*c);
+        for _ in 0..30 {
+            if squared < 1e-6 {
+                break;
+            }
+
+            // Central differences for the Jacobian, columns by coefficient
+            let mut jacobian = [[0.0; 3]; 3];
+            for j in 0..3 {
+                let (mut up, mut down) = (*c, *c);
+                up[j] += STEP;
+                down[j] -= STEP;
+                let (up, down) = (self.lab(up), self.lab(down));
+                for i in 0..3 {
+                    jacobian[i][j] = (up[i] - down[i]) / (2.0 * STEP);
+                }
+            }
+            let step = match solve3(jacobian, residual) {
+                Some(step) => step,
+                None => break,
+            };
+
+            // Halve steps that overshoot
+            let mut scale = 1.0;
+            let improved = loop {
+                let mut next = [0, 1, 2].map(|i| c[i] - scale * step[i]);
+                // Colors at the edge of the gamut need steep sigmoids, kept finite
+                let max = next.iter().fold(0.0f64, |m, c| m.max(c.abs()));
+                if max > 200.0 {
+                    next = next.map(|c| c * 200.0 / max);
+                }
+                let (next_residual, next_squared) = error(next);
+                if next_squared < squared {
+                    break Some((next, next_residual, next_squared));
+                }
+                scale /= 2.0;
+                if scale < 1e-3 {
+                    break None;
+                }
+            };
+            match improved {
+                Some((next, next_residual, next_squared)) => {
+                    *c = next;
+                    residual = next_residual;
+                    squared = next_squared;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// CIELAB of a linear sRGB color, relative to RGB white
+fn lab(rgb: [f64; 3]) -> [f64; 3] {
+    const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+        [0.412_456_4, 0.357_576_1, 0.180_437_5],
+        [0.212_672_9, 0.715_152_2, 0.072_175],
+        [0.019_333_9, 0.119_192, 0.950_304_1],
+    ];
+    let f = |t: f64| {
+        const DELTA: f64 = 6.0 / 29.0;
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    };
+    let [x, y, z] = SRGB_TO_XYZ.map(|row| {
+        let white: f64 = row.iter().sum();
+        f((row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]) / white)
+    });
+    [116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z)]
+}
+
+/// Solution of `a x = b` by Cramer's rule, `None` if `a` is singular
+fn solve3(a: [[f64; 3]; 3], b: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(a);
+    if d.abs() < 1e-15 {
+        return None;
+    }
+    let mut x = [0.0; 3];
+    for (column, x) in x.iter_mut().enumerate() {
+        let mut m = a;
+        for row in 0..3 {
+            m[row][column] = b[row];
+        }
+        *x = det(m) / d;
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Color a reflectance spectrum reflects white light as, integrated finely
+    fn reflected(rgb: Color) -> Color {
+        let sigmoid = RgbSigmoid::new(rgb);
+        let mut xyz = [0.0; 3];
+        let mut white = [0.0; 3];
+        let mut lambda = LAMBDA_MIN;
+        while lambda <= LAMBDA_MAX {
+            let cmf = cie_xyz(lambda).map(|c| c * white_illuminant(lambda));
+            for c in 0..3 {
+                xyz[c] += sigmoid.eval(lambda) * cmf[c];
+                white[c] += cmf[c];
+            }
+            lambda += 1.0;
+        }
+        let color = Color::from_xyz(xyz[0], xyz[1], xyz[2]);
+        let white = Color::from_xyz(white[0], white[1], white[2]);
+        Color {
+            r: color.r / white.r,
+            g: color.g / white.g,
+            b: color.b / white.b,
+        }
+    }
+
+    #[test]
+    fn upsampled_colors_reflect_as_themselves() {
+        let colors = [
+            [0.5, 0.5, 0.5],
+            [0.8, 0.1, 0.1],
+            [0.1, 0.6, 0.2],
+            [0.2, 0.3, 0.9],
+            [0.9, 0.8, 0.3],
+            [0.05, 0.02, 0.01],
+        ];
+        for &color in colors.iter() {
+            let rgb = Color::from(color);
+            let back = reflected(rgb);
+            for (a, b) in [(rgb.r, back.r), (rgb.g, back.g), (rgb.b, back.b)] {
+                assert!((a - b).abs() < 0.02, "{:?} reflects as {:?}", rgb, back);
+            }
+            let spectrum = RgbSigmoid::new(rgb);
+            let mut lambda = LAMBDA_MIN;
+            while lambda <= LAMBDA_MAX {
+                assert!((0.0..=1.0).contains(&spectrum.eval(lambda)));
+                lambda += 10.0;
+            }
+        }
+    }
+
+    #[test]
+    fn white_light_is_white_on_film() {
+        let n = 20_000;
+        let mut sum = Color::BLACK;
+        for i in 0..n {
+            let wavelengths = SampledWavelengths::sample_visible((i as float + 0.5) / n as float);
+            let light = wavelengths.emission(Color::WHITE * 0.25);
+            sum = sum + wavelengths.to_color(light);
+        }
+        let mean = sum / n as float;
+        for c in [mean.r, mean.g, mean.b] {
+            assert!((c - 0.25).abs() < 0.005, "{:?}", mean);
+        }
+    }
+
+    #[test]
+    fn visible_wavelengths_follow_their_density() {
+        // The density integrates to one over the visible range
+        let mut total = 0.0;
+        let mut lambda = LAMBDA_MIN;
+        while lambda < LAMBDA_MAX {
+            total += visible_pdf(lambda + 0.5);
+            lambda += 1.0;
+        }
+        assert!((total - 1.0).abs() < 0.01, "{}", total);
+
+        let mut wavelengths = SampledWavelengths::sample_visible(0.3);
+        assert!(wavelengths
+            .lambda
+            .iter()
+            .all(|l| (LAMBDA_MIN..=LAMBDA_MAX).contains(l)));
+        let hero = wavelengths.pdf[0];
+        wavelengths.terminate_secondary();
+        assert!(wavelengths.secondary_terminated());
+        assert_eq!(wavelengths.pdf[0], hero / WAVELENGTHS as float);
+    }
+}
@@ -0,0 +1,188 @@
+//! Reader for STL triangle files, as exported by CAD programs
+//! https://en.wikipedia.org/wiki/STL_(file_format)
+
+use crate::mesh::{Mesh, MeshTriangle};
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Size of the header and of each triangle of a binary file
+const BINARY_HEADER: usize = 84;
+const BINARY_TRIANGLE: usize = 50;
+
+/// Loads an ASCII or binary STL file, scaled by `scale`.
+/// Corners at the same position become shared vertices, and triangles are flat shaded,
+/// as the stored facet normals only repeat the winding of the triangles.
+pub fn load<P: AsRef<Path>>(path: P, scale: float) -> io::Result<Mesh> {
+    parse(&fs::read(path)?, scale)
+}
+
+pub fn parse(bytes: &[u8], scale: float) -> io::Result<Mesh> {
+    // Binary files may also start with "solid", so their exact length is checked first
+    let binary_count = bytes
+        .get(80..BINARY_HEADER)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let corners = match binary_count {
+        Some(count) if bytes.len() == BINARY_HEADER + count * BINARY_TRIANGLE => {
+            read_binary(bytes, count)
+        }
+        _ if bytes.trim_ascii_start().starts_with(b"solid") => read_ascii(bytes)?,
+        _ => return Err(invalid("File is neither ASCII nor binary STL")),
+    };
+
+    let mut positions = Vec::new();
+    let mut triangles = Vec::with_capacity(corners.len() / 3);
+    let mut indices = HashMap::new();
+    let mut vertex = |p: Point| {
+        // Adding zero turns negative zeros positive, so that they match
+        let key = [
+            (p.x + 0.0).to_bits(),
+            (p.y + 0.0).to_bits(),
+            (p.z + 0.0).to_bits(),
+        ];
+        *indices.entry(key).or_insert_with(|| {
+            positions.push(p * scale);
+            positions.len() as u32 - 1
+        })
+    };
+    for triangle in corners.chunks_exact(3) {
+        triangles.push(MeshTriangle {
+            vertices: [
+                vertex(triangle[0]),
+                vertex(triangle[1]),
+                vertex(triangle[2]),
+            ],
+            material_id: None,
+        });
+    }
+
+    Ok(Mesh::new(positions, Vec::new(), Vec::new(), triangles))
+}
+
+/// Corners of each triangle, after their normal
+fn read_binary(bytes: &[u8], count: usize) -> Vec<Point> {
+    let read =
+        |at: usize| float::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+    let mut corners = Vec::with_capacity(count * 3);
+    for i in 0..count {
+        let triangle = BINARY_HEADER + i * BINARY_TRIANGLE;
+        for corner in 1..4 {
+            let at = triangle + corner * 12;
+            corners.push(Vector {
+                x: read(at),
+                y: read(at + 4),
+                z: read(at + 8),
+            });
+        }
+    }
+    corners
+}
+
+/// Corners from the `vertex` lines, three for each facet
+fn read_ascii(bytes: &[u8]) -> io::Result<Vec<Point>> {
+    let text = std::str::from_utf8(bytes).map_err(|_| invalid("STL file is not text"))?;
+    let mut words = text.split_ascii_whitespace();
+    let mut corners = Vec::new();
+    while let Some(word) = words.next() {
+        if word != "vertex" {
+            continue;
+        }
+        let mut coordinate = || -> io::Result<float> {
+            words
+                .next()
+                .and_then(|w| w.parse().ok())
+                .ok_or_else(|| invalid("Invalid STL vertex"))
+        };
+        corners.push(Vector {
+            x: coordinate()?,
+            y: coordinate()?,
+            z: coordinate()?,
+        });
+    }
+    if corners.len() % 3 != 0 {
+        return Err(invalid("STL facet without three vertices"));
+    }
+    Ok(corners)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two triangles of a unit square, sharing an edge
+    const ASCII: &str = "solid square
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 0 0
+      vertex 1 1 0
+    endloop
+  endfacet
+  facet normal 0 0 1
+    outer loop
+      vertex 0 0 0
+      vertex 1 1 0
+      vertex 0 1 0
+    endloop
+  endfacet
+endsolid square
+";
+
+    fn binary() -> Vec<u8> {
+        // Header starting like an ASCII file
+        let mut bytes = b"solid square".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend(2u32.to_le_bytes());
+        let triangles = [
+            [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]],
+            [[0.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+        ];
+        for triangle in triangles.iter() {
+            for x in [0.0f32, 0.0, 1.0].iter() {
+                bytes.extend(x.to_le_bytes());
+            }
+            for corner in triangle.iter() {
+                for x in corner.iter() {
+                    bytes.extend((*x as f32).to_le_bytes());
+                }
+            }
+            bytes.extend(0u16.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn ascii_and_binary_agree() {
+        let ascii = parse(ASCII.as_bytes(), 10.0).unwrap();
+        assert_eq!(ascii.len(), 2);
+        // Corners of the shared edge are merged
+        assert_eq!(ascii.positions.len(), 4);
+        assert_eq!(
+            ascii.positions[2],
+            Vector {
+                x: 10.0,
+                y: 10.0,
+                z: 0.0
+            }
+        );
+        assert!(approx_eq(ascii.area(), 100.0));
+
+        assert_eq!(parse(&binary(), 10.0).unwrap(), ascii);
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        assert!(parse(ASCII.replace("vertex 0 1 0", "vertex 0 1").as_bytes(), 1.0).is_err());
+        let bytes = binary();
+        assert!(parse(&bytes[..bytes.len() - 1], 1.0).is_err());
+        assert!(parse(b"not a mesh", 1.0).is_err());
+    }
+}
@@ -0,0 +1,589 @@
+use crate::color::Color;
+use crate::mipmap::{Filter, MipMap};
+use crate::noise;
+use crate::prelude::*;
+use crate::vector::{Point, Vector};
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Shared, immutable texture that can be used by any number of materials
+pub type TextureRef = Arc<dyn Texture>;
+
+/// Surface information available to a texture lookup
+#[derive(Debug, Clone, Copy)]
+pub struct SurfacePoint {
+    /// World space hit position
+    pub point: Point,
+    /// Unit surface normal
+    pub normal: Vector,
+    /// Surface parameterization, barycentric coordinates for triangles without UVs
+    pub uv: [float; 2],
+    /// Change of `uv` between neighbouring pixels, zero when unknown
+    pub duvdx: [float; 2],
+    pub duvdy: [float; 2],
+    /// Vertex color of meshes, white elsewhere
+    pub color: Color,
+}
+
+/// Procedural or constant value varying over a surface.
+/// Anything that can be used as a material parameter is a texture.
+pub trait Texture: fmt::Debug + Send + Sync {
+    /// Color at the given surface point
+    fn color(&self, at: &SurfacePoint) -> Color;
+
+    /// Scalar value at the given surface point, e.g. a roughness or a mixing ratio
+    fn value(&self, at: &SurfacePoint) -> float {
+        self.color(at).luminance()
+    }
+}
+
+/// Constant color
+impl Texture for Color {
+    fn color(&self, _at: &SurfacePoint) -> Color {
+        *self
+    }
+}
+
+/// Constant scalar, gray when used as a color
+impl Texture for float {
+    fn color(&self, _at: &SurfacePoint) -> Color {
+        Color::WHITE * *self
+    }
+
+    fn value(&self, _at: &SurfacePoint) -> float {
+        *self
+    }
+}
+
+/// Selects which coordinates a pattern is evaluated in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mapping {
+    /// World space position, for solid textures that do not need a parameterization
+    Solid,
+    /// Surface UV coordinates as (u, v, 0)
+    Uv,
+}
+
+impl Mapping {
+    fn domain(self, at: &SurfacePoint) -> Point {
+        match self {
+            Self::Solid => at.point,
+            Self::Uv => Point {
+                x: at.uv[0],
+                y: at.uv[1],
+                z: 0.0,
+            },
+        }
+    }
+}
+
+/// Alternating cells of two textures
+#[derive(Debug, Clone)]
+pub struct Checker {
+    pub even: TextureRef,
+    pub odd: TextureRef,
+    pub mapping: Mapping,
+}
+
+impl Checker {
+    fn select(&self, at: &SurfacePoint) -> &TextureRef {
+        let p = self.mapping.domain(at);
+        let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        if sum.rem_euclid(2) == 0 {
+            &self.even
+        } else {
+            &self.odd
+        }
+    }
+}
+
+impl Texture for Checker {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.select(at).color(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        self.select(at).value(at)
+    }
+}
+
+/// Linear gradient between two colors from `start` to `end`, clamped outside the segment
+#[derive(Debug, Clone)]
+pub struct Gradient {
+    pub from: Color,
+    pub to: Color,
+    pub start: Point,
+    pub end: Point,
+    pub mapping: Mapping,
+}
+
+impl Texture for Gradient {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.from.mix(self.to, self.value(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let axis = self.end - self.start;
+        let t = (self.mapping.domain(at) - self.start).dot(axis) / axis.len2();
+        t.clamp(0.0, 1.0)
+    }
+}
+
+/// Basis function for the noise textures
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoiseBasis {
+    Perlin,
+    Simplex,
+}
+
+impl NoiseBasis {
+    fn eval(self, p: Point) -> float {
+        match self {
+            Self::Perlin => noise::perlin(p),
+            Self::Simplex => noise::simplex(p),
+        }
+    }
+}
+
+/// Fractional Brownian motion noise, in range 0..1
+#[derive(Debug, Clone)]
+pub struct Fbm {
+    pub basis: NoiseBasis,
+    pub octaves: usize,
+    pub lacunarity: float,
+    pub gain: float,
+    pub mapping: Mapping,
+}
+
+impl Fbm {
+    pub fn new(basis: NoiseBasis, octaves: usize) -> Self {
+        Self {
+            basis,
+            octaves,
+            lacunarity: 2.0,
+            gain: 0.5,
+            mapping: Mapping::Solid,
+        }
+    }
+}
+
+impl Texture for Fbm {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        Color::WHITE * self.value(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let basis = self.basis;
+        let n = noise::fbm(
+            |p| basis.eval(p),
+            self.mapping.domain(at),
+            self.octaves,
+            self.lacunarity,
+            self.gain,
+        );
+        (0.5 + 0.5 * n).clamp(0.0, 1.0)
+    }
+}
+
+/// Sum of absolute noise octaves, in range 0..1
+#[derive(Debug, Clone)]
+pub struct Turbulence {
+    pub basis: NoiseBasis,
+    pub octaves: usize,
+    pub lacunarity: float,
+    pub gain: float,
+    pub mapping: Mapping,
+}
+
+impl Turbulence {
+    pub fn new(basis: NoiseBasis, octaves: usize) -> Self {
+        Self {
+            basis,
+            octaves,
+            lacunarity: 2.0,
+            gain: 0.5,
+            mapping: Mapping::Solid,
+        }
+    }
+}
+
+impl Texture for Turbulence {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        Color::WHITE * self.value(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let basis = self.basis;
+        noise::turbulence(
+            |p| basis.eval(p),
+            self.mapping.domain(at),
+            self.octaves,
+            self.lacunarity,
+            self.gain,
+        )
+        .clamp(0.0, 1.0)
+    }
+}
+
+/// Quantity of the cellular noise a `Voronoi` texture outputs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoronoiFeature {
+    /// Distance to the closest feature point
+    F1,
+    /// Distance to the second closest feature point
+    F2,
+    /// Difference of the two, zero on cell borders
+    Edge,
+    /// Random constant value per cell
+    Cell,
+}
+
+/// Worley (cellular) noise, in range 0..1
+#[derive(Debug, Clone)]
+pub struct Voronoi {
+    pub feature: VoronoiFeature,
+    pub mapping: Mapping,
+}
+
+impl Texture for Voronoi {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        Color::WHITE * self.value(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let w = noise::worley(self.mapping.domain(at));
+        let v = match self.feature {
+            VoronoiFeature::F1 => w.f1,
+            VoronoiFeature::F2 => w.f2,
+            VoronoiFeature::Edge => w.f2 - w.f1,
+            VoronoiFeature::Cell => w.cell,
+        };
+        v.clamp(0.0, 1.0)
+    }
+}
+
+/// Veined marble: sine bands along x, distorted by turbulence
+#[derive(Debug, Clone)]
+pub struct Marble {
+    pub base: Color,
+    pub vein: Color,
+    /// Bands per unit length
+    pub frequency: float,
+    /// Strength of the distortion
+    pub turbulence: float,
+    pub octaves: usize,
+    pub mapping: Mapping,
+}
+
+impl Texture for Marble {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.base.mix(self.vein, self.value(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let p = self.mapping.domain(at);
+        let t = noise::turbulence(noise::perlin, p, self.octaves, 2.0, 0.5);
+        let phase = (p.x * self.frequency + self.turbulence * t) * std::f32::consts::PI;
+        (0.5 + 0.5 * phase.sin()).powi(3)
+    }
+}
+
+/// Concentric growth rings around the y axis, distorted by noise
+#[derive(Debug, Clone)]
+pub struct Wood {
+    pub light: Color,
+    pub dark: Color,
+    /// Rings per unit length
+    pub frequency: float,
+    /// Strength of the distortion
+    pub turbulence: float,
+    pub mapping: Mapping,
+}
+
+impl Texture for Wood {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.light.mix(self.dark, self.value(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let p = self.mapping.domain(at);
+        let distortion = noise::perlin_vector(p) * self.turbulence;
+        let q = p + distortion;
+        let r = (q.x * q.x + q.z * q.z).sqrt() * self.frequency;
+        let ring = r - r.floor();
+        // Sharp dark edge at the start of each ring, fading out
+        (1.0 - ring).powi(4)
+    }
+}
+
+/// Scales the lookup coordinates, making the pattern smaller for factors above one
+#[derive(Debug, Clone)]
+pub struct Scale {
+    pub inner: TextureRef,
+    pub factor: Vector,
+}
+
+impl Scale {
+    fn transform(&self, at: &SurfacePoint) -> SurfacePoint {
+        SurfacePoint {
+            point: Point {
+                x: at.point.x * self.factor.x,
+                y: at.point.y * self.factor.y,
+                z: at.point.z * self.factor.z,
+            },
+            uv: [at.uv[0] * self.factor.x, at.uv[1] * self.factor.y],
+            duvdx: [at.duvdx[0] * self.factor.x, at.duvdx[1] * self.factor.y],
+            duvdy: [at.duvdy[0] * self.factor.x, at.duvdy[1] * self.factor.y],
+            ..*at
+        }
+    }
+}
+
+impl Texture for Scale {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.inner.color(&self.transform(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        self.inner.value(&self.transform(at))
+    }
+}
+
+/// Translates the lookup coordinates
+#[derive(Debug, Clone)]
+pub struct Offset {
+    pub inner: TextureRef,
+    pub offset: Vector,
+}
+
+impl Offset {
+    fn transform(&self, at: &SurfacePoint) -> SurfacePoint {
+        SurfacePoint {
+            point: at.point + self.offset,
+            uv: [at.uv[0] + self.offset.x, at.uv[1] + self.offset.y],
+            ..*at
+        }
+    }
+}
+
+impl Texture for Offset {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.inner.color(&self.transform(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        self.inner.value(&self.transform(at))
+    }
+}
+
+/// Blends two textures, using the scalar value of `amount` as the ratio of `b`
+#[derive(Debug, Clone)]
+pub struct Mix {
+    pub a: TextureRef,
+    pub b: TextureRef,
+    pub amount: TextureRef,
+}
+
+impl Mix {
+    fn ratio(&self, at: &SurfacePoint) -> float {
+        self.amount.value(at).clamp(0.0, 1.0)
+    }
+}
+
+impl Texture for Mix {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.a.color(at).mix(self.b.color(at), self.ratio(at))
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let t = self.ratio(at);
+        self.a.value(at) * (1.0 - t) + self.b.value(at) * t
+    }
+}
+
+/// Component-wise product of two textures
+#[derive(Debug, Clone)]
+pub struct Multiply {
+    pub a: TextureRef,
+    pub b: TextureRef,
+}
+
+impl Texture for Multiply {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        self.a.color(at) * self.b.color(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        self.a.value(at) * self.b.value(at)
+    }
+}
+
+/// Color of the vertices of a mesh, interpolated over its triangles
+#[derive(Debug, Clone, Copy)]
+pub struct VertexColor;
+
+impl Texture for VertexColor {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        at.color
+    }
+}
+
+/// Scalar from one channel of a color texture, such as the packed maps of glTF materials
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub texture: TextureRef,
+    /// Red, green or blue as 0, 1 or 2
+    pub channel: usize,
+}
+
+impl Texture for Channel {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        Color::WHITE * self.value(at)
+    }
+
+    fn value(&self, at: &SurfacePoint) -> float {
+        let color = self.texture.color(at);
+        match self.channel {
+            0 => color.r,
+            1 => color.g,
+            _ => color.b,
+        }
+    }
+}
+
+/// Image mapped using the surface UV coordinates, with v pointing up
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+    pub mipmap: Arc<MipMap>,
+    pub filter: Filter,
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, at: &SurfacePoint) -> Color {
+        let st = [at.uv[0], 1.0 - at.uv[1]];
+        let dst0 = [at.duvdx[0], -at.duvdx[1]];
+        let dst1 = [at.duvdy[0], -at.duvdy[1]];
+        self.mipmap.lookup(self.filter, st, dst0, dst1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: float, y: float, z: float) -> SurfacePoint {
+        SurfacePoint {
+            point: Point { x, y, z },
+            normal: Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            uv: [x, y],
+            duvdx: [0.0; 2],
+            duvdy: [0.0; 2],
+            color: Color::WHITE,
+        }
+    }
+
+    #[test]
+    fn checker_alternates() {
+        let checker = Checker {
+            even: Arc::new(Color::WHITE),
+            odd: Arc::new(Color::BLACK),
+            mapping: Mapping::Uv,
+        };
+
+        assert_eq!(checker.color(&at(0.5, 0.5, 0.0)), Color::WHITE);
+        assert_eq!(checker.color(&at(1.5, 0.5, 0.0)), Color::BLACK);
+        assert_eq!(checker.color(&at(-0.5, 0.5, 0.0)), Color::BLACK);
+        assert_eq!(checker.color(&at(1.5, 1.5, 0.0)), Color::WHITE);
+    }
+
+    #[test]
+    fn scale_and_offset_compose() {
+        let checker: TextureRef = Arc::new(Checker {
+            even: Arc::new(0.0),
+            odd: Arc::new(1.0),
+            mapping: Mapping::Solid,
+        });
+        let scaled = Scale {
+            inner: checker.clone(),
+            factor: Vector {
+                x: 2.0,
+                y: 2.0,
+                z: 2.0,
+            },
+        };
+        let offset = Offset {
+            inner: checker,
+            offset: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+
+        assert!(approx_eq(scaled.value(&at(0.25, 0.1, 0.1)), 0.0));
+        assert!(approx_eq(scaled.value(&at(0.75, 0.1, 0.1)), 1.0));
+        assert!(approx_eq(offset.value(&at(0.5, 0.1, 0.1)), 1.0));
+    }
+
+    #[test]
+    fn marble_and_wood_follow_their_mapping() {
+        let marble = Marble {
+            base: Color::WHITE,
+            vein: Color::BLACK,
+            frequency: 0.7,
+            turbulence: 2.0,
+            octaves: 3,
+            mapping: Mapping::Uv,
+        };
+        let wood = Wood {
+            light: Color::WHITE,
+            dark: Color::BLACK,
+            frequency: 3.0,
+            turbulence: 0.2,
+            mapping: Mapping::Uv,
+        };
+        // Same UV coordinates at different points
+        let (mut a, mut b) = (at(0.3, 0.6, 0.0), at(0.3, 0.6, 0.0));
+        a.point = Point {
+            x: 5.0,
+            y: -2.0,
+            z: 7.0,
+        };
+        assert_eq!(marble.color(&a), marble.color(&b));
+        assert_eq!(wood.color(&a), wood.color(&b));
+
+        b.uv = [0.4, 0.1];
+        assert_ne!(marble.value(&a), marble.value(&b));
+        assert_ne!(wood.value(&a), wood.value(&b));
+    }
+
+    #[test]
+    fn mix_by_gradient() {
+        let mix = Mix {
+            a: Arc::new(Color::RED),
+            b: Arc::new(Color::BLUE),
+            amount: Arc::new(Gradient {
+                from: Color::BLACK,
+                to: Color::WHITE,
+                start: Point::ZERO,
+                end: Point {
+                    x: 2.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                mapping: Mapping::Solid,
+            }),
+        };
+
+        let c = mix.color(&at(1.0, 5.0, 5.0));
+        assert!(approx_eq(c.r, 0.5));
+        assert!(approx_eq(c.b, 0.5));
+        assert_eq!(mix.color(&at(-1.0, 0.0, 0.0)), Color::RED);
+        assert_eq!(mix.color(&at(3.0, 0.0, 0.0)), Color::BLUE);
+    }
+}
@@ -1,8 +1,7 @@
 use crate::prelude::*;
-use rand::prelude::*;
 use rand_distr::{Distribution, Normal};
 
-use std::ops::{Add, Mul, Neg, Sub};
+use std::ops::{Add, Index, Mul, Neg, Sub};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Vector {
@@ -32,7 +31,8 @@ impl Vector {
 
     pub fn normalized(self) -> Self {
         let len = self.len();
-        assert!(len > 0.0001);
+        // Any length works, small scenes have short vectors
+        assert!(len > 0.0);
         Self {
             x: self.x / len,
             y: self.y / len,
@@ -57,6 +57,67 @@ impl Vector {
         self - normal * (2.0 * self.dot(normal) / self.len2())
     }
 
+    /// Direction refracted through a surface whose unit normal faces against it, where `eta` is
+    /// the index of refraction behind the surface over the one in front.
+    /// `None` for total internal reflection.
+    pub fn refract(self, normal: Self, eta: float) -> Option<Self> {
+        let direction = self.normalized();
+        let cos_i = -direction.dot(normal);
+        let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+        if sin2_t >= 1.0 {
+            return None;
+        }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some(direction * (1.0 / eta) + normal * (cos_i / eta - cos_t))
+    }
+
+    /// Component-wise minimum
+    pub fn min(self, other: Self) -> Self {
+        Self {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            z: self.z.min(other.z),
+        }
+    }
+
+    /// Component-wise maximum
+    pub fn max(self, other: Self) -> Self {
+        Self {
+            x: self.x.max(other.x),
+            y: self.y.max(other.y),
+            z: self.z.max(other.z),
+        }
+    }
+
+    /// Component-wise absolute value
+    pub fn abs(self) -> Self {
+        Self {
+            x: self.x.abs(),
+            y: self.y.abs(),
+            z: self.z.abs(),
+        }
+    }
+
+    /// Two unit vectors perpendicular to this unit vector and to each other
+    /// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+    pub fn coordinate_system(self) -> (Self, Self) {
+        let sign = (1.0 as float).copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Self {
+                x: 1.0 + sign * self.x * self.x * a,
+                y: sign * b,
+                z: -sign * self.x,
+            },
+            Self {
+                x: b,
+                y: sign + self.y * self.y * a,
+                z: -self.y,
+            },
+        )
+    }
+
     /// Random unit vector
     /// https://stackoverflow.com/a/8453514/2867076
     pub fn random() -> Self {
@@ -144,6 +205,20 @@ impl Mul<float> for Vector {
     }
 }
 
+/// Component by axis number, x = 0, y = 1, z = 2
+impl Index<usize> for Vector {
+    type Output = float;
+
+    fn index(&self, axis: usize) -> &float {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Axis out of range: {}", axis),
+        }
+    }
+}
+
 pub type Point = Vector;
 
 #[cfg(test)]
@@ -196,4 +271,26 @@ mod tests {
         assert!(approx_eq(d.y, 0.0));
         assert!(approx_eq(d.z, 0.0));
     }
+
+    #[test]
+    fn refraction_follows_snells_law() {
+        let normal = Vector {
+            x: 0.0,
+            y: 1.0,
+            z: 0.0,
+        };
+        let incoming = Vector {
+            x: 1.0,
+            y: -1.0,
+            z: 0.0,
+        };
+        let refracted = incoming.refract(normal, 1.5).unwrap();
+        let sin_i = (0.5 as float).sqrt();
+        assert!(approx_eq(refracted.len(), 1.0));
+        assert!(approx_eq(refracted.x, sin_i / 1.5));
+        assert!(refracted.y < 0.0);
+
+        // Leaving glass this steeply reflects everything
+        assert_eq!(incoming.refract(normal, 1.0 / 1.5), None);
+    }
 }
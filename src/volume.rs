@@ -0,0 +1,598 @@
+//! Voxel grids of density for smoke, clouds and fire, which vary through participating media,
+//! and readers for raw and Mitsuba `.vol` grid files
+//! https://www.pbr-book.org/4ed/Volume_Scattering/Media#GridMedium
+//! https://mitsuba.readthedocs.io/en/stable/src/generated/plugins_volumes.html#grid-based-volume-data-source-gridvolume
+
+use crate::bounds::Bounds;
+use crate::matrix::{Matrix, Transform};
+use crate::prelude::*;
+use crate::ray::Ray;
+use crate::vector::{Point, Vector};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Voxels along each side of a brick
+const BRICK: usize = 8;
+
+/// Cells along each side of the grid of majorants, coarse so that rays cross few of them
+const MAJORANT_RESOLUTION: usize = 16;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Number of voxels in a grid of `resolution`, `None` if it overflows
+pub(crate) fn voxel_count(resolution: [usize; 3]) -> Option<usize> {
+    resolution
+        .iter()
+        .try_fold(1_usize, |count, &n| count.checked_mul(n))
+}
+
+/// Values at the voxels of a box, stored in bricks of 8³ voxels. Bricks that are zero
+/// everywhere are left out like the leaf nodes of OpenVDB, so the empty space around smoke
+/// takes no memory.
+#[derive(Clone, PartialEq)]
+pub struct Grid {
+    resolution: [usize; 3],
+    /// Bricks along each axis
+    bricks_per_axis: [usize; 3],
+    /// `None` for bricks that are zero everywhere
+    bricks: Vec<Option<Box<[float]>>>,
+}
+
+impl Grid {
+    /// Grid of `resolution` voxels from their values, with x varying fastest and z slowest
+    pub fn new(resolution: [usize; 3], values: &[float]) -> Self {
+        assert_eq!(values.len(), resolution.iter().product::<usize>());
+        let bricks_per_axis = [
+            resolution[0].div_ceil(BRICK),
+            resolution[1].div_ceil(BRICK),
+            resolution[2].div_ceil(BRICK),
+        ];
+        let mut bricks = Vec::with_capacity(bricks_per_axis.iter().product());
+        for bz in 0..bricks_per_axis[2] {
+            for by in 0..bricks_per_axis[1] {
+                for bx in 0..bricks_per_axis[0] {
+                    let mut brick = vec![0.0; BRICK * BRICK * BRICK];
+                    for z in 0..BRICK.min(resolution[2] - bz * BRICK) {
+                        for y in 0..BRICK.min(resolution[1] - by * BRICK) {
+                            let row = ((bz * BRICK + z) * resolution[1] + by * BRICK + y)
+                                * resolution[0]
+                                + bx * BRICK;
+                            let width = BRICK.min(resolution[0] - bx * BRICK);
+                            let start = (z * BRICK + y) * BRICK;
+                            brick[start..start + width].copy_from_slice(&values[row..row + width]);
+                        }
+                    }
+                    let empty = brick.iter().all(|&value| value == 0.0);
+                    bricks.push(if empty {
+                        None
+                    } else {
+                        Some(brick.into_boxed_slice())
+                    });
+                }
+            }
+        }
+        Self {
+            resolution,
+            bricks_per_axis,
+            bricks,
+        }
+    }
+
+    /// Voxels along each axis
+    pub fn resolution(&self) -> [usize; 3] {
+        self.resolution
+    }
+
+    /// Value of a voxel, zero outside of the grid
+    pub fn voxel(&self, x: i64, y: i64, z: i64) -> float {
+        let [nx, ny, nz] = self.resolution;
+        if x < 0 || y < 0 || z < 0 || x as usize >= nx || y as usize >= ny || z as usize >= nz {
+            return 0.0;
+        }
+        let (x, y, z) = (x as usize, y as usize, z as usize);
+        let brick = ((z / BRICK) * self.bricks_per_axis[1] + y / BRICK) * self.bricks_per_axis[0]
+            + x / BRICK;
+        match &self.bricks[brick] {
+            Some(values) => values[((z % BRICK) * BRICK + y % BRICK) * BRICK + x % BRICK],
+            None => 0.0,
+        }
+    }
+
+    /// Trilinearly interpolated value at a point of the unit cube that the grid spans, with
+    /// the voxel values at the voxel centers. Fades to zero over the outer half voxel.
+    pub fn lookup(&self, point: Point) -> float {
+        let sample = |axis: usize| point[axis] * self.resolution[axis] as float - 0.5;
+        let (x, y, z) = (sample(0), sample(1), sample(2));
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (dx, dy, dz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i64, y0 as i64, z0 as i64);
+
+        let lerp = |t: float, a: float, b: float| a + (b - a) * t;
+        let row = |y: i64, z: i64| lerp(dx, self.voxel(x0, y, z), self.voxel(x0 + 1, y, z));
+        let slice = |z: i64| lerp(dy, row(y0, z), row(y0 + 1, z));
+        lerp(dz, slice(z0), slice(z0 + 1))
+    }
+
+    /// Largest value that lookups within a box of the unit cube return
+    pub fn max(&self, bounds: Bounds) -> float {
+        let range = |axis: usize| {
+            let n = self.resolution[axis] as float;
+            let last = self.resolution[axis] as i64 - 1;
+            let min = (bounds.min[axis] * n - 0.5).floor() as i64;
+            let max = (bounds.max[axis] * n - 0.5).floor() as i64 + 1;
+            min.clamp(0, last)..=max.clamp(0, last)
+        };
+        let mut max: float = 0.0;
+        for z in range(2) {
+            for y in range(1) {
+                for x in range(0) {
+                    max = max.max(self.voxel(x, y, z));
+                }
+            }
+        }
+        max
+    }
+}
+
+/// Grids can be huge, so only their size is shown
+impl fmt::Debug for Grid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Grid")
+            .field("resolution", &self.resolution)
+            .field(
+                "stored_bricks",
+                &self.bricks.iter().filter(|brick| brick.is_some()).count(),
+            )
+            .finish()
+    }
+}
+
+/// Density grid placed in the scene, with an optional grid scaling the emission of fire
+#[derive(Debug, Clone, PartialEq)]
+pub struct Volume {
+    density: Grid,
+    emission: Option<Grid>,
+    /// From the unit cube the grids span to world space
+    transform: Transform,
+    /// Largest density in each cell of a coarse grid over the unit cube
+    majorants: Vec<float>,
+}
+
+impl Volume {
+    /// Volume filling `bounds` in the space that `to_world` maps to world space.
+    /// `None` if the bounds are flat or the transform can not be inverted.
+    pub fn new(
+        density: Grid,
+        emission: Option<Grid>,
+        bounds: Bounds,
+        to_world: Matrix,
+    ) -> Option<Self> {
+        let transform = Transform::new(
+            to_world * Matrix::translation(bounds.min) * Matrix::scale(bounds.diagonal()),
+        )?;
+
+        let n = MAJORANT_RESOLUTION;
+        let mut majorants = Vec::with_capacity(n * n * n);
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let corner = |x: usize, y: usize, z: usize| Point {
+                        x: x as float / n as float,
+                        y: y as float / n as float,
+                        z: z as float / n as float,
+                    };
+                    majorants.push(density.max(Bounds {
+                        min: corner(x, y, z),
+                        max: corner(x + 1, y + 1, z + 1),
+                    }));
+                }
+            }
+        }
+
+        Some(Self {
+            density,
+            emission,
+            transform,
+            majorants,
+        })
+    }
+
+    /// World space box around the volume
+    pub fn bounds(&self) -> Bounds {
+        self.transform.bounds(Bounds {
+            min: Vector::ZERO,
+            max: Point {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        })
+    }
+
+    /// Density at a world space point, zero outside of the volume
+    pub fn density(&self, point: Point) -> float {
+        self.density
+            .lookup(self.transform.to_object.mul_translate(point))
+    }
+
+    /// Scale of the emission at a world space point, one everywhere without an emission grid
+    pub fn emission(&self, point: Point) -> float {
+        match &self.emission {
+            Some(emission) => emission.lookup(self.transform.to_object.mul_translate(point)),
+            None => 1.0,
+        }
+    }
+
+    /// Parts of a ray up to `distance` inside the volume, each with a bound on the density in it
+    pub fn majorants(&self, ray: &Ray, distance: float) -> Majorants<'_> {
+        // Parameters along the transformed ray are the same as along the world space one
+        let origin = self.transform.to_object.mul_translate(ray.origin);
+        let direction = self.transform.to_object.mul_rotate(ray.direction);
+
+        // Clip the ray to the unit cube
+        let mut t_min: float = 0.0;
+        let mut t_max = distance;
+        for axis in 0..3 {
+            let inverse = 1.0 / direction[axis];
+            let near = -origin[axis] * inverse;
+            let far = (1.0 - origin[axis]) * inverse;
+            let (near, far) = if near <= far {
+                (near, far)
+            } else {
+                (far, near)
+            };
+            // NaN when the ray runs along a face, which then bounds nothing
+            t_min = t_min.max(near);
+            t_max = t_max.min(far);
+        }
+        if t_min >= t_max {
+            return Majorants::uniform(0.0);
+        }
+        Majorants::dda(
+            &self.majorants,
+            MAJORANT_RESOLUTION,
+            origin,
+            direction,
+            t_min,
+            t_max,
+        )
+    }
+}
+
+/// Part of a ray with an upper bound of the density along it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub t_min: float,
+    pub t_max: float,
+    pub majorant: float,
+}
+
+/// Segments of a ray through the cells of a majorant grid, found by walking the grid like
+/// a digital differential analyzer
+/// https://www.pbr-book.org/4ed/Volume_Scattering/Media#DDAMajorantIterator
+#[derive(Debug, Clone)]
+pub struct Majorants<'a> {
+    majorants: &'a [float],
+    resolution: usize,
+    t_min: float,
+    t_max: float,
+    /// Current cell
+    cell: [i64; 3],
+    /// Parameter at which the ray enters the next cell along each axis
+    next_crossing: [float; 3],
+    /// Parameter the ray takes to cross a cell along each axis
+    delta: [float; 3],
+    step: [i64; 3],
+}
+
+impl<'a> Majorants<'a> {
+    /// Single segment up to `distance` with a majorant of one, for homogeneous media
+    pub fn uniform(distance: float) -> Self {
+        Self {
+            majorants: &[1.0],
+            resolution: 1,
+            t_min: 0.0,
+            t_max: distance,
+            cell: [0; 3],
+            next_crossing: [float::INFINITY; 3],
+            delta: [0.0; 3],
+            step: [0; 3],
+        }
+    }
+
+    /// Segments from `t_min` to `t_max` of a ray in the unit cube that a grid of majorants spans
+    fn dda(
+        majorants: &'a [float],
+        resolution: usize,
+        origin: Point,
+        direction: Vector,
+        t_min: float,
+        t_max: float,
+    ) -> Self {
+        let n = resolution as float;
+        let mut cell = [0; 3];
+        let mut next_crossing = [float::INFINITY; 3];
+        let mut delta = [0.0; 3];
+        let mut step = [0; 3];
+        for axis in 0..3 {
+            let entry = (origin[axis] + direction[axis] * t_min) * n;
+            cell[axis] = (entry.floor() as i64).clamp(0, resolution as i64 - 1);
+            let d = direction[axis] * n;
+            if d > 0.0 {
+                next_crossing[axis] = t_min + ((cell[axis] + 1) as float - entry) / d;
+                delta[axis] = 1.0 / d;
+                step[axis] = 1;
+            } else if d < 0.0 {
+                next_crossing[axis] = t_min + (cell[axis] as float - entry) / d;
+                delta[axis] = -1.0 / d;
+                step[axis] = -1;
+            }
+        }
+        Self {
+            majorants,
+            resolution,
+            t_min,
+            t_max,
+            cell,
+            next_crossing,
+            delta,
+            step,
+        }
+    }
+}
+
+impl Iterator for Majorants<'_> {
+    type Item = Segment;
+
+    fn next(&mut self) -> Option<Segment> {
+        if self.t_min.is_nan() || self.t_min >= self.t_max {
+            return None;
+        }
+        let n = self.resolution as i64;
+        let [x, y, z] = self.cell;
+        let majorant = self.majorants[((z * n + y) * n + x) as usize];
+
+        let axis = (0..3)
+            .min_by(|&a, &b| self.next_crossing[a].total_cmp(&self.next_crossing[b]))
+            .unwrap();
+        let t_end = self.t_max.min(self.next_crossing[axis]);
+        let segment = Segment {
+            t_min: self.t_min,
+            t_max: t_end,
+            majorant,
+        };
+
+        self.t_min = t_end;
+        self.cell[axis] += self.step[axis];
+        self.next_crossing[axis] += self.delta[axis];
+        if self.cell[axis] < 0 || self.cell[axis] >= n {
+            self.t_min = self.t_max;
+        }
+        Some(segment)
+    }
+}
+
+/// Reads a Mitsuba `.vol` grid, which also gives the box it fills
+pub fn load_vol<P: AsRef<Path>>(path: P) -> io::Result<(Grid, Bounds)> {
+    parse_vol(&fs::read(path)?)
+}
+
+pub fn parse_vol(bytes: &[u8]) -> io::Result<(Grid, Bounds)> {
+    const HEADER: usize = 48;
+    if bytes.len() < HEADER || &bytes[..3] != b"VOL" {
+        return Err(invalid("Not a .vol grid"));
+    }
+    if bytes[3] != 3 {
+        return Err(invalid("Unsupported .vol version"));
+    }
+    let word = |i: usize| {
+        let mut array = [0; 4];
+        array.copy_from_slice(&bytes[4 + 4 * i..8 + 4 * i]);
+        array
+    };
+    let int = |i: usize| i32::from_le_bytes(word(i));
+    let number = |i: usize| f32::from_le_bytes(word(i)) as float;
+
+    let encoding = int(0);
+    let resolution = [int(1), int(2), int(3)];
+    if resolution.iter().any(|&n| n <= 0) {
+        return Err(invalid("Empty .vol grid"));
+    }
+    let resolution = resolution.map(|n| n as usize);
+    if int(4) != 1 {
+        return Err(invalid("Only single channel .vol grids are supported"));
+    }
+    let bounds = Bounds {
+        min: Point {
+            x: number(5),
+            y: number(6),
+            z: number(7),
+        },
+        max: Point {
+            x: number(8),
+            y: number(9),
+            z: number(10),
+        },
+    };
+
+    let voxels = voxel_count(resolution).ok_or_else(|| invalid("Oversized .vol grid"))?;
+    let data = &bytes[HEADER..];
+    let values = match encoding {
+        // 32 bit floats
+        1 => decode_f32(data, voxels),
+        // Bytes, from zero to one
+        3 => decode_u8(data, voxels),
+        _ => return Err(invalid("Unsupported .vol encoding")),
+    }
+    .ok_or_else(|| invalid("Truncated .vol grid"))?;
+    Ok((Grid::new(resolution, &values), bounds))
+}
+
+/// Reads a grid of `resolution` voxels without a header, as little endian 32 bit floats or as
+/// bytes from zero to one depending on the size of the file, with x varying fastest
+pub fn load_raw<P: AsRef<Path>>(path: P, resolution: [usize; 3]) -> io::Result<Grid> {
+    parse_raw(&fs::read(path)?, resolution)
+}
+
+pub fn parse_raw(bytes: &[u8], resolution: [usize; 3]) -> io::Result<Grid> {
+    let voxels = voxel_count(resolution).ok_or_else(|| invalid("Oversized raw grid"))?;
+    let values = if bytes.len() == voxels {
+        decode_u8(bytes, voxels)
+    } else if Some(bytes.len()) == voxels.checked_mul(4) {
+        decode_f32(bytes, voxels)
+    } else {
+        None
+    }
+    .ok_or_else(|| invalid("Raw grid size does not match its resolution"))?;
+    Ok(Grid::new(resolution, &values))
+}
+
+fn decode_f32(bytes: &[u8], count: usize) -> Option<Vec<float>> {
+    let bytes = bytes.get(..count.checked_mul(4)?)?;
+    let values = bytes.chunks_exact(4).map(|chunk| {
+        let mut array = [0; 4];
+        array.copy_from_slice(chunk);
+        f32::from_le_bytes(array) as float
+    });
+    Some(values.collect())
+}
+
+fn decode_u8(bytes: &[u8], count: usize) -> Option<Vec<float>> {
+    let bytes = bytes.get(..count)?;
+    Some(bytes.iter().map(|&byte| byte as float / 255.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::angle::Angle;
+
+    fn v(x: float, y: float, z: float) -> Vector {
+        Vector { x, y, z }
+    }
+
+    /// Grid whose density rises along x, with an empty half along z
+    fn ramp() -> Grid {
+        let resolution = [20, 12, 10];
+        let mut values = Vec::new();
+        for z in 0..10 {
+            for _ in 0..12 {
+                for x in 0..20 {
+                    values.push(if z < 5 { x as float } else { 0.0 });
+                }
+            }
+        }
+        Grid::new(resolution, &values)
+    }
+
+    #[test]
+    fn lookups_interpolate_between_voxel_centers() {
+        let grid = ramp();
+        assert_eq!(grid.voxel(7, 3, 2), 7.0);
+        assert_eq!(grid.voxel(7, 3, 8), 0.0);
+        assert_eq!(grid.voxel(-1, 3, 2), 0.0);
+        // Only the bricks that are not zero are stored
+        assert_eq!(
+            grid.bricks.iter().filter(|brick| brick.is_some()).count(),
+            3 * 2
+        );
+
+        let center = |x: usize| (x as float + 0.5) / 20.0;
+        let at = |x: float| grid.lookup(v(x, 0.5, 0.1));
+        assert!(approx_eq(at(center(7)), 7.0));
+        assert!(approx_eq(at((center(7) + center(8)) / 2.0), 7.5));
+        // Fades to zero beyond the last voxel center
+        assert!(approx_eq(at(1.0), 19.0 / 2.0));
+        assert!(approx_eq(grid.lookup(v(0.5, 0.5, 0.9)), 0.0));
+
+        let half = Bounds {
+            min: v(0.0, 0.0, 0.0),
+            max: v(0.5, 1.0, 1.0),
+        };
+        assert_eq!(grid.max(half), 10.0);
+    }
+
+    #[test]
+    fn majorants_bound_the_density_along_rays() {
+        let volume = Volume::new(
+            ramp(),
+            None,
+            Bounds {
+                min: v(-1.0, -2.0, 0.0),
+                max: v(3.0, 1.0, 2.0),
+            },
+            Matrix::rotation(v(0.0, 1.0, 0.0), Angle { radians: 0.5 }),
+        )
+        .unwrap();
+        let (center, radius) = volume.bounds().bounding_sphere();
+
+        for _ in 0..200 {
+            let origin = center + Vector::random() * (radius * 2.0);
+            let target = center + Vector::random() * (radius * 0.5);
+            let ray = Ray::new(origin, target - origin);
+            let distance = (target - origin).len() * 2.0;
+
+            let mut end = None;
+            for segment in volume.majorants(&ray, distance) {
+                assert!(segment.t_min <= segment.t_max);
+                assert!(segment.t_max <= distance);
+                if let Some(end) = end {
+                    assert!(approx_eq(segment.t_min, end));
+                }
+                end = Some(segment.t_max);
+                for i in 0..20 {
+                    let t =
+                        segment.t_min + (segment.t_max - segment.t_min) * (i as float + 0.5) / 20.0;
+                    assert!(volume.density(ray.at(t)) <= segment.majorant + 1e-3);
+                }
+            }
+        }
+
+        // Rays that miss the volume cross nothing
+        let ray = Ray::new(center + v(0.0, radius * 2.0, 0.0), v(1.0, 0.0, 0.0));
+        assert_eq!(volume.majorants(&ray, float::INFINITY).count(), 0);
+    }
+
+    #[test]
+    fn reads_vol_and_raw_grids() {
+        let mut bytes = b"VOL\x03".to_vec();
+        for int in [1i32, 2, 1, 1, 1].iter() {
+            bytes.extend_from_slice(&int.to_le_bytes());
+        }
+        for number in [0.0f32, 0.0, 0.0, 2.0, 1.0, 1.0, 0.25, 0.75].iter() {
+            bytes.extend_from_slice(&number.to_le_bytes());
+        }
+        let (grid, bounds) = parse_vol(&bytes).unwrap();
+        assert_eq!(grid.resolution(), [2, 1, 1]);
+        assert_eq!(bounds.max, v(2.0, 1.0, 1.0));
+        assert_eq!(grid.voxel(1, 0, 0), 0.75);
+        assert!(parse_vol(&bytes[..bytes.len() - 1]).is_err());
+
+        let grid = parse_raw(&[0, 255, 51], [3, 1, 1]).unwrap();
+        assert!(approx_eq(grid.voxel(2, 0, 0), 0.2));
+        let grid = parse_raw(&bytes[48..], [2, 1, 1]).unwrap();
+        assert_eq!(grid.voxel(0, 0, 0), 0.25);
+        assert!(parse_raw(&[0, 1], [3, 1, 1]).is_err());
+
+        // Sizes that overflow are errors rather than panics
+        let huge = (i32::MAX as u32).to_le_bytes();
+        let mut oversized = bytes.clone();
+        for i in 1..4 {
+            oversized[4 + 4 * i..8 + 4 * i].copy_from_slice(&huge);
+        }
+        assert!(parse_vol(&oversized).is_err());
+        let mut wrapping = bytes.clone();
+        // 2^62 voxels of 4 bytes wrap around to zero bytes
+        wrapping[8..12].copy_from_slice(&(1i32 << 30).to_le_bytes());
+        wrapping[12..16].copy_from_slice(&(1i32 << 30).to_le_bytes());
+        wrapping[16..20].copy_from_slice(&4i32.to_le_bytes());
+        assert!(parse_vol(&wrapping).is_err());
+        assert!(parse_raw(&[0; 8], [usize::MAX, 2, 1]).is_err());
+        assert!(parse_raw(&[], [1 << 62, 1, 1]).is_err());
+    }
+}